@@ -0,0 +1,80 @@
+//! Times encoding and decoding the high-frequency `Heartbeat` and
+//! `Coordinator` payloads with each wire codec:
+//!
+//! ```text
+//! cargo run --release --example codec_bench --features bincode [iterations]
+//! ```
+//!
+//! Only the payload codec is timed; the length prefix, protocol version and
+//! any HMAC tag around it are the same for both.
+
+use cloud_p2p::message::Message;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+struct Timing {
+    bytes: usize,
+    encode: Duration,
+    decode: Duration,
+}
+
+fn time(iterations: u32, encode: impl Fn() -> Vec<u8>, decode: impl Fn(&[u8]) -> Message) -> Timing {
+    let payload = encode();
+    let started = Instant::now();
+    for _ in 0..iterations {
+        black_box(encode());
+    }
+    let encode_time = started.elapsed();
+    let started = Instant::now();
+    for _ in 0..iterations {
+        black_box(decode(black_box(&payload)));
+    }
+    Timing {
+        bytes: payload.len(),
+        encode: encode_time / iterations,
+        decode: started.elapsed() / iterations,
+    }
+}
+
+fn main() {
+    let iterations = std::env::args().nth(1).map_or(1_000_000, |arg| arg.parse().expect("iterations"));
+    let samples = [
+        Message::Heartbeat {
+            node_id: 3,
+            term: 12,
+            sent_at_us: 1_712_345_678,
+            rtt_us: Some(240),
+        },
+        Message::Coordinator {
+            leader_id: 7,
+            successor_id: Some(6),
+            backup_successors: vec![5, 4],
+            term: 12,
+            correlation_id: 0x5eed_1234_abcd_ef01,
+        },
+    ];
+
+    println!("{:<12} {:<8} {:>6} {:>12} {:>12}", "message", "codec", "bytes", "encode", "decode");
+    for message in &samples {
+        let json = time(
+            iterations,
+            || serde_json::to_vec(message).unwrap(),
+            |payload| serde_json::from_slice(payload).unwrap(),
+        );
+        let bincode = time(
+            iterations,
+            || bincode::serialize(message).unwrap(),
+            |payload| bincode::deserialize(payload).unwrap(),
+        );
+        for (codec, timing) in [("json", &json), ("bincode", &bincode)] {
+            println!(
+                "{:<12} {:<8} {:>6} {:>12?} {:>12?}",
+                message.kind(),
+                codec,
+                timing.bytes,
+                timing.encode,
+                timing.decode
+            );
+        }
+    }
+}
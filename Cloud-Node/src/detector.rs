@@ -0,0 +1,412 @@
+use crate::node::Timings;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+
+/// Decides when a silent peer should be presumed dead
+pub trait FailureDetector: Send + Sync {
+    /// Record that a periodic liveness message (`Heartbeat` or `Coordinator`)
+    /// from `node_id` arrived at `now`
+    fn heartbeat(&mut self, node_id: u32, now: Instant);
+
+    /// Forget `node_id`'s history and start watching it afresh from `now`,
+    /// e.g. when it has just become our leader
+    fn reset(&mut self, node_id: u32, now: Instant);
+
+    /// Stop tracking `node_id`
+    fn remove(&mut self, node_id: u32);
+
+    /// Whether `node_id` should be presumed dead at `now`; nodes never heard
+    /// from are not considered failed
+    fn is_failed(&self, node_id: u32, now: Instant) -> bool;
+
+    /// Whether `node_id` is halfway to being presumed dead, so it is worth
+    /// probing before `is_failed` trips. Defaults to `is_failed`.
+    fn is_suspect(&self, node_id: u32, now: Instant) -> bool {
+        self.is_failed(node_id, now)
+    }
+}
+
+/// Which failure detector a node runs, selected by the config file's
+/// `failure_detector` object (`{"kind": "fixed_timeout"}` by default)
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DetectorConfig {
+    /// Presume a node dead after `failure_timeout_ms` of silence
+    #[default]
+    FixedTimeout,
+    /// Presume a node dead once the suspicion level phi, derived from its
+    /// recent heartbeat inter-arrival times, exceeds `threshold`
+    PhiAccrual {
+        #[serde(default = "default_phi_threshold")]
+        threshold: f64,
+        /// Inter-arrival samples kept per node
+        #[serde(default = "default_phi_window")]
+        window: usize,
+        /// Floor on the standard deviation, so a very regular stream does not
+        /// make the detector hair-trigger
+        #[serde(
+            rename = "min_std_dev_ms",
+            default = "default_phi_min_std_dev",
+            with = "crate::node::duration_ms"
+        )]
+        min_std_dev: Duration,
+    },
+}
+
+fn default_phi_threshold() -> f64 {
+    8.0
+}
+
+fn default_phi_window() -> usize {
+    100
+}
+
+fn default_phi_min_std_dev() -> Duration {
+    Duration::from_millis(500)
+}
+
+impl DetectorConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Self::PhiAccrual { threshold, window, min_std_dev } = *self {
+            if threshold.is_nan() || threshold <= 0.0 {
+                anyhow::bail!("Phi threshold must be positive (got {})", threshold);
+            }
+            if window < 2 {
+                anyhow::bail!("Phi window must hold at least 2 samples (got {})", window);
+            }
+            if min_std_dev.is_zero() {
+                anyhow::bail!("Phi minimum standard deviation must be non-zero");
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the configured detector
+    pub fn build(&self, timings: &Timings) -> Box<dyn FailureDetector> {
+        match *self {
+            Self::FixedTimeout => Box::new(FixedTimeoutDetector::new(timings.failure_timeout)),
+            Self::PhiAccrual { threshold, window, min_std_dev } => Box::new(PhiAccrualDetector::new(
+                threshold,
+                window,
+                min_std_dev,
+                timings.failure_timeout,
+            )),
+        }
+    }
+}
+
+/// The original detector: a node is dead once it has been silent for longer than `timeout`
+#[derive(Debug)]
+pub struct FixedTimeoutDetector {
+    timeout: Duration,
+    last_seen: HashMap<u32, Instant>,
+}
+
+impl FixedTimeoutDetector {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_seen: HashMap::new(),
+        }
+    }
+}
+
+impl FailureDetector for FixedTimeoutDetector {
+    fn heartbeat(&mut self, node_id: u32, now: Instant) {
+        self.last_seen.insert(node_id, now);
+    }
+
+    fn reset(&mut self, node_id: u32, now: Instant) {
+        self.last_seen.insert(node_id, now);
+    }
+
+    fn remove(&mut self, node_id: u32) {
+        self.last_seen.remove(&node_id);
+    }
+
+    fn is_failed(&self, node_id: u32, now: Instant) -> bool {
+        self.last_seen
+            .get(&node_id)
+            .is_some_and(|&seen| now.saturating_duration_since(seen) > self.timeout)
+    }
+
+    fn is_suspect(&self, node_id: u32, now: Instant) -> bool {
+        self.last_seen
+            .get(&node_id)
+            .is_some_and(|&seen| now.saturating_duration_since(seen) > self.timeout / 2)
+    }
+}
+
+/// Heartbeat history for one node
+#[derive(Debug, Default)]
+struct ArrivalWindow {
+    last: Option<Instant>,
+    intervals: VecDeque<f64>, // milliseconds
+}
+
+/// Phi-accrual detector (Hayashibara et al.): phi is -log10 of the probability
+/// that a heartbeat arrives later than now, given a normal distribution fitted
+/// to recent inter-arrival times. It adapts to each node's actual heartbeat
+/// rhythm, so a slow but steady node is not mistaken for a dead one.
+#[derive(Debug)]
+pub struct PhiAccrualDetector {
+    threshold: f64,
+    window: usize,
+    min_std_dev: Duration,
+    /// Used until a node has produced at least one inter-arrival sample
+    bootstrap_timeout: Duration,
+    arrivals: HashMap<u32, ArrivalWindow>,
+}
+
+impl PhiAccrualDetector {
+    pub fn new(threshold: f64, window: usize, min_std_dev: Duration, bootstrap_timeout: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            min_std_dev,
+            bootstrap_timeout,
+            arrivals: HashMap::new(),
+        }
+    }
+
+    /// Current suspicion level for `node_id`, or `None` while there is no
+    /// inter-arrival history to judge it by
+    pub fn phi(&self, node_id: u32, now: Instant) -> Option<f64> {
+        let arrivals = self.arrivals.get(&node_id)?;
+        let last = arrivals.last?;
+        if arrivals.intervals.is_empty() {
+            return None;
+        }
+
+        let count = arrivals.intervals.len() as f64;
+        let mean = arrivals.intervals.iter().sum::<f64>() / count;
+        let variance = arrivals.intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / count;
+        let std_dev = variance.sqrt().max(self.min_std_dev.as_secs_f64() * 1000.0);
+
+        let elapsed = now.saturating_duration_since(last).as_secs_f64() * 1000.0;
+        Some(phi(elapsed, mean, std_dev))
+    }
+}
+
+/// -log10(P(arrival later than `elapsed`)) for a normal distribution, using the
+/// logistic approximation of its CDF from Akka's detector
+fn phi(elapsed: f64, mean: f64, std_dev: f64) -> f64 {
+    let y = (elapsed - mean) / std_dev;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+impl FailureDetector for PhiAccrualDetector {
+    fn heartbeat(&mut self, node_id: u32, now: Instant) {
+        let arrivals = self.arrivals.entry(node_id).or_default();
+        if let Some(last) = arrivals.last {
+            if arrivals.intervals.len() == self.window {
+                arrivals.intervals.pop_front();
+            }
+            arrivals
+                .intervals
+                .push_back(now.saturating_duration_since(last).as_secs_f64() * 1000.0);
+        }
+        arrivals.last = Some(now);
+    }
+
+    fn reset(&mut self, node_id: u32, now: Instant) {
+        self.arrivals.insert(
+            node_id,
+            ArrivalWindow {
+                last: Some(now),
+                intervals: VecDeque::new(),
+            },
+        );
+    }
+
+    fn remove(&mut self, node_id: u32) {
+        self.arrivals.remove(&node_id);
+    }
+
+    fn is_failed(&self, node_id: u32, now: Instant) -> bool {
+        match self.phi(node_id, now) {
+            Some(phi) => phi > self.threshold,
+            None => self
+                .arrivals
+                .get(&node_id)
+                .and_then(|arrivals| arrivals.last)
+                .is_some_and(|last| now.saturating_duration_since(last) > self.bootstrap_timeout),
+        }
+    }
+
+    fn is_suspect(&self, node_id: u32, now: Instant) -> bool {
+        match self.phi(node_id, now) {
+            Some(phi) => phi > self.threshold / 2.0,
+            None => self
+                .arrivals
+                .get(&node_id)
+                .and_then(|arrivals| arrivals.last)
+                .is_some_and(|last| now.saturating_duration_since(last) > self.bootstrap_timeout / 2),
+        }
+    }
+}
+
+/// Disagreement between the wall clock and the monotonic clock that counts as
+/// the wall clock being stepped
+pub const CLOCK_STEP_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// The wall clock. Failure detection never reads it: leader and peer timeouts
+/// run on the monotonic clock, so stepping the wall clock can neither hide a
+/// dead leader nor fail a live one.
+#[cfg(not(any(test, feature = "testing")))]
+pub fn wall_clock() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(any(test, feature = "testing"))]
+static WALL_CLOCK_OFFSET_US: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(0);
+
+/// Under the `testing` feature the wall clock advances with the runtime's
+/// clock, so paused time is not mistaken for a step, plus whatever
+/// [`step_wall_clock`] has added
+#[cfg(any(test, feature = "testing"))]
+pub fn wall_clock() -> SystemTime {
+    static START: std::sync::OnceLock<(Instant, SystemTime)> = std::sync::OnceLock::new();
+    let (mono, wall) = *START.get_or_init(|| (Instant::now(), SystemTime::now()));
+    let now = wall + Instant::now().saturating_duration_since(mono);
+    let offset = WALL_CLOCK_OFFSET_US.load(std::sync::atomic::Ordering::Relaxed);
+    if offset >= 0 {
+        now + Duration::from_micros(offset as u64)
+    } else {
+        now - Duration::from_micros(offset.unsigned_abs())
+    }
+}
+
+/// Step the wall clock of every node in the process by `by_us` microseconds,
+/// backwards if negative, as an NTP correction would
+#[cfg(any(test, feature = "testing"))]
+pub fn step_wall_clock(by_us: i64) {
+    WALL_CLOCK_OFFSET_US.fetch_add(by_us, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// How far the wall clock jumped relative to the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockStep {
+    Forward(Duration),
+    Backward(Duration),
+}
+
+impl fmt::Display for ClockStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClockStep::Forward(by) => write!(f, "forward by {:.1}s", by.as_secs_f64()),
+            ClockStep::Backward(by) => write!(f, "backward by {:.1}s", by.as_secs_f64()),
+        }
+    }
+}
+
+/// Spots the wall clock being stepped by comparing how far it moved against
+/// the monotonic clock since the previous check. Only used to log the step:
+/// nothing times out on the wall clock.
+#[derive(Debug)]
+pub struct ClockWatch {
+    mono: Instant,
+    wall: SystemTime,
+}
+
+impl ClockWatch {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            mono: now,
+            wall: wall_clock(),
+        }
+    }
+
+    /// The step since the last check, if the clocks disagree by more than
+    /// [`CLOCK_STEP_TOLERANCE`]
+    pub fn check(&mut self, now: Instant) -> Option<ClockStep> {
+        let wall = wall_clock();
+        let mono_elapsed = now.saturating_duration_since(self.mono);
+        let step = match wall.duration_since(self.wall) {
+            Ok(wall_elapsed) if wall_elapsed > mono_elapsed + CLOCK_STEP_TOLERANCE => {
+                Some(ClockStep::Forward(wall_elapsed - mono_elapsed))
+            }
+            Ok(wall_elapsed) if wall_elapsed + CLOCK_STEP_TOLERANCE < mono_elapsed => {
+                Some(ClockStep::Backward(mono_elapsed - wall_elapsed))
+            }
+            Ok(_) => None,
+            // The wall clock now reads earlier than at the last check
+            Err(e) => {
+                let behind = e.duration() + mono_elapsed;
+                (behind > CLOCK_STEP_TOLERANCE).then_some(ClockStep::Backward(behind))
+            }
+        };
+        self.mono = now;
+        self.wall = wall;
+        step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Arrival times of a heartbeat every second give or take 400ms, with
+    /// every 20th held up an extra 1.5s as a busy network would
+    fn jittery_arrivals(start: Instant, count: usize) -> Vec<Instant> {
+        let mut rng = StdRng::seed_from_u64(30);
+        let mut at = start;
+        (0..count)
+            .map(|n| {
+                let mut gap = Duration::from_millis(rng.gen_range(600..=1400));
+                if n % 20 == 19 {
+                    gap += Duration::from_millis(1500);
+                }
+                at += gap;
+                at
+            })
+            .collect()
+    }
+
+    #[test]
+    fn phi_tolerates_jitter_and_trips_once_heartbeats_stop() {
+        let start = Instant::now();
+        let arrivals = jittery_arrivals(start, 200);
+        let mut phi = PhiAccrualDetector::new(8.0, 100, Duration::from_millis(500), Duration::from_secs(3));
+        let mut fixed = FixedTimeoutDetector::new(Duration::from_secs(2));
+        phi.reset(1, start);
+        fixed.reset(1, start);
+
+        let mut fixed_false_alarms = 0;
+        for &at in &arrivals {
+            // Judged the instant before each heartbeat lands, the latest it can be
+            let before = at - Duration::from_millis(1);
+            assert!(!phi.is_failed(1, before), "phi failed a live node at {:?}", before - start);
+            fixed_false_alarms += usize::from(fixed.is_failed(1, before));
+            phi.heartbeat(1, at);
+            fixed.heartbeat(1, at);
+        }
+        // The same stream keeps tripping a fixed timeout of twice the mean
+        assert!(fixed_false_alarms >= 5, "only {} false alarms", fixed_false_alarms);
+
+        // Silence: suspicion rises steadily, suspect before failed, failed soon
+        let last = *arrivals.last().unwrap();
+        let levels: Vec<f64> = (1..=20)
+            .map(|step| phi.phi(1, last + Duration::from_millis(step * 500)).unwrap())
+            .collect();
+        assert!(levels.windows(2).all(|pair| pair[0] <= pair[1]), "phi fell during silence: {:?}", levels);
+        let first = |check: &dyn Fn(Instant) -> bool| {
+            (1..=100).map(|tenths| last + Duration::from_millis(tenths * 100)).find(|&at| check(at)).unwrap() - last
+        };
+        let suspect = first(&|at| phi.is_suspect(1, at));
+        let failed = first(&|at| phi.is_failed(1, at));
+        assert!(suspect < failed, "suspect after {:?}, failed after {:?}", suspect, failed);
+        assert!(failed > Duration::from_secs(3) && failed < Duration::from_secs(8), "failed after {:?}", failed);
+    }
+}
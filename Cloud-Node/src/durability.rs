@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How writes to the state file, the WAL and the image store reach stable
+/// storage, selected by the config file's `durability` object
+/// (`{"fsync": "batched", "flush_interval_ms": 1000}` by default)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "fsync", rename_all = "snake_case")]
+pub enum DurabilityPolicy {
+    /// fsync every write before it returns: nothing written is lost to a
+    /// power failure, at a cost in throughput
+    Always,
+    /// fsync new files, WAL frames and directory changes every
+    /// `flush_interval_ms`; a power failure loses at most that much. A file
+    /// about to replace another (the state file, or an ACL or image stored
+    /// again) is still fsynced at once, so the rename can never outlive the
+    /// new contents and lose the old ones too.
+    Batched {
+        #[serde(
+            rename = "flush_interval_ms",
+            default = "default_flush_interval",
+            with = "crate::node::duration_ms"
+        )]
+        flush_interval: Duration,
+    },
+    /// Never fsync; the OS writes data back in its own time
+    None,
+}
+
+impl Default for DurabilityPolicy {
+    fn default() -> Self {
+        Self::Batched {
+            flush_interval: default_flush_interval(),
+        }
+    }
+}
+
+fn default_flush_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// A written file that can be forced to stable storage: a [`File`], or a
+/// stand-in that records the call
+pub trait Fsync {
+    fn fsync(&self) -> io::Result<()>;
+}
+
+impl Fsync for File {
+    fn fsync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// Applies a [`DurabilityPolicy`] to each write. Clones share the files
+/// awaiting a batched flush, so one handle can be given to every store and
+/// another flushed on a timer.
+///
+/// ```
+/// use cloud_p2p::durability::{Durability, DurabilityPolicy, Fsync};
+/// use std::cell::Cell;
+/// use std::path::Path;
+///
+/// #[derive(Default)]
+/// struct MockFile {
+///     fsyncs: Cell<u32>,
+/// }
+///
+/// impl Fsync for MockFile {
+///     fn fsync(&self) -> std::io::Result<()> {
+///         self.fsyncs.set(self.fsyncs.get() + 1);
+///         Ok(())
+///     }
+/// }
+///
+/// let file = MockFile::default();
+/// Durability::new(DurabilityPolicy::Always).written(&file, Path::new("image"))?;
+/// assert_eq!(file.fsyncs.get(), 1);
+///
+/// let file = MockFile::default();
+/// Durability::new(DurabilityPolicy::None).written(&file, Path::new("image"))?;
+/// assert_eq!(file.fsyncs.get(), 0);
+///
+/// // Batched writes wait for the next flush
+/// let file = MockFile::default();
+/// Durability::new(DurabilityPolicy::default()).written(&file, Path::new("image"))?;
+/// assert_eq!(file.fsyncs.get(), 0);
+///
+/// // Unless they are about to replace another file
+/// let file = MockFile::default();
+/// Durability::new(DurabilityPolicy::default()).replacing(&file)?;
+/// assert_eq!(file.fsyncs.get(), 1);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Durability {
+    policy: DurabilityPolicy,
+    dirty: Arc<Mutex<BTreeSet<PathBuf>>>, // Written since the last batched flush
+}
+
+impl Durability {
+    pub fn new(policy: DurabilityPolicy) -> Self {
+        Self {
+            policy,
+            dirty: Arc::default(),
+        }
+    }
+
+    pub fn policy(&self) -> DurabilityPolicy {
+        self.policy
+    }
+
+    /// Call once `file` has been written. `path` is where the file will be
+    /// found when a batched flush runs, so after any rename.
+    pub fn written(&self, file: &impl Fsync, path: &Path) -> io::Result<()> {
+        match self.policy {
+            DurabilityPolicy::Always => file.fsync(),
+            DurabilityPolicy::Batched { .. } => {
+                self.dirty.lock().unwrap().insert(path.to_path_buf());
+                Ok(())
+            }
+            DurabilityPolicy::None => Ok(()),
+        }
+    }
+
+    /// Call once `file` has been written to a temp path it is about to be
+    /// renamed over an existing file from. Whatever the policy batches, the
+    /// rename must not reach the disk before the contents do, or a crash
+    /// leaves neither the old file nor the new one.
+    pub fn replacing(&self, file: &impl Fsync) -> io::Result<()> {
+        match self.policy {
+            DurabilityPolicy::Always | DurabilityPolicy::Batched { .. } => file.fsync(),
+            DurabilityPolicy::None => Ok(()),
+        }
+    }
+
+    /// Call once `file` has been written to a temp path it is about to be
+    /// renamed to `path` from: [`replacing`](Self::replacing) if a file is
+    /// already there, otherwise [`written`](Self::written), as a new file has
+    /// no old contents to lose
+    pub fn renaming(&self, file: &impl Fsync, path: &Path) -> io::Result<()> {
+        if path.exists() {
+            self.replacing(file)
+        } else {
+            self.written(file, path)
+        }
+    }
+
+    /// Whether `path` is waiting for the next batched flush
+    #[cfg(test)]
+    pub(crate) fn is_dirty(&self, path: &Path) -> bool {
+        self.dirty.lock().unwrap().contains(path)
+    }
+
+    /// Call once a file in `dir` was created, renamed or removed, so the
+    /// change to the directory itself survives
+    pub fn dir_changed(&self, dir: &Path) -> io::Result<()> {
+        match self.policy {
+            DurabilityPolicy::Always => File::open(dir)?.sync_all(),
+            DurabilityPolicy::Batched { .. } => {
+                self.dirty.lock().unwrap().insert(dir.to_path_buf());
+                Ok(())
+            }
+            DurabilityPolicy::None => Ok(()),
+        }
+    }
+
+    /// fsync every file and directory written since the last flush; only a
+    /// batched policy leaves any. Paths that have since gone are skipped, and
+    /// ones that fail are kept for the next flush.
+    pub fn flush(&self) -> io::Result<()> {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        let mut first_error = None;
+        for path in dirty {
+            match File::open(&path).and_then(|file| file.sync_all()) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    self.dirty.lock().unwrap().insert(path);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
@@ -0,0 +1,210 @@
+use crate::message::Message;
+use crate::transport::{BoxFuture, BoxedStream, Listener, Transport};
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Selects the messages a fault applies to, e.g. `|m| matches!(m, Message::Heartbeat { .. })`
+pub type Matcher = Arc<dyn Fn(&Message) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+struct Fault {
+    matches: Matcher,
+    drop_probability: f64,
+    delay: Duration,
+}
+
+/// Shared by every stream of one transport so a seed fixes the whole run
+#[derive(Clone)]
+struct Faults {
+    faults: Vec<Fault>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl Faults {
+    /// Decide a frame's fate when it is written: `None` drops it, otherwise
+    /// it is held back for the returned delay
+    fn judge(&self, frame: &[u8]) -> Option<Duration> {
+        // Frames that don't decode without a key (cluster-key tagged or
+        // foreign) pass through untouched
+        let Ok((message, _)) = Message::from_bytes(frame, None) else {
+            return Some(Duration::ZERO);
+        };
+        let mut delay = Duration::ZERO;
+        for fault in self.faults.iter().filter(|fault| (fault.matches)(&message)) {
+            if fault.drop_probability > 0.0
+                && self.rng.lock().unwrap().gen_bool(fault.drop_probability)
+            {
+                return None;
+            }
+            delay = delay.max(fault.delay);
+        }
+        Some(delay)
+    }
+
+    fn wrap(&self, stream: BoxedStream) -> BoxedStream {
+        let (reader, writer) = tokio::io::split(stream);
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(forward(writer, rx));
+        Box::new(FaultyStream {
+            reader,
+            frames: Some(tx),
+            pending: Vec::new(),
+            faults: self.clone(),
+        })
+    }
+}
+
+/// Wraps another transport and drops or delays selected messages on every
+/// connection it opens or accepts. Drops and delays are drawn from an RNG
+/// seeded at construction, so a failing run can be replayed.
+///
+/// Faults are applied by the sending side, to whole frames. A delayed frame
+/// holds back later frames on the same connection, as on a slow TCP link.
+/// Under TLS or a cluster key the frames can't be inspected and pass through
+/// unchanged.
+pub struct FaultyTransport {
+    inner: Arc<dyn Transport>,
+    faults: Faults,
+}
+
+impl FaultyTransport {
+    pub fn new(inner: Arc<dyn Transport>, seed: u64) -> Self {
+        Self {
+            inner,
+            faults: Faults {
+                faults: Vec::new(),
+                rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+            },
+        }
+    }
+
+    /// Drop each message selected by `matches` with probability `probability`
+    pub fn with_drop_rate(
+        mut self,
+        matches: impl Fn(&Message) -> bool + Send + Sync + 'static,
+        probability: f64,
+    ) -> Self {
+        self.faults.faults.push(Fault {
+            matches: Arc::new(matches),
+            drop_probability: probability.clamp(0.0, 1.0),
+            delay: Duration::ZERO,
+        });
+        self
+    }
+
+    /// Deliver each message selected by `matches` `delay` late
+    pub fn with_delay(
+        mut self,
+        matches: impl Fn(&Message) -> bool + Send + Sync + 'static,
+        delay: Duration,
+    ) -> Self {
+        self.faults.faults.push(Fault {
+            matches: Arc::new(matches),
+            drop_probability: 0.0,
+            delay,
+        });
+        self
+    }
+}
+
+impl Transport for FaultyTransport {
+    fn listen<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let inner = self.inner.listen(addr).await?;
+            Ok(Box::new(FaultyListener {
+                inner,
+                faults: self.faults.clone(),
+            }) as Box<dyn Listener>)
+        })
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<BoxedStream>> {
+        Box::pin(async move {
+            let stream = self.inner.connect(addr).await?;
+            Ok(self.faults.wrap(stream))
+        })
+    }
+}
+
+struct FaultyListener {
+    inner: Box<dyn Listener>,
+    faults: Faults,
+}
+
+impl Listener for FaultyListener {
+    fn accept(&mut self) -> BoxFuture<'_, Result<(BoxedStream, String)>> {
+        Box::pin(async move {
+            let (stream, addr) = self.inner.accept().await?;
+            Ok((self.faults.wrap(stream), addr))
+        })
+    }
+}
+
+/// Write frames to the real stream in order, each no earlier than its due time
+async fn forward(
+    mut writer: WriteHalf<BoxedStream>,
+    mut frames: mpsc::UnboundedReceiver<(Vec<u8>, Instant)>,
+) {
+    while let Some((frame, due)) = frames.recv().await {
+        sleep_until(due).await;
+        if writer.write_all(&frame).await.is_err() || writer.flush().await.is_err() {
+            // Dropping the receiver surfaces the failure to the next write
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+struct FaultyStream {
+    reader: ReadHalf<BoxedStream>,
+    frames: Option<mpsc::UnboundedSender<(Vec<u8>, Instant)>>,
+    /// Written bytes not yet forming a whole frame
+    pending: Vec<u8>,
+    faults: Faults,
+}
+
+impl AsyncRead for FaultyStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FaultyStream {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(frames) = this.frames.as_ref().filter(|frames| !frames.is_closed()) else {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        };
+        this.pending.extend_from_slice(data);
+        while this.pending.len() >= 4 {
+            let len = u32::from_be_bytes([this.pending[0], this.pending[1], this.pending[2], this.pending[3]]);
+            let end = 4 + len as usize;
+            if this.pending.len() < end {
+                break;
+            }
+            let frame: Vec<u8> = this.pending.drain(..end).collect();
+            if let Some(delay) = this.faults.judge(&frame) {
+                let _ = frames.send((frame, Instant::now() + delay));
+            }
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // The forwarding task drains what is queued, then shuts the stream down
+        self.frames = None;
+        Poll::Ready(Ok(()))
+    }
+}
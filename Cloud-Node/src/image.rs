@@ -0,0 +1,1292 @@
+use crate::durability::Durability;
+use crate::message::Message;
+use crate::network::{NetworkError, PeerConnection};
+use crate::storage::{FileStorage, Storage};
+use crate::wal::{Wal, WalOp};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::Cursor;
+use std::path::Path;
+use tokio::time::{Duration, Instant};
+
+/// Raw image bytes carried by a single `ImageChunk`
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// zstd level for chunk payloads; low enough to keep up with the network
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+/// Largest image accepted for reassembly (64 MiB)
+pub const MAX_IMAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Partial uploads that receive no chunk for this long are discarded
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the leader waits for a quorum of replica acks before failing a write
+pub const REPLICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most distinct uploads the leader replicates at once; more are refused
+/// until one of them completes or times out
+pub const MAX_IN_FLIGHT_UPLOADS: usize = 64;
+
+/// Most partial uploads one sender may have open at once; chunks starting
+/// another are dropped. Matches `MAX_IN_FLIGHT_UPLOADS` so the leader's
+/// replication stream always fits.
+pub const MAX_PARTIAL_UPLOADS_PER_SENDER: usize = MAX_IN_FLIGHT_UPLOADS;
+
+/// Most partial uploads held across all senders; room for the leader's
+/// stream plus as much again for clients and other peers
+pub const MAX_PARTIAL_UPLOADS: usize = 2 * MAX_IN_FLIGHT_UPLOADS;
+
+/// Longest edge of generated thumbnails, in pixels
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+/// How long a follower honors a fetch the leader redirected to it
+pub const FETCH_GRANT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a follower holds a fetch whose grant may still be in flight
+/// from the leader (the redirected client can overtake it) before refusing it
+pub const FETCH_GRANT_WAIT: Duration = Duration::from_secs(2);
+
+/// How often followers compare their images with the leader's and pull any they lack
+pub const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many times a node re-sends an image whose receiver reported a checksum mismatch
+pub const CHECKSUM_RETRIES: u32 = 3;
+
+/// Marks the start of an embedded watermark payload
+const WATERMARK_MAGIC: &[u8; 4] = b"CPWM";
+
+/// Magic, owner node ID (u32) and image ID length (u16) preceding the image ID
+const WATERMARK_HEADER_LEN: usize = WATERMARK_MAGIC.len() + 4 + 2;
+
+/// Length of the random AES-GCM nonce stored at the start of each encrypted replica
+const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM key used to encrypt image replicas at rest
+#[derive(Clone)]
+pub struct ImageKey {
+    cipher: Aes256Gcm,
+}
+
+impl fmt::Debug for ImageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ImageKey(..)")
+    }
+}
+
+impl ImageKey {
+    /// Load a 256-bit key stored either as 32 raw bytes or as 64 hex characters
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read(path).context(format!("Failed to read {}", path.display()))?;
+
+        let key = if content.len() == 32 {
+            content
+        } else {
+            let hex = String::from_utf8_lossy(&content);
+            decode_hex(hex.trim()).context(format!(
+                "{} must hold a 32-byte key as raw bytes or 64 hex characters",
+                path.display()
+            ))?
+        };
+
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow::anyhow!("{} does not hold a 32-byte key", path.display()))?;
+        Ok(Self { cipher })
+    }
+
+    /// Encrypt under a fresh random nonce, returning `nonce || ciphertext`
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt image"))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Reverse `encrypt`, failing if the data was tampered with or sealed under another key
+    fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            anyhow::bail!("Encrypted image is truncated");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt image (wrong key or corrupted file)"))
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("Odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .context("Invalid hex digit")
+        })
+        .collect()
+}
+
+/// Who may fetch an image, and how often, stored next to each replica
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclEntry {
+    pub image_id: String,
+    pub allowed_node_ids: Vec<u32>,
+    /// Fetches each allowed node gets before its access is revoked; `None` is unlimited
+    #[serde(default)]
+    pub max_views: Option<u32>,
+    /// Fetches served so far per requester (only tracked when `max_views` is set)
+    #[serde(default)]
+    pub views: BTreeMap<u32, u32>,
+    /// Nodes known to hold a replica: this node, plus the followers that
+    /// acknowledged it (on the leader) or the leader that pushed it
+    #[serde(default)]
+    pub replicas: BTreeSet<u32>,
+    /// `content_hash` of the stored bytes, re-checked whenever they are served;
+    /// `None` for images stored before hashes were recorded
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// Which upload of the ID this is (see [`ImageStore`])
+    #[serde(default)]
+    pub generation: u64,
+    /// Owner the image was watermarked for when it was stored, if any
+    #[serde(default)]
+    pub owner: Option<u32>,
+    /// Size of the stored image in bytes, watermark included; `None` for
+    /// images stored before sizes (and owners) were recorded
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+impl AclEntry {
+    pub fn allows(&self, node_id: u32) -> bool {
+        self.allowed_node_ids.contains(&node_id)
+    }
+
+    /// Whether `node_id` has used up its views of the image
+    pub fn exhausted(&self, node_id: u32) -> bool {
+        self.max_views
+            .is_some_and(|max_views| self.views.get(&node_id).copied().unwrap_or(0) >= max_views)
+    }
+
+    /// Whether `upload` asks for the access and watermark the image was
+    /// stored with, so it may share the stored copy instead of getting its own
+    pub fn same_options(&self, upload: &Upload) -> bool {
+        let allowed: BTreeSet<u32> = self.allowed_node_ids.iter().copied().collect();
+        allowed == upload.allowed_node_ids.iter().copied().collect()
+            && self.max_views == upload.max_views
+            && self.owner == upload.watermark_owner
+    }
+}
+
+/// An image being routed through the cluster together with its upload options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    pub image_id: String,
+    pub bytes: Vec<u8>,
+    pub allowed_node_ids: Vec<u32>,
+    /// Owner to embed with `embed_watermark` before the image is stored, and
+    /// recorded in its ACL
+    pub watermark_owner: Option<u32>,
+    pub max_views: Option<u32>,
+    /// Which upload of the ID this is (see [`ImageStore`]); `None` for a new
+    /// upload, which the leader stores at the ID's next generation
+    pub generation: Option<u64>,
+}
+
+impl Upload {
+    pub fn acl(&self) -> AclEntry {
+        AclEntry {
+            image_id: self.image_id.clone(),
+            allowed_node_ids: self.allowed_node_ids.clone(),
+            max_views: self.max_views,
+            views: BTreeMap::new(),
+            replicas: BTreeSet::new(),
+            content_hash: Some(content_hash(&self.bytes)),
+            generation: self.generation.unwrap_or(0),
+            owner: self.watermark_owner,
+            size: Some(self.bytes.len() as u64),
+        }
+    }
+}
+
+/// One generation of an image ID, as anti-entropy lists stored images and
+/// tombstones
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ImageVersion {
+    pub image_id: String,
+    pub generation: u64,
+}
+
+/// One image a node holds, as reported by `ListImages`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageMeta {
+    pub image_id: String,
+    /// Size of the stored image in bytes, watermark included
+    pub size: u64,
+    /// Owner named by the image's watermark, if it carries one
+    pub owner: Option<u32>,
+    /// Nodes this node knows to hold a replica, itself included
+    pub replicas: Vec<u32>,
+}
+
+/// Hex-encoded SHA-256 of an image's bytes
+pub fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The ID an image is stored under unless its uploader names it: its full
+/// `content_hash`. The leader keeps one copy of any bytes, whatever ID they
+/// arrive under, so uploading them again returns the stored image.
+///
+/// ```
+/// use cloud_p2p::image::{content_hash, content_id};
+///
+/// let id = content_id(b"holiday.jpg bytes");
+/// assert_eq!(id, content_id(b"holiday.jpg bytes"));
+/// assert_ne!(id, content_id(b"other bytes"));
+/// assert_eq!(id, content_hash(b"holiday.jpg bytes"));
+/// ```
+pub fn content_id(bytes: &[u8]) -> String {
+    content_hash(bytes)
+}
+
+/// Whether `bytes` start like a format that is already compressed (JPEG, PNG,
+/// GIF or zstd), so compressing them again would only waste CPU
+pub fn is_precompressed(bytes: &[u8]) -> bool {
+    const MAGIC: [&[u8]; 4] = [b"\xFF\xD8\xFF", b"\x89PNG\r\n\x1A\n", b"GIF8", b"\x28\xB5\x2F\xFD"];
+    MAGIC.iter().any(|magic| bytes.starts_with(magic))
+}
+
+/// zstd-compress one chunk's bytes, or `None` if that does not make them smaller
+fn compress_chunk(data: &[u8]) -> Option<Vec<u8>> {
+    zstd::bulk::compress(data, COMPRESSION_LEVEL)
+        .ok()
+        .filter(|compressed| compressed.len() < data.len())
+}
+
+/// Image bytes that do not match the `content_hash` they were sent or stored with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptImage {
+    pub image_id: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for CorruptImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Image {} failed its checksum (expected {}, got {})", self.image_id, self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for CorruptImage {}
+
+/// Check image bytes against the `content_hash` they were sent or stored with
+pub fn verify_hash(image_id: &str, bytes: &[u8], expected: &str) -> std::result::Result<(), CorruptImage> {
+    let actual = content_hash(bytes);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(CorruptImage {
+            image_id: image_id.to_string(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// Downscale a PNG or JPEG so its longest edge is at most `THUMBNAIL_SIZE`,
+/// re-encoded as PNG. Fails for payloads that are not a decodable image.
+pub fn make_thumbnail(bytes: &[u8]) -> Result<Vec<u8>> {
+    let decoded = ::image::load_from_memory(bytes).context("Upload is not a PNG or JPEG image")?;
+    let thumbnail = decoded.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+    let mut encoded = Cursor::new(Vec::new());
+    thumbnail
+        .write_to(&mut encoded, ::image::ImageFormat::Png)
+        .context("Failed to encode thumbnail")?;
+    Ok(encoded.into_inner())
+}
+
+/// Hide `owner_node_id` and `image_id` in the least significant bit of every
+/// pixel sample, returning the result as PNG so the payload survives storage.
+/// Each sample changes by at most one level, which is invisible to the eye.
+pub fn embed_watermark(bytes: &[u8], owner_node_id: u32, image_id: &str) -> Result<Vec<u8>> {
+    let id_len = u16::try_from(image_id.len()).context("Image ID is too long to watermark")?;
+    let mut payload = Vec::with_capacity(WATERMARK_HEADER_LEN + image_id.len());
+    payload.extend_from_slice(WATERMARK_MAGIC);
+    payload.extend_from_slice(&owner_node_id.to_be_bytes());
+    payload.extend_from_slice(&id_len.to_be_bytes());
+    payload.extend_from_slice(image_id.as_bytes());
+
+    let decoded = ::image::load_from_memory(bytes).context("Upload is not a PNG or JPEG image")?;
+    let mut pixels = if decoded.color().has_alpha() {
+        ::image::DynamicImage::ImageRgba8(decoded.to_rgba8())
+    } else {
+        ::image::DynamicImage::ImageRgb8(decoded.to_rgb8())
+    };
+
+    let samples: &mut [u8] = match &mut pixels {
+        ::image::DynamicImage::ImageRgba8(buffer) => buffer,
+        ::image::DynamicImage::ImageRgb8(buffer) => buffer,
+        _ => unreachable!("converted to 8-bit RGB(A) above"),
+    };
+    if payload.len() * 8 > samples.len() {
+        anyhow::bail!("Image is too small to carry a watermark");
+    }
+    for (i, sample) in samples.iter_mut().take(payload.len() * 8).enumerate() {
+        let bit = (payload[i / 8] >> (7 - i % 8)) & 1;
+        *sample = (*sample & !1) | bit;
+    }
+
+    let mut encoded = Cursor::new(Vec::new());
+    pixels
+        .write_to(&mut encoded, ::image::ImageFormat::Png)
+        .context("Failed to encode watermarked image")?;
+
+    // Lossless PNG can be much larger than the JPEG it came from
+    let encoded = encoded.into_inner();
+    if encoded.len() > MAX_IMAGE_SIZE {
+        anyhow::bail!(
+            "Watermarked image is too large to store ({} bytes, limit {})",
+            encoded.len(),
+            MAX_IMAGE_SIZE
+        );
+    }
+    Ok(encoded)
+}
+
+/// Recover the `(owner_node_id, image_id)` embedded by `embed_watermark`, or
+/// `None` if the bytes are not an image or carry no watermark
+pub fn extract_watermark(bytes: &[u8]) -> Option<(u32, String)> {
+    let decoded = ::image::load_from_memory(bytes).ok()?;
+    let samples = if decoded.color().has_alpha() {
+        decoded.to_rgba8().into_raw()
+    } else {
+        decoded.to_rgb8().into_raw()
+    };
+
+    let read = |offset: usize, len: usize| -> Option<Vec<u8>> {
+        let bits = samples.get(offset * 8..(offset + len) * 8)?;
+        Some(
+            bits.chunks(8)
+                .map(|byte| byte.iter().fold(0u8, |acc, sample| (acc << 1) | (sample & 1)))
+                .collect(),
+        )
+    };
+
+    let header = read(0, WATERMARK_HEADER_LEN)?;
+    if &header[..WATERMARK_MAGIC.len()] != WATERMARK_MAGIC {
+        return None;
+    }
+    let owner_node_id = u32::from_be_bytes(header[4..8].try_into().ok()?);
+    let id_len = u16::from_be_bytes(header[8..10].try_into().ok()?) as usize;
+    let image_id = String::from_utf8(read(WATERMARK_HEADER_LEN, id_len)?).ok()?;
+    Some((owner_node_id, image_id))
+}
+
+/// Image replicas kept in a [`Storage`] backend, one blob per image, with
+/// ACLs, thumbnails, the tombstones of deleted images and an index of images
+/// by content hash in its `acl`, `thumb`, `deleted` and `hash` namespaces. On
+/// disk that is one file per image under the image directory and its `.acl`,
+/// `.thumb`, `.deleted` and `.hash` subdirectories
+/// (image IDs cannot start with `.`).
+///
+/// With a [`Wal`], storing and deleting an image are logged before they
+/// touch storage, so one a crash interrupts is finished on the next start.
+///
+/// Image IDs are content hashes, so a deleted image's bytes come back under
+/// the same ID if they are uploaded again. Each upload of an ID therefore
+/// has a generation, kept in its ACL: 0 at first, and one past the ID's
+/// tombstone when the leader stores it again after a delete (see
+/// [`next_generation`](Self::next_generation)). A tombstone
+/// records the highest generation deleted and removes only copies at or
+/// below it, so a replica that missed a delete is still cleared, while a
+/// tombstone that reaches a node late cannot take out a newer upload.
+#[derive(Debug)]
+pub struct ImageStore {
+    images: Box<dyn Storage>,
+    acls: Box<dyn Storage>,
+    thumbnails: Box<dyn Storage>,
+    tombstones: Box<dyn Storage>,
+    /// Image ID by `content_hash` of its stored bytes, for `find_by_hash`
+    hashes: Box<dyn Storage>,
+    key: Option<ImageKey>,
+    wal: Option<Wal>,
+}
+
+impl ImageStore {
+    /// Store images as files under `dir`
+    pub fn new(dir: &Path) -> Self {
+        Self::with_storage(Box::new(FileStorage::new(dir)))
+    }
+
+    /// Store images in `storage`
+    pub fn with_storage(storage: Box<dyn Storage>) -> Self {
+        Self {
+            acls: storage.scoped("acl"),
+            thumbnails: storage.scoped("thumb"),
+            tombstones: storage.scoped("deleted"),
+            hashes: storage.scoped("hash"),
+            images: storage,
+            key: None,
+            wal: None,
+        }
+    }
+
+    /// Encrypt replicas at rest with `key`
+    pub fn with_key(mut self, key: ImageKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Log image writes and deletes to a write-ahead log in `wal_dir`, whose
+    /// segments rotate past `segment_size` bytes and are synced as
+    /// `durability` says. Operations the log shows a crash cut short are
+    /// finished first.
+    pub fn with_wal(mut self, wal_dir: &Path, segment_size: u64, durability: Durability) -> Result<Self> {
+        let (wal, unfinished) = Wal::open(wal_dir, segment_size)?;
+        let wal = wal.with_durability(durability);
+        let count = unfinished.len();
+        for op in unfinished {
+            self.apply(&op).context("Failed to redo an operation from the WAL")?;
+        }
+        wal.checkpoint()?;
+        if count > 0 {
+            info!("📜 Redid {} unfinished image operation(s) from the WAL in {}", count, wal_dir.display());
+        }
+        self.wal = Some(wal);
+        Ok(self)
+    }
+
+    /// Image IDs become file names, so only allow characters that cannot
+    /// escape the image directory
+    pub fn validate_id(image_id: &str) -> Result<()> {
+        let valid = !image_id.is_empty()
+            && !image_id.starts_with('.')
+            && image_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        if !valid {
+            anyhow::bail!("Invalid image ID {:?}", image_id);
+        }
+        Ok(())
+    }
+
+    /// Write an image replica, encrypting it first if a key is configured
+    pub fn save(&self, image_id: &str, bytes: &[u8]) -> Result<()> {
+        self.write_sealed(self.images.as_ref(), image_id, bytes)
+    }
+
+    /// Write an image replica together with its ACL, through the WAL if
+    /// there is one
+    pub fn save_with_acl(&self, acl: &AclEntry, bytes: &[u8]) -> Result<()> {
+        Self::validate_id(&acl.image_id)?;
+        let op = WalOp::Store {
+            acl: acl.clone(),
+            sealed: self.seal(bytes)?,
+        };
+        self.logged(&op)
+    }
+
+    /// Write the thumbnail for an image, encrypted like the replica
+    pub fn save_thumbnail(&self, image_id: &str, bytes: &[u8]) -> Result<()> {
+        self.write_sealed(self.thumbnails.as_ref(), image_id, bytes)
+    }
+
+    fn write_sealed(&self, storage: &dyn Storage, image_id: &str, bytes: &[u8]) -> Result<()> {
+        Self::validate_id(image_id)?;
+        storage.put(image_id, &self.seal(bytes)?)
+    }
+
+    fn seal(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match &self.key {
+            Some(key) => key.encrypt(bytes),
+            None => Ok(bytes.to_vec()),
+        }
+    }
+
+    /// Apply `op`, logging it first and marking it done after if there is a WAL
+    fn logged(&self, op: &WalOp) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return self.apply(op);
+        };
+        let seq = match op {
+            WalOp::Store { acl, sealed } => wal.begin_store(acl, sealed)?,
+            WalOp::Delete { image_id, generation } => wal.begin_delete(image_id, *generation)?,
+        };
+        self.apply(op)?;
+        wal.done(seq)
+    }
+
+    /// Every step of `op` can be repeated, so a half-applied one is redone whole
+    fn apply(&self, op: &WalOp) -> Result<()> {
+        match op {
+            WalOp::Store { acl, sealed } => {
+                self.acls.put(&format!("{}.json", acl.image_id), &serde_json::to_vec_pretty(acl)?)?;
+                self.images.put(&acl.image_id, sealed)?;
+                match &acl.content_hash {
+                    Some(content_hash) => self.hashes.put(content_hash, acl.image_id.as_bytes()),
+                    None => Ok(()),
+                }
+            }
+            // The tombstone is written first: a crash part-way still counts as deleted
+            WalOp::Delete { image_id, generation } => {
+                let buried = self.deleted_generation(image_id)?.map_or(*generation, |g| g.max(*generation));
+                self.tombstones.put(image_id, &buried.to_be_bytes())?;
+                let acl = self.load_acl(image_id)?;
+                if acl.as_ref().is_some_and(|acl| acl.generation > *generation) {
+                    return Ok(());
+                }
+                if let Some(content_hash) = acl.and_then(|acl| acl.content_hash) {
+                    if self.hashes.get(&content_hash)?.is_some_and(|indexed| indexed == image_id.as_bytes()) {
+                        self.hashes.delete(&content_hash)?;
+                    }
+                }
+                self.images.delete(image_id)?;
+                self.acls.delete(&format!("{}.json", image_id))?;
+                self.thumbnails.delete(image_id)
+            }
+        }
+    }
+
+    /// Write the ACL for an image
+    pub fn save_acl(&self, acl: &AclEntry) -> Result<()> {
+        Self::validate_id(&acl.image_id)?;
+        self.acls.put(&format!("{}.json", acl.image_id), &serde_json::to_vec_pretty(acl)?)
+    }
+
+    /// Load the ACL for an image, or `None` if this node does not hold it
+    pub fn load_acl(&self, image_id: &str) -> Result<Option<AclEntry>> {
+        Self::validate_id(image_id)?;
+        let Some(content) = self.acls.get(&format!("{}.json", image_id))? else {
+            return Ok(None);
+        };
+
+        let acl = serde_json::from_slice(&content).context(format!("Failed to parse ACL for {}", image_id))?;
+        Ok(Some(acl))
+    }
+
+    /// The ACL of a stored image whose bytes, as stored, have `content_hash`,
+    /// under whatever ID, looked up in the hash index; images stored before
+    /// the index was kept never match
+    pub fn find_by_hash(&self, content_hash: &str) -> Result<Option<AclEntry>> {
+        if content_hash.is_empty() || !content_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let Some(indexed) = self.hashes.get(content_hash)? else {
+            return Ok(None);
+        };
+        let image_id = String::from_utf8(indexed).context("Invalid image ID in the hash index")?;
+        // The entry outlives an overwrite of its image with other bytes
+        let acl = self.load_acl(&image_id)?.filter(|acl| acl.content_hash.as_deref() == Some(content_hash));
+        Ok(acl.filter(|_| self.contains(&image_id)))
+    }
+
+    /// IDs of every image replica in the store, sorted
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut image_ids = self.images.list()?;
+        image_ids.retain(|image_id| Self::validate_id(image_id).is_ok());
+        Ok(image_ids)
+    }
+
+    /// Describe a stored image from its ACL, or `None` if this node does not
+    /// hold it. Only images stored before the ACL recorded their size and
+    /// owner are loaded, to read them from the bytes and watermark.
+    pub fn meta(&self, image_id: &str) -> Result<Option<ImageMeta>> {
+        let Some(acl) = self.load_acl(image_id)?.filter(|_| self.contains(image_id)) else {
+            return Ok(None);
+        };
+        let (size, owner) = match acl.size {
+            Some(size) => (size, acl.owner),
+            None => match self.load(image_id)? {
+                Some(bytes) => {
+                    let owner = extract_watermark(&bytes).map(|(owner_node_id, _)| owner_node_id);
+                    (bytes.len() as u64, owner)
+                }
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(ImageMeta {
+            image_id: image_id.to_string(),
+            size,
+            owner,
+            replicas: acl.replicas.into_iter().collect(),
+        }))
+    }
+
+    /// Whether this node holds a replica of the image
+    pub fn contains(&self, image_id: &str) -> bool {
+        Self::validate_id(image_id).is_ok() && self.images.contains(image_id).unwrap_or(false)
+    }
+
+    /// Load an image replica, or `None` if this node does not hold it
+    pub fn load(&self, image_id: &str) -> Result<Option<Vec<u8>>> {
+        self.read_sealed(self.images.as_ref(), image_id)
+    }
+
+    /// Load an image replica like `load`, failing if its bytes no longer
+    /// match the hash recorded in its ACL
+    pub fn load_verified(&self, image_id: &str) -> Result<Option<Vec<u8>>> {
+        let Some(bytes) = self.load(image_id)? else {
+            return Ok(None);
+        };
+        if let Some(expected) = self.load_acl(image_id)?.and_then(|acl| acl.content_hash) {
+            verify_hash(image_id, &bytes, &expected)?;
+        }
+        Ok(Some(bytes))
+    }
+
+    /// Load the thumbnail for an image, or `None` if this node does not hold it
+    pub fn load_thumbnail(&self, image_id: &str) -> Result<Option<Vec<u8>>> {
+        self.read_sealed(self.thumbnails.as_ref(), image_id)
+    }
+
+    /// Remove an image with its ACL and thumbnail, leaving a tombstone so
+    /// anti-entropy does not copy it back from a replica that missed the delete.
+    /// Goes through the WAL if there is one.
+    pub fn delete(&self, image_id: &str) -> Result<()> {
+        self.delete_through(image_id, self.generation(image_id)?)
+    }
+
+    /// Record that every generation of the image up to `generation` was
+    /// deleted, removing our copy unless it is a later one
+    pub fn delete_through(&self, image_id: &str, generation: u64) -> Result<()> {
+        Self::validate_id(image_id)?;
+        self.logged(&WalOp::Delete {
+            image_id: image_id.to_string(),
+            generation,
+        })
+    }
+
+    /// The generation of our copy of the image, or of the last one deleted if
+    /// we hold none; 0 for an ID we have never seen
+    pub fn generation(&self, image_id: &str) -> Result<u64> {
+        match self.load_acl(image_id)? {
+            Some(acl) => Ok(acl.generation),
+            None => Ok(self.deleted_generation(image_id)?.unwrap_or(0)),
+        }
+    }
+
+    /// The generation a new upload of the image is stored at: that of our
+    /// copy, which it replaces, or else one past the last one deleted. Fails
+    /// once the ID has used up every generation.
+    pub fn next_generation(&self, image_id: &str) -> Result<u64> {
+        if let Some(acl) = self.load_acl(image_id)? {
+            return Ok(acl.generation);
+        }
+        match self.deleted_generation(image_id)? {
+            Some(deleted) => deleted
+                .checked_add(1)
+                .with_context(|| format!("Image {} was deleted too many times to store again", image_id)),
+            None => Ok(0),
+        }
+    }
+
+    /// The highest generation of the image that was deleted, if any
+    pub fn deleted_generation(&self, image_id: &str) -> Result<Option<u64>> {
+        Self::validate_id(image_id)?;
+        let Some(tombstone) = self.tombstones.get(image_id)? else {
+            return Ok(None);
+        };
+        // Tombstones written before generations are empty, burying the first
+        match <[u8; 8]>::try_from(tombstone.as_slice()) {
+            Ok(bytes) => Ok(Some(u64::from_be_bytes(bytes))),
+            Err(_) if tombstone.is_empty() => Ok(Some(0)),
+            Err(_) => anyhow::bail!("Corrupt tombstone for image {}", image_id),
+        }
+    }
+
+    /// Whether `generation` of the image was deleted, so it must not be stored
+    pub fn is_deleted(&self, image_id: &str, generation: u64) -> bool {
+        self.deleted_generation(image_id).ok().flatten().is_some_and(|deleted| deleted >= generation)
+    }
+
+    /// The highest deleted generation of every deleted image, sorted by ID
+    pub fn tombstones(&self) -> Result<Vec<ImageVersion>> {
+        self.tombstones
+            .list()?
+            .into_iter()
+            .map(|image_id| {
+                let generation = self.deleted_generation(&image_id)?.unwrap_or(0);
+                Ok(ImageVersion { image_id, generation })
+            })
+            .collect()
+    }
+
+    /// Every image replica in the store with its generation, sorted by ID
+    pub fn versions(&self) -> Result<Vec<ImageVersion>> {
+        self.list()?
+            .into_iter()
+            .map(|image_id| {
+                let generation = self.load_acl(&image_id)?.map_or(0, |acl| acl.generation);
+                Ok(ImageVersion { image_id, generation })
+            })
+            .collect()
+    }
+
+    fn read_sealed(&self, storage: &dyn Storage, image_id: &str) -> Result<Option<Vec<u8>>> {
+        Self::validate_id(image_id)?;
+        let Some(bytes) = storage.get(image_id)? else {
+            return Ok(None);
+        };
+
+        match &self.key {
+            Some(key) => key.decrypt(&bytes).map(Some).context(format!("Failed to decrypt {}", image_id)),
+            None => Ok(Some(bytes)),
+        }
+    }
+}
+
+/// Split an upload into `ImageChunk` messages of at most `CHUNK_SIZE` bytes.
+/// An empty image still produces one (empty) chunk. Each chunk is compressed
+/// on its own when that shrinks it, unless the image is already in a
+/// compressed format.
+pub fn chunks(upload: &Upload) -> Vec<Message> {
+    let parts: Vec<&[u8]> = if upload.bytes.is_empty() {
+        vec![&upload.bytes[..]]
+    } else {
+        upload.bytes.chunks(CHUNK_SIZE).collect()
+    };
+    let total = parts.len() as u32;
+    let content_hash = content_hash(&upload.bytes);
+    let compress = !is_precompressed(&upload.bytes);
+
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(seq, data)| {
+            let packed = if compress { compress_chunk(data) } else { None };
+            Message::ImageChunk {
+                image_id: upload.image_id.clone(),
+                seq: seq as u32,
+                total,
+                compressed: packed.is_some(),
+                data: packed.unwrap_or_else(|| data.to_vec()),
+                allowed_node_ids: upload.allowed_node_ids.clone(),
+                watermark_owner: upload.watermark_owner,
+                max_views: upload.max_views,
+                content_hash: content_hash.clone(),
+                generation: upload.generation,
+            }
+        })
+        .collect()
+}
+
+/// Stream an image to a peer as bounded chunks, so other traffic on the
+/// connection can interleave instead of waiting behind one huge frame
+pub async fn send_image(conn: &PeerConnection, upload: &Upload) -> std::result::Result<(), NetworkError> {
+    for chunk in chunks(upload) {
+        conn.send(&chunk).await?;
+    }
+    Ok(())
+}
+
+/// Where an `ImageChunk` came from, for the per-sender cap on partial uploads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkSender {
+    /// A cluster member, by node ID
+    Peer(u32),
+    /// An anonymous connection, by `PeerConnection::id`
+    Connection(usize),
+}
+
+/// Chunks received so far for one image
+struct PartialImage {
+    parts: Vec<Option<Vec<u8>>>,
+    allowed_node_ids: Vec<u32>,
+    watermark_owner: Option<u32>,
+    max_views: Option<u32>,
+    content_hash: String,
+    generation: Option<u64>,
+    received: u32,
+    last_update: Instant,
+}
+
+/// Reassembles chunked images keyed by sender and image ID, tolerating
+/// out-of-order arrival; two senders uploading the same ID at once each get
+/// their own upload rather than mixing chunks.
+/// At most `MAX_PARTIAL_UPLOADS` are held at once, and at most
+/// `MAX_PARTIAL_UPLOADS_PER_SENDER` from any one sender, so a peer that opens
+/// uploads and never finishes them cannot exhaust memory.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<(ChunkSender, String), PartialImage>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record one `ImageChunk` from `sender`, returning the full upload once
+    /// every chunk has arrived, or an error if the reassembled bytes do not
+    /// match their hash. A chunk that would open an upload beyond the caps is
+    /// dropped.
+    pub fn insert(&mut self, sender: ChunkSender, chunk: Message) -> Option<std::result::Result<Upload, CorruptImage>> {
+        let Message::ImageChunk {
+            image_id,
+            seq,
+            total,
+            data,
+            compressed,
+            allowed_node_ids,
+            watermark_owner,
+            max_views,
+            content_hash,
+            generation,
+        } = chunk
+        else {
+            return None;
+        };
+
+        // Bounded by CHUNK_SIZE, so a small chunk cannot inflate into a huge one.
+        // A chunk that fails to decompress is kept as is: the image then fails
+        // its checksum and is sent again, rather than stalling until it expires.
+        let data = if compressed {
+            zstd::bulk::decompress(&data, CHUNK_SIZE).unwrap_or_else(|e| {
+                warn!("Chunk {}/{} of image {} failed to decompress: {}", seq, total, image_id, e);
+                data
+            })
+        } else {
+            data
+        };
+
+        let max_chunks = MAX_IMAGE_SIZE.div_ceil(CHUNK_SIZE) as u32;
+        if total == 0 || total > max_chunks || seq >= total || data.len() > CHUNK_SIZE {
+            warn!("Dropping malformed chunk {}/{} of image {}", seq, total, image_id);
+            return None;
+        }
+
+        let key = (sender, image_id);
+        if !self.partial.contains_key(&key) {
+            if self.partial.len() >= MAX_PARTIAL_UPLOADS {
+                warn!("Dropping chunk of image {}: {} partial uploads already open", key.1, MAX_PARTIAL_UPLOADS);
+                return None;
+            }
+            let open = self.partial.keys().filter(|(from, _)| *from == sender).count();
+            if open >= MAX_PARTIAL_UPLOADS_PER_SENDER {
+                warn!("Dropping chunk of image {}: {:?} already has {} partial uploads open", key.1, sender, open);
+                return None;
+            }
+        }
+
+        let partial = self
+            .partial
+            .entry(key.clone())
+            .or_insert_with(|| PartialImage {
+                parts: vec![None; total as usize],
+                allowed_node_ids: Vec::new(),
+                watermark_owner: None,
+                max_views: None,
+                content_hash: String::new(),
+                generation: None,
+                received: 0,
+                last_update: Instant::now(),
+            });
+
+        // A different chunk count means a new upload of the same ID; start over
+        if partial.parts.len() != total as usize {
+            *partial = PartialImage {
+                parts: vec![None; total as usize],
+                allowed_node_ids: Vec::new(),
+                watermark_owner: None,
+                max_views: None,
+                content_hash: String::new(),
+                generation: None,
+                received: 0,
+                last_update: Instant::now(),
+            };
+        }
+
+        let slot = &mut partial.parts[seq as usize];
+        if slot.is_none() {
+            partial.received += 1;
+        }
+        *slot = Some(data);
+        partial.allowed_node_ids = allowed_node_ids;
+        partial.watermark_owner = watermark_owner;
+        partial.max_views = max_views;
+        partial.content_hash = content_hash;
+        partial.generation = generation;
+        partial.last_update = Instant::now();
+
+        if partial.received < total {
+            return None;
+        }
+
+        let partial = self.partial.remove(&key)?;
+        let (_, image_id) = key;
+        let bytes: Vec<u8> = partial.parts.into_iter().flatten().flatten().collect();
+        if let Err(e) = verify_hash(&image_id, &bytes, &partial.content_hash) {
+            return Some(Err(e));
+        }
+        Some(Ok(Upload {
+            image_id,
+            bytes,
+            allowed_node_ids: partial.allowed_node_ids,
+            watermark_owner: partial.watermark_owner,
+            max_views: partial.max_views,
+            generation: partial.generation,
+        }))
+    }
+
+    /// Discard uploads that have been missing chunks for longer than `timeout`,
+    /// returning their image IDs
+    pub fn expire(&mut self, timeout: Duration) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.partial.retain(|(_, image_id), partial| {
+            let keep = partial.last_update.elapsed() <= timeout;
+            if !keep {
+                expired.push(image_id.clone());
+            }
+            keep
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const SENDER: ChunkSender = ChunkSender::Peer(1);
+
+    fn upload(image_id: &str, len: usize) -> Upload {
+        Upload {
+            image_id: image_id.to_string(),
+            bytes: (0..len).map(|i| (i * 7 % 251) as u8).collect(),
+            allowed_node_ids: vec![1, 2],
+            watermark_owner: None,
+            max_views: Some(3),
+            generation: None,
+        }
+    }
+
+    fn upload_of(bytes: impl IntoIterator<Item = u8>) -> Upload {
+        Upload {
+            bytes: bytes.into_iter().collect(),
+            ..upload("img", 0)
+        }
+    }
+
+    /// The first of two chunks of `image_id`, which leaves an upload open
+    fn opening_chunk(image_id: &str) -> Message {
+        Message::ImageChunk {
+            image_id: image_id.to_string(),
+            seq: 0,
+            total: 2,
+            compressed: false,
+            data: vec![0; 4],
+            allowed_node_ids: Vec::new(),
+            watermark_owner: None,
+            max_views: None,
+            content_hash: String::new(),
+            generation: None,
+        }
+    }
+
+    /// Whether `sender` has an upload of `image_id` open
+    fn is_open(reassembler: &Reassembler, sender: ChunkSender, image_id: &str) -> bool {
+        reassembler.partial.contains_key(&(sender, image_id.to_string()))
+    }
+
+    fn stored(store: &ImageStore, upload: &Upload) {
+        store.save_with_acl(&upload.acl(), &upload.bytes).unwrap();
+    }
+
+    #[test]
+    fn tombstone_buries_only_the_generations_it_covers() {
+        let store = ImageStore::with_storage(Box::new(MemoryStorage::new()));
+        let mut first = upload("img", 10);
+        first.generation = Some(0);
+        stored(&store, &first);
+        store.delete("img").unwrap();
+        assert!(!store.contains("img"));
+        assert!(store.is_deleted("img", 0));
+        assert!(!store.is_deleted("img", 1));
+
+        // The same bytes stored again, one generation on, outlive a late
+        // tombstone for the first
+        assert_eq!(store.next_generation("img").unwrap(), 1);
+        first.generation = Some(1);
+        stored(&store, &first);
+        store.delete_through("img", 0).unwrap();
+        assert!(store.contains("img"));
+        assert_eq!(store.versions().unwrap(), [ImageVersion { image_id: "img".into(), generation: 1 }]);
+
+        // Deleting the second raises the tombstone to it
+        store.delete("img").unwrap();
+        assert!(!store.contains("img"));
+        assert_eq!(store.tombstones().unwrap(), [ImageVersion { image_id: "img".into(), generation: 1 }]);
+        assert!(!store.is_deleted("img", 2));
+    }
+
+    #[test]
+    fn tombstone_never_lowers_its_generation() {
+        let store = ImageStore::with_storage(Box::new(MemoryStorage::new()));
+        store.delete_through("img", 3).unwrap();
+        store.delete_through("img", 1).unwrap();
+        assert_eq!(store.deleted_generation("img").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn last_generation_cannot_be_stored_again() {
+        let store = ImageStore::with_storage(Box::new(MemoryStorage::new()));
+        store.delete_through("img", u64::MAX - 1).unwrap();
+        assert_eq!(store.next_generation("img").unwrap(), u64::MAX);
+        store.delete_through("img", u64::MAX).unwrap();
+        assert!(store.next_generation("img").is_err());
+    }
+
+    #[test]
+    fn empty_tombstone_buries_the_first_generation() {
+        let storage = MemoryStorage::new();
+        storage.scoped("deleted").put("img", &[]).unwrap();
+        let store = ImageStore::with_storage(Box::new(storage));
+        assert!(store.is_deleted("img", 0));
+        assert!(!store.is_deleted("img", 1));
+    }
+
+    fn key(byte: u8) -> ImageKey {
+        ImageKey {
+            cipher: Aes256Gcm::new_from_slice(&[byte; 32]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn replicas_are_encrypted_at_rest_and_decrypted_on_load() {
+        let storage = MemoryStorage::new();
+        let store = ImageStore::with_storage(Box::new(storage.clone())).with_key(key(1));
+        let image = upload("img", 4096);
+        stored(&store, &image);
+        store.save_thumbnail("img", b"thumbnail bytes").unwrap();
+
+        // What reaches the backend is neither the plaintext nor contains it
+        let at_rest = storage.get("img").unwrap().unwrap();
+        assert_ne!(at_rest, image.bytes);
+        assert!(!at_rest.windows(64).any(|window| image.bytes.starts_with(window)));
+        assert_ne!(storage.scoped("thumb").get("img").unwrap().unwrap(), b"thumbnail bytes");
+
+        assert_eq!(store.load("img").unwrap(), Some(image.bytes.clone()));
+        assert_eq!(store.load_verified("img").unwrap(), Some(image.bytes));
+        assert_eq!(store.load_thumbnail("img").unwrap().as_deref(), Some(&b"thumbnail bytes"[..]));
+
+        // Sealed under a random nonce: the same bytes never look alike twice
+        let again = upload("again", 4096);
+        stored(&store, &again);
+        assert_ne!(storage.get("again").unwrap(), Some(at_rest));
+
+        // Another key cannot read them
+        let stranger = ImageStore::with_storage(Box::new(storage)).with_key(key(2));
+        assert!(stranger.load("img").is_err());
+    }
+
+    #[test]
+    fn stored_images_are_found_by_hash_through_the_persisted_index() {
+        let storage = MemoryStorage::new();
+        let store = ImageStore::with_storage(Box::new(storage.clone()));
+        let first = upload("first", 512);
+        let hash = content_hash(&first.bytes);
+        stored(&store, &first);
+        assert_eq!(storage.scoped("hash").get(&hash).unwrap().as_deref(), Some(&b"first"[..]));
+
+        // The index outlives the store that wrote it
+        let reopened = ImageStore::with_storage(Box::new(storage.clone()));
+        assert_eq!(reopened.find_by_hash(&hash).unwrap().map(|acl| acl.image_id).as_deref(), Some("first"));
+
+        // Overwriting the ID with other bytes leaves the old hash unmatched
+        let overwrite = Upload {
+            bytes: (0..512).map(|i| (i % 13) as u8).collect(),
+            ..upload("first", 0)
+        };
+        stored(&store, &overwrite);
+        assert_eq!(store.find_by_hash(&hash).unwrap(), None);
+
+        // Deleting the image drops its entry
+        let second = upload("second", 256);
+        stored(&store, &second);
+        let second_hash = content_hash(&second.bytes);
+        assert!(store.find_by_hash(&second_hash).unwrap().is_some());
+        store.delete("second").unwrap();
+        assert_eq!(store.find_by_hash(&second_hash).unwrap(), None);
+        assert_eq!(storage.scoped("hash").get(&second_hash).unwrap(), None);
+    }
+
+    #[test]
+    fn meta_comes_from_the_acl_without_reading_the_image() {
+        let storage = MemoryStorage::new();
+        let store = ImageStore::with_storage(Box::new(storage.clone())).with_key(key(1));
+        let image = Upload {
+            watermark_owner: Some(7),
+            ..upload("img", 4096)
+        };
+        stored(&store, &image);
+
+        // A store that cannot decrypt the image still describes it
+        let stranger = ImageStore::with_storage(Box::new(storage)).with_key(key(2));
+        let meta = stranger.meta("img").unwrap().unwrap();
+        assert_eq!((meta.size, meta.owner), (4096, Some(7)));
+
+        // Images stored before the ACL recorded their size are measured from their bytes
+        let mut legacy = store.load_acl("img").unwrap().unwrap();
+        (legacy.size, legacy.owner) = (None, None);
+        store.save_acl(&legacy).unwrap();
+        let meta = store.meta("img").unwrap().unwrap();
+        assert_eq!((meta.size, meta.owner), (4096, None));
+        assert!(stranger.meta("img").is_err());
+        assert_eq!(store.meta("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn shuffled_chunks_reassemble_the_image() {
+        let sent = upload("img", 5 * CHUNK_SIZE + 17);
+        let mut chunks = chunks(&sent);
+        assert_eq!(chunks.len(), 6);
+        chunks.reverse();
+        chunks.swap(1, 4);
+
+        let mut reassembler = Reassembler::new();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert!(reassembler.insert(SENDER, chunk).is_none());
+        }
+        let received = reassembler.insert(SENDER, last).unwrap().unwrap();
+        assert_eq!(received.bytes, sent.bytes);
+        assert_eq!(received.allowed_node_ids, sent.allowed_node_ids);
+        assert_eq!(received.max_views, sent.max_views);
+    }
+
+    /// Whether each chunk was sent compressed, and the data bytes they carry in all
+    fn on_the_wire(chunks: &[Message]) -> (Vec<bool>, usize) {
+        chunks
+            .iter()
+            .map(|chunk| match chunk {
+                Message::ImageChunk { compressed, data, .. } => (*compressed, data.len()),
+                _ => panic!("not a chunk: {:?}", chunk),
+            })
+            .fold((Vec::new(), 0), |(mut flags, total), (compressed, len)| {
+                flags.push(compressed);
+                (flags, total + len)
+            })
+    }
+
+    fn reassembled(chunks: Vec<Message>) -> Upload {
+        let mut reassembler = Reassembler::new();
+        let mut received = None;
+        for chunk in chunks {
+            received = reassembler.insert(SENDER, chunk);
+        }
+        received.expect("image incomplete").unwrap()
+    }
+
+    #[test]
+    fn a_compressible_image_is_sent_smaller_and_arrives_intact() {
+        let sent = upload("img", 3 * CHUNK_SIZE + 17);
+        let mut chunks = chunks(&sent);
+        let (compressed, len) = on_the_wire(&chunks);
+        // The 17-byte tail would only grow, so it goes as it is
+        assert_eq!(compressed, [true, true, true, false]);
+        assert!(len < sent.bytes.len() / 10, "{} bytes sent for {}", len, sent.bytes.len());
+
+        chunks.reverse();
+        assert_eq!(reassembled(chunks).bytes, sent.bytes);
+    }
+
+    #[test]
+    fn incompressible_and_precompressed_images_are_sent_as_they_are() {
+        let mut state = 1u32;
+        let noise = upload_of((0..2 * CHUNK_SIZE).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }));
+        // Compressible, but headed like a JPEG, so not worth trying
+        let jpeg = upload_of(b"\xFF\xD8\xFF".iter().copied().chain(upload("img", CHUNK_SIZE).bytes));
+
+        for sent in [noise, jpeg] {
+            let chunks = chunks(&sent);
+            let (compressed, len) = on_the_wire(&chunks);
+            assert!(compressed.iter().all(|&compressed| !compressed));
+            assert_eq!(len, sent.bytes.len());
+            assert_eq!(reassembled(chunks).bytes, sent.bytes);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upload_missing_a_chunk_expires() {
+        let mut chunks = chunks(&upload("img", 3 * CHUNK_SIZE));
+        chunks.remove(1);
+
+        let mut reassembler = Reassembler::new();
+        for chunk in chunks {
+            assert!(reassembler.insert(SENDER, chunk).is_none());
+        }
+        assert!(reassembler.expire(CHUNK_TIMEOUT).is_empty());
+        tokio::time::advance(CHUNK_TIMEOUT + Duration::from_millis(1)).await;
+        assert_eq!(reassembler.expire(CHUNK_TIMEOUT), ["img"]);
+    }
+
+    #[test]
+    fn one_sender_cannot_open_more_than_its_share() {
+        let mut reassembler = Reassembler::new();
+        for i in 0..MAX_PARTIAL_UPLOADS_PER_SENDER {
+            reassembler.insert(SENDER, opening_chunk(&format!("img-{}", i)));
+        }
+        reassembler.insert(SENDER, opening_chunk("one-too-many"));
+        assert!(!is_open(&reassembler, SENDER, "one-too-many"));
+
+        // Other senders are unaffected, and open uploads still take chunks
+        reassembler.insert(ChunkSender::Connection(7), opening_chunk("other"));
+        assert!(is_open(&reassembler, ChunkSender::Connection(7), "other"));
+        let mut next = opening_chunk("img-0");
+        if let Message::ImageChunk { seq, .. } = &mut next {
+            *seq = 1;
+        }
+        assert!(reassembler.insert(SENDER, next).is_some());
+    }
+
+    #[test]
+    fn partial_uploads_are_capped_across_senders() {
+        let mut reassembler = Reassembler::new();
+        for i in 0..MAX_PARTIAL_UPLOADS {
+            let sender = ChunkSender::Peer((i / MAX_PARTIAL_UPLOADS_PER_SENDER) as u32);
+            reassembler.insert(sender, opening_chunk(&format!("img-{}", i)));
+        }
+        assert_eq!(reassembler.partial.len(), MAX_PARTIAL_UPLOADS);
+
+        reassembler.insert(ChunkSender::Peer(99), opening_chunk("one-too-many"));
+        assert_eq!(reassembler.partial.len(), MAX_PARTIAL_UPLOADS);
+        assert!(!is_open(&reassembler, ChunkSender::Peer(99), "one-too-many"));
+    }
+
+    #[test]
+    fn two_senders_uploading_one_image_id_do_not_mix_chunks() {
+        let ours = upload("img", 2 * CHUNK_SIZE + 5);
+        let mut theirs = upload("img", 2 * CHUNK_SIZE + 5);
+        theirs.bytes.reverse();
+        let other = ChunkSender::Connection(7);
+
+        let mut reassembler = Reassembler::new();
+        let mut received = Vec::new();
+        for (a, b) in chunks(&ours).into_iter().zip(chunks(&theirs)) {
+            received.extend(reassembler.insert(SENDER, a));
+            received.extend(reassembler.insert(other, b));
+        }
+        let received: Vec<_> = received.into_iter().map(|upload| upload.unwrap().bytes).collect();
+        assert_eq!(received, [ours.bytes, theirs.bytes]);
+        assert!(reassembler.partial.is_empty());
+    }
+}
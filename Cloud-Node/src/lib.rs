@@ -0,0 +1,67 @@
+//! Leader election engine for the cloud P2P image-sharing cluster.
+//!
+//! Embed a [`Node`] in a host service by building it from a [`Config`],
+//! grabbing a [`NodeHandle`] to observe leadership, and driving [`Node::run`]
+//! on the Tokio runtime:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! let config = cloud_p2p::Config::from_file("config.local.json")?;
+//! let (node, mut changes) = cloud_p2p::Node::new(0, config)?;
+//! let handle = node.handle();
+//! tokio::spawn(node.run());
+//!
+//! while changes.changed().await.is_ok() {
+//!     let state = *changes.borrow();
+//!     println!("node {} sees leader {:?} (term {})", handle.node_id(), state.leader, state.term);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Time
+//!
+//! Every timer and timestamp goes through `tokio::time` (`Instant`, `sleep`,
+//! `interval`, `timeout`), never `std::time::Instant` or `SystemTime`, so the
+//! runtime's clock is the only clock. A test can substitute virtual time with
+//! `#[tokio::test(start_paused = true)]` or `tokio::time::pause`, enabled by
+//! the `testing` feature. On a [`MemoryNetwork`](transport::MemoryNetwork),
+//! time then jumps straight past `failure_timeout` whenever the cluster is idle.
+//!
+//! The clock is read by:
+//! - `Node`'s background tasks: reconnecting, heartbeats, `Coordinator`
+//!   broadcasts, successor updates, metrics, and the failure detector with its
+//!   election backoff and takeover wait
+//! - `Node`'s message loop, which stamps heartbeats and leader changes,
+//!   times the leader lease, expires pending replications, forwarded
+//!   uploads, checksum re-sends and fetch grants, and paces the anti-entropy
+//!   check
+//! - [`FailureDetector`](detector::FailureDetector) implementations only see
+//!   the `now` their caller passes in
+//! - [`Reassembler`](image::Reassembler), which stamps chunks, caps open uploads and expires stalled ones
+//! - `PeerConnection` and TLS handshakes, through `io_timeout`
+//! - the UDP node's staleness sweep and leader timeout
+//!
+//! The wall clock, [`detector::wall_clock`], is read only for log timestamps,
+//! to seed the UDP node's `Reliable` sequence numbers, and by
+//! [`ClockWatch`](detector::ClockWatch) to log when NTP or an operator steps
+//! it. No timeout depends on it, so a clock step cannot fake a live leader.
+
+pub mod detector;
+pub mod durability;
+#[cfg(any(test, feature = "testing"))]
+pub mod fault;
+pub mod image;
+pub mod logging;
+pub mod message;
+pub mod metrics;
+pub mod network;
+pub mod node;
+pub mod state;
+pub mod storage;
+pub mod tls;
+pub mod transport;
+pub mod udp;
+pub mod wal;
+
+pub use node::{ClusterView, Config, LeaderChangeReason, LeaderState, Node, NodeHandle, NodeInfo, Timings};
@@ -0,0 +1,149 @@
+use crate::node::LeaderState;
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use std::fmt;
+use tokio::sync::watch;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Span, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Output format for log lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable, emoji-decorated lines
+    #[default]
+    Pretty,
+    /// One JSON object per line for log aggregators
+    Json,
+}
+
+/// Span around everything a node does on behalf of one election, from the
+/// event that started it to the `Coordinator` that ends it. Records logged
+/// inside it carry `correlation_id`, the same on every node, so a log
+/// aggregator can follow the election across the cluster.
+pub fn election_span(correlation_id: u64) -> Span {
+    tracing::info_span!("election", correlation_id = %format_args!("{:016x}", correlation_id))
+}
+
+/// Which records to keep: `directives` if given, else `RUST_LOG`, else
+/// `info`. Directives use `RUST_LOG` syntax, a default level followed by
+/// per-module overrides, and each module logs under its path:
+/// `cloud_p2p::node` (elections, replication), `cloud_p2p::network`
+/// (connections and frames), `cloud_p2p::transport` (address resolution),
+/// `cloud_p2p::udp` (the UDP node), `cloud_p2p::image`, `cloud_p2p::wal`, and `cloud_p2p::metrics`.
+///
+/// ```
+/// use tracing::Level;
+/// use tracing_subscriber::prelude::*;
+///
+/// let filter = cloud_p2p::logging::filter(Some("info,cloud_p2p::network=debug")).unwrap();
+/// tracing::subscriber::with_default(tracing_subscriber::registry().with(filter), || {
+///     assert!(tracing::enabled!(target: "cloud_p2p::network", Level::DEBUG));
+///     assert!(!tracing::enabled!(target: "cloud_p2p::node", Level::DEBUG));
+///     assert!(tracing::enabled!(target: "cloud_p2p::node", Level::INFO));
+/// });
+/// assert!(cloud_p2p::logging::filter(Some("cloud_p2p::node=loud")).is_err());
+/// ```
+pub fn filter(directives: Option<&str>) -> Result<EnvFilter> {
+    match directives {
+        Some(directives) => EnvFilter::try_new(directives).context(format!("Invalid log level {:?}", directives)),
+        None => Ok(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))),
+    }
+}
+
+/// Install the global logger, keeping the records `filter` lets through.
+/// `log` records are routed through it, so they pick up the election span
+/// they were logged in. In JSON mode every record is tagged with this node's
+/// ID and, when `leader_rx` is given, the leader and term it currently sees.
+pub fn init(format: LogFormat, filter: EnvFilter, node_id: u32, leader_rx: Option<watch::Receiver<LeaderState>>) {
+    let output = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let output = match format {
+        LogFormat::Pretty => output.boxed(),
+        LogFormat::Json => output.event_format(JsonFormat { node_id, leader_rx }).boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(CorrelationLayer)
+        .with(output)
+        .init();
+}
+
+/// A span's correlation ID, kept in its extensions for the JSON formatter
+struct CorrelationId(String);
+
+/// Remembers the correlation ID of each span that has one
+struct CorrelationLayer;
+
+impl<S> Layer<S> for CorrelationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = FieldCollector::default();
+        attrs.record(&mut fields);
+        if let (Some(correlation_id), Some(span)) = (fields.correlation_id, ctx.span(id)) {
+            span.extensions_mut().insert(CorrelationId(correlation_id));
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldCollector {
+    message: String,
+    correlation_id: Option<String>,
+}
+
+impl Visit for FieldCollector {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            "correlation_id" => self.correlation_id = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+struct JsonFormat {
+    node_id: u32,
+    leader_rx: Option<watch::Receiver<LeaderState>>,
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        // Records bridged from `log` name their original target here
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut fields = FieldCollector::default();
+        event.record(&mut fields);
+        let correlation_id = ctx.event_scope().and_then(|scope| {
+            scope
+                .into_iter()
+                .find_map(|span| span.extensions().get::<CorrelationId>().map(|id| id.0.clone()))
+        });
+
+        let state = self.leader_rx.as_ref().map(|rx| *rx.borrow());
+        let line = serde_json::json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "node_id": self.node_id,
+            "leader": state.and_then(|s| s.leader),
+            "term": state.map(|s| s.term),
+            "correlation_id": correlation_id,
+            "event": fields.message,
+        });
+        writeln!(writer, "{}", line)
+    }
+}
@@ -1,582 +1,905 @@
-use clap::Parser;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use cloud_p2p::image::{self, ChunkSender, ImageKey, ImageStore, Reassembler, Upload};
+use cloud_p2p::logging::{self, LogFormat};
+use cloud_p2p::message::{ClusterKey, Message};
+use cloud_p2p::network::PeerConnection;
+use cloud_p2p::node::{parse_node_list, NODES_ENV};
+use cloud_p2p::tls::{self, TlsConfig};
+use cloud_p2p::transport::{self, MemoryNetwork};
+use cloud_p2p::udp::UdpNode;
+use cloud_p2p::{Config, Node, NodeHandle, NodeInfo};
+use log::info;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
-use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
-use tokio::time::{sleep, interval};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-enum Message {
-    Discovery {
-        sender_id: u32,
-        timestamp: u64,
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsConnector;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Transport {
+    /// Persistent TCP connections with successor takeover
+    Tcp,
+    /// Connectionless UDP datagrams with a Bully election
+    Udp,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a running node's view of the cluster
+    Status {
+        /// Address of any node, e.g. 127.0.0.1:8080
+        #[arg(long)]
+        connect: String,
+    },
+    /// List the images a node holds, with their size, owner and known replicas
+    Images {
+        /// Address of any node, e.g. 127.0.0.1:8080
+        #[arg(long)]
+        connect: String,
     },
-    LeaderAnnounce {
-        leader_id: u32,
-        timestamp: u64,
+    /// Make the leader step down so its successor takes over; the cluster
+    /// must run with --cluster-key and the same key must be passed here
+    ForceElection {
+        /// Address of any node, e.g. 127.0.0.1:8080
+        #[arg(long)]
+        connect: String,
+
+        /// ID recorded in the leader's log as having asked for the election
+        #[arg(long, default_value_t = 0)]
+        requester_id: u32,
     },
-    Election {
-        sender_id: u32,
-        timestamp: u64,
+    /// Upload an image for replication across the cluster
+    Upload {
+        /// Address of any node, e.g. 127.0.0.1:8080
+        #[arg(long)]
+        connect: String,
+
+        /// ID to store the image under; derived from the file's contents if
+        /// not given, so uploading the same file again returns the stored image
+        #[arg(long)]
+        image_id: Option<String>,
+
+        /// Image file to upload
+        #[arg(long)]
+        file: PathBuf,
+
+        /// Node IDs allowed to fetch the image, e.g. 1,2
+        #[arg(long, visible_alias = "acl", value_delimiter = ',')]
+        allow: Vec<u32>,
+
+        /// Embed an ownership watermark naming this node ID (output is stored as PNG)
+        #[arg(long)]
+        watermark_owner: Option<u32>,
+
+        /// Fetches each allowed node gets before its access is revoked
+        #[arg(long)]
+        max_views: Option<u32>,
     },
-    ElectionOk {
-        sender_id: u32,
-        timestamp: u64,
+    /// Fetch an image from the leader, subject to the image's ACL, and check
+    /// it against its content hash before writing it out
+    #[command(visible_alias = "download")]
+    Fetch {
+        /// Address of any node, e.g. 127.0.0.1:8080
+        #[arg(long)]
+        connect: String,
+
+        /// ID of the image to fetch
+        #[arg(long, visible_alias = "id")]
+        image_id: String,
+
+        /// Node ID to fetch on behalf of
+        #[arg(long)]
+        requester_id: u32,
+
+        /// Where to write the image
+        #[arg(long)]
+        out: PathBuf,
+
+        /// Fetch the image's low-resolution thumbnail instead
+        #[arg(long)]
+        thumbnail: bool,
     },
-    Coordinator {
-        leader_id: u32,
-        timestamp: u64,
+    /// Delete an image from every node; only its owner may
+    Delete {
+        /// Address of any node, e.g. 127.0.0.1:8080
+        #[arg(long)]
+        connect: String,
+
+        /// ID of the image to delete
+        #[arg(long)]
+        image_id: String,
+
+        /// Node ID to delete on behalf of: the image's watermarked owner, or
+        /// a node on its ACL if it carries no watermark
+        #[arg(long)]
+        requester_id: u32,
     },
-    Heartbeat {
-        leader_id: u32,
-        successor_id: Option<u32>,  // Second-highest active node
-        timestamp: u64,
+    /// Print the owner and image ID watermarked into an image file
+    Watermark {
+        /// Image file to inspect
+        #[arg(long)]
+        file: PathBuf,
     },
-    HeartbeatAck {
-        sender_id: u32,
-        timestamp: u64,
+    /// Time leader failover: start an in-process cluster on the in-memory
+    /// transport, kill its leader and measure how long the survivors take to
+    /// agree on a new one; print the percentiles as JSON
+    Bench {
+        /// Nodes in each cluster
+        #[arg(long, default_value_t = 5)]
+        nodes: u32,
+
+        /// Failovers to measure, each in a fresh cluster
+        #[arg(long, default_value_t = 10)]
+        runs: u32,
+
+        /// Config file whose timings and other settings the nodes use; its
+        /// node list and seeds are ignored
+        #[arg(long)]
+        config: Option<String>,
+
+        /// Give up on a cluster that has not converged after this many seconds
+        #[arg(long, default_value_t = 120)]
+        timeout_secs: u64,
     },
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum NodeState {
-    Follower,
-    Candidate,
-    Leader,
-}
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// This node's ID; must appear in the node list
+    #[arg(short, long, required = true)]
+    id: Option<u32>,
+
+    /// Config file path (optional, will use default if not provided).
+    /// Nodes in CLOUD_NODES (comma-separated id@address) override the
+    /// file's, and --listen/--advertise/--observer override both; with
+    /// neither a file nor CLOUD_NODES, a three-node local cluster is assumed,
+    /// node N listening on 127.0.0.1 at port 8080 + N, unless --seed is given.
+    #[arg(short, long)]
+    config: Option<String>,
 
-#[derive(Debug, Clone, Deserialize)]
-struct NodeConfig {
-    id: u32,
-    address: String,
+    /// Address of an existing member to learn the rest of the cluster from,
+    /// e.g. 10.0.0.1:8080; repeat or comma-separate for several. With seeds,
+    /// the config need only list this node, e.g. through --bind (TCP only)
+    #[arg(long = "seed", visible_alias = "peers", value_delimiter = ',')]
+    seeds: Vec<String>,
+
+    /// Listen on this address instead of the one in the config, e.g.
+    /// 0.0.0.0:8080; adds this node if the config does not list it
+    #[arg(long, visible_alias = "listen")]
+    bind: Option<String>,
+
+    /// Address peers should dial to reach this node, when it differs from
+    /// the bind address (behind NAT or in a container)
+    #[arg(long)]
+    advertise: Option<String>,
+
+    /// Follow the cluster for monitoring without ever becoming leader or successor
+    #[arg(long)]
+    observer: bool,
+
+    /// Transport used to exchange election messages
+    #[arg(short, long, value_enum, default_value = "tcp")]
+    transport: Transport,
+
+    /// Directory for persisted leadership state (TCP only)
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Directory for image replicas (TCP only)
+    #[arg(long)]
+    image_dir: Option<PathBuf>,
+
+    /// Directory for the write-ahead log of image writes and deletes, which
+    /// are replayed from it after a crash (TCP only)
+    #[arg(long, requires = "image_dir")]
+    wal_dir: Option<PathBuf>,
+
+    /// File holding the AES-256 key (32 raw bytes or 64 hex characters) used
+    /// to encrypt image replicas at rest
+    #[arg(long, requires = "image_dir")]
+    key_file: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9100, along
+    /// with a /healthz readiness check (TCP only)
+    #[arg(long)]
+    metrics_addr: Option<String>,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value = "pretty")]
+    log_format: LogFormat,
+
+    /// Which log records to show, in RUST_LOG syntax, overriding RUST_LOG;
+    /// e.g. info,cloud_p2p::network=debug. Modules log under
+    /// cloud_p2p::node, cloud_p2p::network, cloud_p2p::transport,
+    /// cloud_p2p::udp, cloud_p2p::image, cloud_p2p::wal and cloud_p2p::metrics
+    #[arg(long, value_name = "DIRECTIVES")]
+    log_level: Option<String>,
+
+    /// PEM certificate chain this node presents to peers; enables TLS (TCP
+    /// only). With a command, the certificate presented to a cluster that
+    /// runs with --tls-client-auth
+    #[arg(long, global = true, requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Refuse peers and clients without a certificate signed by --tls-ca, and
+    /// admit node N only if its certificate's common name is node-N.
+    /// Certificates that list extended key usages need clientAuth as well as
+    /// serverAuth.
+    #[arg(long, requires = "tls_cert")]
+    tls_client_auth: bool,
+
+    /// PEM certificate of the CA that signed every node's certificate; on its
+    /// own, lets the status/upload/fetch commands talk to a TLS cluster
+    #[arg(long, global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// Shared secret every node and client must hold; each frame carries an
+    /// HMAC-SHA256 tag under it and frames that don't verify are rejected
+    #[arg(long, global = true)]
+    cluster_key: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
-    nodes: Vec<NodeConfig>,
+/// How the status/upload/fetch commands reach a node
+struct Dialer {
+    tls: Option<TlsConnector>,
+    cluster_key: Option<ClusterKey>,
 }
 
-struct Node {
-    id: u32,
-    address: SocketAddr,
-    all_nodes: HashMap<u32, SocketAddr>,
-    state: Arc<RwLock<NodeState>>,
-    current_leader: Arc<RwLock<Option<u32>>>,
-    successor_hint: Arc<RwLock<Option<u32>>>,  // Known successor from leader
-    active_nodes: Arc<RwLock<HashMap<u32, SystemTime>>>,  // Track last seen time for each node
-    last_heartbeat: Arc<RwLock<SystemTime>>,
-    election_in_progress: Arc<RwLock<bool>>,
-    socket: Arc<UdpSocket>,
+impl Dialer {
+    async fn connect(&self, addr: &str) -> anyhow::Result<PeerConnection> {
+        let stream = TcpStream::connect(transport::resolve(addr).await?)
+            .await
+            .context(format!("Failed to connect to {}", addr))?;
+        let conn = match &self.tls {
+            Some(tls) => {
+                let stream = tls
+                    .connect(tls::server_name(addr)?, stream)
+                    .await
+                    .context(format!("TLS handshake with {} failed", addr))?;
+                PeerConnection::from_stream(stream)
+            }
+            None => PeerConnection::new(stream),
+        };
+        Ok(conn.with_cluster_key(self.cluster_key.clone()))
+    }
 }
 
-impl Node {
-    async fn new(id: u32, config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
-        let node_config = config
-            .nodes
-            .iter()
-            .find(|n| n.id == id)
-            .ok_or("Node ID not found in config")?;
-
-        let address: SocketAddr = node_config.address.parse()?;
-        let socket = UdpSocket::bind(address).await?;
-        
-        let mut all_nodes = HashMap::new();
-        for node in &config.nodes {
-            all_nodes.insert(node.id, node.address.parse()?);
-        }
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
 
-        println!("Node {} starting at {}", id, address);
-
-        Ok(Self {
-            id,
-            address,
-            all_nodes,
-            state: Arc::new(RwLock::new(NodeState::Follower)),
-            current_leader: Arc::new(RwLock::new(None)),
-            successor_hint: Arc::new(RwLock::new(None)),
-            active_nodes: Arc::new(RwLock::new(HashMap::new())),
-            last_heartbeat: Arc::new(RwLock::new(SystemTime::now())),
-            election_in_progress: Arc::new(RwLock::new(false)),
-            socket: Arc::new(socket),
-        })
+    let cluster_key = args
+        .cluster_key
+        .as_deref()
+        .map(|secret| ClusterKey::new(secret.as_bytes()))
+        .transpose()?;
+    let dialer = Dialer {
+        tls: match (&args.command, &args.tls_cert, &args.tls_key, &args.tls_ca) {
+            (Some(_), Some(cert), Some(key), Some(ca)) => Some(TlsConfig::from_files(cert, key, ca)?.connector().clone()),
+            (Some(_), _, _, Some(ca)) => Some(tls::connector(ca)?),
+            _ => None,
+        },
+        cluster_key: cluster_key.clone(),
+    };
+    match &args.command {
+        Some(Command::Status { connect }) => return print_status(&dialer, connect).await,
+        Some(Command::Images { connect }) => return print_images(&dialer, connect).await,
+        Some(Command::ForceElection { connect, requester_id }) => {
+            return force_election(&dialer, connect, *requester_id).await
+        }
+        Some(Command::Upload { connect, image_id, file, allow, watermark_owner, max_views }) => {
+            return upload_image(&dialer, connect, image_id.as_deref(), file, allow, *watermark_owner, *max_views).await
+        }
+        Some(Command::Fetch { connect, image_id, requester_id, out, thumbnail }) => {
+            return fetch_image(&dialer, connect, image_id, *requester_id, out, *thumbnail).await
+        }
+        Some(Command::Delete { connect, image_id, requester_id }) => {
+            return delete_image(&dialer, connect, image_id, *requester_id).await
+        }
+        Some(Command::Watermark { file }) => return print_watermark(file),
+        Some(Command::Bench { nodes, runs, config, timeout_secs }) => {
+            return bench(*nodes, *runs, config.as_deref(), Duration::from_secs(*timeout_secs)).await
+        }
+        None => {}
     }
+    let id = args.id.context("--id is required")?;
 
-    async fn start(self: Arc<Self>) {
-        // Start message listener
-        let node_clone = Arc::clone(&self);
-        tokio::spawn(async move {
-            node_clone.listen().await;
-        });
-
-        // Give listener time to start
-        sleep(Duration::from_millis(500)).await;
-
-        // Discover cluster
-        self.discover_cluster().await;
-
-        // Start heartbeat monitor
-        let node_clone = Arc::clone(&self);
-        tokio::spawn(async move {
-            node_clone.monitor_leader().await;
-        });
-
-        // Start heartbeat sender
-        let node_clone = Arc::clone(&self);
-        tokio::spawn(async move {
-            node_clone.send_heartbeats().await;
-        });
-
-        // Start status reporter
-        let node_clone = Arc::clone(&self);
-        tokio::spawn(async move {
-            node_clone.report_status().await;
-        });
-
-        println!("Node {} started successfully", self.id);
+    // Precedence, lowest first: config file (or a three-node local cluster),
+    // then CLOUD_NODES, then this node's own command-line flags
+    let env_nodes = match std::env::var(NODES_ENV) {
+        Ok(spec) if !spec.trim().is_empty() => {
+            Some(parse_node_list(&spec).context(format!("Invalid {}", NODES_ENV))?)
+        }
+        _ => None,
+    };
+    let mut config: Config = match (&args.config, &env_nodes) {
+        (Some(config_path), _) => Config::load(config_path)?,
+        (None, None) if args.seeds.is_empty() => Config::localhost(3),
+        (None, _) => Config::default(),
+    };
+    if let Some(nodes) = env_nodes {
+        config.merge_nodes(nodes);
     }
-
-    fn calculate_successor(
-        &self,
-        active_nodes: &HashMap<u32, SystemTime>,
-        exclude_id: u32, // the id to exclude (the current leader)
-    ) -> Option<u32> {
-        // Gather candidates from active nodes and ensure self is included
-        let mut ids: Vec<u32> = active_nodes.keys().copied().collect();
-        ids.push(self.id);
-    
-        // De-dup and sort descending
-        ids.sort_unstable_by(|a, b| b.cmp(a));
-        ids.dedup();
-    
-        // Exclude the leader (or any exclude_id) and return the highest remaining
-        ids.into_iter().find(|&id| id != exclude_id)
+    config.seeds.extend(args.seeds.iter().cloned());
+    if !config.nodes.iter().any(|node| node.id == id) {
+        if let Some(bind) = &args.bind {
+            config.nodes.push(NodeInfo {
+                id,
+                bind_address: bind.clone(),
+                advertise_address: None,
+                priority: 0,
+                observer: false,
+            });
+        }
     }
-    
+    if let Some(me) = config.nodes.iter_mut().find(|node| node.id == id) {
+        if let Some(bind) = args.bind {
+            me.bind_address = bind;
+        }
+        if let Some(advertise) = args.advertise {
+            me.advertise_address = Some(advertise);
+        }
+        if args.observer {
+            me.observer = true;
+        }
+    }
+    config.validate().context(match &args.config {
+        Some(config_path) => format!("Invalid config {}", config_path),
+        None => "Invalid config".to_string(),
+    })?;
 
-    async fn discover_cluster(&self) {
-        println!("Node {}: Starting cluster discovery...", self.id);
+    let log_filter = logging::filter(args.log_level.as_deref())?;
 
-        let discovery_msg = Message::Discovery {
-            sender_id: self.id,
-            timestamp: current_timestamp(),
-        };
+    let tls = match (&args.tls_cert, &args.tls_key, &args.tls_ca) {
+        (Some(cert), Some(key), Some(ca)) if args.tls_client_auth => {
+            Some(TlsConfig::from_files(cert, key, ca)?.with_client_auth()?)
+        }
+        (Some(cert), Some(key), Some(ca)) => Some(TlsConfig::from_files(cert, key, ca)?),
+        (None, None, None) => None,
+        _ => anyhow::bail!("A node needs --tls-cert, --tls-key and --tls-ca together"),
+    };
 
-        // Send to all other nodes
-        for (node_id, addr) in &self.all_nodes {
-            if *node_id != self.id {
-                self.send_message(addr, &discovery_msg).await;
+    match args.transport {
+        Transport::Tcp => {
+            let (mut node, leader_rx) = Node::new(id, config)?;
+            logging::init(args.log_format, log_filter, id, Some(leader_rx));
+            if let Some(state_dir) = &args.state_dir {
+                node = node.with_state_dir(state_dir);
+            }
+            if let Some(image_dir) = &args.image_dir {
+                node = node.with_image_dir(image_dir);
+            }
+            if let Some(key_file) = &args.key_file {
+                node = node.with_image_key(ImageKey::from_file(key_file)?);
+            }
+            if let Some(wal_dir) = &args.wal_dir {
+                node = node.with_wal_dir(wal_dir);
+            }
+            if let Some(metrics_addr) = args.metrics_addr {
+                node = node.with_metrics_addr(metrics_addr);
+            }
+            if let Some(tls) = tls {
+                node = node.with_tls(tls);
+            }
+            if let Some(cluster_key) = cluster_key {
+                node = node.with_cluster_key(cluster_key);
+            }
+            let handle = node.handle();
+            let run = node.run();
+            tokio::pin!(run);
+            tokio::select! {
+                result = &mut run => result?,
+                terminated = terminated() => {
+                    terminated?;
+                    // Leave the cluster cleanly rather than dropping the node
+                    handle.shutdown();
+                    run.await?;
+                }
+            }
+        }
+        Transport::Udp => {
+            if tls.is_some() {
+                anyhow::bail!("TLS is only supported over the TCP transport");
+            }
+            logging::init(args.log_format, log_filter, id, None);
+            let mut node = UdpNode::new(id, &config).await?;
+            if let Some(cluster_key) = cluster_key {
+                node = node.with_cluster_key(cluster_key);
             }
+            let node = Arc::new(node);
+            node.start().await;
+
+            // Keep running
+            terminated().await?;
         }
+    }
+
+    info!("Shutting down node {}...", id);
 
-        // Wait for responses
-        sleep(Duration::from_secs(2)).await;
+    Ok(())
+}
+
+/// Wait for Ctrl-C or SIGTERM, the signals that ask a node to stop
+async fn terminated() -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = sigterm.recv() => info!("🛑 SIGTERM received - shutting down"),
+    }
+    Ok(())
+}
 
-        let leader = *self.current_leader.read().await;
-        if leader.is_none() {
-            println!("Node {}: No leader found, starting election...", self.id);
-            self.start_election().await;
-        } else {
-            println!("Node {}: Discovered leader is Node {}", self.id, leader.unwrap());
+async fn upload_image(
+    dialer: &Dialer,
+    addr: &str,
+    image_id: Option<&str>,
+    file: &PathBuf,
+    allow: &[u32],
+    watermark_owner: Option<u32>,
+    max_views: Option<u32>,
+) -> anyhow::Result<()> {
+    if let Some(image_id) = image_id {
+        ImageStore::validate_id(image_id)?;
+    }
+    let bytes = std::fs::read(file).context(format!("Failed to read {}", file.display()))?;
+    // Without an ID, the same file always gets the same one
+    let image_id = match image_id {
+        Some(image_id) => image_id.to_string(),
+        None => image::content_id(&bytes),
+    };
+    let image_id = image_id.as_str();
+    if bytes.len() > image::MAX_IMAGE_SIZE {
+        anyhow::bail!(
+            "{} is too large to upload ({} bytes, limit {})",
+            file.display(),
+            bytes.len(),
+            image::MAX_IMAGE_SIZE
+        );
+    }
+
+    let upload = Upload {
+        image_id: image_id.to_string(),
+        bytes,
+        allowed_node_ids: allow.to_vec(),
+        watermark_owner,
+        max_views,
+        generation: None,
+    };
+    let addr = leader_address(dialer, addr).await?;
+    let addr = addr.as_str();
+    let conn = dialer.connect(addr).await?;
+    image::send_image(&conn, &upload).await?;
+
+    // The leader answers once a majority of replicas are on disk, or after its own timeout;
+    // an upload that arrived corrupted is sent again
+    let mut attempts = 0;
+    let response = loop {
+        let response = tokio::time::timeout(image::REPLICATION_TIMEOUT * 2, conn.receive_one())
+            .await
+            .context(format!("Timed out waiting for {} to confirm image {}", addr, image_id))??;
+        let Message::ChecksumMismatch { node_id, .. } = response else {
+            break response;
+        };
+        if attempts >= image::CHECKSUM_RETRIES {
+            anyhow::bail!("Image {} kept arriving corrupted at Node {}", image_id, node_id);
+        }
+        attempts += 1;
+        println!("Image {} arrived corrupted at Node {}, sending it again", image_id, node_id);
+        image::send_image(&conn, &upload).await?;
+    };
+
+    match response {
+        Message::StoreResult { durable: true, acks, quorum, stored_as: Some(stored_as), .. } => {
+            println!(
+                "{} is already stored as image {} ({}/{} replicas)",
+                file.display(),
+                stored_as,
+                acks,
+                quorum
+            );
+            Ok(())
+        }
+        Message::StoreResult { durable: true, acks, quorum, .. } => {
+            println!("Uploaded {} as image {} ({}/{} replicas)", file.display(), image_id, acks, quorum);
+            Ok(())
         }
+        Message::StoreResult { error: Some(error), .. } => {
+            anyhow::bail!("Image {} was rejected: {}", image_id, error)
+        }
+        Message::StoreResult { acks, quorum, .. } => {
+            anyhow::bail!("Image {} is not durable: {}/{} replicas acknowledged", image_id, acks, quorum)
+        }
+        other => anyhow::bail!("Unexpected reply from {}: {:?}", addr, other),
     }
+}
 
-    async fn start_election(&self) {
-        let mut election_in_progress = self.election_in_progress.write().await;
-        if *election_in_progress {
-            return;
+async fn fetch_image(
+    dialer: &Dialer,
+    addr: &str,
+    image_id: &str,
+    requester_id: u32,
+    out: &PathBuf,
+    thumbnail: bool,
+) -> anyhow::Result<()> {
+    let mut addr = leader_address(dialer, addr).await?;
+    let request = if thumbnail {
+        Message::FetchThumbnail {
+            image_id: image_id.to_string(),
+            requester_id,
         }
-        *election_in_progress = true;
-        drop(election_in_progress);
-
-        println!("Node {}: Starting election...", self.id);
-
-        // Check if we have a successor hint
-        let successor_hint = *self.successor_hint.read().await;
-        
-        // IMPROVED BULLY: Check if we ARE the successor
-        if let Some(successor_id) = successor_hint {
-            if successor_id == self.id {
-                println!("Node {}: I am the successor! Becoming leader directly.", self.id);
-                self.become_leader().await;
-                *self.election_in_progress.write().await = false;
-                return;
-            } else if successor_id > self.id {
-                // We know about a higher successor, defer to it first
-                println!("Node {}: Deferring to known successor Node {}", self.id, successor_id);
-                
-                let election_msg = Message::Election {
-                    sender_id: self.id,
-                    timestamp: current_timestamp(),
-                };
-                
-                if let Some(successor_addr) = self.all_nodes.get(&successor_id) {
-                    self.send_message(successor_addr, &election_msg).await;
+    } else {
+        Message::FetchImage {
+            image_id: image_id.to_string(),
+            requester_id,
+        }
+    };
+
+    // The leader may redirect once to the follower that should serve the image.
+    // A copy that arrives corrupted is asked for again from the leader, which
+    // hands the retry to the least loaded replica, usually another one.
+    let leader_addr = addr.clone();
+    let mut redirected = false;
+    let mut attempts = 0;
+    loop {
+        let conn = dialer.connect(&addr).await?;
+        conn.send(&request).await?;
+
+        let mut reassembler = Reassembler::new();
+        loop {
+            let response = tokio::time::timeout(image::CHUNK_TIMEOUT, conn.receive_one())
+                .await
+                .context(format!("Timed out waiting for image {} from {}", image_id, addr))??;
+
+            match response {
+                Message::AccessDenied { reason, .. } => anyhow::bail!("Access denied: {}", reason),
+                Message::FetchRedirect { .. } if redirected => {
+                    anyhow::bail!("Too many redirects fetching image {}", image_id)
                 }
-                
-                // Wait briefly for successor to respond
-                sleep(Duration::from_millis(800)).await;
-                
-                // Check if we got a response
-                let state = self.state.read().await;
-                if *state == NodeState::Leader {
-                    drop(state);
-                    *self.election_in_progress.write().await = false;
-                    return;
+                Message::FetchRedirect { serve_node_id, address, .. } => {
+                    println!("Redirected to Node {} at {}", serve_node_id, address);
+                    addr = address;
+                    redirected = true;
+                    break;
                 }
-                drop(state);
-                
-                // Successor didn't respond, fall back to normal election
-                println!("Node {}: Successor didn't respond, falling back to normal election", self.id);
+                chunk @ Message::ImageChunk { .. } => match reassembler.insert(ChunkSender::Connection(conn.id()), chunk) {
+                    Some(Ok(upload)) => {
+                        std::fs::write(out, &upload.bytes).context(format!("Failed to write {}", out.display()))?;
+                        let what = if thumbnail { "thumbnail of image" } else { "image" };
+                        println!("Fetched {} {} ({} bytes) to {}", what, image_id, upload.bytes.len(), out.display());
+                        return Ok(());
+                    }
+                    Some(Err(corrupt)) => {
+                        if attempts >= image::CHECKSUM_RETRIES {
+                            anyhow::bail!("Image {} kept arriving corrupted: {}", image_id, corrupt);
+                        }
+                        attempts += 1;
+                        println!("{} from {}, fetching it again", corrupt, addr);
+                        addr = leader_addr.clone();
+                        redirected = false;
+                        break;
+                    }
+                    None => {}
+                },
+                other => anyhow::bail!("Unexpected reply from {}: {:?}", addr, other),
             }
         }
+    }
+}
 
-        // Normal Bully Algorithm election
-        let election_msg = Message::Election {
-            sender_id: self.id,
-            timestamp: current_timestamp(),
-        };
-
-        let higher_nodes: Vec<_> = self
-            .all_nodes
-            .iter()
-            .filter(|(id, _)| **id > self.id)
-            .collect();
-
-        if higher_nodes.is_empty() {
-            // No higher nodes, become leader
-            self.become_leader().await;
-            *self.election_in_progress.write().await = false;
-            return;
+async fn delete_image(dialer: &Dialer, addr: &str, image_id: &str, requester_id: u32) -> anyhow::Result<()> {
+    let addr = leader_address(dialer, addr).await?;
+    let addr = addr.as_str();
+    let conn = dialer.connect(addr).await?;
+    conn.send(&Message::DeleteImage {
+        image_id: image_id.to_string(),
+        requester_id,
+    })
+    .await?;
+
+    let response = tokio::time::timeout(Duration::from_secs(30), conn.receive_one())
+        .await
+        .context(format!("Timed out waiting for the delete of image {} at {}", image_id, addr))??;
+    match response {
+        Message::ImageDeleted { .. } => {
+            println!("Deleted image {}", image_id);
+            Ok(())
         }
+        Message::AccessDenied { reason, .. } => anyhow::bail!("Access denied: {}", reason),
+        other => anyhow::bail!("Unexpected reply from {}: {:?}", addr, other),
+    }
+}
 
-        // Contact all higher nodes
-        for (_, addr) in &higher_nodes {
-            self.send_message(addr, &election_msg).await;
-        }
+async fn print_images(dialer: &Dialer, addr: &str) -> anyhow::Result<()> {
+    let conn = dialer.connect(addr).await?;
+    conn.send(&Message::ListImages {}).await?;
 
-        // Wait for OK responses
-        sleep(Duration::from_millis(1500)).await;
+    let response = tokio::time::timeout(Duration::from_secs(30), conn.receive_one())
+        .await
+        .context(format!("Timed out waiting for the image list from {}", addr))??;
+    let Message::ImageList { entries } = response else {
+        anyhow::bail!("Unexpected reply from {}: {:?}", addr, response);
+    };
 
-        // Check if we should become leader
-        let state = self.state.read().await;
-        if *state != NodeState::Leader {
-            println!("Node {}: No response from higher nodes", self.id);
-            drop(state);
-            self.become_leader().await;
-        }
+    if entries.is_empty() {
+        println!("No images stored at {}", addr);
+        return Ok(());
+    }
 
-        *self.election_in_progress.write().await = false;
+    let width = entries.iter().map(|meta| meta.image_id.len()).max().unwrap_or(0).max("IMAGE".len());
+    println!("{:<width$}  {:>10}  {:<5}  REPLICAS", "IMAGE", "SIZE", "OWNER");
+    for meta in &entries {
+        let owner = meta.owner.map_or("-".to_string(), |owner| owner.to_string());
+        let replicas: Vec<String> = meta.replicas.iter().map(u32::to_string).collect();
+        println!("{:<width$}  {:>10}  {:<5}  {}", meta.image_id, meta.size, owner, replicas.join(","));
     }
+    Ok(())
+}
 
-    async fn become_leader(&self) {
-        println!("Node {}: Becoming leader!", self.id);
-    
-        *self.state.write().await = NodeState::Leader;
-        *self.current_leader.write().await = Some(self.id);
-        *self.last_heartbeat.write().await = SystemTime::now();
-    
-        // NEW: successor_hint is meaningless for a leader—clear it
-        *self.successor_hint.write().await = None;
-    
-        // (Optional) clear any stale active_nodes, start fresh
-        self.active_nodes.write().await.clear();
-    
-        // announce...
-        let coordinator_msg = Message::Coordinator {
-            leader_id: self.id,
-            timestamp: current_timestamp(),
-        };
-        for (node_id, addr) in &self.all_nodes {
-            if *node_id != self.id {
-                self.send_message(addr, &coordinator_msg).await;
+fn print_watermark(file: &PathBuf) -> anyhow::Result<()> {
+    let bytes = std::fs::read(file).context(format!("Failed to read {}", file.display()))?;
+    match image::extract_watermark(&bytes) {
+        Some((owner_node_id, image_id)) => println!("Image {} owned by Node {}", image_id, owner_node_id),
+        None => anyhow::bail!("{} carries no watermark", file.display()),
+    }
+    Ok(())
+}
+
+/// Run `runs` failovers of a `node_count`-node in-memory cluster and print
+/// how long each took to converge, in milliseconds of wall-clock time
+async fn bench(node_count: u32, runs: u32, config_path: Option<&str>, timeout: Duration) -> anyhow::Result<()> {
+    if node_count < 2 {
+        anyhow::bail!("A failover needs at least 2 nodes");
+    }
+    if runs == 0 {
+        anyhow::bail!("--runs must be at least 1");
+    }
+    let mut config = match config_path {
+        Some(config_path) => Config::load(config_path)?,
+        None => Config::default(),
+    };
+    // The in-memory transport never binds these addresses, so runs can't
+    // collide with a real cluster on the same ports
+    config.seeds.clear();
+    config.nodes = Config::localhost(node_count).nodes;
+    config.validate().context("Invalid bench config")?;
+
+    let mut samples = Vec::new();
+    let mut failed = 0;
+    for run in 1..=runs {
+        match bench_failover(&config, timeout).await? {
+            Some(elapsed) => {
+                eprintln!("run {}/{}: converged in {:.1} ms", run, runs, elapsed.as_secs_f64() * 1000.0);
+                samples.push(elapsed.as_secs_f64() * 1000.0);
+            }
+            None => {
+                eprintln!("run {}/{}: did not converge within {:?}", run, runs, timeout);
+                failed += 1;
             }
         }
     }
-    
-    async fn send_heartbeats(&self) {
-        let mut interval = interval(Duration::from_secs(2));
-        
-        loop {
-            interval.tick().await;
-            
-            let state = self.state.read().await;
-            if *state == NodeState::Leader {
-                drop(state);
-                
-                // Calculate successor from active nodes
-                let active_nodes = self.active_nodes.read().await;
-                // exclude self (the leader) to get the next-highest active node
-                let successor_id = self.calculate_successor(&active_nodes, self.current_leader.read().await.unwrap());
-                drop(active_nodes);
-
-                if let Some(succ_id) = successor_id {
-                    println!("Node {}: Current successor is Node {}", self.id, succ_id);
-                }
 
-                
-                let heartbeat_msg = Message::Heartbeat {
-                    leader_id: self.id,
-                    successor_id,
-                    timestamp: current_timestamp(),
-                };
+    samples.sort_by(f64::total_cmp);
+    // Nearest-rank percentile of the sorted samples
+    let percentile = |p: f64| {
+        let rank = ((p / 100.0) * samples.len() as f64).ceil() as usize;
+        samples.get(rank.saturating_sub(1)).copied()
+    };
+    let mean = (!samples.is_empty()).then(|| samples.iter().sum::<f64>() / samples.len() as f64);
+    let report = serde_json::json!({
+        "nodes": node_count,
+        "runs": runs,
+        "failed": failed,
+        "convergence_ms": {
+            "min": samples.first(),
+            "p50": percentile(50.0),
+            "p90": percentile(90.0),
+            "p99": percentile(99.0),
+            "max": samples.last(),
+            "mean": mean,
+        },
+        "samples_ms": samples,
+    });
+    println!("{}", report);
+    Ok(())
+}
 
-                for (node_id, addr) in &self.all_nodes {
-                    if *node_id != self.id {
-                        self.send_message(addr, &heartbeat_msg).await;
-                    }
-                }
-            }
-        }
+/// Start a cluster, wait for it to settle, kill the leader and time how long
+/// the survivors take to agree on a new one; `None` if either step times out
+async fn bench_failover(config: &Config, timeout: Duration) -> anyhow::Result<Option<Duration>> {
+    let network = MemoryNetwork::new();
+    let mut handles = Vec::new();
+    let mut tasks = Vec::new();
+    for info in &config.nodes {
+        let (node, _) = Node::new(info.id, config.clone())?;
+        let node = node.with_transport(network.transport(&info.bind_address));
+        handles.push(node.handle());
+        tasks.push(tokio::spawn(node.run()));
     }
 
-    async fn monitor_leader(&self) {
-        let mut interval = interval(Duration::from_secs(1));
-        
-        loop {
-            interval.tick().await;
-            
-            let state = self.state.read().await;
-            if *state != NodeState::Leader {
-                drop(state);
-                
-                let last_hb = self.last_heartbeat.read().await;
-                let elapsed = SystemTime::now()
-                    .duration_since(*last_hb)
-                    .unwrap_or(Duration::from_secs(0));
-                
-                drop(last_hb);
-                
-                if elapsed > Duration::from_secs(5) {
-                    let election_in_progress = *self.election_in_progress.read().await;
-                    if !election_in_progress {
-                        println!("Node {}: Leader timeout detected!", self.id);
-                        *self.current_leader.write().await = None;
-                        self.start_election().await;
-                    }
-                }
-            }
+    let mut elapsed = None;
+    if let Ok(old_leader) = tokio::time::timeout(timeout, agreed_leader(&handles, None)).await {
+        let started = tokio::time::Instant::now();
+        let leader = handles.iter().position(|handle| handle.node_id() == old_leader).expect("leader is a member");
+        let leader = handles.remove(leader);
+        network.kill(&config.nodes[leader.node_id() as usize].bind_address);
+        leader.shutdown();
+        if tokio::time::timeout(timeout, agreed_leader(&handles, Some(old_leader))).await.is_ok() {
+            elapsed = Some(started.elapsed());
         }
     }
 
-    async fn listen(&self) {
-        let mut buf = [0u8; 4096];
-        
-        loop {
-            match self.socket.recv_from(&mut buf).await {
-                Ok((len, addr)) => {
-                    if let Ok(message) = serde_json::from_slice::<Message>(&buf[..len]) {
-                        self.handle_message(message, addr).await;
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Node {}: Error receiving: {}", self.id, e);
-                }
-            }
-        }
+    for info in &config.nodes {
+        network.kill(&info.bind_address);
     }
+    for handle in &handles {
+        handle.shutdown();
+    }
+    for task in tasks {
+        task.abort();
+    }
+    Ok(elapsed)
+}
 
-    async fn handle_message(&self, message: Message, _addr: SocketAddr) {
-        match message {
-            Message::Discovery { sender_id, .. } => {
-                // Track that this node is active
-                let mut active_nodes = self.active_nodes.write().await;
-                active_nodes.insert(sender_id, SystemTime::now());
-                drop(active_nodes);
-                
-                let state = self.state.read().await;
-                if *state == NodeState::Leader {
-                    drop(state);
-                    
-                    let response = Message::LeaderAnnounce {
-                        leader_id: self.id,
-                        timestamp: current_timestamp(),
-                    };
-                    
-                    if let Some(sender_addr) = self.all_nodes.get(&sender_id) {
-                        self.send_message(sender_addr, &response).await;
-                    }
-                }
-            }
-            
-            Message::LeaderAnnounce { leader_id, .. } => {
-                let current = *self.current_leader.read().await;
-                if current.is_none() || leader_id > current.unwrap() {
-                    println!("Node {}: Accepting Node {} as leader", self.id, leader_id);
-                    *self.current_leader.write().await = Some(leader_id);
-                    *self.state.write().await = NodeState::Follower;
-                    *self.last_heartbeat.write().await = SystemTime::now();
-                }
-            }
-            
-            Message::Election { sender_id, .. } => {
-                // Track that this node is active
-                let mut active_nodes = self.active_nodes.write().await;
-                active_nodes.insert(sender_id, SystemTime::now());
-                drop(active_nodes);
-                
-                if sender_id < self.id {
-                    // We have higher ID, send OK and start our own election
-                    let ok_msg = Message::ElectionOk {
-                        sender_id: self.id,
-                        timestamp: current_timestamp(),
-                    };
-                    
-                    if let Some(sender_addr) = self.all_nodes.get(&sender_id) {
-                        self.send_message(sender_addr, &ok_msg).await;
-                    }
-                    
-                    // Start our own election
-                    let election_in_progress = *self.election_in_progress.read().await;
-                    if !election_in_progress {
-                        // Call directly instead of spawning - we're already in async context
-                        self.start_election().await;
-                    }
-                }
-            }
-            
-            Message::ElectionOk { sender_id, .. } => {
-                println!("Node {}: Higher node {} responded to election", self.id, sender_id);
-                *self.state.write().await = NodeState::Follower;
-            }
-            
-            Message::Coordinator { leader_id, .. } => {
-                println!("Node {}: New coordinator is Node {}", self.id, leader_id);
-                *self.current_leader.write().await = Some(leader_id);
-                *self.state.write().await = NodeState::Follower;
-                *self.last_heartbeat.write().await = SystemTime::now();
-            }
-            
-            Message::Heartbeat { leader_id, successor_id, .. } => {
-                let current = *self.current_leader.read().await;
-                if current == Some(leader_id) {
-                    *self.last_heartbeat.write().await = SystemTime::now();
-                    
-                    // Store successor hint
-                    *self.successor_hint.write().await = successor_id;
-                    
-                    // Send acknowledgment back to leader
-                    let ack_msg = Message::HeartbeatAck {
-                        sender_id: self.id,
-                        timestamp: current_timestamp(),
-                    };
-                    
-                    if let Some(leader_addr) = self.all_nodes.get(&leader_id) {
-                        self.send_message(leader_addr, &ack_msg).await;
+/// Wait until every node follows the same leader, other than `old_leader`,
+/// and that leader knows it leads
+async fn agreed_leader(handles: &[NodeHandle], old_leader: Option<u32>) -> u32 {
+    loop {
+        let mut leaders = Vec::new();
+        for handle in handles {
+            leaders.push(handle.current_leader().await);
+        }
+        if let Some(Some(leader)) = leaders.first().copied() {
+            if Some(leader) != old_leader && leaders.iter().all(|&l| l == Some(leader)) {
+                if let Some(handle) = handles.iter().find(|handle| handle.node_id() == leader) {
+                    if handle.is_leader().await {
+                        return leader;
                     }
                 }
             }
-            
-            Message::HeartbeatAck { sender_id, .. } => {
-                // Leader receives acks to track active nodes
-                let state = self.state.read().await;
-                if *state == NodeState::Leader {
-                    drop(state);
-                    let mut active_nodes = self.active_nodes.write().await;
-                    active_nodes.insert(sender_id, SystemTime::now());
-                }
-            }
         }
+        tokio::time::sleep(Duration::from_millis(10)).await;
     }
+}
 
-    async fn send_message(&self, addr: &SocketAddr, message: &Message) {
-        if let Ok(data) = serde_json::to_vec(message) {
-            let _ = self.socket.send_to(&data, addr).await;
+/// Address of the leader, as the node at `addr` knows it. Falls back to
+/// `addr` itself when that node leads, knows no leader, or can't place it;
+/// a follower forwards what it is sent to the leader anyway.
+async fn leader_address(dialer: &Dialer, addr: &str) -> anyhow::Result<String> {
+    let conn = dialer.connect(addr).await?;
+    let (node_id, leader_id) = match request_status(&conn, addr).await? {
+        Message::StatusResponse { is_leader: true, .. } => return Ok(addr.to_string()),
+        Message::StatusResponse { node_id, leader: Some(leader), .. } => (node_id, leader),
+        Message::StatusResponse { node_id, leader: None, .. } => {
+            println!("Node {} knows of no leader; sending to it anyway", node_id);
+            return Ok(addr.to_string());
         }
-    }
+        other => anyhow::bail!("Unexpected reply from {}: {:?}", addr, other),
+    };
 
-    async fn report_status(&self) {
-        let mut interval = interval(Duration::from_secs(5));
-        loop {
-            interval.tick().await;
-    
-            let state = self.state.read().await.clone();
-            let leader = *self.current_leader.read().await;
-            let last_hb = self.last_heartbeat.read().await;
-            let elapsed = SystemTime::now()
-                .duration_since(*last_hb)
-                .unwrap_or(Duration::from_secs(0))
-                .as_secs_f64();
-            drop(last_hb);
-    
-            if state == NodeState::Leader {
-                // Leader: compute successor from current acks
-                let active_nodes = self.active_nodes.read().await;
-                let computed_succ = self.calculate_successor(&active_nodes, self.id);
-                let active_count = active_nodes.len()+1; // acks from others only
-                drop(active_nodes);
-    
-                println!(
-                    "Node {} Status: State={:?}, Leader={:?}, Successor(computed)={:?}, Active nodes={}, Time since heartbeat={:.1}s",
-                    self.id, state, leader, computed_succ, active_count, elapsed
-                );
-            } else {
-                // Follower: show the hint learned from leader heartbeats
-                let successor_hint = *self.successor_hint.read().await;
-                println!(
-                    "Node {} Status: State={:?}, Leader={:?}, Successor(hint)={:?}, Time since heartbeat={:.1}s",
-                    self.id, state, leader, successor_hint, elapsed
-                );
-            }
+    conn.send(&Message::ListMembers {}).await?;
+    let members = tokio::time::timeout(Duration::from_secs(5), conn.receive_one())
+        .await
+        .context(format!("Timed out waiting for the member list from {}", addr))??;
+    let address = match members {
+        Message::MemberList { nodes } => nodes
+            .into_iter()
+            .find(|node| node.id == leader_id)
+            .map(|node| node.advertised_address().to_string()),
+        _ => None,
+    };
+    conn.close().await;
+
+    match address {
+        Some(address) => {
+            println!("Node {} is not the leader; using leader Node {} at {}", node_id, leader_id, address);
+            Ok(address)
+        }
+        None => {
+            println!("Node {} can't say where leader Node {} is; it will forward", node_id, leader_id);
+            Ok(addr.to_string())
         }
     }
-}    
+}
 
-fn current_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+/// Ask the node on `conn` for its view of the cluster
+async fn request_status(conn: &PeerConnection, addr: &str) -> anyhow::Result<Message> {
+    conn.send(&Message::StatusRequest {}).await?;
+    let response = tokio::time::timeout(Duration::from_secs(5), conn.receive_one())
+        .await
+        .context(format!("Timed out waiting for status from {}", addr))??;
+    Ok(response)
 }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Node ID (0, 1, or 2)
-    #[arg(short, long)]
-    id: u32,
-    
-    /// Config file path (optional, will use default if not provided)
-    #[arg(short, long)]
-    config: Option<String>,
+/// Ask for a forced election, then watch the node's view until the leader changes
+async fn force_election(dialer: &Dialer, addr: &str, requester_id: u32) -> anyhow::Result<()> {
+    let conn = dialer.connect(addr).await?;
+    let leader = |response: Message| match response {
+        Message::StatusResponse { leader, .. } => Ok(leader),
+        other => anyhow::bail!("Unexpected reply from {}: {:?}", addr, other),
+    };
+
+    let Some(old_leader) = leader(request_status(&conn, addr).await?)? else {
+        anyhow::bail!("Node at {} knows of no leader to replace", addr);
+    };
+    conn.send(&Message::ForceElection { requester_id }).await?;
+    println!("Asked leader Node {} to step down", old_leader);
+
+    for _ in 0..20 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        if let Some(new_leader) = leader(request_status(&conn, addr).await?)? {
+            if new_leader != old_leader {
+                println!("Leader changed from Node {} to Node {}", old_leader, new_leader);
+                return Ok(());
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "Node {} is still the leader; check its log (forced elections need the cluster key, and a successor)",
+        old_leader
+    )
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    
-    // Default config
-    let config_json = r#"{
-        "nodes": [
-            {"id": 0, "address": "127.0.0.1:8080"},
-            {"id": 1, "address": "127.0.0.1:8081"},
-            {"id": 2, "address": "127.0.0.1:8083"}
-        ]
-    }"#;
-    
-    let config: Config = if let Some(config_path) = args.config {
-        let config_str = tokio::fs::read_to_string(config_path).await?;
-        serde_json::from_str(&config_str)?
-    } else {
-        serde_json::from_str(config_json)?
+async fn print_status(dialer: &Dialer, addr: &str) -> anyhow::Result<()> {
+    let conn = dialer.connect(addr).await?;
+    let response = request_status(&conn, addr).await?;
+
+    let Message::StatusResponse {
+        node_id,
+        is_leader,
+        leader,
+        successor,
+        term,
+        alive_nodes,
+        leader_change_reason,
+        peer_rtt_ms,
+        peer_traffic,
+        peer_states,
+    } = response
+    else {
+        anyhow::bail!("Unexpected reply from {}: {:?}", addr, response);
     };
-    
-    let node = Arc::new(Node::new(args.id, &config).await?);
-    node.start().await;
-    
-    // Keep running
-    tokio::signal::ctrl_c().await?;
-    println!("\nShutting down node {}...", args.id);
-    
+
+    let show = |id: Option<u32>| id.map_or("none".to_string(), |id| format!("Node {}", id));
+    println!("Node {} ({})", node_id, if is_leader { "leader" } else { "follower" });
+    println!("  Leader:      {}", show(leader));
+    println!("  Successor:   {}", show(successor));
+    println!("  Term:        {}", term);
+    println!("  Last change: {}", leader_change_reason);
+    println!("  Alive nodes: {:?}", alive_nodes);
+    if !peer_rtt_ms.is_empty() {
+        let rtts: Vec<String> = peer_rtt_ms
+            .iter()
+            .map(|(id, ms)| format!("Node {} {:.2}ms", id, ms))
+            .collect();
+        println!("  Peer RTT:    {}", rtts.join(", "));
+    }
+    if !peer_states.is_empty() {
+        let states: Vec<String> = peer_states
+            .iter()
+            .map(|(id, state)| format!("Node {} {}", id, state))
+            .collect();
+        println!("  Peers:       {}", states.join(", "));
+    }
+    if !peer_traffic.is_empty() {
+        println!("  Traffic:");
+        for row in &peer_traffic {
+            println!(
+                "    Node {} {:<16} sent {} ({} bytes), received {} ({} bytes)",
+                row.peer, row.msg_type, row.sent_messages, row.sent_bytes, row.received_messages, row.received_bytes
+            );
+        }
+    }
+
     Ok(())
 }
-// let config_json = r#"{
-//     "nodes": [
-//         {"id": 0, "address": "10.40.61.79:8080"},
-//         {"id": 1, "address": "10.40.58.169:8081"},
-//         {"id": 2, "address": "10.40.50.93:8083"}
-//     ]
-// }"#;
\ No newline at end of file
@@ -1,8 +1,265 @@
+use crate::image::{ImageMeta, ImageVersion};
+use crate::node::{LeaderChangeReason, NodeInfo};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Wire protocol version, bumped whenever the `Message` layout changes
+pub const PROTOCOL_VERSION: u16 = 42;
+
+/// Errors raised while decoding a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The peer speaks a different protocol version
+    VersionMismatch { expected: u16, found: u16 },
+    /// The frame's HMAC tag is missing or does not match the cluster key
+    BadTag,
+    /// The frame is authentic but was already received, or is too far behind
+    /// its sender's newest frame to tell
+    Replayed { sender: u64, seq: u64 },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::VersionMismatch { expected, found } => write!(
+                f,
+                "Protocol version mismatch: expected {}, got {}",
+                expected, found
+            ),
+            ProtocolError::BadTag => write!(f, "Message authentication failed"),
+            ProtocolError::Replayed { sender, seq } => {
+                write!(f, "Replayed frame {} from sender {:016x}", seq, sender)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Length of the HMAC-SHA256 tag that follows the payload on authenticated frames
+pub const TAG_LEN: usize = 32;
+
+/// Length of the sender and sequence number that precede the payload on
+/// authenticated frames
+pub const FRESHNESS_LEN: usize = 16;
+
+/// How far a frame may trail the newest one seen from its sender and still
+/// be accepted. Frames between two nodes travel over more than one connection
+/// (and UDP), so they can arrive slightly out of order.
+pub const REPLAY_WINDOW: u64 = 1024;
+
+const WINDOW_WORDS: usize = (REPLAY_WINDOW / 64) as usize;
+
+/// Senders whose windows are remembered; each client invocation is a new
+/// sender, so the one heard from least recently is forgotten first
+const MAX_TRACKED_SENDERS: usize = 1024;
+
+/// Sequence numbers seen from one sender: the newest, and a bitmap of which
+/// of the `REPLAY_WINDOW` before it have arrived (bit `i` is `newest - i`)
+struct ReplayWindow {
+    newest: u64,
+    seen: [u64; WINDOW_WORDS],
+    last_heard: u64,
+}
+
+impl ReplayWindow {
+    fn new(seq: u64, now: u64) -> Self {
+        let mut window = Self {
+            newest: seq,
+            seen: [0; WINDOW_WORDS],
+            last_heard: now,
+        };
+        window.seen[0] = 1;
+        window
+    }
+
+    /// Record `seq`, unless it has been seen already or is too old to tell
+    fn accept(&mut self, seq: u64, now: u64) -> bool {
+        if seq > self.newest {
+            self.advance(seq - self.newest);
+            self.newest = seq;
+            self.seen[0] |= 1;
+        } else {
+            let age = self.newest - seq;
+            if age >= REPLAY_WINDOW {
+                return false;
+            }
+            let (word, bit) = ((age / 64) as usize, age % 64);
+            if self.seen[word] & (1 << bit) != 0 {
+                return false;
+            }
+            self.seen[word] |= 1 << bit;
+        }
+        self.last_heard = now;
+        true
+    }
+
+    /// Age every recorded sequence number by `by`
+    fn advance(&mut self, by: u64) {
+        if by >= REPLAY_WINDOW {
+            self.seen = [0; WINDOW_WORDS];
+            return;
+        }
+        let (words, bits) = ((by / 64) as usize, (by % 64) as u32);
+        for i in (0..WINDOW_WORDS).rev() {
+            let whole = if i >= words { self.seen[i - words] << bits } else { 0 };
+            let carry = if bits > 0 && i > words { self.seen[i - words - 1] >> (64 - bits) } else { 0 };
+            self.seen[i] = whole | carry;
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReplayGuard {
+    windows: HashMap<u64, ReplayWindow>,
+    clock: u64, // Counts accepted frames, to find the least recently heard sender
+}
+
+impl ReplayGuard {
+    fn accept(&mut self, sender: u64, seq: u64) -> bool {
+        self.clock += 1;
+        let now = self.clock;
+        if let Some(window) = self.windows.get_mut(&sender) {
+            return window.accept(seq, now);
+        }
+        if self.windows.len() >= MAX_TRACKED_SENDERS {
+            let stalest = self.windows.iter().min_by_key(|(_, w)| w.last_heard).map(|(&id, _)| id);
+            if let Some(id) = stalest {
+                self.windows.remove(&id);
+            }
+        }
+        self.windows.insert(sender, ReplayWindow::new(seq, now));
+        true
+    }
+}
+
+/// Shared cluster secret used to tag every frame, so only holders of the
+/// secret can inject messages such as `Coordinator` or `Takeover`.
+///
+/// Each key also names a sender, chosen at random, and numbers the frames it
+/// tags, so a captured frame replayed later is refused even though its tag
+/// still verifies. Clones share the sender, its sequence and the record of
+/// what has been received. That record lives in memory only: a node that
+/// restarts, or has forgotten a long idle sender, accepts one more copy of
+/// frames it had already seen.
+#[derive(Clone)]
+pub struct ClusterKey {
+    mac: Arc<Hmac<Sha256>>, // Keyed state, cloned per frame
+    sender: u64,
+    next_seq: Arc<AtomicU64>,
+    received: Arc<Mutex<ReplayGuard>>,
+}
+
+impl fmt::Debug for ClusterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ClusterKey(..)")
+    }
+}
+
+impl ClusterKey {
+    pub fn new(secret: &[u8]) -> anyhow::Result<Self> {
+        if secret.is_empty() {
+            anyhow::bail!("Cluster key must not be empty");
+        }
+        let mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        Ok(Self {
+            mac: Arc::new(mac),
+            sender: rand::random(),
+            next_seq: Arc::new(AtomicU64::new(0)),
+            received: Arc::new(Mutex::new(ReplayGuard::default())),
+        })
+    }
+
+    /// Sender and next sequence number, as they precede an outgoing payload
+    fn next_freshness(&self) -> [u8; FRESHNESS_LEN] {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let mut header = [0u8; FRESHNESS_LEN];
+        header[..8].copy_from_slice(&self.sender.to_be_bytes());
+        header[8..].copy_from_slice(&seq.to_be_bytes());
+        header
+    }
+
+    /// Check an authenticated frame's freshness header, recording it as received
+    fn check_fresh(&self, header: &[u8]) -> Result<(), ProtocolError> {
+        let sender = u64::from_be_bytes(header[..8].try_into().expect("8 byte sender"));
+        let seq = u64::from_be_bytes(header[8..FRESHNESS_LEN].try_into().expect("8 byte sequence"));
+        if self.received.lock().unwrap().accept(sender, seq) {
+            Ok(())
+        } else {
+            Err(ProtocolError::Replayed { sender, seq })
+        }
+    }
+
+    fn tag(&self, data: &[u8]) -> [u8; TAG_LEN] {
+        let mut mac = (*self.mac).clone();
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Constant-time check of `tag` against `data`
+    fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        let mut mac = (*self.mac).clone();
+        mac.update(data);
+        mac.verify_slice(tag).is_ok()
+    }
+}
+
+/// Messages and bytes of one type exchanged with one peer, as reported in
+/// `StatusResponse`. Bytes are whole frames, length prefix and tag included.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerTraffic {
+    pub peer: u32,
+    /// `Message` variant, as named by [`Message::kind`]
+    pub msg_type: String,
+    pub sent_messages: u64,
+    pub sent_bytes: u64,
+    pub received_messages: u64,
+    pub received_bytes: u64,
+}
+
+/// Where a node's connection to a peer stands, as reported in `StatusResponse`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PeerState {
+    /// A live connection, whichever side dialed it
+    Connected,
+    /// Dialing the peer now
+    Connecting,
+    /// The last dial failed; waiting out the reconnect backoff before the next
+    Backoff,
+    /// No connection, whether never made or lost, and no dial yet
+    Dead,
+}
+
+impl fmt::Display for PeerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PeerState::Connected => "connected",
+            PeerState::Connecting => "connecting",
+            PeerState::Backoff => "backoff",
+            PeerState::Dead => "dead",
+        };
+        f.write_str(name)
+    }
+}
 
 /// Message types for the modified Bully algorithm
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Message {
+    /// First frame on every connection a node dials. It names the sender, so
+    /// the accepting side attributes the connection to it rather than to
+    /// whichever node the first real message happens to mention.
+    Hello {
+        node_id: u32,
+        /// Address peers dial to reach the sender
+        address: String,
+        protocol_version: u16,
+    },
+
     /// Recovery/Discovery: "Who is the leader?"
     WhoIsLeader { 
         node_id: u32,
@@ -13,29 +270,826 @@ pub enum Message {
     Coordinator { 
         leader_id: u32,
         successor_id: Option<u32>,
+        /// Nodes next in line after the successor, best first; each takes
+        /// over at once if every node ahead of it is down too
+        backup_successors: Vec<u32>,
+        /// Election term; coordinators from an older term are stale
+        term: u64,
+        /// Election that made `leader_id` leader for `term`, or 0 if the
+        /// sender never saw it (see [`Message::correlation_id`])
+        correlation_id: u64,
     },
     
     /// Regular heartbeat from nodes to leader
     Heartbeat { 
         node_id: u32,
+        /// Highest election term the sender has seen; a leader on an older
+        /// term has been deposed and does not count the sender as its own
+        term: u64,
+        /// When it was sent, in microseconds on the sender's clock; echoed
+        /// back in `HeartbeatAck` so the sender can time the round trip
+        sent_at_us: u64,
+        /// The sender's smoothed round-trip time to the receiver, once measured
+        rtt_us: Option<u64>,
+    },
+
+    /// Leader acknowledges a heartbeat
+    HeartbeatAck {
+        node_id: u32,
+        sent_at_us: u64,
     },
     
+    /// Follower probes a leader whose heartbeats are overdue
+    Ping {
+        from_id: u32,
+    },
+
+    /// Answer to `Ping`: the node is alive even if its heartbeats are late
+    Pong {
+        from_id: u32,
+    },
+    
+    /// UDP only: a message that must not be lost (an announced `Coordinator`,
+    /// `Election` or `ElectionOk`), resent until the receiver acknowledges
+    /// `seq`; receivers drop the copies they have already handled
+    Reliable {
+        from_id: u32,
+        seq: u64,
+        message: Box<Message>,
+    },
+
+    /// UDP only: acknowledges the `Reliable` message `seq`
+    ReliableAck {
+        from_id: u32,
+        seq: u64,
+    },
+
     /// Non-successor node notifies successor of leader failure
     Takeover {
         from_id: u32,
+        correlation_id: u64,
+    },
+
+    /// Bully election: sent to every higher-ID node
+    Election {
+        from_id: u32,
+        correlation_id: u64,
+    },
+
+    /// Bully election: a higher-ID node is alive and takes over the election
+    ElectionOk {
+        from_id: u32,
+        correlation_id: u64,
+    },
+
+    /// Leader is stepping down gracefully and hands off to its successor
+    Resign {
+        leader_id: u32,
+        successor_id: Option<u32>,
+    },
+
+    /// Operator asks for a new leader without killing the current one: the
+    /// leader steps down and its successor takes over. Only honoured when the
+    /// cluster authenticates frames with a `ClusterKey`.
+    ForceElection {
+        requester_id: u32,
+    },
+
+    /// Node announces its address so members that lack it in their config can
+    /// dial it; the leader gossips it on to every other peer. Accepted only
+    /// from `node_id` itself or from the receiver's leader.
+    Join {
+        node_id: u32,
+        address: String,
+        /// The joining node's election priority; ignored by members that
+        /// already know the node, and capped at the lowest one they know by
+        /// those that don't
+        priority: u32,
+        /// Whether the joining node only observes; a known node can switch
+        /// to observing this way, but never back
+        observer: bool,
+    },
+
+    /// Leader's full membership, sent after each periodic `Coordinator` so
+    /// followers learn the addresses of nodes that joined through it
+    Membership {
+        leader_id: u32,
+        nodes: Vec<NodeInfo>,
+    },
+
+    /// Node is being decommissioned and should be dropped from membership.
+    /// Accepted only from `node_id` itself or from the receiver's leader,
+    /// which relays the departures it hears first-hand.
+    Leave {
+        node_id: u32,
+    },
+
+    /// Image upload: a client sends it to any node, followers forward it to
+    /// the leader, and the leader fans it out to every alive follower
+    StoreImage {
+        image_id: String,
+        bytes: Vec<u8>,
+        /// Nodes allowed to fetch the image, set by the owner at upload time
+        allowed_node_ids: Vec<u32>,
+        /// Owner to watermark the image with before it is stored, if any
+        watermark_owner: Option<u32>,
+        /// Fetches each allowed node gets before its access is revoked
+        max_views: Option<u32>,
+        /// Hex SHA-256 of `bytes`, checked before the receiver persists them
+        content_hash: String,
+        /// Which upload of the ID this is (see `ImageStore`); `None` for a new
+        /// upload, which the leader stores at the ID's next generation
+        generation: Option<u64>,
+    },
+
+    /// One segment of an image streamed in `CHUNK_SIZE` pieces; chunks may
+    /// arrive in any order and are reassembled by `image_id`. Every chunk
+    /// repeats the upload's options so no separate header message is needed.
+    ImageChunk {
+        image_id: String,
+        seq: u32,
+        total: u32,
+        data: Vec<u8>,
+        /// `data` is zstd-compressed; the receiver decompresses it before reassembly
+        compressed: bool,
+        allowed_node_ids: Vec<u32>,
+        watermark_owner: Option<u32>,
+        max_views: Option<u32>,
+        /// Hex SHA-256 of the whole reassembled image
+        content_hash: String,
+        /// Which upload of the ID this is (see `ImageStore`); `None` for a new
+        /// upload, which the leader stores at the ID's next generation
+        generation: Option<u64>,
+    },
+
+    /// An image's bytes did not match its `content_hash` on `node_id`, which
+    /// discarded them; whoever sent the image should send it again
+    ChecksumMismatch {
+        image_id: String,
+        node_id: u32,
+    },
+
+    /// Follower confirms to the leader that its replica of an image is on disk
+    ReplicaAck {
+        image_id: String,
+        node_id: u32,
+    },
+
+    /// Leader replicates a requester's fetch count for a view-limited image,
+    /// so a new leader keeps enforcing the quota after a failover
+    ViewCount {
+        image_id: String,
+        requester_id: u32,
+        views: u32,
+    },
+
+    /// A leader that stepped down when a partition healed lists the images it
+    /// holds, so the new leader can pull any written while they were apart.
+    /// The leader also sends its own in answer to `SyncImages`.
+    ImageInventory {
+        node_id: u32,
+        images: Vec<ImageVersion>,
+        /// Images the sender knows were deleted, through the given generation.
+        /// A follower deletes its copies of those too; the leader only deletes
+        /// through an authorized `DeleteImage`, so it ignores them. Neither
+        /// pulls a generation it has a tombstone for.
+        deleted: Vec<ImageVersion>,
+    },
+
+    /// Follower's periodic anti-entropy check: asks the leader for its
+    /// `ImageInventory`, so it can pull any image it missed while it was down
+    SyncImages {
+        node_id: u32,
+    },
+
+    /// Asks the sender of an `ImageInventory` for the images it has and
+    /// `node_id` lacks: the leader pulling from a former leader, or a follower
+    /// from its leader. They come back as `ImageChunk`s and are stored as
+    /// uploads on the leader and as replicas on a follower.
+    PullImages {
+        node_id: u32,
+        image_ids: Vec<String>,
+    },
+
+    /// Leader replicates the thumbnail it generated for an image
+    StoreThumbnail {
+        image_id: String,
+        bytes: Vec<u8>,
+    },
+
+    /// Outcome of an upload: durable once a majority of alive nodes (the
+    /// leader included) hold the image, or failed when the quorum timed out
+    /// or the upload was rejected (`error` says why). `stored_as` names the
+    /// image an earlier upload of the same bytes is stored as, when that is
+    /// not `image_id`.
+    StoreResult {
+        image_id: String,
+        durable: bool,
+        acks: u32,
+        quorum: u32,
+        error: Option<String>,
+        stored_as: Option<String>,
+    },
+
+    /// Client asks for an image on behalf of `requester_id`; the leader
+    /// streams it back as `ImageChunk`s if the image's ACL allows it
+    FetchImage {
+        image_id: String,
+        requester_id: u32,
+    },
+
+    /// Client asks the leader for an image's thumbnail, subject to the same
+    /// ACL as the full image; it is streamed back as `ImageChunk`s
+    FetchThumbnail {
+        image_id: String,
+        requester_id: u32,
+    },
+
+    /// Leader sends an authorized fetch to a less-loaded follower: the client
+    /// should repeat its `FetchImage` against `address`
+    FetchRedirect {
+        image_id: String,
+        serve_node_id: u32,
+        address: String,
+    },
+
+    /// Leader authorizes a follower to serve one redirected fetch
+    FetchGrant {
+        image_id: String,
+        requester_id: u32,
+    },
+
+    /// Follower finished (or gave up on) a granted fetch
+    FetchDone {
+        image_id: String,
+        requester_id: u32,
+        node_id: u32,
+    },
+
+    /// Fetch or delete refused: the requester is not allowed to, the image
+    /// does not exist, or the node asked is not the leader
+    AccessDenied {
+        image_id: String,
+        reason: String,
+    },
+
+    /// Client asks the leader to delete an image on behalf of `requester_id`,
+    /// who must own it (or be on its ACL if it carries no watermark). The
+    /// leader deletes its copy and relays the request to every alive follower.
+    DeleteImage {
+        image_id: String,
+        requester_id: u32,
+    },
+
+    /// Leader confirms to the client that an image is deleted
+    ImageDeleted {
+        image_id: String,
+    },
+
+    /// Client asks a node which images it holds
+    ListImages {},
+
+    /// Answer to `ListImages`: the node's local replicas, sorted by ID
+    ImageList {
+        entries: Vec<ImageMeta>,
+    },
+
+    /// A starting node asks one of its seeds which nodes are in the cluster
+    ListMembers {},
+
+    /// Answer to `ListMembers`: every node the seed knows, itself included
+    MemberList {
+        nodes: Vec<NodeInfo>,
+    },
+
+    /// External tooling asks a node for its view of the cluster
+    StatusRequest {},
+
+    /// A node's view of the cluster, answered by leaders and followers alike
+    StatusResponse {
+        node_id: u32,
+        is_leader: bool,
+        leader: Option<u32>,
+        successor: Option<u32>,
+        term: u64,
+        alive_nodes: Vec<u32>,
+        /// What caused the node's latest leadership change
+        leader_change_reason: LeaderChangeReason,
+        /// Smoothed round-trip time to each peer this node has measured or
+        /// been told about, in milliseconds
+        peer_rtt_ms: Vec<(u32, f64)>,
+        /// Traffic on this node's current connection to each peer, by peer
+        /// and then message type
+        peer_traffic: Vec<PeerTraffic>,
+        /// State of this node's connection to every other known node
+        peer_states: Vec<(u32, PeerState)>,
     },
 }
 
+/// A fresh ID for an election starting here
+pub fn new_correlation_id() -> u64 {
+    // Zero is reserved for "unknown"
+    rand::random::<u64>().max(1)
+}
+
 impl Message {
-    /// Serialize message to JSON bytes with length prefix
-    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
-        let json = serde_json::to_string(self)?;
-        let len = json.len() as u32;
+    /// Name of the variant, as used to break down traffic by message type
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "Hello",
+            Message::WhoIsLeader { .. } => "WhoIsLeader",
+            Message::Coordinator { .. } => "Coordinator",
+            Message::Heartbeat { .. } => "Heartbeat",
+            Message::HeartbeatAck { .. } => "HeartbeatAck",
+            Message::Ping { .. } => "Ping",
+            Message::Pong { .. } => "Pong",
+            Message::Reliable { .. } => "Reliable",
+            Message::ReliableAck { .. } => "ReliableAck",
+            Message::Takeover { .. } => "Takeover",
+            Message::Election { .. } => "Election",
+            Message::ElectionOk { .. } => "ElectionOk",
+            Message::Resign { .. } => "Resign",
+            Message::ForceElection { .. } => "ForceElection",
+            Message::Join { .. } => "Join",
+            Message::Membership { .. } => "Membership",
+            Message::Leave { .. } => "Leave",
+            Message::StoreImage { .. } => "StoreImage",
+            Message::ImageChunk { .. } => "ImageChunk",
+            Message::ChecksumMismatch { .. } => "ChecksumMismatch",
+            Message::ReplicaAck { .. } => "ReplicaAck",
+            Message::ViewCount { .. } => "ViewCount",
+            Message::ImageInventory { .. } => "ImageInventory",
+            Message::SyncImages { .. } => "SyncImages",
+            Message::PullImages { .. } => "PullImages",
+            Message::StoreThumbnail { .. } => "StoreThumbnail",
+            Message::StoreResult { .. } => "StoreResult",
+            Message::FetchImage { .. } => "FetchImage",
+            Message::FetchThumbnail { .. } => "FetchThumbnail",
+            Message::FetchRedirect { .. } => "FetchRedirect",
+            Message::FetchGrant { .. } => "FetchGrant",
+            Message::FetchDone { .. } => "FetchDone",
+            Message::AccessDenied { .. } => "AccessDenied",
+            Message::DeleteImage { .. } => "DeleteImage",
+            Message::ImageDeleted { .. } => "ImageDeleted",
+            Message::ListImages { .. } => "ListImages",
+            Message::ImageList { .. } => "ImageList",
+            Message::ListMembers { .. } => "ListMembers",
+            Message::MemberList { .. } => "MemberList",
+            Message::StatusRequest { .. } => "StatusRequest",
+            Message::StatusResponse { .. } => "StatusResponse",
+        }
+    }
+
+    /// The election this message is part of. Minted where the election starts
+    /// (a leader timing out, say) and copied into every `Takeover`, `Election`,
+    /// `ElectionOk` and `Coordinator` it leads to, on every node.
+    pub fn correlation_id(&self) -> Option<u64> {
+        match self {
+            Message::Coordinator { correlation_id, .. }
+            | Message::Takeover { correlation_id, .. }
+            | Message::Election { correlation_id, .. }
+            | Message::ElectionOk { correlation_id, .. } => Some(*correlation_id).filter(|&id| id != 0),
+            _ => None,
+        }
+    }
+
+    /// Serialize message to bytes with length prefix and protocol version.
+    /// With a cluster key, the key's sender and next sequence number precede
+    /// the payload, and an HMAC tag over all of it follows; both are counted
+    /// in the length prefix. Every call makes a distinct frame.
+    pub fn to_bytes(&self, key: Option<&ClusterKey>) -> anyhow::Result<Vec<u8>> {
+        let payload = self.encode()?;
+        let (fresh_len, tag_len) = if key.is_some() { (FRESHNESS_LEN, TAG_LEN) } else { (0, 0) };
+        let len = (2 + fresh_len + payload.len() + tag_len) as u32;
         
-        let mut bytes = Vec::with_capacity(4 + json.len());
+        let mut bytes = Vec::with_capacity(4 + len as usize);
         bytes.extend_from_slice(&len.to_be_bytes());
-        bytes.extend_from_slice(json.as_bytes());
+        bytes.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        if let Some(key) = key {
+            bytes.extend_from_slice(&key.next_freshness());
+        }
+        bytes.extend_from_slice(&payload);
+        if let Some(key) = key {
+            let tag = key.tag(&bytes[4..]);
+            bytes.extend_from_slice(&tag);
+        }
         
         Ok(bytes)
     }
-}
\ No newline at end of file
+
+    /// Deserialize one length-prefixed message from the front of `buf`,
+    /// returning the message and the number of bytes consumed.
+    /// Frames from a different protocol version fail with `ProtocolError::VersionMismatch`;
+    /// with a cluster key, frames whose tag does not verify fail with `ProtocolError::BadTag`,
+    /// and authentic frames the key has already accepted fail with `ProtocolError::Replayed`.
+    pub fn from_bytes(buf: &[u8], key: Option<&ClusterKey>) -> anyhow::Result<(Self, usize)> {
+        if buf.len() < 4 {
+            anyhow::bail!("Incomplete length prefix: {} of 4 bytes", buf.len());
+        }
+
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let end = 4 + len;
+        if buf.len() < end {
+            anyhow::bail!("Incomplete message: expected {} bytes, got {}", len, buf.len() - 4);
+        }
+
+        if len < 2 {
+            anyhow::bail!("Message too short to carry a protocol version");
+        }
+
+        let version = u16::from_be_bytes([buf[4], buf[5]]);
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                found: version,
+            }
+            .into());
+        }
+
+        let (payload_start, payload_end) = match key {
+            Some(key) => {
+                // Authenticate before decoding so forged payloads never reach serde
+                if len < 2 + FRESHNESS_LEN + TAG_LEN {
+                    return Err(ProtocolError::BadTag.into());
+                }
+                let (data, tag) = buf[4..end].split_at(len - TAG_LEN);
+                if !key.verify(data, tag) {
+                    return Err(ProtocolError::BadTag.into());
+                }
+                // Only authentic frames may advance the replay window
+                key.check_fresh(&buf[6..6 + FRESHNESS_LEN])?;
+                (6 + FRESHNESS_LEN, end - TAG_LEN)
+            }
+            None => (6, end),
+        };
+
+        let message = Self::decode(&buf[payload_start..payload_end])?;
+        Ok((message, end))
+    }
+
+    /// Encode the payload with the codec selected at compile time (JSON by default)
+    #[cfg(not(feature = "bincode"))]
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    #[cfg(not(feature = "bincode"))]
+    fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+
+    /// Encode the payload with bincode for compact heartbeat/coordinator traffic
+    #[cfg(feature = "bincode")]
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    #[cfg(feature = "bincode")]
+    fn decode(payload: &[u8]) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(payload)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Encode `message` into a frame and decode it again, checking the
+    /// whole frame is consumed
+    fn round_trip(message: &Message) -> Message {
+        let bytes = message.to_bytes(None).unwrap();
+        let (decoded, used) = Message::from_bytes(&bytes, None).unwrap();
+        assert_eq!(used, bytes.len());
+        decoded
+    }
+
+    fn version(image_id: &str, generation: u64) -> ImageVersion {
+        ImageVersion { image_id: image_id.into(), generation }
+    }
+
+    /// One of every variant, with optional fields both set and unset
+    fn samples() -> Vec<Message> {
+        let hash = "ab".repeat(32);
+        vec![
+            Message::Hello { node_id: 1, address: "127.0.0.1:8081".into(), protocol_version: PROTOCOL_VERSION },
+            Message::WhoIsLeader { node_id: 1, from_address: "127.0.0.1:8081".into() },
+            Message::Coordinator {
+                leader_id: 3,
+                successor_id: Some(2),
+                backup_successors: vec![1, 0],
+                term: 7,
+                correlation_id: 42,
+            },
+            Message::Coordinator {
+                leader_id: 3,
+                successor_id: None,
+                backup_successors: vec![],
+                term: 1,
+                correlation_id: 0,
+            },
+            Message::Heartbeat { node_id: 1, term: 7, sent_at_us: 1_500_000, rtt_us: Some(250) },
+            Message::Heartbeat { node_id: 1, term: 7, sent_at_us: 0, rtt_us: None },
+            Message::HeartbeatAck { node_id: 3, sent_at_us: 1_500_000 },
+            Message::Ping { from_id: 1 },
+            Message::Pong { from_id: 3 },
+            Message::Reliable {
+                from_id: 1,
+                seq: 9,
+                message: Box::new(Message::Election { from_id: 1, correlation_id: 5 }),
+            },
+            Message::ReliableAck { from_id: 3, seq: 9 },
+            Message::Takeover { from_id: 1, correlation_id: 5 },
+            Message::Election { from_id: 1, correlation_id: 5 },
+            Message::ElectionOk { from_id: 3, correlation_id: 5 },
+            Message::Resign { leader_id: 3, successor_id: Some(2) },
+            Message::Resign { leader_id: 3, successor_id: None },
+            Message::ForceElection { requester_id: 0 },
+            Message::Join { node_id: 4, address: "127.0.0.1:8084".into(), priority: 1, observer: false },
+            Message::Membership {
+                leader_id: 3,
+                nodes: vec![NodeInfo {
+                    id: 4,
+                    bind_address: "127.0.0.1:8084".into(),
+                    advertise_address: None,
+                    priority: 1,
+                    observer: false,
+                }],
+            },
+            Message::Leave { node_id: 4 },
+            Message::StoreImage {
+                image_id: "img".into(),
+                bytes: vec![0, 1, 2, 255],
+                allowed_node_ids: vec![1, 2],
+                watermark_owner: Some(1),
+                max_views: None,
+                content_hash: hash.clone(),
+                generation: None,
+            },
+            Message::ImageChunk {
+                image_id: "img".into(),
+                seq: 1,
+                total: 3,
+                data: vec![9; 16],
+                compressed: true,
+                allowed_node_ids: vec![],
+                watermark_owner: None,
+                max_views: Some(2),
+                content_hash: hash.clone(),
+                generation: Some(3),
+            },
+            Message::ChecksumMismatch { image_id: "img".into(), node_id: 2 },
+            Message::ReplicaAck { image_id: "img".into(), node_id: 2 },
+            Message::ViewCount { image_id: "img".into(), requester_id: 1, views: 2 },
+            Message::ImageInventory {
+                node_id: 3,
+                images: vec![version("a", 0), version("b", 2)],
+                deleted: vec![version("c", 1)],
+            },
+            Message::SyncImages { node_id: 1 },
+            Message::PullImages { node_id: 3, image_ids: vec!["a".into()] },
+            Message::StoreThumbnail { image_id: "img".into(), bytes: vec![1, 2, 3] },
+            Message::StoreResult {
+                image_id: "img".into(),
+                durable: true,
+                acks: 2,
+                quorum: 2,
+                error: None,
+                stored_as: Some("other".into()),
+            },
+            Message::StoreResult {
+                image_id: "img".into(),
+                durable: false,
+                acks: 1,
+                quorum: 2,
+                error: Some("Quorum timed out".into()),
+                stored_as: None,
+            },
+            Message::FetchImage { image_id: "img".into(), requester_id: 1 },
+            Message::FetchThumbnail { image_id: "img".into(), requester_id: 1 },
+            Message::FetchRedirect { image_id: "img".into(), serve_node_id: 2, address: "127.0.0.1:8082".into() },
+            Message::FetchGrant { image_id: "img".into(), requester_id: 1 },
+            Message::FetchDone { image_id: "img".into(), requester_id: 1, node_id: 2 },
+            Message::AccessDenied { image_id: "img".into(), reason: "Not on the ACL".into() },
+            Message::DeleteImage { image_id: "img".into(), requester_id: 1 },
+            Message::ImageDeleted { image_id: "img".into() },
+            Message::ListImages {},
+            Message::ImageList {
+                entries: vec![ImageMeta {
+                    image_id: "img".into(),
+                    size: 1024,
+                    owner: Some(1),
+                    replicas: vec![1, 2, 3],
+                }],
+            },
+            Message::ListMembers {},
+            Message::MemberList { nodes: vec![] },
+            Message::StatusRequest {},
+            Message::StatusResponse {
+                node_id: 1,
+                is_leader: false,
+                leader: Some(3),
+                successor: Some(2),
+                term: 7,
+                alive_nodes: vec![1, 2, 3],
+                leader_change_reason: LeaderChangeReason::Announced,
+                peer_rtt_ms: vec![(3, 0.25)],
+                peer_traffic: vec![PeerTraffic {
+                    peer: 3,
+                    msg_type: "Heartbeat".into(),
+                    sent_messages: 10,
+                    sent_bytes: 640,
+                    received_messages: 10,
+                    received_bytes: 400,
+                }],
+                peer_states: vec![(2, PeerState::Backoff), (3, PeerState::Connected)],
+            },
+        ]
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let samples = samples();
+        // Keeps `samples` honest when a variant is added
+        let kinds: HashSet<&str> = samples.iter().map(Message::kind).collect();
+        assert_eq!(kinds.len(), 41);
+
+        for message in &samples {
+            assert_eq!(&round_trip(message), message);
+        }
+    }
+
+    /// `every_variant_round_trips` exercises whichever codec is compiled in;
+    /// with bincode selected, check the JSON codec still agrees, since
+    /// clusters built without the feature keep speaking it
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn every_variant_round_trips_as_json_too() {
+        for message in &samples() {
+            let json = serde_json::to_vec(message).unwrap();
+            assert_eq!(&serde_json::from_slice::<Message>(&json).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn short_buffers_are_incomplete() {
+        let bytes = Message::Ping { from_id: 1 }.to_bytes(None).unwrap();
+        for len in 0..bytes.len() {
+            let error = Message::from_bytes(&bytes[..len], None).unwrap_err();
+            assert!(error.to_string().starts_with("Incomplete"), "{} bytes: {}", len, error);
+        }
+    }
+
+    #[test]
+    fn frames_decode_back_to_back() {
+        let samples = samples();
+        let mut buf = Vec::new();
+        for message in &samples {
+            buf.extend(message.to_bytes(None).unwrap());
+        }
+        // Half of the next frame is still to arrive
+        let partial = Message::Pong { from_id: 2 }.to_bytes(None).unwrap();
+        buf.extend_from_slice(&partial[..partial.len() / 2]);
+
+        let mut offset = 0;
+        for message in &samples {
+            let (decoded, used) = Message::from_bytes(&buf[offset..], None).unwrap();
+            assert_eq!(&decoded, message);
+            offset += used;
+        }
+        assert!(Message::from_bytes(&buf[offset..], None).is_err());
+        assert_eq!(buf.len() - offset, partial.len() / 2);
+    }
+
+    #[test]
+    fn other_protocol_versions_are_a_version_mismatch() {
+        let mut bytes = Message::Ping { from_id: 1 }.to_bytes(None).unwrap();
+        let older = PROTOCOL_VERSION - 1;
+        bytes[4..6].copy_from_slice(&older.to_be_bytes());
+
+        let error = Message::from_bytes(&bytes, None).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::VersionMismatch { expected: PROTOCOL_VERSION, found: older })
+        );
+    }
+
+    /// Nodes covering every `NodeInfo` field, optional ones set and unset
+    fn varied_nodes() -> Vec<NodeInfo> {
+        vec![
+            NodeInfo {
+                id: 0,
+                bind_address: "0.0.0.0:8080".into(),
+                advertise_address: None,
+                priority: 2,
+                observer: false,
+            },
+            NodeInfo {
+                id: 1,
+                bind_address: "0.0.0.0:8080".into(),
+                advertise_address: Some("10.0.0.2:8080".into()),
+                priority: 0,
+                observer: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn node_info_round_trips_with_and_without_advertise_address() {
+        let message = Message::MemberList { nodes: varied_nodes() };
+        assert_eq!(round_trip(&message), message);
+    }
+
+    /// Membership gossip and member lists, through the compiled-in codec
+    /// and, when that is bincode, through JSON as well
+    #[test]
+    fn node_info_messages_round_trip_in_every_codec() {
+        let messages = [
+            Message::Membership {
+                leader_id: 2,
+                nodes: varied_nodes(),
+            },
+            Message::MemberList { nodes: varied_nodes() },
+        ];
+        for message in &messages {
+            assert_eq!(&round_trip(message), message);
+            #[cfg(feature = "bincode")]
+            {
+                let json = serde_json::to_vec(message).unwrap();
+                assert_eq!(&serde_json::from_slice::<Message>(&json).unwrap(), message);
+            }
+        }
+    }
+
+    /// Why `from_bytes` refused `bytes` under `key`
+    fn refusal(bytes: &[u8], key: &ClusterKey) -> Option<ProtocolError> {
+        Message::from_bytes(bytes, Some(key)).unwrap_err().downcast_ref::<ProtocolError>().cloned()
+    }
+
+    #[test]
+    fn tagged_frames_verify_and_tampered_ones_are_a_bad_tag() {
+        let (sender, receiver) = (ClusterKey::new(b"secret").unwrap(), ClusterKey::new(b"secret").unwrap());
+        let message = Message::Takeover { from_id: 1, correlation_id: 7 };
+        let bytes = message.to_bytes(Some(&sender)).unwrap();
+        assert_eq!(Message::from_bytes(&bytes, Some(&receiver)).unwrap(), (message.clone(), bytes.len()));
+
+        // Flipping any bit of the freshness header, payload or tag breaks it
+        let bytes = message.to_bytes(Some(&sender)).unwrap();
+        for at in [6, 6 + FRESHNESS_LEN, bytes.len() - TAG_LEN - 1, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[at] ^= 1;
+            assert_eq!(refusal(&tampered, &receiver), Some(ProtocolError::BadTag), "byte {} flipped", at);
+        }
+        // ... and the untouched frame still verifies after those attempts
+        assert_eq!(Message::from_bytes(&bytes, Some(&receiver)).unwrap().0, message);
+
+        // Nor does a frame tagged under another secret, or not tagged at all
+        let stranger = ClusterKey::new(b"other secret").unwrap();
+        assert_eq!(refusal(&message.to_bytes(Some(&stranger)).unwrap(), &receiver), Some(ProtocolError::BadTag));
+        assert_eq!(refusal(&message.to_bytes(None).unwrap(), &receiver), Some(ProtocolError::BadTag));
+    }
+
+    #[test]
+    fn captured_frames_are_replayed_and_fresh_ones_accepted() {
+        let (sender, receiver) = (ClusterKey::new(b"secret").unwrap(), ClusterKey::new(b"secret").unwrap());
+        let message = Message::Takeover { from_id: 1, correlation_id: 7 };
+        let captured = message.to_bytes(Some(&sender)).unwrap();
+        assert!(Message::from_bytes(&captured, Some(&receiver)).is_ok());
+        assert_eq!(refusal(&captured, &receiver), Some(ProtocolError::Replayed { sender: sender.sender, seq: 0 }));
+
+        // The same message sent again is a new frame, and gets through
+        let fresh = message.to_bytes(Some(&sender)).unwrap();
+        assert_eq!(Message::from_bytes(&fresh, Some(&receiver)).unwrap().0, message);
+        assert!(refusal(&captured, &receiver).is_some());
+    }
+
+    #[test]
+    fn least_recently_heard_sender_is_forgotten_first() {
+        let receiver = ClusterKey::new(b"secret").unwrap();
+        let heard = |key: &ClusterKey| {
+            let bytes = Message::Ping { from_id: 1 }.to_bytes(Some(key)).unwrap();
+            Message::from_bytes(&bytes, Some(&receiver)).is_ok()
+        };
+        let ours = ClusterKey::new(b"secret").unwrap();
+        let captured = Message::Ping { from_id: 1 }.to_bytes(Some(&ours)).unwrap();
+        assert!(Message::from_bytes(&captured, Some(&receiver)).is_ok());
+
+        // Filling every slot, then hearing from us again, makes another
+        // sender the stalest: the next newcomer evicts that one
+        for _ in 1..MAX_TRACKED_SENDERS {
+            assert!(heard(&ClusterKey::new(b"secret").unwrap()));
+        }
+        assert!(heard(&ours));
+        assert!(heard(&ClusterKey::new(b"secret").unwrap()));
+        assert_eq!(receiver.received.lock().unwrap().windows.len(), MAX_TRACKED_SENDERS);
+        assert!(refusal(&captured, &receiver).is_some());
+
+        // Once we are the stalest we are forgotten, and the captured frame
+        // is accepted one more time
+        for _ in 0..MAX_TRACKED_SENDERS {
+            assert!(heard(&ClusterKey::new(b"secret").unwrap()));
+        }
+        assert!(Message::from_bytes(&captured, Some(&receiver)).is_ok());
+        assert!(refusal(&captured, &receiver).is_some());
+    }
+}
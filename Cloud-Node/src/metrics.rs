@@ -0,0 +1,218 @@
+use crate::message::PeerTraffic;
+use crate::node::{LeaderChangeReason, NodeHandle};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::fmt::Write as _;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Election health counters and gauges for one node, rendered in the
+/// Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    elections_started: AtomicU64,
+    leader_changes: Mutex<BTreeMap<LeaderChangeReason, u64>>,
+    heartbeats_sent: AtomicU64,
+    messages_dropped: AtomicU64,
+    connections_refused: AtomicU64,
+    is_leader: AtomicBool,
+    alive_nodes: AtomicU64,
+    since_leader_heartbeat: AtomicU64, // f64 seconds, stored as bits
+    peer_rtt: Mutex<BTreeMap<u32, f64>>, // Seconds, by peer ID
+}
+
+impl Metrics {
+    pub fn election_started(&self) {
+        self.elections_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn leader_changed(&self, reason: LeaderChangeReason) {
+        *self.leader_changes.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn heartbeat_sent(&self) {
+        self.heartbeats_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_refused(&self) {
+        self.connections_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_is_leader(&self, is_leader: bool) {
+        self.is_leader.store(is_leader, Ordering::Relaxed);
+    }
+
+    pub fn set_alive_nodes(&self, count: usize) {
+        self.alive_nodes.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_since_leader_heartbeat(&self, seconds: f64) {
+        self.since_leader_heartbeat.store(seconds.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_peer_rtt(&self, peer: u32, seconds: f64) {
+        self.peer_rtt.lock().unwrap().insert(peer, seconds);
+    }
+
+    pub fn remove_peer_rtt(&self, peer: u32) {
+        self.peer_rtt.lock().unwrap().remove(&peer);
+    }
+
+    /// Render every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+
+        metric(
+            "elections_started_total",
+            "counter",
+            "Leader failures this node reacted to",
+            self.elections_started.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "heartbeats_sent_total",
+            "counter",
+            "Heartbeats sent to the leader",
+            self.heartbeats_sent.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "messages_dropped_total",
+            "counter",
+            "Peer messages dropped because this node's inbound queue was full",
+            self.messages_dropped.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "connections_refused_total",
+            "counter",
+            "Inbound connections closed because a connection limit was reached",
+            self.connections_refused.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "current_is_leader",
+            "gauge",
+            "1 if this node is the leader, else 0",
+            (self.is_leader.load(Ordering::Relaxed) as u8).to_string(),
+        );
+        metric(
+            "known_alive_nodes",
+            "gauge",
+            "Nodes the leader has heard from, including itself (0 on followers)",
+            self.alive_nodes.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "seconds_since_last_leader_heartbeat",
+            "gauge",
+            "Seconds since the leader was last heard from (0 on the leader)",
+            f64::from_bits(self.since_leader_heartbeat.load(Ordering::Relaxed)).to_string(),
+        );
+
+        // One sample per cause, labelled with its name
+        let _ = writeln!(out, "# HELP leader_changes_total Times this node switched to a different leader, by cause");
+        let _ = writeln!(out, "# TYPE leader_changes_total counter");
+        for (reason, count) in self.leader_changes.lock().unwrap().iter() {
+            let _ = writeln!(out, "leader_changes_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        // One sample per peer, labelled with its ID
+        let _ = writeln!(out, "# HELP peer_rtt_seconds Smoothed heartbeat round-trip time to each peer");
+        let _ = writeln!(out, "# TYPE peer_rtt_seconds gauge");
+        for (peer, seconds) in self.peer_rtt.lock().unwrap().iter() {
+            let _ = writeln!(out, "peer_rtt_seconds{{peer=\"{}\"}} {}", peer, seconds);
+        }
+
+        out
+    }
+}
+
+/// Render per-peer traffic counters in the Prometheus text format, one sample
+/// per peer and message type
+pub fn render_traffic(traffic: &[PeerTraffic]) -> String {
+    let mut out = String::new();
+    let mut series = |name: &str, help: &str, value: fn(&PeerTraffic) -> u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for row in traffic {
+            let _ = writeln!(out, "{}{{peer=\"{}\",msg_type=\"{}\"}} {}", name, row.peer, row.msg_type, value(row));
+        }
+    };
+
+    series("peer_messages_sent_total", "Messages sent to each peer, by type", |t| t.sent_messages);
+    series("peer_bytes_sent_total", "Bytes sent to each peer, by message type", |t| t.sent_bytes);
+    series("peer_messages_received_total", "Messages received from each peer, by type", |t| t.received_messages);
+    series("peer_bytes_received_total", "Bytes received from each peer, by message type", |t| t.received_bytes);
+
+    out
+}
+
+/// Serve `GET /metrics`, and `GET /healthz` for readiness probes (200 once
+/// `node` is healthy, 503 before), on `addr` until the process exits
+pub async fn serve(addr: String, metrics: Arc<Metrics>, node: NodeHandle) -> Result<()> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .context(format!("Failed to bind metrics endpoint to {}", addr))?;
+
+    info!("📈 Serving metrics on http://{}/metrics and readiness on /healthz", addr);
+
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                debug!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+        let node = node.clone();
+        tokio::spawn(async move {
+            // Only the request line matters, so one read is enough
+            let mut buf = [0u8; 1024];
+            let n = match stream.read(&mut buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    debug!("Metrics request from {} failed: {}", peer, e);
+                    return;
+                }
+            };
+
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let response = if request.starts_with("GET /metrics ") {
+                let body = metrics.render() + &render_traffic(&node.peer_traffic().await);
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else if request.starts_with("GET /healthz ") {
+                let (status, body) = if node.is_healthy().await {
+                    ("200 OK", "ready\n")
+                } else {
+                    ("503 Service Unavailable", "not ready\n")
+                };
+                format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                debug!("Metrics response to {} failed: {}", peer, e);
+            }
+        });
+    }
+}
@@ -1,44 +1,280 @@
-use crate::message::Message;
+use crate::message::{ClusterKey, Message, PROTOCOL_VERSION};
+use crate::metrics::Metrics;
+use crate::tls::{self, TlsConfig};
+use crate::transport::{TcpTransport, Transport};
 use anyhow::{Context, Result};
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, RwLock, Semaphore};
+use tokio::time::{timeout, Instant};
 
-/// Manages TCP connections between nodes
+/// Default upper bound on the advertised length of a single frame (1 MiB)
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Default deadline for finishing a frame once it starts arriving, for each
+/// send, and for the TLS handshake
+pub const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default cap on inbound connections a node serves at once
+pub const MAX_CONNECTIONS: usize = 1024;
+
+/// Default cap on inbound connections served at once from one source IP
+pub const MAX_CONNECTIONS_PER_SOURCE: usize = 64;
+
+/// Default capacity of a node's inbound peer message queue
+pub const MESSAGE_QUEUE_CAPACITY: usize = 1024;
+
+/// Consecutive failed sends after which a connection stops trying for a while
+pub const SEND_FAILURE_THRESHOLD: u32 = 3;
+
+/// How long sends on a connection are skipped once it trips; the first send
+/// after that is let through to probe the peer
+pub const SEND_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Why a `PeerConnection` could not send or receive a frame
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The peer closed or reset the connection, or we shut it down after a
+    /// send timed out part-way through a frame
+    ConnectionClosed,
+    /// A started frame or a send did not finish within the I/O timeout
+    Timeout(Duration),
+    /// The peer announced a frame larger than the connection accepts
+    Oversized { len: usize, max: usize },
+    /// A frame could not be decoded (malformed payload, other protocol
+    /// version, bad HMAC tag), or a message could not be encoded
+    Decode(anyhow::Error),
+    /// Any other I/O failure on the stream
+    Io(io::Error),
+    /// Recent sends all failed, so this one was not attempted; carries how
+    /// long until the next send is tried
+    CircuitOpen(Duration),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::ConnectionClosed => write!(f, "Connection closed"),
+            NetworkError::Timeout(after) => write!(f, "Timed out after {:?}", after),
+            NetworkError::Oversized { len, max } => {
+                write!(f, "Message length {} exceeds maximum of {} bytes", len, max)
+            }
+            NetworkError::Decode(_) => write!(f, "Failed to decode message"),
+            NetworkError::Io(_) => write!(f, "Connection I/O failed"),
+            NetworkError::CircuitOpen(retry_in) => {
+                write!(f, "Sends keep failing; next attempt in {:?}", retry_in)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetworkError::Decode(e) => Some(e.as_ref()),
+            NetworkError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NetworkError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => NetworkError::ConnectionClosed,
+            _ => NetworkError::Io(e),
+        }
+    }
+}
+
+/// Sending half of a node's bounded inbound queue of peer messages. A node
+/// that falls behind drops new messages, counting them in its metrics, rather
+/// than buffering an unbounded backlog; elections and replication recover
+/// through their own timeouts and retries.
+#[derive(Clone)]
+pub struct MessageSender {
+    tx: mpsc::Sender<(u32, Message)>,
+    metrics: Arc<Metrics>,
+}
+
+impl MessageSender {
+    pub fn new(tx: mpsc::Sender<(u32, Message)>, metrics: Arc<Metrics>) -> Self {
+        Self { tx, metrics }
+    }
+
+    /// Queue a message from `node_id`, or drop it if the queue is full.
+    /// Fails only once the node has stopped receiving.
+    pub fn send(&self, (node_id, message): (u32, Message)) -> Result<()> {
+        match self.tx.try_send((node_id, message)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full((_, message))) => {
+                self.metrics.message_dropped();
+                debug!("Inbound queue full - dropped {:?} from Node {}", message, node_id);
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => anyhow::bail!("Message channel closed"),
+        }
+    }
+}
+
+/// Manages connections between nodes, over TCP unless another transport is plugged in
 #[derive(Clone)]
 pub struct NetworkLayer {
     listen_addr: String,
+    transport: Arc<dyn Transport>,
+    max_message_size: usize,
+    io_timeout: Duration,
+    tls: Option<TlsConfig>,
+    cluster_key: Option<ClusterKey>,
+    max_connections: usize,
+    max_connections_per_source: usize,
 }
 
 impl NetworkLayer {
     pub fn new(listen_addr: String) -> Self {
-        Self { listen_addr }
+        Self {
+            listen_addr,
+            transport: Arc::new(TcpTransport::new()),
+            max_message_size: MAX_MESSAGE_SIZE,
+            io_timeout: IO_TIMEOUT,
+            tls: None,
+            cluster_key: None,
+            max_connections: MAX_CONNECTIONS,
+            max_connections_per_source: MAX_CONNECTIONS_PER_SOURCE,
+        }
     }
 
-    /// Start listening for incoming connections
+    /// Open and accept connections through `transport` instead of TCP sockets
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Encrypt every connection, accepted and dialed, with TLS
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Tag outgoing frames with `key` and drop connections whose frames don't verify
+    pub fn with_cluster_key(mut self, key: ClusterKey) -> Self {
+        self.cluster_key = Some(key);
+        self
+    }
+
+    /// Whether frames are tagged and verified with a cluster key
+    pub fn is_authenticated(&self) -> bool {
+        self.cluster_key.is_some()
+    }
+
+    /// Override the maximum frame size accepted from peers
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Override the deadline for reading a started frame, sending one, and
+    /// completing a TLS handshake
+    pub fn with_io_timeout(mut self, io_timeout: Duration) -> Self {
+        self.io_timeout = io_timeout;
+        self
+    }
+
+    /// Override how many inbound connections are served at once, in total and
+    /// from any one source IP; connections beyond either limit are closed
+    /// as soon as they are accepted
+    pub fn with_connection_limits(mut self, max_connections: usize, max_connections_per_source: usize) -> Self {
+        self.max_connections = max_connections;
+        self.max_connections_per_source = max_connections_per_source;
+        self
+    }
+
+    /// Start listening for incoming connections on behalf of node `my_id`
     pub async fn start_listener(
         &self,
-        tx: mpsc::UnboundedSender<(u32, Message)>,
+        my_id: u32,
+        tx: MessageSender,
+        client_tx: mpsc::Sender<(PeerConnection, Message)>,
         peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
     ) -> Result<()> {
-        let listener = TcpListener::bind(&self.listen_addr)
-            .await
-            .context(format!("Failed to bind to {}", self.listen_addr))?;
+        let mut listener = self.transport.listen(&self.listen_addr).await?;
+        let slots = Arc::new(Semaphore::new(self.max_connections));
+        let sources = Arc::new(std::sync::Mutex::new(HashMap::new()));
 
         info!("📡 Listening on {}", self.listen_addr);
 
         loop {
             match listener.accept().await {
                 Ok((stream, addr)) => {
+                    // Dropping the stream closes it, so a flood costs no task or buffers
+                    let Ok(slot) = slots.clone().try_acquire_owned() else {
+                        warn!("Refusing connection from {}: {} connections already open", addr, self.max_connections);
+                        tx.metrics.connection_refused();
+                        continue;
+                    };
+                    let Some(source_slot) = SourceSlot::acquire(&sources, &addr, self.max_connections_per_source) else {
+                        warn!(
+                            "Refusing connection from {}: {} connections already open from that source",
+                            addr, self.max_connections_per_source
+                        );
+                        tx.metrics.connection_refused();
+                        continue;
+                    };
+
                     debug!("New connection from {}", addr);
                     let tx = tx.clone();
+                    let client_tx = client_tx.clone();
                     let peers = peers.clone();
+                    let max_message_size = self.max_message_size;
+                    let io_timeout = self.io_timeout;
+                    let tls = self.tls.clone();
+                    let client_auth = tls.as_ref().is_some_and(TlsConfig::requires_client_auth);
+                    let cluster_key = self.cluster_key.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, tx, peers).await {
-                            error!("Connection error from {}: {}", addr, e);
+                        // Both slots are released when the connection ends
+                        let _slots = (slot, source_slot);
+                        // Handshake inside the task so a slow peer can't stall accept()
+                        let mut cert_name = None;
+                        let conn = match tls {
+                            Some(tls) => match timeout(io_timeout, tls.acceptor().accept(stream)).await {
+                                Ok(Ok(stream)) => {
+                                    cert_name = stream
+                                        .get_ref()
+                                        .1
+                                        .peer_certificates()
+                                        .and_then(|certs| certs.first())
+                                        .and_then(tls::common_name);
+                                    PeerConnection::from_stream(stream)
+                                }
+                                Ok(Err(e)) => {
+                                    warn!("TLS handshake with {} failed: {}", addr, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    warn!("TLS handshake with {} timed out", addr);
+                                    return;
+                                }
+                            },
+                            None => PeerConnection::from_stream(stream),
+                        };
+                        let conn = conn
+                            .with_max_message_size(max_message_size)
+                            .with_io_timeout(io_timeout)
+                            .with_cluster_key(cluster_key);
+                        let cert_name = client_auth.then_some(cert_name);
+                        if let Err(e) = Self::handle_connection(my_id, conn, cert_name, tx, client_tx, peers).await {
+                            error!("Connection error from {}: {:#}", addr, e);
                         }
                     });
                 }
@@ -49,45 +285,101 @@ impl NetworkLayer {
         }
     }
 
-    /// Handle an incoming connection
+    /// Handle an incoming connection. Under TLS client authentication,
+    /// `cert_name` holds `Some` of the common name in the certificate the
+    /// peer presented, which must match the node ID it claims.
     async fn handle_connection(
-        stream: TcpStream,
-        tx: mpsc::UnboundedSender<(u32, Message)>,
+        my_id: u32,
+        peer_conn: PeerConnection,
+        cert_name: Option<Option<String>>,
+        tx: MessageSender,
+        client_tx: mpsc::Sender<(PeerConnection, Message)>,
         peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
     ) -> Result<()> {
-        let peer_conn = PeerConnection::new(stream);
         let read_conn = peer_conn.clone();
         
         // Read first message to identify the node
         let first_msg = read_conn.receive_one().await?;
         
-        // Extract node ID from first message
-        let node_id = match &first_msg {
-            Message::WhoIsLeader { node_id, .. } => *node_id,
-            Message::Heartbeat { node_id } => *node_id,
-            Message::Coordinator { leader_id, .. } => *leader_id,
-            Message::Takeover { from_id } => *from_id,
+        // Status queries and uploads come from tooling, not cluster members:
+        // pass the connection's requests to the node instead of registering
+        // it. Only requests a client makes may open one; replies and
+        // member-to-member traffic must follow a Hello.
+        if matches!(
+            first_msg,
+            Message::StatusRequest {}
+                | Message::ForceElection { .. }
+                | Message::ListImages {}
+                | Message::ListMembers {}
+                | Message::StoreImage { .. }
+                | Message::ImageChunk { .. }
+                | Message::FetchImage { .. }
+                | Message::FetchThumbnail { .. }
+                | Message::DeleteImage { .. }
+        ) {
+            debug!("Client request received");
+            // The queue is bounded: while the node is behind on client
+            // requests, this waits and stops reading, so a client that
+            // floods us is throttled by its own TCP window instead of
+            // buffering its frames here
+            client_tx.send((peer_conn.clone(), first_msg)).await?;
+            loop {
+                match read_conn.receive_one().await {
+                    Ok(message) => client_tx.send((peer_conn.clone(), message)).await?,
+                    Err(NetworkError::ConnectionClosed) => {
+                        debug!("Client connection closed");
+                        return Ok(());
+                    }
+                    Err(e) => return Err(anyhow::Error::from(e).context("Failed to read client request")),
+                }
+            }
+        }
+        
+        // Members open with Hello; no other frame reliably names its sender
+        let Message::Hello { node_id, address, protocol_version } = first_msg else {
+            anyhow::bail!("Connection did not identify itself with Hello");
         };
+        if protocol_version != PROTOCOL_VERSION {
+            anyhow::bail!(
+                "Node {} speaks protocol version {}, expected {}",
+                node_id,
+                protocol_version,
+                PROTOCOL_VERSION
+            );
+        }
         
-        info!("🔌 Connection identified: Node {}", node_id);
+        if let Some(cert_name) = cert_name {
+            if cert_name.as_deref() != Some(tls::node_common_name(node_id).as_str()) {
+                tx.metrics.connection_refused();
+                anyhow::bail!(
+                    "Refusing Node {}: its certificate names {}, not {}",
+                    node_id,
+                    cert_name.as_deref().unwrap_or("no one"),
+                    tls::node_common_name(node_id)
+                );
+            }
+        }
         
-        // Store connection
-        peers.write().await.insert(node_id, peer_conn.clone());
+        info!("🔌 Connection identified: Node {} ({})", node_id, address);
         
-        // Forward first message
-        tx.send((node_id, first_msg))?;
+        // Store connection, unless we already hold the one both sides keep;
+        // a redundant one is still drained until the peer closes it
+        register_peer(&peers, my_id, node_id, &peer_conn).await;
         
         // Continue reading messages
-        Self::read_loop(node_id, read_conn, tx).await?;
+        let result = Self::read_loop(node_id, read_conn, tx).await;
         
-        Ok(())
+        // Forget the dead connection so the peer can be dialed again
+        remove_peer(&peers, node_id, &peer_conn).await;
+        
+        result
     }
 
     /// Continuous read loop for a connection
     async fn read_loop(
         node_id: u32,
         conn: PeerConnection,
-        tx: mpsc::UnboundedSender<(u32, Message)>,
+        tx: MessageSender,
     ) -> Result<()> {
         loop {
             match conn.receive_one().await {
@@ -97,12 +389,12 @@ impl NetworkLayer {
                         break;
                     }
                 }
+                Err(NetworkError::ConnectionClosed) => {
+                    debug!("Connection closed: Node {}", node_id);
+                    break;
+                }
                 Err(e) => {
-                    if e.to_string().contains("UnexpectedEof") {
-                        debug!("Connection closed: Node {}", node_id);
-                    } else {
-                        error!("Read error from Node {}: {}", node_id, e);
-                    }
+                    error!("Read error from Node {}: {:#}", node_id, anyhow::Error::from(e));
                     break;
                 }
             }
@@ -110,55 +402,963 @@ impl NetworkLayer {
         Ok(())
     }
 
-    /// Connect to a remote node
-    pub async fn connect_to_peer(&self, peer_addr: &str) -> Result<PeerConnection> {
-        let stream = TcpStream::connect(peer_addr)
-            .await
-            .context(format!("Failed to connect to {}", peer_addr))?;
+    /// Connect to a remote node as node `my_id`, reachable at `my_address`,
+    /// introducing ourselves with `Hello`
+    pub async fn connect_to_peer(&self, my_id: u32, my_address: &str, peer_addr: &str) -> Result<PeerConnection> {
+        let conn = self.connect(peer_addr).await?;
+        let hello = Message::Hello {
+            node_id: my_id,
+            address: my_address.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        };
+        conn.send(&hello).await?;
 
         info!("🔗 Connected to {}", peer_addr);
-        Ok(PeerConnection::new(stream))
+        Ok(conn)
     }
+
+    /// Our connection to `node_id`, dialing `peer_addr` only if `peers` has
+    /// none, so rediscovering a peer never stacks up a second connection to
+    /// it. Connections in `peers` are live: their read loop removes them when
+    /// they drop.
+    ///
+    /// A new connection is registered with [`register_peer`] and returned with
+    /// `true`; the caller must start reading from it. If another connection
+    /// to the node won the race meanwhile, that one is returned instead.
+    pub async fn get_or_connect(
+        &self,
+        peers: &RwLock<HashMap<u32, PeerConnection>>,
+        my_id: u32,
+        my_address: &str,
+        node_id: u32,
+        peer_addr: &str,
+    ) -> Result<(PeerConnection, bool)> {
+        if let Some(conn) = peers.read().await.get(&node_id) {
+            return Ok((conn.clone(), false));
+        }
+
+        let conn = self.connect_to_peer(my_id, my_address, peer_addr).await?;
+        if register_peer(peers, my_id, node_id, &conn).await {
+            return Ok((conn, true));
+        }
+        let existing = peers.read().await.get(&node_id).cloned();
+        existing.map(|conn| (conn, false)).context(format!("Connection to Node {} dropped", node_id))
+    }
+
+    /// Open a connection to `peer_addr` with this layer's TLS, frame limits
+    /// and cluster key, without introducing ourselves: the other side treats
+    /// it as a client, as for a seed's member list
+    pub async fn connect(&self, peer_addr: &str) -> Result<PeerConnection> {
+        let stream = self.transport.connect(peer_addr).await?;
+
+        let conn = match &self.tls {
+            Some(tls) => {
+                let handshake = tls.connector().connect(tls::server_name(peer_addr)?, stream);
+                let stream = timeout(self.io_timeout, handshake)
+                    .await
+                    .context(format!("TLS handshake with {} timed out", peer_addr))?
+                    .context(format!("TLS handshake with {} failed", peer_addr))?;
+                PeerConnection::from_stream(stream)
+            }
+            None => PeerConnection::from_stream(stream),
+        };
+
+        Ok(PeerConnection { dialed: true, ..conn }
+            .with_max_message_size(self.max_message_size)
+            .with_io_timeout(self.io_timeout)
+            .with_cluster_key(self.cluster_key.clone()))
+    }
+}
+
+/// An inbound connection counted against its source IP's limit until dropped
+struct SourceSlot {
+    sources: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+    source: String,
+}
+
+impl SourceSlot {
+    /// Count a connection from `addr`, or `None` if its source is at `limit`.
+    /// Sources are IPs; addresses that don't parse as `ip:port` (in-memory
+    /// transports) are counted as they are.
+    fn acquire(sources: &Arc<std::sync::Mutex<HashMap<String, usize>>>, addr: &str, limit: usize) -> Option<Self> {
+        let source = addr
+            .parse::<SocketAddr>()
+            .map_or_else(|_| addr.to_string(), |addr| addr.ip().to_string());
+        let mut counts = sources.lock().unwrap();
+        let count = counts.entry(source.clone()).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            sources: sources.clone(),
+            source,
+        })
+    }
+}
+
+impl Drop for SourceSlot {
+    fn drop(&mut self) {
+        let mut counts = self.sources.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.source) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.source);
+            }
+        }
+    }
+}
+
+/// Skips sends on a connection whose sends keep failing, such as a dead
+/// socket the read loop has not noticed yet, so periodic tasks stop
+/// retrying and logging it on every tick. After `SEND_FAILURE_THRESHOLD`
+/// failures in a row it opens for `SEND_COOLDOWN`; the next send is then a
+/// probe, whose success closes it again and whose failure reopens it.
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// How long sends are still held back, if they are
+    fn blocked_for(&self, now: Instant) -> Option<Duration> {
+        self.open_until
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Note how a send went; returns whether this failure opened the breaker
+    fn record(&mut self, ok: bool, now: Instant) -> bool {
+        if ok {
+            if self.open_until.take().is_some() {
+                info!("Sends to peer succeed again after {} failures", self.failures);
+            }
+            self.failures = 0;
+            return false;
+        }
+        self.failures += 1;
+        if self.failures < SEND_FAILURE_THRESHOLD {
+            return false;
+        }
+        let opened = self.open_until.is_none();
+        self.open_until = Some(now + SEND_COOLDOWN);
+        opened
+    }
+}
+
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Messages and bytes one connection has carried, by message type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCount {
+    pub messages: u64,
+    /// Whole frames, length prefix and tag included
+    pub bytes: u64,
+}
+
+/// Traffic over one connection in each direction, keyed by [`Message::kind`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub sent: BTreeMap<&'static str, TrafficCount>,
+    pub received: BTreeMap<&'static str, TrafficCount>,
 }
 
-/// Represents a connection to a peer node
+impl PeerStats {
+    fn count(counts: &mut BTreeMap<&'static str, TrafficCount>, message: &Message, bytes: usize) {
+        let count = counts.entry(message.kind()).or_default();
+        count.messages += 1;
+        count.bytes += bytes as u64;
+    }
+}
+
+/// Represents a connection to a peer node, over plain TCP or TLS
 #[derive(Clone)]
 pub struct PeerConnection {
-    stream: Arc<tokio::sync::Mutex<TcpStream>>,
+    // Halves are locked separately so a pending read never blocks a send
+    reader: Arc<tokio::sync::Mutex<BoxedReader>>,
+    writer: Arc<tokio::sync::Mutex<BoxedWriter>>,
+    max_message_size: usize,
+    io_timeout: Duration,
+    cluster_key: Option<ClusterKey>,
+    /// Whether we dialed this connection rather than accepted it
+    dialed: bool,
+    /// Set once a send times out part-way through a frame; the stream can't
+    /// carry further frames after that
+    broken: Arc<AtomicBool>,
+    breaker: Arc<Mutex<CircuitBreaker>>,
+    stats: Arc<Mutex<PeerStats>>,
 }
 
 impl PeerConnection {
     pub fn new(stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self::from_halves(Box::new(reader), Box::new(writer))
+    }
+
+    /// Wrap any bidirectional stream, such as a TLS session
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        Self::from_halves(Box::new(reader), Box::new(writer))
+    }
+
+    fn from_halves(reader: BoxedReader, writer: BoxedWriter) -> Self {
         Self {
-            stream: Arc::new(tokio::sync::Mutex::new(stream)),
+            reader: Arc::new(tokio::sync::Mutex::new(reader)),
+            writer: Arc::new(tokio::sync::Mutex::new(writer)),
+            max_message_size: MAX_MESSAGE_SIZE,
+            io_timeout: IO_TIMEOUT,
+            cluster_key: None,
+            dialed: false,
+            broken: Arc::new(AtomicBool::new(false)),
+            breaker: Arc::default(),
+            stats: Arc::default(),
         }
     }
 
-    /// Send a message to this peer
-    pub async fn send(&self, message: &Message) -> Result<()> {
-        let mut stream = self.stream.lock().await;
-        let bytes = message.to_bytes()?;
-        stream.write_all(&bytes).await?;
-        Ok(())
+    /// Override the maximum frame size accepted on this connection
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Override the deadline for finishing a started frame and for each send
+    pub fn with_io_timeout(mut self, io_timeout: Duration) -> Self {
+        self.io_timeout = io_timeout;
+        self
+    }
+
+    /// Authenticate frames on this connection with `key`, if any
+    pub fn with_cluster_key(mut self, key: Option<ClusterKey>) -> Self {
+        self.cluster_key = key;
+        self
+    }
+
+    /// Whether both handles refer to the same underlying stream
+    pub fn same_as(&self, other: &PeerConnection) -> bool {
+        Arc::ptr_eq(&self.writer, &other.writer)
+    }
+
+    /// Identifies the underlying stream among those currently open; shared
+    /// by every handle for which `same_as` holds
+    pub fn id(&self) -> usize {
+        Arc::as_ptr(&self.writer) as *const () as usize
+    }
+
+    /// Whether we dialed this connection rather than accepted it
+    pub fn dialed(&self) -> bool {
+        self.dialed
+    }
+
+    /// Messages sent and received on this connection so far, by type
+    pub fn stats(&self) -> PeerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Stop sending on this connection. The peer sees end-of-stream and drops
+    /// its side, which in turn ends our read loop.
+    pub async fn close(&self) {
+        let _ = self.writer.lock().await.shutdown().await;
+    }
+
+    /// Send a message to this peer. After repeated failures, sends are
+    /// refused with [`NetworkError::CircuitOpen`] for a cooldown rather than
+    /// attempted (see [`SEND_FAILURE_THRESHOLD`]).
+    pub async fn send(&self, message: &Message) -> std::result::Result<(), NetworkError> {
+        let mut stream = self.writer.lock().await;
+        if let Some(retry_in) = self.breaker.lock().unwrap().blocked_for(Instant::now()) {
+            return Err(NetworkError::CircuitOpen(retry_in));
+        }
+        let result = self.write_frame(&mut stream, message).await;
+        if !matches!(result, Err(NetworkError::Decode(_))) {
+            let opened = self.breaker.lock().unwrap().record(result.is_ok(), Instant::now());
+            if let (true, Err(e)) = (opened, &result) {
+                warn!(
+                    "{} sends to peer failed in a row (last: {}) - holding sends for {:?}",
+                    SEND_FAILURE_THRESHOLD, e, SEND_COOLDOWN
+                );
+            }
+        }
+        result
+    }
+
+    async fn write_frame(&self, stream: &mut BoxedWriter, message: &Message) -> std::result::Result<(), NetworkError> {
+        if self.broken.load(Ordering::Relaxed) {
+            // An earlier send timed out part-way through a frame
+            return Err(NetworkError::ConnectionClosed);
+        }
+        // Encoded under the lock, so sequence numbers rise along the stream
+        // rather than trailing the receiver's replay window
+        let bytes = message.to_bytes(self.cluster_key.as_ref()).map_err(NetworkError::Decode)?;
+
+        let sent = timeout(self.io_timeout, async {
+            stream.write_all(&bytes).await?;
+            // TLS buffers records until flushed; a no-op for plain TCP
+            stream.flush().await
+        })
+        .await;
+        match sent {
+            Ok(result) => {
+                result?;
+                PeerStats::count(&mut self.stats.lock().unwrap().sent, message, bytes.len());
+                Ok(())
+            }
+            Err(_) => {
+                // Part of the frame may be on the wire, so nothing sent after it
+                // would parse; tell the peer we're done and refuse further sends
+                self.broken.store(true, Ordering::Relaxed);
+                let _ = timeout(self.io_timeout, stream.shutdown()).await;
+                Err(NetworkError::Timeout(self.io_timeout))
+            }
+        }
     }
     
-    /// Receive one message from this peer
-    pub async fn receive_one(&self) -> Result<Message> {
-        let mut stream = self.stream.lock().await;
+    /// Receive one message from this peer. Waiting for a frame to start has no
+    /// deadline, since links between followers can be idle for long stretches,
+    /// but once its first byte arrives the rest must follow within the I/O timeout.
+    /// With a cluster key, a frame already received on any connection is refused
+    /// (see [`ClusterKey`]).
+    pub async fn receive_one(&self) -> std::result::Result<Message, NetworkError> {
+        let mut stream = self.reader.lock().await;
+        let timed_out = |_| NetworkError::Timeout(self.io_timeout);
         
         // Read length prefix (4 bytes)
-        let len = stream.read_u32().await
-            .context("Failed to read message length")? as usize;
+        let mut prefix = [0u8; 4];
+        prefix[0] = stream.read_u8().await?;
+        timeout(self.io_timeout, stream.read_exact(&mut prefix[1..]))
+            .await
+            .map_err(timed_out)??;
+        let len = u32::from_be_bytes(prefix) as usize;
         
-        // Read message data
-        let mut buffer = vec![0u8; len];
-        stream.read_exact(&mut buffer).await
-            .context("Failed to read message")?;
+        // Reject oversized frames before allocating for them
+        if len > self.max_message_size {
+            return Err(NetworkError::Oversized {
+                len,
+                max: self.max_message_size,
+            });
+        }
+        
+        // Read message data behind the prefix
+        let mut buffer = vec![0u8; 4 + len];
+        buffer[..4].copy_from_slice(&prefix);
+        timeout(self.io_timeout, stream.read_exact(&mut buffer[4..]))
+            .await
+            .map_err(timed_out)??;
         
         // Deserialize message
-        let message: Message = serde_json::from_slice(&buffer)
-            .context("Failed to deserialize message")?;
+        let (message, _) = Message::from_bytes(&buffer, self.cluster_key.as_ref()).map_err(NetworkError::Decode)?;
+        PeerStats::count(&mut self.stats.lock().unwrap().received, &message, buffer.len());
         
         Ok(message)
     }
-}
\ No newline at end of file
+}
+
+/// Record `conn` as our connection to `node_id`. When both nodes dial each
+/// other, each side keeps the connection dialed by the lower node ID and
+/// closes the other; otherwise a newer connection replaces an older one.
+/// Returns false if `conn` was the redundant one.
+pub async fn register_peer(
+    peers: &RwLock<HashMap<u32, PeerConnection>>,
+    my_id: u32,
+    node_id: u32,
+    conn: &PeerConnection,
+) -> bool {
+    let keep_dialed_by = my_id.min(node_id);
+    let dialed_by = |c: &PeerConnection| if c.dialed { my_id } else { node_id };
+
+    let mut peers_lock = peers.write().await;
+    let existing = peers_lock.get(&node_id).cloned();
+    if let Some(existing) = &existing {
+        if existing.same_as(conn) {
+            return true;
+        }
+        if dialed_by(existing) == keep_dialed_by && dialed_by(conn) != keep_dialed_by {
+            drop(peers_lock);
+            debug!("Closing redundant connection to Node {} (dialed by Node {})", node_id, dialed_by(conn));
+            conn.close().await;
+            return false;
+        }
+    }
+    peers_lock.insert(node_id, conn.clone());
+    drop(peers_lock);
+
+    if let Some(existing) = existing {
+        debug!("Closing superseded connection to Node {} (dialed by Node {})", node_id, dialed_by(&existing));
+        existing.close().await;
+    }
+    true
+}
+
+/// Drop `conn` from the peer map, unless it has already been replaced by a newer connection
+pub async fn remove_peer(
+    peers: &RwLock<HashMap<u32, PeerConnection>>,
+    node_id: u32,
+    conn: &PeerConnection,
+) {
+    let mut peers = peers.write().await;
+    if peers.get(&node_id).is_some_and(|current| current.same_as(conn)) {
+        peers.remove(&node_id);
+        info!("🔌 Lost connection to Node {}", node_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ProtocolError;
+    use tracing_subscriber::prelude::*;
+
+    /// Collects formatted log output for inspection
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_prefix_is_rejected_before_the_body_is_read() {
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let conn = PeerConnection::from_stream(ours);
+
+        // Only the prefix is sent: reading a body this size would hang until
+        // the I/O timeout, and allocating for it would take 4 GiB
+        theirs.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        let received = timeout(Duration::from_secs(1), conn.receive_one()).await.expect("receive_one hung");
+        assert!(matches!(
+            received,
+            Err(NetworkError::Oversized { len, max: MAX_MESSAGE_SIZE }) if len == u32::MAX as usize
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_peer_stalling_inside_the_length_prefix_times_out() {
+        let io_timeout = Duration::from_secs(5);
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let conn = PeerConnection::from_stream(ours).with_io_timeout(io_timeout);
+
+        // Two of the four prefix bytes, then silence with the stream held open
+        theirs.write_all(&[0, 0]).await.unwrap();
+        let started = tokio::time::Instant::now();
+        let received = timeout(io_timeout * 2, conn.receive_one()).await.expect("receive_one hung");
+        assert!(matches!(received, Err(NetworkError::Timeout(after)) if after == io_timeout));
+        assert_eq!(started.elapsed(), io_timeout);
+        drop(theirs);
+    }
+
+    #[tokio::test]
+    async fn version_mismatch_surfaces_as_a_protocol_error() {
+        let mut frame = Message::Ping { from_id: 1 }.to_bytes(None).unwrap();
+        frame[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let conn = PeerConnection::from_stream(ours);
+        theirs.write_all(&frame).await.unwrap();
+        let Err(NetworkError::Decode(error)) = conn.receive_one().await else {
+            panic!("expected a decode error");
+        };
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                found: PROTOCOL_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn a_peer_hanging_up_closes_the_connection_without_an_error() {
+        // `log` records reach the thread's subscriber through the bridge
+        let _ = tracing_log::LogTracer::init();
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::logging::filter(Some("debug")).unwrap())
+            .with(tracing_subscriber::fmt::layer().with_ansi(false).with_writer({
+                let captured = captured.clone();
+                move || captured.clone()
+            }));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let ping = Message::Ping { from_id: 1 };
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            // Hung up between frames: reads and sends both see it closed
+            let (ours, theirs) = tokio::io::duplex(1024);
+            let conn = PeerConnection::from_stream(ours);
+            drop(theirs);
+            assert!(matches!(conn.receive_one().await, Err(NetworkError::ConnectionClosed)));
+            assert!(matches!(conn.send(&ping).await, Err(NetworkError::ConnectionClosed)));
+
+            // Hung up one byte short of a whole frame
+            let frame = ping.to_bytes(None).unwrap();
+            let (ours, mut theirs) = tokio::io::duplex(1024);
+            let conn = PeerConnection::from_stream(ours);
+            theirs.write_all(&frame[..frame.len() - 1]).await.unwrap();
+            drop(theirs);
+            assert!(matches!(conn.receive_one().await, Err(NetworkError::ConnectionClosed)));
+
+            // The read loop passes messages on until the peer goes, then ends
+            let (ours, theirs) = tokio::io::duplex(1024);
+            let (tx, mut rx) = mpsc::channel(8);
+            let reader = tokio::spawn(NetworkLayer::read_loop(
+                1,
+                PeerConnection::from_stream(ours),
+                MessageSender::new(tx, Arc::default()),
+            ));
+            let peer = PeerConnection::from_stream(theirs);
+            peer.send(&ping).await.unwrap();
+            assert_eq!(rx.recv().await, Some((1, ping.clone())));
+            drop(peer);
+            timeout(Duration::from_secs(1), reader).await.expect("read loop kept running").unwrap().unwrap();
+        });
+
+        let logged = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logged.contains("Connection closed: Node 1"), "{}", logged);
+        assert!(!logged.contains("ERROR"), "{}", logged);
+    }
+
+    #[tokio::test]
+    async fn max_message_size_is_configurable() {
+        let message = Message::Ping { from_id: 1 };
+        let frame = message.to_bytes(None).unwrap();
+        let body_len = frame.len() - 4;
+
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let conn = PeerConnection::from_stream(ours).with_max_message_size(body_len);
+        theirs.write_all(&frame).await.unwrap();
+        assert_eq!(conn.receive_one().await.unwrap(), message);
+
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let conn = PeerConnection::from_stream(ours).with_max_message_size(body_len - 1);
+        theirs.write_all(&frame).await.unwrap();
+        assert!(matches!(conn.receive_one().await, Err(NetworkError::Oversized { .. })));
+    }
+
+    #[test]
+    fn a_full_peer_queue_drops_and_counts_the_overflow() {
+        let metrics = Arc::new(Metrics::default());
+        let (tx, rx) = mpsc::channel(8);
+        let sender = MessageSender::new(tx, metrics.clone());
+
+        for _ in 0..1000 {
+            sender.send((1, Message::Ping { from_id: 1 })).unwrap();
+        }
+        assert_eq!(rx.len(), 8);
+        assert!(metrics.render().contains("\nmessages_dropped_total 992\n"));
+
+        drop(rx);
+        assert!(sender.send((1, Message::Ping { from_id: 1 })).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_flooding_client_waits_for_room_in_the_client_queue() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (tx, _rx) = mpsc::channel(8);
+        let tx = MessageSender::new(tx, Arc::default());
+        let (client_tx, mut client_rx) = mpsc::channel(4);
+        let conn = PeerConnection::from_stream(ours);
+        tokio::spawn(NetworkLayer::handle_connection(0, conn, None, tx, client_tx, Arc::default()));
+
+        // Each frame is a quarter of the pipe: with the queue full the node
+        // stops reading, the pipe fills, and the client's sends stall
+        let client = PeerConnection::from_stream(theirs);
+        let frame = Message::StoreImage {
+            image_id: "img".into(),
+            bytes: vec![0; 16 * 1024],
+            allowed_node_ids: Vec::new(),
+            watermark_owner: None,
+            max_views: None,
+            content_hash: String::new(),
+            generation: None,
+        };
+        let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let flood = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                for _ in 0..100 {
+                    client.send(&frame).await.unwrap();
+                    sent.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(client_rx.len(), 4);
+        let stalled_at = sent.load(Ordering::Relaxed);
+        assert!(stalled_at < 20, "{} frames sent into a full queue", stalled_at);
+
+        // Taking requests off the queue lets the rest through
+        for _ in 0..100 {
+            client_rx.recv().await.unwrap();
+        }
+        flood.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_client_requests_open_a_connection_without_hello() {
+        let open_with = |first: Message| async move {
+            let (ours, theirs) = tokio::io::duplex(64 * 1024);
+            let (tx, _rx) = mpsc::channel(8);
+            let tx = MessageSender::new(tx, Arc::default());
+            let (client_tx, mut client_rx) = mpsc::channel(4);
+            let conn = PeerConnection::from_stream(ours);
+            let handled = tokio::spawn(NetworkLayer::handle_connection(0, conn, None, tx, client_tx, Arc::default()));
+            let client = PeerConnection::from_stream(theirs);
+            client.send(&first).await.unwrap();
+            drop(client);
+            let result = timeout(Duration::from_secs(1), handled).await.expect("connection kept open").unwrap();
+            (result, client_rx.try_recv().ok().map(|(_, message)| message))
+        };
+
+        let request = Message::FetchImage {
+            image_id: "img".into(),
+            requester_id: 7,
+        };
+        let (result, passed) = open_with(request.clone()).await;
+        assert!(result.is_ok());
+        assert_eq!(passed, Some(request));
+
+        // Replies and member traffic are for connections that said Hello
+        let replies = [
+            Message::StoreResult {
+                image_id: "img".into(),
+                durable: true,
+                acks: 3,
+                quorum: 2,
+                error: None,
+                stored_as: None,
+            },
+            Message::StoreThumbnail {
+                image_id: "img".into(),
+                bytes: Vec::new(),
+            },
+            Message::ImageList { entries: Vec::new() },
+        ];
+        for reply in replies {
+            let (result, passed) = open_with(reply.clone()).await;
+            let error = result.expect_err("a reply opened a client connection");
+            assert!(error.to_string().contains("did not identify itself with Hello"), "{}", error);
+            assert_eq!(passed, None, "{:?} reached the node", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_client_sending_garbage_ends_its_connection_with_an_error() {
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let (tx, _rx) = mpsc::channel(8);
+        let tx = MessageSender::new(tx, Arc::default());
+        let (client_tx, mut client_rx) = mpsc::channel(4);
+        let conn = PeerConnection::from_stream(ours);
+        let handled = tokio::spawn(NetworkLayer::handle_connection(0, conn, None, tx, client_tx, Arc::default()));
+
+        // A request, then a frame claiming to be larger than any message may be
+        theirs.write_all(&Message::ListImages {}.to_bytes(None).unwrap()).await.unwrap();
+        theirs.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        let result = timeout(Duration::from_secs(1), handled).await.expect("connection kept open").unwrap();
+        let error = result.expect_err("the bad frame was dropped silently");
+        assert!(error.to_string().contains("Failed to read client request"), "{:#}", error);
+        assert_eq!(client_rx.recv().await.map(|(_, message)| message), Some(Message::ListImages {}));
+        assert!(client_rx.try_recv().is_err());
+    }
+
+    /// A node listening on a free loopback port
+    struct Listener {
+        address: String,
+        network: NetworkLayer,
+        peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
+        metrics: Arc<Metrics>,
+        rx: mpsc::Receiver<(u32, Message)>,
+    }
+
+    /// Node 0 listening with the given limits
+    async fn listen_with_limits(max_connections: usize, max_connections_per_source: usize) -> Listener {
+        listen_as(0, |network| network.with_connection_limits(max_connections, max_connections_per_source)).await
+    }
+
+    async fn listen_as(my_id: u32, build: impl FnOnce(NetworkLayer) -> NetworkLayer) -> Listener {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        let network = build(NetworkLayer::new(address.clone()));
+        let (tx, rx) = mpsc::channel(64);
+        let metrics = Arc::new(Metrics::default());
+        let sender = MessageSender::new(tx, metrics.clone());
+        let (client_tx, _) = mpsc::channel(64);
+        let peers: Arc<RwLock<HashMap<u32, PeerConnection>>> = Arc::default();
+        tokio::spawn({
+            let network = network.clone();
+            let peers = peers.clone();
+            async move { network.start_listener(my_id, sender, client_tx, peers).await }
+        });
+        while TcpStream::connect(&address).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The probe's slot is freed once the listener sees it close
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Listener {
+            address,
+            network,
+            peers,
+            metrics,
+            rx,
+        }
+    }
+
+    /// A bare socket from loopback address `source`, as a flooder would open
+    async fn open_from(source: &str, target: &str) -> TcpStream {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind(format!("{}:0", source).parse().unwrap()).unwrap();
+        socket.connect(target.parse().unwrap()).await.unwrap()
+    }
+
+    /// Whether the listener is holding `stream` open rather than having closed it
+    async fn held_open(stream: &mut TcpStream) -> bool {
+        timeout(Duration::from_millis(300), stream.read(&mut [0; 1])).await.is_err()
+    }
+
+    /// Dial as node 1 from 127.0.0.1 and wait a while for node 0 to register it
+    async fn peer_admitted(listener: &Listener) -> bool {
+        let network = NetworkLayer::new("127.0.0.1:1".to_string());
+        let Ok(_conn) = network.connect_to_peer(1, "127.0.0.1:1", &listener.address).await else {
+            return false;
+        };
+        timeout(Duration::from_millis(500), async {
+            while !listener.peers.read().await.contains_key(&1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_flooding_source_is_capped_while_a_peer_still_connects() {
+        let listener = listen_with_limits(16, 2).await;
+
+        let mut flood = Vec::new();
+        for _ in 0..5 {
+            flood.push(open_from("127.0.0.2", &listener.address).await);
+        }
+        let mut open = 0;
+        for stream in &mut flood {
+            open += usize::from(held_open(stream).await);
+        }
+        assert_eq!(open, 2);
+        assert!(listener.metrics.render().contains("\nconnections_refused_total 3\n"));
+
+        assert!(peer_admitted(&listener).await, "a peer from another source was refused");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn connections_beyond_the_total_are_refused_until_one_closes() {
+        let listener = listen_with_limits(3, 2).await;
+
+        // Two sources fill the three slots, and the fourth connection is refused
+        let mut flood = Vec::new();
+        for source in ["127.0.0.2", "127.0.0.2", "127.0.0.3", "127.0.0.3"] {
+            flood.push(open_from(source, &listener.address).await);
+        }
+        let mut open = Vec::new();
+        for stream in &mut flood {
+            open.push(held_open(stream).await);
+        }
+        assert_eq!(open, [true, true, true, false]);
+        assert!(!peer_admitted(&listener).await, "a peer got in over the total");
+
+        // A slot frees up as soon as a flooding connection closes
+        drop(flood.remove(0));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(peer_admitted(&listener).await, "the peer was refused with a slot free");
+        assert!(listener.metrics.render().contains("\nconnections_refused_total 2\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn nodes_dialing_each_other_at_once_keep_one_connection() {
+        let zero = listen_as(0, |network| network).await;
+        let mut one = listen_as(1, |network| network).await;
+
+        let (dialed_by_zero, dialed_by_one) = tokio::join!(
+            zero.network.get_or_connect(&zero.peers, 0, &zero.address, 1, &one.address),
+            one.network.get_or_connect(&one.peers, 1, &one.address, 0, &zero.address),
+        );
+        dialed_by_zero.unwrap();
+        let (dialed_by_one, _) = dialed_by_one.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Both sides hold the connection node 0 dialed, and node 1's is closed
+        let kept_by_zero = zero.peers.read().await[&1].clone();
+        let kept_by_one = one.peers.read().await[&0].clone();
+        assert!(kept_by_zero.dialed());
+        assert!(!kept_by_one.dialed());
+        // Had node 0's connection been registered first, node 1 gets that one back
+        if dialed_by_one.dialed() {
+            let closed = timeout(Duration::from_secs(1), dialed_by_one.receive_one()).await.expect("still open");
+            assert!(closed.is_err());
+        } else {
+            assert!(dialed_by_one.same_as(&kept_by_one));
+        }
+
+        // And the kept one carries traffic both ways
+        kept_by_zero.send(&Message::Ping { from_id: 0 }).await.unwrap();
+        assert_eq!(one.rx.recv().await, Some((0, Message::Ping { from_id: 0 })));
+        kept_by_one.send(&Message::Ping { from_id: 1 }).await.unwrap();
+        assert_eq!(kept_by_zero.receive_one().await.unwrap(), Message::Ping { from_id: 1 });
+    }
+
+    #[tokio::test]
+    async fn repeated_connects_to_a_peer_reuse_one_connection() {
+        let zero = listen_as(0, |network| network).await;
+        let one = NetworkLayer::new("127.0.0.1:1".to_string());
+        let peers: RwLock<HashMap<u32, PeerConnection>> = RwLock::default();
+
+        let (first, fresh) = one.get_or_connect(&peers, 1, "127.0.0.1:1", 0, &zero.address).await.unwrap();
+        assert!(fresh);
+        for _ in 0..5 {
+            let (again, fresh) = one.get_or_connect(&peers, 1, "127.0.0.1:1", 0, &zero.address).await.unwrap();
+            assert!(!fresh);
+            assert!(again.same_as(&first));
+        }
+
+        // Once the connection is gone from the map, the next call dials anew
+        peers.write().await.remove(&0);
+        let (second, fresh) = one.get_or_connect(&peers, 1, "127.0.0.1:1", 0, &zero.address).await.unwrap();
+        assert!(fresh);
+        assert!(!second.same_as(&first));
+        assert!(peers.read().await[&0].same_as(&second));
+
+        // A peer that cannot be reached leaves nothing behind
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let nowhere = format!("127.0.0.1:{}", port);
+        assert!(one.get_or_connect(&peers, 1, "127.0.0.1:1", 2, &nowhere).await.is_err());
+        assert!(!peers.read().await.contains_key(&2));
+    }
+
+    /// Never has anything to read; its writes fail while `failing` is set.
+    /// Counts the writes attempted.
+    struct FlakyStream {
+        failing: Arc<AtomicBool>,
+        writes: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl AsyncRead for FlakyStream {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for FlakyStream {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            data: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                std::task::Poll::Ready(Err(io::Error::other("write failed")))
+            } else {
+                std::task::Poll::Ready(Ok(data.len()))
+            }
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_that_keep_failing_are_held_until_a_probe_gets_through() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let writes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let conn = PeerConnection::from_stream(FlakyStream { failing: failing.clone(), writes: writes.clone() });
+        let ping = Message::Ping { from_id: 1 };
+
+        for _ in 0..SEND_FAILURE_THRESHOLD {
+            assert!(matches!(conn.send(&ping).await, Err(NetworkError::Io(_))));
+        }
+        let attempted = writes.load(Ordering::SeqCst);
+
+        // Held for the cooldown, without touching the stream
+        for _ in 0..3 {
+            let held = conn.send(&ping).await;
+            let within_cooldown = matches!(held, Err(NetworkError::CircuitOpen(retry_in)) if retry_in <= SEND_COOLDOWN);
+            assert!(within_cooldown, "{:?}", held);
+            tokio::time::advance(Duration::from_secs(2)).await;
+        }
+        assert_eq!(writes.load(Ordering::SeqCst), attempted);
+
+        // A probe that fails holds sends for another cooldown
+        tokio::time::advance(SEND_COOLDOWN).await;
+        assert!(matches!(conn.send(&ping).await, Err(NetworkError::Io(_))));
+        assert!(writes.load(Ordering::SeqCst) > attempted);
+        assert!(matches!(conn.send(&ping).await, Err(NetworkError::CircuitOpen(_))));
+
+        // One that gets through lets every send through again
+        failing.store(false, Ordering::SeqCst);
+        tokio::time::advance(SEND_COOLDOWN).await;
+        for _ in 0..5 {
+            conn.send(&ping).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn each_end_counts_the_messages_and_bytes_of_every_type_it_carries() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (sender, receiver) = (PeerConnection::from_stream(ours), PeerConnection::from_stream(theirs));
+        let heartbeat = Message::Heartbeat { node_id: 1, term: 7, sent_at_us: 1_500_000, rtt_us: None };
+        let thumbnail = Message::StoreThumbnail {
+            image_id: "img".into(),
+            bytes: vec![0; 1024],
+        };
+        let pong = Message::Pong { from_id: 1 };
+        let sent: Vec<&Message> = [(&heartbeat, 7), (&thumbnail, 3), (&pong, 1)]
+            .into_iter()
+            .flat_map(|(message, count)| std::iter::repeat_n(message, count))
+            .collect();
+        for &message in &sent {
+            sender.send(message).await.unwrap();
+        }
+        for _ in &sent {
+            receiver.receive_one().await.unwrap();
+        }
+
+        // Bytes are whole frames, prefix included
+        let frame = |message: &Message| message.to_bytes(None).unwrap().len() as u64;
+        let count = |messages: u64, message: &Message| TrafficCount { messages, bytes: messages * frame(message) };
+        let expected = BTreeMap::from([
+            ("Heartbeat", count(7, &heartbeat)),
+            ("StoreThumbnail", count(3, &thumbnail)),
+            ("Pong", count(1, &pong)),
+        ]);
+        assert_eq!(sender.stats(), PeerStats { sent: expected.clone(), received: BTreeMap::new() });
+        assert_eq!(receiver.stats(), PeerStats { sent: BTreeMap::new(), received: expected });
+
+        // A send that fails carries nothing, so counts nothing
+        drop(receiver);
+        assert!(sender.send(&pong).await.is_err());
+        assert_eq!(sender.stats().sent["Pong"].messages, 1);
+    }
+}
@@ -1,525 +1,3582 @@
-use crate::message::Message;
-use crate::network::{NetworkLayer, PeerConnection};
+use crate::detector::{ClockWatch, DetectorConfig, FailureDetector};
+use crate::durability::{Durability, DurabilityPolicy};
+use crate::image::{
+    self, AclEntry, ChunkSender, CorruptImage, ImageKey, ImageMeta, ImageStore, ImageVersion, Reassembler, Upload,
+    ANTI_ENTROPY_INTERVAL,
+    CHECKSUM_RETRIES, CHUNK_TIMEOUT, FETCH_GRANT_TIMEOUT, FETCH_GRANT_WAIT, REPLICATION_TIMEOUT,
+};
+use crate::logging;
+use crate::message::{new_correlation_id, ClusterKey, Message, PeerState, PeerTraffic};
+use crate::metrics::{self, Metrics};
+use crate::network::{
+    remove_peer, MessageSender, NetworkError, NetworkLayer, PeerConnection, PeerStats, IO_TIMEOUT,
+    MAX_CONNECTIONS, MAX_CONNECTIONS_PER_SOURCE, MAX_MESSAGE_SIZE, MESSAGE_QUEUE_CAPACITY,
+};
+use crate::state::{PersistedState, StateStore};
+use crate::storage::{FileStorage, Storage};
+use crate::tls::TlsConfig;
+use crate::transport::{TcpTransport, Transport, LISTEN_BACKLOG};
+use crate::wal;
 use anyhow::{Context, Result};
+use futures::future::join_all;
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::{mpsc, RwLock};
-use tokio::time::{interval, timeout, Duration};
+use tokio::sync::{mpsc, watch, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep_until, timeout, timeout_at, Duration, Instant};
+use tracing::{Instrument, Span};
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
-const COORDINATOR_INTERVAL: Duration = Duration::from_secs(2);
-const FAILURE_TIMEOUT: Duration = Duration::from_secs(6); // 3x heartbeat
-const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(8); // Wait for successor
+/// Election and network timing knobs, serialized as milliseconds in the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timings {
+    /// How often followers heartbeat the leader
+    #[serde(rename = "heartbeat_interval_ms", with = "duration_ms")]
+    pub heartbeat_interval: Duration,
+    /// How often the leader broadcasts `Coordinator`
+    #[serde(rename = "coordinator_interval_ms", with = "duration_ms")]
+    pub coordinator_interval: Duration,
+    /// Silence after which the leader is presumed dead
+    #[serde(rename = "failure_timeout_ms", with = "duration_ms")]
+    pub failure_timeout: Duration,
+    /// How long a follower waits for the successor to take over
+    #[serde(rename = "takeover_timeout_ms", with = "duration_ms")]
+    pub takeover_timeout: Duration,
+    /// How soon to re-dial a peer whose connection dropped; each failed
+    /// attempt doubles the wait, up to `reconnect_max_interval`
+    #[serde(rename = "reconnect_interval_ms", with = "duration_ms")]
+    pub reconnect_interval: Duration,
+    /// Cap on the wait between attempts to reach an unreachable peer
+    #[serde(rename = "reconnect_max_interval_ms", with = "duration_ms")]
+    pub reconnect_max_interval: Duration,
+    /// Silence after which a peer no longer counts as active; the leader
+    /// drops such followers from its alive set
+    #[serde(rename = "stale_node_timeout_ms", with = "duration_ms")]
+    pub stale_node_timeout: Duration,
+    /// How long a follower waits for the leader to answer a `Ping` before
+    /// presuming it dead, and a candidate for higher ranked nodes to answer
+    /// its `Election`
+    #[serde(rename = "probe_timeout_ms", with = "duration_ms")]
+    pub probe_timeout: Duration,
+    /// Upper bound on the randomized pause before reacting to a leader failure
+    #[serde(rename = "election_jitter_ms", with = "duration_ms")]
+    pub election_jitter: Duration,
+    /// How long a starting node waits for a `Coordinator` after each round
+    /// of `WhoIsLeader`
+    #[serde(rename = "discovery_timeout_ms", with = "duration_ms")]
+    pub discovery_timeout: Duration,
+    /// Deadline for receiving the rest of a frame once it starts arriving,
+    /// for each send, and for TLS handshakes
+    #[serde(rename = "io_timeout_ms", with = "duration_ms")]
+    pub io_timeout: Duration,
+    /// How long a follower's heartbeat keeps backing the leader's lease;
+    /// with `min_quorum` above 1, a leader serves writes only while a quorum
+    /// of followers is within it
+    #[serde(rename = "leader_lease_ms", with = "duration_ms")]
+    pub leader_lease: Duration,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(2),
+            coordinator_interval: Duration::from_secs(2),
+            failure_timeout: Duration::from_secs(6), // 3x heartbeat
+            takeover_timeout: Duration::from_secs(8), // Wait for successor
+            reconnect_interval: Duration::from_secs(2),
+            reconnect_max_interval: Duration::from_secs(30),
+            stale_node_timeout: Duration::from_secs(6),
+            probe_timeout: Duration::from_secs(1),
+            election_jitter: Duration::from_millis(500),
+            discovery_timeout: Duration::from_secs(5),
+            io_timeout: IO_TIMEOUT,
+            leader_lease: Duration::from_secs(4), // 2x heartbeat
+        }
+    }
+}
+
+impl Timings {
+    /// Reject timings that would declare a healthy leader dead between heartbeats
+    pub fn validate(&self) -> Result<()> {
+        if self.heartbeat_interval.is_zero()
+            || self.coordinator_interval.is_zero()
+            || self.reconnect_interval.is_zero()
+        {
+            anyhow::bail!("Heartbeat, coordinator, and reconnect intervals must be non-zero");
+        }
+        if self.reconnect_max_interval < self.reconnect_interval {
+            anyhow::bail!(
+                "Reconnect max interval ({:?}) must be at least the reconnect interval ({:?})",
+                self.reconnect_max_interval,
+                self.reconnect_interval
+            );
+        }
+        if self.io_timeout.is_zero() {
+            anyhow::bail!("I/O timeout must be non-zero");
+        }
+        if self.probe_timeout.is_zero() {
+            anyhow::bail!("Probe timeout must be non-zero");
+        }
+        if self.discovery_timeout.is_zero() {
+            anyhow::bail!("Discovery timeout must be non-zero");
+        }
+        if self.failure_timeout < self.heartbeat_interval * 3 {
+            anyhow::bail!(
+                "Failure timeout ({:?}) must be at least 3x the heartbeat interval ({:?})",
+                self.failure_timeout,
+                self.heartbeat_interval
+            );
+        }
+        if self.stale_node_timeout < self.heartbeat_interval * 3 {
+            anyhow::bail!(
+                "Stale node timeout ({:?}) must be at least 3x the heartbeat interval ({:?})",
+                self.stale_node_timeout,
+                self.heartbeat_interval
+            );
+        }
+        if self.leader_lease < self.heartbeat_interval * 2 {
+            anyhow::bail!(
+                "Leader lease ({:?}) must be at least 2x the heartbeat interval ({:?})",
+                self.leader_lease,
+                self.heartbeat_interval
+            );
+        }
+        Ok(())
+    }
+
+    /// How often background tasks look for failed nodes and successor
+    /// changes: twice per heartbeat, so a silent leader is noticed soon after
+    /// `failure_timeout`
+    pub fn check_interval(&self) -> Duration {
+        (self.heartbeat_interval / 2).max(Duration::from_millis(1))
+    }
+
+    /// Randomized pause before a node acts on a leader failure, so followers
+    /// that notice it at the same moment do not all send election traffic at
+    /// once. `election_jitter` is split into one slot per node and higher
+    /// ranked nodes draw from earlier slots, since they would win the election anyway.
+    pub fn election_backoff(&self, my_id: u32, nodes: &[NodeInfo]) -> Duration {
+        let slot = self.election_jitter / nodes.len().max(1) as u32;
+        let my_rank = rank(nodes, my_id);
+        let ahead = nodes.iter().filter(|node| node.rank() > my_rank).count() as u32;
+        slot * ahead + slot.mul_f64(rand::random::<f64>())
+    }
+
+    /// Wait before the next attempt to reach a peer after `failures`
+    /// consecutive failed ones: `reconnect_interval` doubled per extra
+    /// failure and capped at `reconnect_max_interval`, then jittered down by
+    /// up to half so peers that lost the same node don't re-dial in lockstep
+    pub fn reconnect_backoff(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        let wait = self
+            .reconnect_interval
+            .saturating_mul(1 << doublings)
+            .min(self.reconnect_max_interval);
+        wait.mul_f64(0.5 + rand::random::<f64>() / 2.0)
+    }
+}
+
+/// Election rank of `id`: its priority from `nodes`, then its ID. Nodes
+/// missing from `nodes` rank at priority 0.
+pub fn rank(nodes: &[NodeInfo], id: u32) -> Rank {
+    nodes.iter().find(|node| node.id == id).map_or(Rank { priority: 0, id }, NodeInfo::rank)
+}
+
+/// Where a node stands in elections: the higher priority wins, and equal
+/// priorities fall back to the ID order (numeric unless a test changes it
+/// with `set_id_order`). Every election decision compares these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rank {
+    pub priority: u32,
+    pub id: u32,
+}
+
+impl Ord for Rank {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then_with(|| id_order()(self.id, other.id))
+    }
+}
+
+impl PartialOrd for Rank {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders node IDs where election ranks tie on priority
+pub type IdOrder = fn(u32, u32) -> Ordering;
+
+fn numeric_order(a: u32, b: u32) -> Ordering {
+    a.cmp(&b)
+}
+
+#[cfg(any(test, feature = "testing"))]
+static ID_ORDER: std::sync::RwLock<IdOrder> = std::sync::RwLock::new(numeric_order);
+
+#[cfg(any(test, feature = "testing"))]
+fn id_order() -> IdOrder {
+    *ID_ORDER.read().unwrap()
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+fn id_order() -> IdOrder {
+    numeric_order
+}
+
+/// Rank node IDs by `order` instead of numerically, for every node in the
+/// process, so a test can steer an election (say, to the lowest ID) without
+/// renumbering its cluster. `order` must be a total order that calls two IDs
+/// equal only when they are the same ID. Set it before starting any node:
+/// nodes that disagree on the order fight over leadership.
+#[cfg(any(test, feature = "testing"))]
+pub fn set_id_order(order: IdOrder) {
+    *ID_ORDER.write().unwrap() = order;
+}
+
+/// Whether `nodes` lists `id` as an observer
+pub fn is_observer(nodes: &[NodeInfo], id: u32) -> bool {
+    nodes.iter().any(|node| node.id == id && node.observer)
+}
+
+/// The successor rule shared by the TCP and UDP nodes: the highest
+/// [`rank`]ed node in `alive` that is neither `leader_id` nor an observer,
+/// or `None` if there is no such node.
+///
+/// `alive` must list every node believed live, including the caller when
+/// it is a candidate itself; the leader is excluded whether or not it is
+/// listed, and duplicates are harmless. Ranks end in the unique ID, so no
+/// two nodes tie and the result does not depend on iteration order.
+pub fn select_successor(nodes: &[NodeInfo], leader_id: u32, alive: impl IntoIterator<Item = u32>) -> Option<u32> {
+    alive
+        .into_iter()
+        .filter(|&id| id != leader_id && !is_observer(nodes, id))
+        .max_by_key(|&id| rank(nodes, id))
+}
+
+/// The first `depth` nodes in line to lead after `leader_id`, best first:
+/// [`select_successor`]'s pick, then the nodes it would pick were the ones
+/// before them gone
+pub fn select_successors(
+    nodes: &[NodeInfo],
+    leader_id: u32,
+    alive: impl IntoIterator<Item = u32>,
+    depth: usize,
+) -> Vec<u32> {
+    let mut candidates: Vec<u32> = alive
+        .into_iter()
+        .filter(|&id| id != leader_id && !is_observer(nodes, id))
+        .collect();
+    candidates.sort_unstable_by_key(|&id| Reverse(rank(nodes, id)));
+    candidates.dedup();
+    candidates.truncate(depth);
+    candidates
+}
+
+pub(crate) mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
+
+/// Static identity of a cluster member
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NodeInfo {
     pub id: u32,
-    pub address: String,
+    /// Address the node listens on, e.g. 0.0.0.0:8080 (`address` in older configs)
+    #[serde(alias = "address")]
+    pub bind_address: String,
+    /// Address peers dial to reach the node, when it differs from the bind
+    /// address (behind NAT or in a container). Always serialized, even when
+    /// unset: bincode writes no field names, so a skipped field would shift
+    /// every one after it.
+    #[serde(default)]
+    pub advertise_address: Option<String>,
+    /// Preference for leadership: among live nodes the highest priority
+    /// leads, with equal priorities falling back to the highest ID
+    #[serde(default)]
+    pub priority: u32,
+    /// Follows the cluster for monitoring but never leads or succeeds
+    #[serde(default)]
+    pub observer: bool,
+}
+
+impl NodeInfo {
+    /// Where peers should dial this node: the advertise address, or the bind
+    /// address when none is set
+    pub fn advertised_address(&self) -> &str {
+        self.advertise_address.as_deref().unwrap_or(&self.bind_address)
+    }
+
+    /// Election rank: priority, then ID
+    pub fn rank(&self) -> Rank {
+        Rank {
+            priority: self.priority,
+            id: self.id,
+        }
+    }
 }
 
+/// Cluster configuration shared by every node
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    /// Cluster members; may be left out when `CLOUD_NODES` supplies them
+    #[serde(default)]
     pub nodes: Vec<NodeInfo>,
+    /// Addresses of existing members to ask for the rest of the membership
+    /// at startup, so `nodes` need only list this node in a large cluster
+    #[serde(default)]
+    pub seeds: Vec<String>,
+    /// Largest frame accepted from a peer, in bytes
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// Peer messages buffered for processing, further ones being dropped,
+    /// and client requests buffered, further ones waiting to be read
+    #[serde(default = "default_message_queue_capacity")]
+    pub message_queue_capacity: usize,
+    /// Incoming TCP connections that may wait to be accepted
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    /// Inbound connections served at once; further ones are closed on accept
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// Inbound connections served at once from any one source IP
+    #[serde(default = "default_max_connections_per_source")]
+    pub max_connections_per_source: usize,
+    /// Nodes, this one included, a node must see before it takes or keeps
+    /// leadership. Short of it the node stays leaderless, so writes are
+    /// refused; 1 (the default) lets a lone node lead. Above 1, a leader also
+    /// needs that many within its `leader_lease` to serve writes.
+    #[serde(default = "default_min_quorum")]
+    pub min_quorum: usize,
+    /// Times a starting node asks for the leader again, each after a silent
+    /// `discovery_timeout`, before it concludes there is none and takes over
+    #[serde(default = "default_discovery_retries")]
+    pub discovery_retries: u32,
+    /// Nodes the leader names as next in line, the successor first. If the
+    /// leader and the nodes ahead in line all fail, the first one left takes
+    /// over at once rather than after an election.
+    #[serde(default = "default_successor_depth")]
+    pub successor_depth: usize,
+    /// Bytes after which the image write-ahead log starts a new segment,
+    /// when the node keeps one
+    #[serde(default = "default_wal_segment_size")]
+    pub wal_segment_size: u64,
+    /// When writes to the state file, WAL and image store are fsynced
+    #[serde(default)]
+    pub durability: DurabilityPolicy,
+    /// Heartbeat and failure-detection timings
+    #[serde(default)]
+    pub timings: Timings,
+    /// How a silent leader is judged dead
+    #[serde(default)]
+    pub failure_detector: DetectorConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            seeds: Vec::new(),
+            max_message_size: default_max_message_size(),
+            message_queue_capacity: default_message_queue_capacity(),
+            listen_backlog: default_listen_backlog(),
+            max_connections: default_max_connections(),
+            max_connections_per_source: default_max_connections_per_source(),
+            min_quorum: default_min_quorum(),
+            discovery_retries: default_discovery_retries(),
+            successor_depth: default_successor_depth(),
+            wal_segment_size: default_wal_segment_size(),
+            durability: DurabilityPolicy::default(),
+            timings: Timings::default(),
+            failure_detector: DetectorConfig::default(),
+        }
+    }
+}
+
+fn default_max_message_size() -> usize {
+    MAX_MESSAGE_SIZE
+}
+
+fn default_message_queue_capacity() -> usize {
+    MESSAGE_QUEUE_CAPACITY
+}
+
+fn default_listen_backlog() -> u32 {
+    LISTEN_BACKLOG
+}
+
+fn default_max_connections() -> usize {
+    MAX_CONNECTIONS
+}
+
+fn default_max_connections_per_source() -> usize {
+    MAX_CONNECTIONS_PER_SOURCE
+}
+
+fn default_min_quorum() -> usize {
+    1
+}
+
+fn default_discovery_retries() -> u32 {
+    2
+}
+
+fn default_successor_depth() -> usize {
+    2
+}
+
+fn default_wal_segment_size() -> u64 {
+    wal::SEGMENT_SIZE
 }
 
 impl Config {
+    /// A cluster of `node_count` nodes on this machine, each listening on
+    /// 127.0.0.1 at port [`LOCALHOST_BASE_PORT`] plus its ID
+    ///
+    /// ```
+    /// let config = cloud_p2p::Config::localhost(3);
+    /// let addresses: Vec<&str> = config.nodes.iter().map(|node| node.bind_address.as_str()).collect();
+    /// assert_eq!(addresses, ["127.0.0.1:8080", "127.0.0.1:8081", "127.0.0.1:8082"]);
+    /// ```
+    pub fn localhost(node_count: u32) -> Self {
+        let nodes = (0..node_count)
+            .map(|id| NodeInfo {
+                id,
+                bind_address: format!("127.0.0.1:{}", u32::from(LOCALHOST_BASE_PORT) + id),
+                advertise_address: None,
+                priority: 0,
+                observer: false,
+            })
+            .collect();
+        Self { nodes, ..Self::default() }
+    }
+
+    /// Load and validate a JSON config file
     pub fn from_file(path: &str) -> Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        serde_json::from_str(&content).context("Failed to parse config")
+        let config = Self::load(path)?;
+        config.validate().context(format!("Invalid config {}", path))?;
+        Ok(config)
+    }
+
+    /// Read a JSON config file without validating it, for callers that merge
+    /// in nodes from the environment or command line before validating
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context(format!("Failed to read config {}", path))?;
+        serde_json::from_str(&content).context(format!("Failed to parse config {}", path))
+    }
+
+    /// Merge in nodes from another source such as [`NODES_ENV`]. A node
+    /// already listed takes the new address, keeping its priority and
+    /// observer flag; any other node is added.
+    pub fn merge_nodes(&mut self, nodes: Vec<NodeInfo>) {
+        for node in nodes {
+            match self.nodes.iter_mut().find(|existing| existing.id == node.id) {
+                Some(existing) => {
+                    existing.bind_address = node.bind_address;
+                    existing.advertise_address = node.advertise_address;
+                }
+                None => self.nodes.push(node),
+            }
+        }
+    }
+
+    /// Catch mistakes in the node list and timings up front, rather than as
+    /// bind or connect failures once the cluster is running
+    pub fn validate(&self) -> Result<()> {
+        if self.nodes.is_empty() {
+            anyhow::bail!("The config lists no nodes");
+        }
+
+        let mut ids = HashSet::new();
+        let mut addresses: HashMap<&str, u32> = HashMap::new();
+        for node in &self.nodes {
+            if !ids.insert(node.id) {
+                anyhow::bail!("Node ID {} is listed more than once", node.id);
+            }
+            check_address(&node.bind_address).context(format!("Node {} has a bad bind address", node.id))?;
+            if let Some(advertise) = &node.advertise_address {
+                check_address(advertise).context(format!("Node {} has a bad advertise address", node.id))?;
+            }
+            // Bind addresses may repeat across hosts (0.0.0.0:8080); the
+            // addresses peers dial may not
+            if let Some(other) = addresses.insert(node.advertised_address(), node.id) {
+                anyhow::bail!(
+                    "Nodes {} and {} are both reached at {}",
+                    other,
+                    node.id,
+                    node.advertised_address()
+                );
+            }
+        }
+        for seed in &self.seeds {
+            check_address(seed).context(format!("Seed {} is not a valid address", seed))?;
+        }
+
+        self.timings.validate()?;
+        self.failure_detector.validate()
+    }
+}
+
+/// Port of node 0 in [`Config::localhost`]; node `id` listens on this plus `id`
+pub const LOCALHOST_BASE_PORT: u16 = 8080;
+
+/// Environment variable listing cluster members as comma-separated
+/// `id@address` entries, e.g. `0@10.0.0.1:8080,1@10.0.0.2:8080`
+pub const NODES_ENV: &str = "CLOUD_NODES";
+
+/// Parse a [`NODES_ENV`]-style list of `id@address` entries
+pub fn parse_node_list(spec: &str) -> Result<Vec<NodeInfo>> {
+    let mut nodes: Vec<NodeInfo> = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (id, address) = entry
+            .split_once('@')
+            .context(format!("{:?} is not an id@address entry", entry))?;
+        let id = id.trim().parse().context(format!("{:?} does not start with a node ID", entry))?;
+        if nodes.iter().any(|node| node.id == id) {
+            anyhow::bail!("Node ID {} is listed more than once", id);
+        }
+        nodes.push(NodeInfo {
+            id,
+            bind_address: address.trim().to_string(),
+            advertise_address: None,
+            priority: 0,
+            observer: false,
+        });
+    }
+    Ok(nodes)
+}
+
+/// Accept `ip:port`, `[ipv6]:port` or `hostname:port`; hostnames are
+/// resolved when dialed
+fn check_address(address: &str) -> Result<()> {
+    if address.parse::<SocketAddr>().is_ok() {
+        return Ok(());
     }
+    let valid = address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && !host.contains(':') && port.parse::<u16>().is_ok());
+    if !valid {
+        anyhow::bail!("{:?} is not a valid address (expected host:port, e.g. 127.0.0.1:8080)", address);
+    }
+    Ok(())
 }
 
+/// A cluster member running the modified Bully algorithm over TCP
 pub struct Node {
     // Identity
     my_id: u32,
     my_address: String,
-    all_nodes: Vec<NodeInfo>,
+    all_nodes: Arc<RwLock<Vec<NodeInfo>>>, // Grows as nodes Join
+    seeds: Vec<String>,
+    timings: Timings,
     
-    // Leadership state
+    // Leadership state. `view_lock` is held for writing across every change
+    // touching more than one of these, so `snapshot` never sees half of one.
+    view_lock: Arc<RwLock<()>>,
     current_leader: Arc<RwLock<Option<u32>>>,
     current_successor: Arc<RwLock<Option<u32>>>,
+    backup_successors: Arc<RwLock<Vec<u32>>>, // Next in line after `current_successor`; stale without one
     am_i_leader: Arc<RwLock<bool>>,
+    current_term: Arc<RwLock<u64>>,
+    election_id: Arc<RwLock<u64>>, // Correlation ID of the election behind `current_term`; 0 if unknown
+    min_quorum: usize,
+    discovery_retries: u32,
+    successor_depth: usize,
+    observer: bool,
+    discovered: Arc<RwLock<bool>>, // Set once discovery ends; the node is not ready before
+    leading_since: Option<(u64, Instant)>, // Term we lead and when we noticed, for the quorum grace period
+    lease_votes: HashMap<u32, LeaseVote>,
     
     // Alive nodes tracking (for leader)
     alive_nodes: Arc<RwLock<HashSet<u32>>>,
     last_heartbeat: Arc<RwLock<HashMap<u32, Instant>>>,
+    detector: Arc<RwLock<Box<dyn FailureDetector>>>,
+    last_pong: Arc<RwLock<HashMap<u32, Instant>>>,
+    last_election_ok: Arc<RwLock<Option<Instant>>>, // When a higher ranked node last answered our `Election`
+    
+    // Bully rounds lower ranked nodes asked us to run, by correlation ID;
+    // the failure detector takes the receiver and runs them
+    election_tx: mpsc::Sender<u64>,
+    election_rx: Option<mpsc::Receiver<u64>>,
+    
+    // Round-trip times: measured to the leader we heartbeat, or reported by
+    // the followers heartbeating us; heartbeat send times count from `epoch`
+    peer_rtt: Arc<RwLock<HashMap<u32, Duration>>>,
+    epoch: Instant,
     
     // Network
     peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
+    dial_states: Arc<RwLock<HashMap<u32, PeerState>>>, // Connecting or Backoff, for peers without a connection
     network: NetworkLayer,
-    message_rx: mpsc::UnboundedReceiver<(u32, Message)>,
-    message_tx: mpsc::UnboundedSender<(u32, Message)>,
+    message_rx: mpsc::Receiver<(u32, Message)>,
+    message_tx: MessageSender,
+    discovery_backlog: Vec<(u32, Message)>, // Held while discovery waits for a Coordinator, then handled
+    client_rx: mpsc::Receiver<(PeerConnection, Message)>,
+    client_tx: mpsc::Sender<(PeerConnection, Message)>,
+    
+    // Persistence
+    state_store: Option<StateStore>,
+    image_store: Option<ImageStore>,
+    wal_dir: Option<PathBuf>, // Opened by `run`, which first finishes what the log shows was cut short
+    wal_segment_size: u64,
+    durability: Durability, // Shared by every store, and flushed on a timer when batched
+    
+    // Images being streamed in as chunks, writes awaiting their quorum (and
+    // the content hash each is writing), and re-sends of images a peer
+    // received corrupted
+    reassembler: Reassembler,
+    pending_writes: HashMap<String, PendingWrite>,
+    in_flight: HashMap<String, String>,
+    forwarded_uploads: HashMap<String, (PeerConnection, Instant)>,
+    checksum_resends: HashMap<(String, u32), (u32, Instant)>,
+    
+    // Read load-balancing: redirect counts (leader), and granted fetches and
+    // fetches still waiting for their grant (followers)
+    fetch_load: HashMap<u32, FetchLoad>,
+    fetch_grants: HashMap<(String, u32), Instant>,
+    ungranted_fetches: HashMap<(String, u32), (PeerConnection, Instant)>,
+    
+    // Leadership-change notifications for embedders
+    leader_tx: Arc<watch::Sender<LeaderState>>,
+    
+    // Listener and background tasks, aborted on shutdown, and the
+    // embedder's request to shut down
+    tasks: Tasks,
+    shutdown_signal: Arc<Notify>,
+    
+    // Monitoring
+    metrics: Arc<Metrics>,
+    metrics_addr: Option<String>,
 }
 
-impl Node {
-    pub fn new(my_id: u32, config: Config) -> Result<Self> {
-        let (message_tx, message_rx) = mpsc::unbounded_channel();
-        
-        let my_node_info = config.nodes.iter()
-            .find(|n| n.id == my_id)
-            .context(format!("Node ID {} not found in config", my_id))?;
+/// The leadership view and election bookkeeping a `Node` shares with its
+/// background tasks, as handles onto the node's own fields
+#[derive(Clone)]
+struct SharedState {
+    my_id: u32,
+    all_nodes: Arc<RwLock<Vec<NodeInfo>>>,
+    view_lock: Arc<RwLock<()>>,
+    current_leader: Arc<RwLock<Option<u32>>>,
+    current_successor: Arc<RwLock<Option<u32>>>,
+    backup_successors: Arc<RwLock<Vec<u32>>>,
+    am_i_leader: Arc<RwLock<bool>>,
+    alive_nodes: Arc<RwLock<HashSet<u32>>>,
+    current_term: Arc<RwLock<u64>>,
+    election_id: Arc<RwLock<u64>>,
+    last_heartbeat: Arc<RwLock<HashMap<u32, Instant>>>,
+    detector: Arc<RwLock<Box<dyn FailureDetector>>>,
+    last_pong: Arc<RwLock<HashMap<u32, Instant>>>,
+    last_election_ok: Arc<RwLock<Option<Instant>>>,
+    peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
+    state_store: Option<StateStore>,
+    leader_tx: Arc<watch::Sender<LeaderState>>,
+    metrics: Arc<Metrics>,
+}
 
-        Ok(Self {
-            my_id,
-            my_address: my_node_info.address.clone(),
-            all_nodes: config.nodes.clone(),
-            network: NetworkLayer::new(my_node_info.address.clone()),
-            
-            current_leader: Arc::new(RwLock::new(None)),
-            current_successor: Arc::new(RwLock::new(None)),
-            am_i_leader: Arc::new(RwLock::new(false)),
-            
-            alive_nodes: Arc::new(RwLock::new(HashSet::new())),
-            last_heartbeat: Arc::new(RwLock::new(HashMap::new())),
-            
-            peers: Arc::new(RwLock::new(HashMap::new())),
-            message_rx,
-            message_tx,
-        })
-    }
+impl SharedState {
+    /// Run one Bully round for election `correlation_id`: challenge every
+    /// higher ranked node with `Election`, and lead for `reason` if none
+    /// answers with `ElectionOk` within `probe_timeout`. A node that answers
+    /// runs a round of its own; if no leader is announced within
+    /// `takeover_timeout`, the failure detector will find the old leader
+    /// still missing and start over.
+    async fn run_election(&self, timings: Timings, correlation_id: u64, reason: LeaderChangeReason) {
+        let Self { my_id, all_nodes, current_term, last_election_ok, peers, .. } = self;
+        let my_id = *my_id;
+        let term_before = *current_term.read().await;
+        let started = Instant::now();
+        let higher: Vec<u32> = {
+            let nodes = all_nodes.read().await;
+            let my_rank = rank(&nodes, my_id);
+            nodes
+                .iter()
+                .filter(|node| node.rank() > my_rank && !node.observer)
+                .map(|node| node.id)
+                .collect()
+        };
 
-    pub async fn run(mut self) -> Result<()> {
-        info!("╔═══════════════════════════════════════════════════════════╗");
-        info!("║ Modified Bully Algorithm - Node Starting                 ║");
-        info!("╠═══════════════════════════════════════════════════════════╣");
-        info!("║ Node ID: {}                                               ║", self.my_id);
-        info!("║ Address: {}                                   ║", self.my_address);
-        info!("╚═══════════════════════════════════════════════════════════╝");
+        let election = Message::Election { from_id: my_id, correlation_id };
+        let mut challenged = Vec::new();
+        for id in higher {
+            if let Some(conn) = peer(peers, id).await {
+                if conn.send(&election).await.is_ok() {
+                    challenged.push(id);
+                }
+            }
+        }
 
-        // Start listener
-        let network = self.network.clone();
-        let tx = self.message_tx.clone();
-        let peers = self.peers.clone();
-        tokio::spawn(async move {
-            if let Err(e) = network.start_listener(tx, peers).await {
-                error!("Listener error: {}", e);
+        if !challenged.is_empty() {
+            info!("🗳️  Challenging higher ranked nodes {:?}", challenged);
+            tokio::time::sleep(timings.probe_timeout).await;
+
+            if last_election_ok.read().await.is_some_and(|at| at >= started) {
+                // A higher node is alive and takes the election from here
+                tokio::time::sleep(timings.takeover_timeout).await;
+                if *current_term.read().await == term_before {
+                    warn!("⚠️  No leader announced after our election - retrying");
+                }
+                return;
             }
-        });
+        }
 
-        tokio::time::sleep(Duration::from_millis(500)).await;
+        if *current_term.read().await > term_before {
+            info!("✅ A leader was announced during the election");
+            return;
+        }
 
-        // Discover network
-        self.discover_network().await?;
+        info!("🏆 No higher ranked node answered - winning the election");
+        let term = self.claim_leadership(correlation_id, reason).await;
+        self.persist().await;
 
-        // Start background tasks
-        self.spawn_background_tasks();
+        let coordinator = Message::Coordinator {
+            leader_id: my_id,
+            successor_id: None,
+            backup_successors: Vec::new(),
+            term,
+            correlation_id,
+        };
+        for (_, peer) in peer_connections(peers).await {
+            let _ = peer.send(&coordinator).await;
+        }
+
+        info!("✅ Successfully became leader (Node {}, {})", my_id, reason);
+    }
+
+    /// Lead a new term on behalf of election `correlation_id`, returning the
+    /// term. Nobody succeeds us until the successor updater picks someone, and
+    /// only we are known alive until followers heartbeat us, as the
+    /// `Coordinator` we announce the term with says.
+    async fn claim_leadership(&self, correlation_id: u64, reason: LeaderChangeReason) -> u64 {
+        let term = {
+            let _view = self.view_lock.write().await;
+            *self.election_id.write().await = correlation_id;
+            *self.am_i_leader.write().await = true;
+            *self.current_leader.write().await = Some(self.my_id);
+            *self.current_successor.write().await = None;
+            *self.alive_nodes.write().await = HashSet::from([self.my_id]);
+            let mut term = self.current_term.write().await;
+            *term += 1;
+            *term
+        };
+        self.publish(reason).await;
+        term
+    }
+
+    /// Forget the leader, ourselves included: without a leader, uploads and
+    /// fetches are refused until a new one is established
+    async fn clear_leadership(&self, reason: LeaderChangeReason) {
+        {
+            let _view = self.view_lock.write().await;
+            *self.am_i_leader.write().await = false;
+            *self.current_leader.write().await = None;
+            *self.current_successor.write().await = None;
+            self.alive_nodes.write().await.clear();
+        }
+        self.publish(reason).await;
+    }
+
+    /// Recompute our successor and the `depth - 1` backups after it as
+    /// leader with [`select_successors`]; returns whether they changed.
+    /// `alive_nodes` already includes ourselves. Does nothing once we have
+    /// stepped down.
+    async fn update_successors(&self, depth: usize) -> bool {
+        let _view = self.view_lock.write().await;
+        if !*self.am_i_leader.read().await {
+            return false;
+        }
+        let mut new_backups = select_successors(
+            &self.all_nodes.read().await,
+            self.my_id,
+            self.alive_nodes.read().await.iter().copied(),
+            depth,
+        );
+        let new_successor = (!new_backups.is_empty()).then(|| new_backups.remove(0));
+
+        let mut successor = self.current_successor.write().await;
+        let mut backups = self.backup_successors.write().await;
+        if *successor == new_successor && *backups == new_backups {
+            return false;
+        }
+
+        if *successor != new_successor {
+            info!("📋 Successor updated: {:?} → {:?}", *successor, new_successor);
+        }
+        if *backups != new_backups {
+            info!("📋 Backup successors updated: {:?} → {:?}", *backups, new_backups);
+        }
+        *successor = new_successor;
+        *backups = new_backups;
+        true
+    }
+
+    async fn publish(&self, reason: LeaderChangeReason) {
+        Node::publish_state(&self.leader_tx, &self.current_leader, &self.am_i_leader, &self.current_term, reason)
+            .await;
+    }
+
+    async fn persist(&self) {
+        Node::persist_state(&self.state_store, &self.current_leader, &self.current_successor, &self.current_term)
+            .await;
+    }
+}
+
+/// A node's listener and background tasks. They are aborted when it is
+/// dropped, as when the task driving `run` is aborted, so a node restarted on
+/// the same address does not find the old one's listener still holding it.
+#[derive(Default)]
+struct Tasks(Vec<JoinHandle<()>>);
+
+impl Tasks {
+    fn push(&mut self, task: JoinHandle<()>) {
+        self.0.push(task);
+    }
+
+    fn abort_all(&mut self) {
+        for task in self.0.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        self.abort_all();
+    }
+}
+
+/// Where to report the outcome of an image write
+enum WriteOrigin {
+    /// Upload received directly on a client connection
+    Client(PeerConnection),
+    /// Upload forwarded by a follower, which relays the result to its client
+    Peer(u32),
+}
+
+/// Image write the leader is holding open until a quorum of replicas acknowledge it
+struct PendingWrite {
+    /// Whoever uploaded the image, plus anyone who uploaded the same bytes
+    /// with the same options while it was in flight, each with the ID they
+    /// asked for; all get the write's outcome
+    origins: Vec<(WriteOrigin, String)>,
+    /// The image's ACL as stored, which an upload joining the write must match
+    acl: AclEntry,
+    content_hash: String,
+    acks: HashSet<u32>,
+    quorum: usize,
+    started: Instant,
+}
+
+/// A follower's unbroken run of heartbeats at one term, which backs the
+/// leader lease once it has lasted a full `leader_lease`
+struct LeaseVote {
+    term: u64,
+    since: Instant,
+    last: Instant,
+}
+
+/// Fetches the leader has redirected to one follower
+#[derive(Debug, Clone, Copy, Default)]
+struct FetchLoad {
+    in_flight: usize,
+    total: u64,
+}
+
+/// Why a node's view of the leadership last changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderChangeReason {
+    /// Nothing has changed since the node started
+    #[default]
+    Startup,
+    /// Persisted state named a leader to follow
+    Restored,
+    /// Discovery found no other node, so this one leads alone
+    DiscoveryEmpty,
+    /// Discovery reached peers but heard no `Coordinator`, so this one took over
+    DiscoveryTimeout,
+    /// A leader announced itself with `Coordinator`
+    Announced,
+    /// This node led, but a competing leader outranked it
+    Outranked,
+    /// The successor took over from a failed leader
+    SuccessorTakeover,
+    /// The leader and its successor both failed, and this node won the election that followed
+    LastNodeStanding,
+    /// The leader failed with no successor named, and this node won the election that followed
+    NoSuccessor,
+    /// The leader failed and this node, an observer, waits for the next one
+    LeaderFailed,
+    /// The leader stepped down and handed off to its successor
+    Handoff,
+    /// Too few nodes to hold leadership, so the node went leaderless
+    NoQuorum,
+    /// A leaderless node saw a quorum again and took over
+    QuorumRegained,
+    /// This node won a Bully election
+    ElectionWon,
+}
+
+impl LeaderChangeReason {
+    /// Label used in logs and the `leader_changes_total` metric
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LeaderChangeReason::Startup => "startup",
+            LeaderChangeReason::Restored => "restored",
+            LeaderChangeReason::DiscoveryEmpty => "discovery_empty",
+            LeaderChangeReason::DiscoveryTimeout => "discovery_timeout",
+            LeaderChangeReason::Announced => "announced",
+            LeaderChangeReason::Outranked => "outranked",
+            LeaderChangeReason::SuccessorTakeover => "successor_takeover",
+            LeaderChangeReason::LastNodeStanding => "last_node_standing",
+            LeaderChangeReason::NoSuccessor => "no_successor",
+            LeaderChangeReason::LeaderFailed => "leader_failed",
+            LeaderChangeReason::Handoff => "handoff",
+            LeaderChangeReason::NoQuorum => "no_quorum",
+            LeaderChangeReason::QuorumRegained => "quorum_regained",
+            LeaderChangeReason::ElectionWon => "election_won",
+        }
+    }
+}
+
+impl std::fmt::Display for LeaderChangeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Leadership as seen by one node, published on every change
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeaderState {
+    pub leader: Option<u32>,
+    pub am_i_leader: bool,
+    pub term: u64,
+    /// What caused the latest change
+    pub reason: LeaderChangeReason,
+}
+
+/// Everything a node believes about the cluster at one instant, detached from
+/// its locks. Unlike reading the fields one by one, a snapshot never mixes
+/// state from before and after a leadership change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClusterView {
+    pub node_id: u32,
+    pub leader: Option<u32>,
+    pub successor: Option<u32>,
+    pub am_i_leader: bool,
+    pub term: u64,
+    /// Nodes the leader has heard from, in ID order; empty on followers
+    pub alive_nodes: Vec<u32>,
+}
+
+impl ClusterView {
+    /// Read guards are taken in field declaration order, under the view lock
+    #[allow(clippy::too_many_arguments)]
+    async fn capture(
+        node_id: u32,
+        view_lock: &RwLock<()>,
+        current_leader: &RwLock<Option<u32>>,
+        current_successor: &RwLock<Option<u32>>,
+        am_i_leader: &RwLock<bool>,
+        current_term: &RwLock<u64>,
+        alive_nodes: &RwLock<HashSet<u32>>,
+    ) -> Self {
+        let _view = view_lock.read().await;
+        let leader = current_leader.read().await;
+        let successor = current_successor.read().await;
+        let am_i_leader = am_i_leader.read().await;
+        let term = current_term.read().await;
+        let alive = alive_nodes.read().await;
+        let mut alive_nodes: Vec<u32> = alive.iter().copied().collect();
+        alive_nodes.sort_unstable();
+        Self {
+            node_id,
+            leader: *leader,
+            successor: *successor,
+            am_i_leader: *am_i_leader,
+            term: *term,
+            alive_nodes,
+        }
+    }
+}
+
+/// Readiness shared by `Node::is_healthy` and `NodeHandle::is_healthy`
+async fn is_ready(
+    discovered: &RwLock<bool>,
+    current_leader: &RwLock<Option<u32>>,
+    am_i_leader: &RwLock<bool>,
+    peers: &RwLock<HashMap<u32, PeerConnection>>,
+) -> bool {
+    if !*discovered.read().await {
+        return false;
+    }
+    if *am_i_leader.read().await {
+        return true;
+    }
+    let leader = *current_leader.read().await;
+    match leader {
+        Some(leader_id) => peers.read().await.contains_key(&leader_id),
+        None => false,
+    }
+}
+
+/// The connection to `id`, cloned so it can be sent on without holding the
+/// peers lock, which would stall every other task touching peers meanwhile
+async fn peer(peers: &RwLock<HashMap<u32, PeerConnection>>, id: u32) -> Option<PeerConnection> {
+    peers.read().await.get(&id).cloned()
+}
+
+/// Every current peer connection, cloned like [`peer`]'s
+async fn peer_connections(peers: &RwLock<HashMap<u32, PeerConnection>>) -> Vec<(u32, PeerConnection)> {
+    peers.read().await.iter().map(|(&id, conn)| (id, conn.clone())).collect()
+}
+
+/// Traffic on each current peer connection, one row per peer and message
+/// type, ordered by peer and then type
+async fn peer_traffic(peers: &RwLock<HashMap<u32, PeerConnection>>) -> Vec<PeerTraffic> {
+    let mut stats: Vec<(u32, PeerStats)> = peers.read().await.iter().map(|(&id, conn)| (id, conn.stats())).collect();
+    stats.sort_unstable_by_key(|&(id, _)| id);
+
+    let mut rows = Vec::new();
+    for (peer, stats) in stats {
+        let kinds: BTreeSet<&str> = stats.sent.keys().chain(stats.received.keys()).copied().collect();
+        for kind in kinds {
+            let sent = stats.sent.get(kind).copied().unwrap_or_default();
+            let received = stats.received.get(kind).copied().unwrap_or_default();
+            rows.push(PeerTraffic {
+                peer,
+                msg_type: kind.to_string(),
+                sent_messages: sent.messages,
+                sent_bytes: sent.bytes,
+                received_messages: received.messages,
+                received_bytes: received.bytes,
+            });
+        }
+    }
+    rows
+}
+
+/// State of the connection to every known node but `my_id`, ordered by ID. A
+/// live connection counts as `Connected` whatever the reconnect task last
+/// recorded, since the peer may have dialed in.
+async fn peer_states(
+    my_id: u32,
+    all_nodes: &RwLock<Vec<NodeInfo>>,
+    peers: &RwLock<HashMap<u32, PeerConnection>>,
+    dial_states: &RwLock<HashMap<u32, PeerState>>,
+) -> Vec<(u32, PeerState)> {
+    let mut ids: Vec<u32> = all_nodes.read().await.iter().map(|node| node.id).filter(|&id| id != my_id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let peers = peers.read().await;
+    let dial_states = dial_states.read().await;
+    ids.into_iter()
+        .map(|id| {
+            let state = if peers.contains_key(&id) {
+                PeerState::Connected
+            } else {
+                dial_states.get(&id).copied().unwrap_or(PeerState::Dead)
+            };
+            (id, state)
+        })
+        .collect()
+}
+
+/// fsync batched writes off the runtime's worker threads
+async fn flush(durability: &Durability) {
+    let durability = durability.clone();
+    match tokio::task::spawn_blocking(move || durability.flush()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Failed to flush writes to disk: {}", e),
+        Err(e) => warn!("Flush task failed: {}", e),
+    }
+}
+
+/// Cheap, cloneable view of a running `Node` for the embedding application
+#[derive(Clone)]
+pub struct NodeHandle {
+    my_id: u32,
+    view_lock: Arc<RwLock<()>>,
+    current_leader: Arc<RwLock<Option<u32>>>,
+    current_successor: Arc<RwLock<Option<u32>>>,
+    am_i_leader: Arc<RwLock<bool>>,
+    current_term: Arc<RwLock<u64>>,
+    alive_nodes: Arc<RwLock<HashSet<u32>>>,
+    discovered: Arc<RwLock<bool>>,
+    all_nodes: Arc<RwLock<Vec<NodeInfo>>>,
+    peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
+    dial_states: Arc<RwLock<HashMap<u32, PeerState>>>,
+    leader_rx: watch::Receiver<LeaderState>,
+    shutdown_signal: Arc<Notify>,
+}
+
+impl NodeHandle {
+    /// ID of the node this handle observes
+    pub fn node_id(&self) -> u32 {
+        self.my_id
+    }
+
+    /// Whether the node currently believes it is the leader
+    pub async fn is_leader(&self) -> bool {
+        *self.am_i_leader.read().await
+    }
+
+    /// The leader this node currently follows, if any
+    pub async fn current_leader(&self) -> Option<u32> {
+        *self.current_leader.read().await
+    }
+
+    /// Whether the node is ready for traffic (see [`Node::is_healthy`])
+    pub async fn is_healthy(&self) -> bool {
+        is_ready(&self.discovered, &self.current_leader, &self.am_i_leader, &self.peers).await
+    }
+
+    /// Messages and bytes exchanged with each connected peer, by message type
+    pub async fn peer_traffic(&self) -> Vec<PeerTraffic> {
+        peer_traffic(&self.peers).await
+    }
+
+    /// State of the node's connection to each other known node (see [`Node::peer_states`])
+    pub async fn peer_states(&self) -> Vec<(u32, PeerState)> {
+        peer_states(self.my_id, &self.all_nodes, &self.peers, &self.dial_states).await
+    }
+
+    /// Subscribe to leadership changes
+    pub fn leader_changes(&self) -> watch::Receiver<LeaderState> {
+        self.leader_rx.clone()
+    }
+
+    /// The node's leadership state, taken at a single instant (see [`Node::snapshot`])
+    pub async fn snapshot(&self) -> ClusterView {
+        ClusterView::capture(
+            self.my_id,
+            &self.view_lock,
+            &self.current_leader,
+            &self.current_successor,
+            &self.am_i_leader,
+            &self.current_term,
+            &self.alive_nodes,
+        )
+        .await
+    }
+
+    /// Ask the node to leave the cluster cleanly (see [`Node::shutdown`]).
+    /// Returns at once; `run` returns when the node is done.
+    pub fn shutdown(&self) {
+        self.shutdown_signal.notify_one();
+    }
+}
+
+impl Node {
+    /// Build a node for `my_id`, which must appear in `config.nodes`.
+    /// The returned receiver observes every change to this node's `LeaderState`.
+    pub fn new(my_id: u32, config: Config) -> Result<(Self, watch::Receiver<LeaderState>)> {
+        let my_node_info = config.nodes.iter()
+            .find(|n| n.id == my_id)
+            .context(format!("Node ID {} not found in config", my_id))?;
+        config.timings.validate()?;
+        config.failure_detector.validate()?;
+        if config.message_queue_capacity == 0 {
+            anyhow::bail!("Message queue capacity must be non-zero");
+        }
+        if config.listen_backlog == 0 {
+            anyhow::bail!("Listen backlog must be non-zero");
+        }
+        if config.max_connections == 0 || config.max_connections_per_source == 0 {
+            anyhow::bail!("Connection limits must be non-zero");
+        }
+        if config.min_quorum == 0 {
+            anyhow::bail!("Minimum quorum must be at least 1");
+        }
+        if config.successor_depth == 0 {
+            anyhow::bail!("Successor depth must be at least 1");
+        }
+        if config.wal_segment_size == 0 {
+            anyhow::bail!("WAL segment size must be non-zero");
+        }
+        if let DurabilityPolicy::Batched { flush_interval } = config.durability {
+            if flush_interval.is_zero() {
+                anyhow::bail!("Durability flush interval must be non-zero");
+            }
+        }
+
+        let metrics = Arc::new(Metrics::default());
+        let (message_tx, message_rx) = mpsc::channel(config.message_queue_capacity);
+        let message_tx = MessageSender::new(message_tx, metrics.clone());
+        let (client_tx, client_rx) = mpsc::channel(config.message_queue_capacity);
+        let (leader_tx, leader_rx) = watch::channel(LeaderState::default());
+        let (election_tx, election_rx) = mpsc::channel(1);
+
+        let node = Self {
+            my_id,
+            my_address: my_node_info.advertised_address().to_string(),
+            all_nodes: Arc::new(RwLock::new(config.nodes.clone())),
+            seeds: config.seeds.clone(),
+            timings: config.timings,
+            network: NetworkLayer::new(my_node_info.bind_address.clone())
+                .with_transport(Arc::new(TcpTransport::new().with_backlog(config.listen_backlog)))
+                .with_max_message_size(config.max_message_size)
+                .with_connection_limits(config.max_connections, config.max_connections_per_source)
+                .with_io_timeout(config.timings.io_timeout),
+            
+            view_lock: Arc::new(RwLock::new(())),
+            current_leader: Arc::new(RwLock::new(None)),
+            current_successor: Arc::new(RwLock::new(None)),
+            backup_successors: Arc::new(RwLock::new(Vec::new())),
+            am_i_leader: Arc::new(RwLock::new(false)),
+            current_term: Arc::new(RwLock::new(0)),
+            election_id: Arc::new(RwLock::new(0)),
+            min_quorum: config.min_quorum,
+            discovery_retries: config.discovery_retries,
+            successor_depth: config.successor_depth,
+            observer: my_node_info.observer,
+            discovered: Arc::new(RwLock::new(false)),
+            leading_since: None,
+            lease_votes: HashMap::new(),
+            
+            alive_nodes: Arc::new(RwLock::new(HashSet::new())),
+            last_heartbeat: Arc::new(RwLock::new(HashMap::new())),
+            detector: Arc::new(RwLock::new(config.failure_detector.build(&config.timings))),
+            last_pong: Arc::new(RwLock::new(HashMap::new())),
+            last_election_ok: Arc::new(RwLock::new(None)),
+            election_tx,
+            election_rx: Some(election_rx),
+            peer_rtt: Arc::new(RwLock::new(HashMap::new())),
+            epoch: Instant::now(),
+            
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            dial_states: Arc::new(RwLock::new(HashMap::new())),
+            message_rx,
+            discovery_backlog: Vec::new(),
+            message_tx,
+            client_rx,
+            client_tx,
+            state_store: None,
+            image_store: None,
+            wal_dir: None,
+            wal_segment_size: config.wal_segment_size,
+            durability: Durability::new(config.durability),
+            reassembler: Reassembler::new(),
+            pending_writes: HashMap::new(),
+            in_flight: HashMap::new(),
+            forwarded_uploads: HashMap::new(),
+            checksum_resends: HashMap::new(),
+            fetch_load: HashMap::new(),
+            fetch_grants: HashMap::new(),
+            ungranted_fetches: HashMap::new(),
+            leader_tx: Arc::new(leader_tx),
+            tasks: Tasks::default(),
+            shutdown_signal: Arc::new(Notify::new()),
+            metrics,
+            metrics_addr: None,
+        };
+
+        Ok((node, leader_rx))
+    }
+
+    /// Handle for querying leadership while `run` owns the node
+    pub fn handle(&self) -> NodeHandle {
+        NodeHandle {
+            my_id: self.my_id,
+            view_lock: self.view_lock.clone(),
+            current_leader: self.current_leader.clone(),
+            current_successor: self.current_successor.clone(),
+            am_i_leader: self.am_i_leader.clone(),
+            current_term: self.current_term.clone(),
+            alive_nodes: self.alive_nodes.clone(),
+            discovered: self.discovered.clone(),
+            all_nodes: self.all_nodes.clone(),
+            peers: self.peers.clone(),
+            dial_states: self.dial_states.clone(),
+            leader_rx: self.leader_tx.subscribe(),
+            shutdown_signal: self.shutdown_signal.clone(),
+        }
+    }
+
+    /// Persist leadership state under `state_dir` so it survives restarts
+    pub fn with_state_dir(mut self, state_dir: &Path) -> Self {
+        self.state_store = Some(StateStore::new(state_dir, self.my_id).with_durability(self.durability.clone()));
+        self
+    }
+
+    /// Store image replicas under `image_dir`
+    pub fn with_image_dir(self, image_dir: &Path) -> Self {
+        let storage = FileStorage::new(image_dir).with_durability(self.durability.clone());
+        self.with_storage(Box::new(storage))
+    }
+
+    /// Store image replicas in `storage` instead of a directory, e.g. a
+    /// [`MemoryStorage`](crate::storage::MemoryStorage) in tests
+    pub fn with_storage(mut self, storage: Box<dyn Storage>) -> Self {
+        self.image_store = Some(ImageStore::with_storage(storage));
+        self
+    }
+
+    /// Log image writes and deletes to a write-ahead log under `wal_dir`
+    /// before applying them, so one a crash cuts short is finished when the
+    /// node next starts. Needs `with_image_dir` or `with_storage`.
+    pub fn with_wal_dir(mut self, wal_dir: &Path) -> Self {
+        self.wal_dir = Some(wal_dir.to_path_buf());
+        self
+    }
+
+    /// Encrypt image replicas at rest with `key`; call after `with_image_dir`
+    /// or `with_storage`
+    pub fn with_image_key(mut self, key: ImageKey) -> Self {
+        self.image_store = self.image_store.map(|store| store.with_key(key));
+        self
+    }
+
+    /// Authenticate every frame with the shared cluster secret
+    pub fn with_cluster_key(mut self, key: ClusterKey) -> Self {
+        self.network = self.network.with_cluster_key(key);
+        self
+    }
+
+    /// Reach peers through `transport` instead of TCP sockets, e.g. a
+    /// [`MemoryNetwork`](crate::transport::MemoryNetwork) in tests
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.network = self.network.with_transport(transport);
+        self
+    }
+
+    /// Encrypt all inter-node and client traffic with TLS
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.network = self.network.with_tls(tls);
+        self
+    }
+
+    /// Serve Prometheus metrics at `http://{addr}/metrics`, and readiness at
+    /// `http://{addr}/healthz`, while running
+    pub fn with_metrics_addr(mut self, addr: String) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Join the cluster and participate in elections until the message
+    /// channel closes, or until [`NodeHandle::shutdown`] is called, which
+    /// leaves the cluster cleanly. Signals are left to the embedding
+    /// program, which should call `shutdown` on SIGTERM.
+    pub async fn run(mut self) -> Result<()> {
+        info!("╔═══════════════════════════════════════════════════════════╗");
+        info!("║ Modified Bully Algorithm - Node Starting                 ║");
+        info!("╠═══════════════════════════════════════════════════════════╣");
+        info!("║ Node ID: {}                                               ║", self.my_id);
+        info!("║ Address: {}                                   ║", self.my_address);
+        info!("╚═══════════════════════════════════════════════════════════╝");
+
+        // Finish image writes and deletes a crash cut short before serving any
+        if let Some(wal_dir) = self.wal_dir.clone() {
+            match self.image_store.take() {
+                Some(store) => {
+                    let store = store.with_wal(&wal_dir, self.wal_segment_size, self.durability.clone())?;
+                    self.image_store = Some(store);
+                }
+                None => warn!("No image directory configured - not keeping a write-ahead log"),
+            }
+        }
+
+        // Start listener
+        let my_id = self.my_id;
+        let network = self.network.clone();
+        let tx = self.message_tx.clone();
+        let client_tx = self.client_tx.clone();
+        let peers = self.peers.clone();
+        self.tasks.push(tokio::spawn(async move {
+            if let Err(e) = network.start_listener(my_id, tx, client_tx, peers).await {
+                error!("Listener error: {}", e);
+            }
+        }));
+
+        // Start metrics endpoint
+        if let Some(addr) = self.metrics_addr.clone() {
+            let metrics = self.metrics.clone();
+            let node = self.handle();
+            self.tasks.push(tokio::spawn(async move {
+                if let Err(e) = metrics::serve(addr, metrics, node).await {
+                    error!("Metrics endpoint error: {}", e);
+                }
+            }));
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Seed beliefs from a previous run
+        self.load_state().await?;
+
+        // Discover network
+        self.discover_network().await?;
+        *self.discovered.write().await = true;
+        self.replay_discovery_backlog().await;
+
+        // Start background tasks
+        self.spawn_background_tasks();
+
+        // Handle messages
+        self.message_loop().await
+    }
+
+    /// Restore leader/successor/term from the state file, if one exists
+    async fn load_state(&mut self) -> Result<()> {
+        let state = match &self.state_store {
+            Some(store) => store.load()?,
+            None => None,
+        };
+        let Some(state) = state else {
+            return Ok(());
+        };
+
+        info!("💾 Restored state: Leader={:?}, Successor={:?}, Term={}",
+              state.leader, state.successor, state.term);
+
+        // Never resume leadership on our own say-so; the cluster may have moved on
+        let leader = state.leader.filter(|&id| id != self.my_id);
+        {
+            let _view = self.view_lock.write().await;
+            *self.current_term.write().await = state.term;
+            if leader.is_some() {
+                *self.current_leader.write().await = leader;
+                *self.current_successor.write().await = state.successor;
+            }
+        }
+
+        if let Some(leader_id) = leader {
+            self.publish(LeaderChangeReason::Restored).await;
+            // Start the failure clock so a leader that died meanwhile is detected
+            self.last_heartbeat.write().await.insert(leader_id, Instant::now());
+            self.detector.write().await.reset(leader_id, Instant::now());
+        }
+
+        Ok(())
+    }
+
+    /// Discover the network and current leader
+    async fn discover_network(&mut self) -> Result<()> {
+        info!("🔍 Discovering network...");
+        self.ask_seeds().await;
+
+        let connected = self.ask_for_leader().await;
+
+        if !connected && self.observer {
+            info!("👀 No other nodes found - observing until a leader appears");
+        } else if !connected && self.min_quorum > 1 {
+            warn!("⛔ No other nodes found - staying leaderless until {} nodes are visible", self.min_quorum);
+        } else if !connected {
+            info!("📍 No other nodes found - I am the leader!");
+            self.shared()
+                .claim_leadership(new_correlation_id(), LeaderChangeReason::DiscoveryEmpty)
+                .await;
+            self.save_state().await;
+        } else {
+            // Wait for coordinator message, asking again in case the first
+            // round or its answer was lost or held up on a busy network
+            info!("⏳ Waiting for leader announcement...");
+            
+            let mut announced = self.wait_for_coordinator(self.timings.discovery_timeout).await;
+            for retry in 1..=self.discovery_retries {
+                if announced.is_some() {
+                    break;
+                }
+                info!("🔁 No leader announcement yet - asking again ({}/{})", retry, self.discovery_retries);
+                self.ask_for_leader().await;
+                announced = self.wait_for_coordinator(self.timings.discovery_timeout).await;
+            }
+            
+            match announced {
+                Some((from_id, coordinator)) => {
+                    // Handled outside the wait, so no deadline cuts it short
+                    self.handle_message(from_id, coordinator).await;
+                    
+                    let leader = *self.current_leader.read().await;
+                    let successor = *self.current_successor.read().await;
+                    let term = *self.current_term.read().await;
+                    info!("✅ Network discovered: Leader={:?}, Successor={:?}, Term={}", leader, successor, term);
+                    
+                    // Announce ourselves in case the leader's config predates us
+                    if let Some(leader_id) = leader {
+                        let join = Message::Join {
+                            node_id: self.my_id,
+                            address: self.my_address.clone(),
+                            priority: rank(&self.all_nodes.read().await, self.my_id).priority,
+                            observer: self.observer,
+                        };
+                        if let Some(conn) = peer(&self.peers, leader_id).await {
+                            let _ = conn.send(&join).await;
+                        }
+                    }
+                }
+                None => {
+                    let known_leader = *self.current_leader.read().await;
+                    if let Some(leader_id) = known_leader {
+                        warn!("⚠️  No coordinator received - keeping restored leader Node {}", leader_id);
+                    } else {
+                        warn!(
+                            "⚠️  No coordinator received after {} attempt(s) - starting election",
+                            self.discovery_retries + 1
+                        );
+                        self.start_election(LeaderChangeReason::DiscoveryTimeout).await;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// [`NetworkLayer::get_or_connect`], publishing the dial in `dial_states`;
+    /// a failed dial leaves the peer in `Backoff` for the reconnect task
+    async fn get_or_connect(&self, node_id: u32, address: &str) -> Result<(PeerConnection, bool)> {
+        self.dial_states.write().await.insert(node_id, PeerState::Connecting);
+        let result = self.network.get_or_connect(&self.peers, self.my_id, &self.my_address, node_id, address).await;
+        let mut dial_states = self.dial_states.write().await;
+        if result.is_ok() {
+            dial_states.remove(&node_id);
+        } else {
+            dial_states.insert(node_id, PeerState::Backoff);
+        }
+        result
+    }
+
+    /// Send `WhoIsLeader` to every other node, dialing any we have no
+    /// connection to; false if none could be reached
+    async fn ask_for_leader(&self) -> bool {
+        let discovery_msg = Message::WhoIsLeader {
+            node_id: self.my_id,
+            from_address: self.my_address.clone(),
+        };
+
+        let mut connected = false;
+        let all_nodes = self.all_nodes.read().await.clone();
+        for node in &all_nodes {
+            if node.id == self.my_id {
+                continue;
+            }
+
+            // The node may have dialed us already; ask over that connection
+            let address = node.advertised_address();
+            match self.get_or_connect(node.id, address).await {
+                Ok((conn, fresh)) => {
+                    if fresh {
+                        // Start read loop for outgoing connection
+                        Self::spawn_peer_reader(node.id, conn.clone(), self.peers.clone(), self.message_tx.clone());
+                    }
+                    if let Err(e) = conn.send(&discovery_msg).await {
+                        warn!("Failed to send discovery to {}: {}", address, e);
+                    } else {
+                        connected = true;
+                    }
+                }
+                Err(e) => {
+                    debug!("Could not connect to node {}: {}", node.id, e);
+                }
+            }
+        }
+        connected
+    }
+
+    /// Ask the seeds for the cluster's members until one answers, and add the
+    /// ones we do not know. Discovery then dials them as if they were
+    /// configured; the leader's `Membership` fills in any the seed lacked.
+    async fn ask_seeds(&self) {
+        for seed in &self.seeds {
+            let asked = async {
+                let conn = self.network.connect(seed).await?;
+                conn.send(&Message::ListMembers {}).await?;
+                let reply = timeout(self.timings.io_timeout, conn.receive_one())
+                    .await
+                    .context("Timed out waiting for the member list")??;
+                conn.close().await;
+                match reply {
+                    Message::MemberList { nodes } => Ok(nodes),
+                    other => anyhow::bail!("Unexpected reply: {:?}", other),
+                }
+            };
+            let nodes = match asked.await {
+                Ok(nodes) => nodes,
+                Err(e) => {
+                    warn!("🌱 Seed {} did not answer: {:#}", seed, e);
+                    continue;
+                }
+            };
+
+            let mut all_nodes = self.all_nodes.write().await;
+            let known = all_nodes.len();
+            for node in nodes {
+                if node.id != self.my_id && !all_nodes.iter().any(|n| n.id == node.id) {
+                    all_nodes.push(node);
+                }
+            }
+            info!("🌱 Learned {} node(s) from seed {}", all_nodes.len() - known, seed);
+            return;
+        }
+
+        if !self.seeds.is_empty() {
+            warn!("⚠️  No seed answered - discovering from the configured nodes only");
+        }
+    }
+
+    /// Wait up to `wait` for the first `Coordinator` and return it, setting
+    /// aside everything else for `replay_discovery_backlog`. Liveness traffic
+    /// is dropped instead, being stale by then, as is anything past one
+    /// queue's worth.
+    async fn wait_for_coordinator(&mut self, wait: Duration) -> Option<(u32, Message)> {
+        let deadline = Instant::now() + wait;
+        while let Ok(Some((from_id, msg))) = timeout_at(deadline, self.message_rx.recv()).await {
+            match msg {
+                Message::Coordinator { .. } => return Some((from_id, msg)),
+                Message::Heartbeat { .. } | Message::HeartbeatAck { .. } | Message::Ping { .. } | Message::Pong { .. } => {}
+                _ if self.discovery_backlog.len() >= self.message_rx.max_capacity() => {
+                    debug!("Discovery backlog full - dropping {} from Node {}", msg.kind(), from_id);
+                }
+                _ => self.discovery_backlog.push((from_id, msg)),
+            }
+        }
+        None
+    }
+
+    /// Handle the messages that arrived while discovery waited, in order, so
+    /// an `Election` or `WhoIsLeader` sent meanwhile still gets its answer
+    async fn replay_discovery_backlog(&mut self) {
+        for (from_id, message) in std::mem::take(&mut self.discovery_backlog) {
+            self.handle_message(from_id, message).await;
+        }
+    }
+
+    /// Read from an outbound connection until it drops, then forget it so the
+    /// reconnect task can dial the peer again
+    fn spawn_peer_reader(
+        node_id: u32,
+        conn: PeerConnection,
+        peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
+        tx: MessageSender,
+    ) {
+        tokio::spawn(async move {
+            if let Err(e) = Self::read_from_peer(node_id, conn.clone(), tx).await {
+                debug!("Read loop ended for node {}: {}", node_id, e);
+            }
+            remove_peer(&peers, node_id, &conn).await;
+        });
+    }
+
+    /// Helper to read from a peer connection and forward messages to channel
+    async fn read_from_peer(
+        node_id: u32,
+        conn: PeerConnection,
+        tx: MessageSender,
+    ) -> Result<()> {
+        loop {
+            match conn.receive_one().await {
+                Ok(message) => {
+                    if tx.send((node_id, message)).is_err() {
+                        warn!("Channel closed for Node {}", node_id);
+                        break;
+                    }
+                }
+                Err(NetworkError::ConnectionClosed) => {
+                    debug!("Connection closed: Node {}", node_id);
+                    break;
+                }
+                Err(e) => {
+                    debug!("Read error from Node {}: {:#}", node_id, anyhow::Error::from(e));
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles onto the state the background tasks share with us
+    fn shared(&self) -> SharedState {
+        SharedState {
+            my_id: self.my_id,
+            all_nodes: self.all_nodes.clone(),
+            view_lock: self.view_lock.clone(),
+            current_leader: self.current_leader.clone(),
+            current_successor: self.current_successor.clone(),
+            backup_successors: self.backup_successors.clone(),
+            am_i_leader: self.am_i_leader.clone(),
+            alive_nodes: self.alive_nodes.clone(),
+            current_term: self.current_term.clone(),
+            election_id: self.election_id.clone(),
+            last_heartbeat: self.last_heartbeat.clone(),
+            detector: self.detector.clone(),
+            last_pong: self.last_pong.clone(),
+            last_election_ok: self.last_election_ok.clone(),
+            peers: self.peers.clone(),
+            state_store: self.state_store.clone(),
+            leader_tx: self.leader_tx.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    fn spawn_background_tasks(&mut self) {
+        let timings = self.timings;
+
+        // Reconnect to peers whose connection dropped
+        let shared = self.shared();
+        let my_address = self.my_address.clone();
+        let network = self.network.clone();
+        let dial_states = self.dial_states.clone();
+        let tx = self.message_tx.clone();
+        self.tasks.push(tokio::spawn(async move {
+            Self::reconnect_task(shared, my_address, network, dial_states, tx, timings).await;
+        }));
+
+        // Heartbeat sender (if not leader)
+        let shared = self.shared();
+        let peer_rtt = self.peer_rtt.clone();
+        let epoch = self.epoch;
+        self.tasks.push(tokio::spawn(async move {
+            Self::heartbeat_sender_task(shared, peer_rtt, epoch, timings.heartbeat_interval).await;
+        }));
+
+        // Coordinator broadcaster (if leader)
+        let shared = self.shared();
+        self.tasks.push(tokio::spawn(async move {
+            Self::coordinator_broadcaster_task(shared, timings.coordinator_interval).await;
+        }));
+
+        // Leader updates successor based on heartbeats
+        let shared = self.shared();
+        let successor_depth = self.successor_depth;
+        self.tasks.push(tokio::spawn(async move {
+            Self::successor_updater_task(shared, successor_depth, timings.check_interval()).await;
+        }));
+
+        // Failure detector
+        let shared = self.shared();
+        let election_rx = self.election_rx.take();
+        let min_quorum = self.min_quorum;
+        let observer = self.observer;
+        self.tasks.push(tokio::spawn(async move {
+            Self::failure_detector_task(shared, election_rx, min_quorum, observer, timings).await;
+        }));
+
+        // Leadership gauges follow the published state
+        let leader_rx = self.leader_tx.subscribe();
+        let metrics = self.metrics.clone();
+        self.tasks.push(tokio::spawn(async move {
+            Self::metrics_updater_task(leader_rx, metrics).await;
+        }));
+
+        // fsync batched writes
+        if let DurabilityPolicy::Batched { flush_interval } = self.durability.policy() {
+            let durability = self.durability.clone();
+            self.tasks.push(tokio::spawn(async move {
+                Self::flush_task(durability, flush_interval).await;
+            }));
+        }
+    }
+
+    /// Background task: fsync what was written since the last flush, every
+    /// `flush_interval`
+    async fn flush_task(durability: Durability, flush_interval: Duration) {
+        let mut ticker = interval(flush_interval);
+        loop {
+            ticker.tick().await;
+            flush(&durability).await;
+        }
+    }
+
+    /// Background task: Re-dial configured peers that have no live connection,
+    /// backing off exponentially from peers that stay unreachable. Each
+    /// peer's dial progress is published in `dial_states`.
+    async fn reconnect_task(
+        shared: SharedState,
+        my_address: String,
+        network: NetworkLayer,
+        dial_states: Arc<RwLock<HashMap<u32, PeerState>>>,
+        tx: MessageSender,
+        timings: Timings,
+    ) {
+        let SharedState { my_id, all_nodes, peers, .. } = shared;
+        // Peer ID -> (consecutive failed attempts, when to try next)
+        let mut retries: HashMap<u32, (u32, Instant)> = HashMap::new();
+        let mut wake = Instant::now();
+
+        loop {
+            sleep_until(wake).await;
+            let now = Instant::now();
+
+            let known_nodes = all_nodes.read().await.clone();
+            for node in &known_nodes {
+                if node.id == my_id || peers.read().await.contains_key(&node.id) {
+                    // Connected, possibly because the peer dialed us: start afresh
+                    retries.remove(&node.id);
+                    dial_states.write().await.remove(&node.id);
+                    continue;
+                }
+                if retries.get(&node.id).is_some_and(|&(_, at)| at > now) {
+                    continue;
+                }
+
+                dial_states.write().await.insert(node.id, PeerState::Connecting);
+                // The peer may dial us while we dial it; keep whichever
+                // connection `register_peer` does, and read only from ours
+                let connected = network
+                    .get_or_connect(&peers, my_id, &my_address, node.id, node.advertised_address())
+                    .await;
+                let conn = match connected {
+                    Ok((conn, true)) => {
+                        info!("🔁 Reconnected to Node {}", node.id);
+                        Self::spawn_peer_reader(node.id, conn.clone(), peers.clone(), tx.clone());
+                        conn
+                    }
+                    Ok((conn, false)) => {
+                        debug!("Node {} dialed us first - keeping its connection", node.id);
+                        conn
+                    }
+                    Err(e) => {
+                        let failures = retries.get(&node.id).map_or(0, |&(failures, _)| failures) + 1;
+                        let wait = timings.reconnect_backoff(failures);
+                        debug!("Reconnect to node {} failed: {} (retrying in {:?})", node.id, e, wait);
+                        retries.insert(node.id, (failures, now + wait));
+                        dial_states.write().await.insert(node.id, PeerState::Backoff);
+                        continue;
+                    }
+                };
+                retries.remove(&node.id);
+                dial_states.write().await.remove(&node.id);
+
+                // Learn the current leader straight away
+                let who = Message::WhoIsLeader {
+                    node_id: my_id,
+                    from_address: my_address.clone(),
+                };
+                if let Err(e) = conn.send(&who).await {
+                    debug!("Failed to ask Node {} for the leader: {}", node.id, e);
+                }
+            }
+
+            retries.retain(|id, _| known_nodes.iter().any(|node| node.id == *id));
+            dial_states.write().await.retain(|id, _| known_nodes.iter().any(|node| node.id == *id));
+
+            // Check for dropped connections every interval, sooner if a retry is due
+            wake = retries
+                .values()
+                .map(|&(_, at)| at)
+                .fold(now + timings.reconnect_interval, Instant::min);
+        }
+    }
+
+    /// Background task: Send heartbeats to leader (if not leader), stamped so
+    /// the leader's acks time the round trip
+    async fn heartbeat_sender_task(
+        shared: SharedState,
+        peer_rtt: Arc<RwLock<HashMap<u32, Duration>>>,
+        epoch: Instant,
+        heartbeat_interval: Duration,
+    ) {
+        let SharedState { my_id, peers, am_i_leader, current_leader, current_term, metrics, .. } = shared;
+        let mut ticker = interval(heartbeat_interval);
+
+        loop {
+            ticker.tick().await;
+
+            if *am_i_leader.read().await {
+                continue; // Leaders don't send heartbeats
+            }
+
+            let leader = *current_leader.read().await;
+            if let Some(leader_id) = leader {
+                let heartbeat = Message::Heartbeat {
+                    node_id: my_id,
+                    term: *current_term.read().await,
+                    sent_at_us: epoch.elapsed().as_micros() as u64,
+                    rtt_us: peer_rtt.read().await.get(&leader_id).map(|rtt| rtt.as_micros() as u64),
+                };
+                
+                if let Some(leader_conn) = peer(&peers, leader_id).await {
+                    match leader_conn.send(&heartbeat).await {
+                        Ok(()) => metrics.heartbeat_sent(),
+                        // Already reported when the breaker opened
+                        Err(NetworkError::CircuitOpen(_)) => {}
+                        Err(e) => debug!("Failed to send heartbeat to leader {}: {}", leader_id, e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Background task: Broadcast coordinator messages (if leader), each
+    /// followed by our membership so followers can dial every member
+    async fn coordinator_broadcaster_task(shared: SharedState, coordinator_interval: Duration) {
+        let SharedState {
+            my_id,
+            all_nodes,
+            peers,
+            am_i_leader,
+            current_successor,
+            backup_successors,
+            current_term,
+            election_id,
+            ..
+        } = shared;
+        let mut ticker = interval(coordinator_interval);
+
+        loop {
+            ticker.tick().await;
+
+            if !*am_i_leader.read().await {
+                continue; // Only leaders broadcast
+            }
+
+            let successor = *current_successor.read().await;
+            let term = *current_term.read().await;
+            let coordinator = Message::Coordinator {
+                leader_id: my_id,
+                successor_id: successor,
+                backup_successors: backup_successors.read().await.clone(),
+                term,
+                correlation_id: *election_id.read().await,
+            };
+            let membership = Message::Membership {
+                leader_id: my_id,
+                nodes: all_nodes.read().await.clone(),
+            };
+
+            for (_, conn) in peer_connections(&peers).await {
+                let _ = conn.send(&coordinator).await;
+                let _ = conn.send(&membership).await;
+            }
+        }
+    }
+
+    /// Background task: Leader updates successor based on alive nodes
+    async fn successor_updater_task(shared: SharedState, successor_depth: usize, check_interval: Duration) {
+        let mut ticker = interval(check_interval);
+
+        loop {
+            ticker.tick().await;
+
+            if !*shared.am_i_leader.read().await {
+                shared.metrics.set_alive_nodes(0);
+                continue;
+            }
+
+            shared.metrics.set_alive_nodes(shared.alive_nodes.read().await.len());
+            shared.update_successors(successor_depth).await;
+        }
+    }
+
+    /// Background task: Detect leader failures, and run the Bully rounds
+    /// lower ranked nodes ask for, one election at a time
+    async fn failure_detector_task(
+        shared: SharedState,
+        election_rx: Option<mpsc::Receiver<u64>>,
+        min_quorum: usize,
+        observer: bool,
+        timings: Timings,
+    ) {
+        let my_id = shared.my_id;
+        let SharedState {
+            all_nodes,
+            current_leader,
+            current_successor,
+            backup_successors,
+            am_i_leader,
+            last_heartbeat,
+            detector,
+            last_pong,
+            peers,
+            metrics,
+            ..
+        } = &shared;
+        let mut ticker = interval(timings.check_interval());
+        // Leader we last pinged, and when
+        let mut probe: Option<(u32, Instant)> = None;
+        let mut clock = ClockWatch::new(Instant::now());
+        let mut election_rx = election_rx;
+
+        loop {
+            let requested = tokio::select! {
+                _ = ticker.tick() => None,
+                Some(correlation_id) = async { election_rx.as_mut()?.recv().await } => Some(correlation_id),
+            };
+
+            if let Some(step) = clock.check(Instant::now()) {
+                warn!("🕰️  Wall clock stepped {}; failure detection runs on the monotonic clock", step);
+            }
+
+            if let Some(correlation_id) = requested {
+                // A lower ranked node is electing a leader; join in, unless the
+                // election is over or our leader still looks alive to us
+                let leader = *current_leader.read().await;
+                let leader_down = match leader {
+                    Some(id) => {
+                        !last_heartbeat.read().await.contains_key(&id)
+                            || detector.read().await.is_failed(id, Instant::now())
+                    }
+                    None => true,
+                };
+                if *am_i_leader.read().await || !leader_down {
+                    continue;
+                }
+                if Self::visible_nodes(peers, leader).await < min_quorum {
+                    debug!("Not joining election {:016x}: no quorum visible", correlation_id);
+                    continue;
+                }
+                shared
+                    .run_election(timings, correlation_id, LeaderChangeReason::ElectionWon)
+                    .instrument(logging::election_span(correlation_id))
+                    .await;
+                continue;
+            }
+
+            if *am_i_leader.read().await {
+                metrics.set_since_leader_heartbeat(0.0);
+                continue; // Leaders don't check for failures
+            }
+
+            let leader_id = match *current_leader.read().await {
+                Some(id) => id,
+                None => continue,
+            };
+
+            // Check if leader has timed out
+            let since_heartbeat = last_heartbeat.read().await.get(&leader_id).map(|t| t.elapsed());
+            if let Some(elapsed) = since_heartbeat {
+                metrics.set_since_leader_heartbeat(elapsed.as_secs_f64());
+            }
+            let now = Instant::now();
+            let (suspect, leader_timeout) = {
+                let detector = detector.read().await;
+                (detector.is_suspect(leader_id, now), detector.is_failed(leader_id, now))
+            };
+
+            if !suspect && !leader_timeout {
+                probe = None;
+                continue;
+            }
+
+            // Heartbeats are overdue: ask the leader directly, so a leader
+            // that is slow or dropping heartbeats is not mistaken for a dead one
+            let pinged = match probe {
+                Some((id, sent)) if id == leader_id => sent,
+                _ => {
+                    debug!("🏓 Heartbeats from leader Node {} overdue - pinging it", leader_id);
+                    if let Some(conn) = peer(peers, leader_id).await {
+                        let _ = conn.send(&Message::Ping { from_id: my_id }).await;
+                    }
+                    probe = Some((leader_id, now));
+                    now
+                }
+            };
+            if last_pong.read().await.get(&leader_id).is_some_and(|&at| at >= pinged) {
+                if leader_timeout {
+                    info!("🏓 Leader Node {} missed its heartbeats but answers pings - still alive", leader_id);
+                    detector.write().await.reset(leader_id, now);
+                }
+                probe = None;
+                continue;
+            }
+            if !leader_timeout || now.duration_since(pinged) < timings.probe_timeout {
+                continue;
+            }
+
+            if observer {
+                // Observers take no part in the takeover; the next Coordinator updates us
+                warn!("⚠️  Leader Node {} timed out - observing until a new one is announced", leader_id);
+                shared.clear_leadership(LeaderChangeReason::LeaderFailed).await;
+                continue;
+            }
+
+            // Leader failed! All that follows is one election, traced as such
+            let correlation_id = new_correlation_id();
+            async {
+                warn!("⚠️  LEADER FAILURE DETECTED: Node {} timeout", leader_id);
+                metrics.election_started();
+
+                let visible = Self::visible_nodes(peers, Some(leader_id)).await;
+                if visible < min_quorum {
+                    warn!("⛔ No quorum ({}/{} nodes visible) - staying leaderless", visible, min_quorum);
+                    shared.clear_leadership(LeaderChangeReason::NoQuorum).await;
+                    return;
+                }
+
+                let successor_id = *current_successor.read().await;
+                // The line of succession the leader last announced, successor first
+                let in_line: Vec<u32> = match successor_id {
+                    Some(id) => std::iter::once(id).chain(backup_successors.read().await.iter().copied()).collect(),
+                    None => Vec::new(),
+                };
+                let place = in_line.iter().position(|&id| id == my_id);
+
+                // Everyone not in line backs off briefly, so a takeover can
+                // reach them before they add election traffic of their own
+                if place.is_none() {
+                    let backoff = timings.election_backoff(my_id, &all_nodes.read().await);
+                    tokio::time::sleep(backoff).await;
+
+                    if let Some(new_leader) = current_leader.read().await.filter(|&id| id != leader_id) {
+                        info!("✅ Node {} took over while backing off", new_leader);
+                        return;
+                    }
+                }
+
+                // A backup takes over only once every node ahead of it in line is down too
+                let take_over = match place {
+                    Some(0) => {
+                        info!("👑 I am successor - TAKING OVER as leader!");
+                        true
+                    }
+                    Some(place) => {
+                        let ahead = &in_line[..place];
+                        if Self::any_answers(my_id, peers, last_pong, ahead, timings.probe_timeout).await {
+                            false
+                        } else if let Some(new_leader) = current_leader.read().await.filter(|&id| id != leader_id) {
+                            info!("✅ Node {} took over", new_leader);
+                            return;
+                        } else {
+                            info!("👑 Nodes {:?} ahead of us in line are down too - TAKING OVER as leader!", ahead);
+                            true
+                        }
+                    }
+                    None => false,
+                };
+
+                if take_over {
+                    let term = shared
+                        .claim_leadership(correlation_id, LeaderChangeReason::SuccessorTakeover)
+                        .await;
+                
+                    shared.persist().await;
+                
+                    // Broadcast takeover
+                    let coordinator = Message::Coordinator {
+                        leader_id: my_id,
+                        successor_id: None, // Will be updated as heartbeats arrive
+                        backup_successors: Vec::new(),
+                        term,
+                        correlation_id,
+                    };
+                
+                    for (_, conn) in peer_connections(peers).await {
+                        let _ = conn.send(&coordinator).await;
+                    }
+                
+                    info!("✅ Successfully became leader (Node {})", my_id);
+                
+                } else if let Some(succ_id) = successor_id {
+                    // I'm not the successor - notify successor and wait
+                    info!("📨 Notifying successor (Node {}) to take over", succ_id);
+                
+                    let takeover = Message::Takeover { from_id: my_id, correlation_id };
+                
+                    if let Some(succ_conn) = peer(peers, succ_id).await {
+                        let _ = succ_conn.send(&takeover).await;
+                    }
+                
+                    // Wait for new coordinator message
+                    tokio::time::sleep(timings.takeover_timeout).await;
+                
+                    // Check if we got a new leader
+                    if let Some(new_leader) = current_leader.read().await.filter(|&id| id != leader_id) {
+                        info!("✅ Node {} took over", new_leader);
+                        return;
+                    }
+                    let successor_alive = last_heartbeat.read().await.contains_key(&succ_id)
+                        && !detector.read().await.is_failed(succ_id, Instant::now());
+                
+                    if !successor_alive {
+                        // Successor also failed - the highest ranked node left leads
+                        warn!("⚠️  Successor also failed - holding an election");
+                        shared.run_election(timings, correlation_id, LeaderChangeReason::LastNodeStanding).await;
+                    }
+                
+                } else {
+                    // No successor known - the highest ranked node left leads
+                    warn!("⚠️  No successor known - holding an election");
+                    shared.run_election(timings, correlation_id, LeaderChangeReason::NoSuccessor).await;
+                }
+
+                // Give the new leader a heartbeat's time to be heard before
+                // failure detection resumes
+                tokio::time::sleep(timings.heartbeat_interval).await;
+            }
+            .instrument(logging::election_span(correlation_id))
+            .await;
+        }
+    }
+
+    /// Background task: Count leader changes and mirror our role into the gauges
+    async fn metrics_updater_task(mut leader_rx: watch::Receiver<LeaderState>, metrics: Arc<Metrics>) {
+        let mut last_leader = None;
+
+        loop {
+            let state = *leader_rx.borrow_and_update();
+            if state.leader != last_leader {
+                metrics.leader_changed(state.reason);
+                last_leader = state.leader;
+            }
+            metrics.set_is_leader(state.am_i_leader);
+
+            if leader_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn message_loop(&mut self) -> Result<()> {
+        let mut housekeeping = interval(Duration::from_secs(1));
+        let mut anti_entropy = interval(ANTI_ENTROPY_INTERVAL);
+
+        loop {
+            tokio::select! {
+                received = self.message_rx.recv() => match received {
+                    Some((from_id, message)) => {
+                        // Handle election traffic inside the election's span
+                        let span = message.correlation_id().map_or_else(Span::none, logging::election_span);
+                        self.handle_message_from(from_id, message).instrument(span).await
+                    }
+                    None => break,
+                },
+                Some((conn, request)) = self.client_rx.recv() => {
+                    self.handle_client_request(&conn, request).await
+                }
+                _ = housekeeping.tick() => {
+                    for image_id in self.reassembler.expire(CHUNK_TIMEOUT) {
+                        warn!("⌛ Discarding partial upload of image {}: chunks missing", image_id);
+                    }
+                    self.expire_writes().await;
+                    self.expire_fetch_grants().await;
+                    self.prune_silent_followers().await;
+                    self.enforce_quorum().await;
+                }
+                _ = anti_entropy.tick() => self.sync_images().await,
+                _ = self.shutdown_signal.notified() => {
+                    info!("🛑 Shutdown requested");
+                    self.shutdown().await;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hand leadership to the current successor without waiting for the failure timeout
+    pub async fn step_down(&mut self) {
+        if !*self.am_i_leader.read().await {
+            return;
+        }
+
+        let successor_id = *self.current_successor.read().await;
+        info!("👋 Stepping down as leader, handing off to {:?}", successor_id);
+
+        let resign = Message::Resign {
+            leader_id: self.my_id,
+            successor_id,
+        };
+
+        for (_, conn) in peer_connections(&self.peers).await {
+            let _ = conn.send(&resign).await;
+        }
+
+        {
+            let _view = self.view_lock.write().await;
+            *self.am_i_leader.write().await = false;
+            *self.current_leader.write().await = successor_id;
+            *self.current_successor.write().await = None;
+            self.alive_nodes.write().await.clear();
+        }
+        self.publish(LeaderChangeReason::Handoff).await;
+        self.save_state().await;
+    }
+
+    /// Announce that this node is leaving so peers drop it from membership
+    /// immediately instead of waiting for failure detection
+    pub async fn leave(&self) {
+        info!("🚪 Leaving the cluster");
+
+        let leave = Message::Leave { node_id: self.my_id };
+        for (_, conn) in peer_connections(&self.peers).await {
+            let _ = conn.send(&leave).await;
+        }
+    }
+
+    /// Operator-requested election: the leader steps down so its successor
+    /// takes over. A follower asked directly (`forward`) passes the request
+    /// to its leader; one relayed by another follower is dropped, so two
+    /// nodes that disagree on the leader can't bounce it between them.
+    /// Without a cluster key anyone who can reach a node could churn
+    /// leadership, so the request is refused.
+    async fn force_election(&mut self, requester_id: u32, forward: bool) {
+        if !self.network.is_authenticated() {
+            warn!("🚫 Refusing forced election requested by {}: the cluster has no cluster key", requester_id);
+            return;
+        }
+
+        if !*self.am_i_leader.read().await {
+            if !forward {
+                debug!("Ignoring forced election relayed to a non-leader");
+                return;
+            }
+            let leader = *self.current_leader.read().await;
+            let leader_conn = match leader {
+                Some(id) => peer(&self.peers, id).await.map(|conn| (id, conn)),
+                None => None,
+            };
+            match leader_conn {
+                Some((leader_id, conn)) => {
+                    info!("➡️  Forwarding forced election to leader Node {}", leader_id);
+                    let _ = conn.send(&Message::ForceElection { requester_id }).await;
+                }
+                _ => warn!("⚠️  No reachable leader - dropping forced election requested by {}", requester_id),
+            }
+            return;
+        }
+
+        if self.current_successor.read().await.is_none() {
+            warn!("⚠️  Forced election requested by {}, but no node could take over", requester_id);
+            return;
+        }
+
+        info!("🗳️  Forced election requested by {}", requester_id);
+        self.step_down().await;
+    }
+
+    /// Leave the cluster cleanly: stop the listener and background tasks, hand
+    /// off leadership if we hold it, tell peers we are leaving, persist our
+    /// state and close every connection. Peers thus see the departure at once
+    /// rather than after `failure_timeout`. Every send is awaited, so nothing
+    /// is left queued when the connections close.
+    pub async fn shutdown(&mut self) {
+        // Stop reconnecting and heartbeating before saying goodbye
+        self.tasks.abort_all();
+
+        self.step_down().await;
+        self.leave().await;
+        self.save_state().await;
+        flush(&self.durability).await;
+
+        let peers: Vec<PeerConnection> = self.peers.write().await.drain().map(|(_, conn)| conn).collect();
+        for conn in &peers {
+            conn.close().await;
+        }
+    }
+
+    /// The leadership state, taken at a single instant: every lock behind it
+    /// is read together, so a concurrent failover shows up entirely or not at all
+    pub async fn snapshot(&self) -> ClusterView {
+        ClusterView::capture(
+            self.my_id,
+            &self.view_lock,
+            &self.current_leader,
+            &self.current_successor,
+            &self.am_i_leader,
+            &self.current_term,
+            &self.alive_nodes,
+        )
+        .await
+    }
+
+    /// Whether the node is ready for traffic: it has finished discovering the
+    /// network and either leads or is connected to the leader it follows.
+    /// A node still in discovery, or cut off from its leader, is not.
+    pub async fn is_healthy(&self) -> bool {
+        is_ready(&self.discovered, &self.current_leader, &self.am_i_leader, &self.peers).await
+    }
+
+    /// State of this node's connection to each other known node, ordered by
+    /// ID: `Connected`, `Connecting` while the reconnect task dials it,
+    /// `Backoff` between failed dials, or `Dead` otherwise, as when a
+    /// connection has just dropped
+    pub async fn peer_states(&self) -> Vec<(u32, PeerState)> {
+        peer_states(self.my_id, &self.all_nodes, &self.peers, &self.dial_states).await
+    }
+
+    /// This node's view of the cluster, as reported to `status` queries
+    async fn status(&self) -> Message {
+        let view = self.snapshot().await;
+
+        // The leader tracks heartbeats; a follower only knows who it is connected to
+        let alive_nodes = if view.am_i_leader {
+            view.alive_nodes
+        } else {
+            let mut ids: Vec<u32> = self.peers.read().await.keys().copied().collect();
+            ids.push(self.my_id);
+            ids.sort_unstable();
+            ids
+        };
+
+        let mut peer_rtt_ms: Vec<(u32, f64)> = self
+            .peer_rtt
+            .read()
+            .await
+            .iter()
+            .map(|(&id, rtt)| (id, rtt.as_secs_f64() * 1000.0))
+            .collect();
+        peer_rtt_ms.sort_unstable_by_key(|&(id, _)| id);
+        let peer_traffic = peer_traffic(&self.peers).await;
+        let peer_states = self.peer_states().await;
+
+        Message::StatusResponse {
+            node_id: self.my_id,
+            is_leader: view.am_i_leader,
+            leader: view.leader,
+            successor: view.successor,
+            term: view.term,
+            alive_nodes,
+            leader_change_reason: self.leader_tx.borrow().reason,
+            peer_rtt_ms,
+            peer_traffic,
+            peer_states,
+        }
+    }
+
+    /// Serve a request from a tooling connection that is not a cluster member
+    async fn handle_client_request(&mut self, conn: &PeerConnection, request: Message) {
+        match request {
+            Message::StatusRequest {} => {
+                if let Err(e) = conn.send(&self.status().await).await {
+                    debug!("Failed to answer status request: {}", e);
+                }
+            }
+            Message::StoreImage { image_id, bytes, allowed_node_ids, watermark_owner, max_views, content_hash, .. } => {
+                if let Err(corrupt) = image::verify_hash(&image_id, &bytes, &content_hash) {
+                    self.reject_corrupt(conn, corrupt).await;
+                    return;
+                }
+                // The leader picks the generation; a client does not get to
+                let upload = Upload { image_id, bytes, allowed_node_ids, watermark_owner, max_views, generation: None };
+                info!("📥 Received upload of image {} ({} bytes)", upload.image_id, upload.bytes.len());
+                self.store_image(upload, WriteOrigin::Client(conn.clone())).await;
+            }
+            chunk @ Message::ImageChunk { .. } => {
+                match self.reassembler.insert(ChunkSender::Connection(conn.id()), chunk) {
+                    Some(Ok(mut upload)) => {
+                        upload.generation = None;
+                        info!("📥 Received upload of image {} ({} bytes)", upload.image_id, upload.bytes.len());
+                        self.store_image(upload, WriteOrigin::Client(conn.clone())).await;
+                    }
+                    Some(Err(corrupt)) => self.reject_corrupt(conn, corrupt).await,
+                    None => {}
+                }
+            }
+            Message::FetchImage { image_id, requester_id } => {
+                self.serve_fetch(conn, image_id, requester_id).await;
+            }
+            Message::FetchThumbnail { image_id, requester_id } => {
+                self.serve_thumbnail(conn, image_id, requester_id).await;
+            }
+            Message::DeleteImage { image_id, requester_id } => {
+                self.delete_image(conn, image_id, requester_id).await;
+            }
+            Message::ForceElection { requester_id } => self.force_election(requester_id, true).await,
+            Message::ListImages {} => {
+                let list = Message::ImageList { entries: self.image_list() };
+                if let Err(e) = conn.send(&list).await {
+                    debug!("Failed to answer image list request: {}", e);
+                }
+            }
+            Message::ListMembers {} => {
+                let list = Message::MemberList {
+                    nodes: self.all_nodes.read().await.clone(),
+                };
+                if let Err(e) = conn.send(&list).await {
+                    debug!("Failed to answer member list request: {}", e);
+                }
+            }
+            _ => debug!("Ignoring unexpected client request"),
+        }
+    }
+
+    /// Leader stores the image, replicates it and waits for a quorum of acks;
+    /// followers forward it to the leader and relay the outcome back to `origin`
+    async fn store_image(&mut self, mut upload: Upload, origin: WriteOrigin) {
+        if let Err(e) = ImageStore::validate_id(&upload.image_id) {
+            warn!("Rejecting image upload: {}", e);
+            self.report_write(&origin, upload.image_id, false, 0, 0, Some(e.to_string())).await;
+            return;
+        }
+        if !*self.am_i_leader.read().await {
+            let leader = *self.current_leader.read().await;
+            let leader_conn = match leader {
+                Some(id) => peer(&self.peers, id).await.map(|conn| (id, conn)),
+                None => None,
+            };
+            let forwarded = match leader_conn {
+                Some((leader_id, conn)) => {
+                    info!("➡️  Forwarding image {} to leader Node {}", upload.image_id, leader_id);
+                    match image::send_image(&conn, &upload).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            warn!("Failed to forward image {} to leader: {}", upload.image_id, e);
+                            false
+                        }
+                    }
+                }
+                None => {
+                    warn!("⚠️  No reachable leader - dropping image {}", upload.image_id);
+                    false
+                }
+            };
+
+            match (forwarded, origin) {
+                (true, WriteOrigin::Client(conn)) => {
+                    self.forwarded_uploads.insert(upload.image_id, (conn, Instant::now()));
+                }
+                (true, WriteOrigin::Peer(_)) => {}
+                (false, origin) => {
+                    let error = "no reachable leader".to_string();
+                    self.report_write(&origin, upload.image_id, false, 0, 0, Some(error)).await
+                }
+            }
+            return;
+        }
+
+        if let Err(error) = self.check_lease().await {
+            warn!("⛔ Refusing image {}: {}", upload.image_id, error);
+            self.report_write(&origin, upload.image_id, false, 0, 0, Some(error)).await;
+            return;
+        }
+
+        // The same bytes with the same options, still replicating under
+        // whatever ID, wait for that write instead of starting another one
+        let content_hash = image::content_hash(&upload.bytes);
+        let in_flight = self
+            .in_flight
+            .get(&content_hash)
+            .and_then(|image_id| self.pending_writes.get_mut(image_id))
+            .filter(|write| write.acl.same_options(&upload));
+        if let Some(write) = in_flight {
+            info!(
+                "♻️  Image {} is already being stored as {} - waiting for that write",
+                upload.image_id, write.acl.image_id
+            );
+            write.origins.push((origin, upload.image_id));
+            return;
+        }
+
+        // The same bytes with the same options are stored once, whatever ID
+        // they arrive under: a repeated upload gets the stored copy back
+        // instead of a second write, provided a majority of the alive nodes
+        // hold it. Otherwise it is written again, under its own ID.
+        let stored = self.stored_copy(&content_hash).filter(|acl| acl.same_options(&upload));
+        if let Some(acl) = stored {
+            let (followers, quorum) = self.write_quorum().await;
+            let replicas = acl
+                .replicas
+                .iter()
+                .filter(|id| **id == self.my_id || followers.contains(id))
+                .count();
+            if replicas >= quorum {
+                info!(
+                    "♻️  Image {} is already stored as {} - returning the existing copy",
+                    upload.image_id, acl.image_id
+                );
+                let result = Message::StoreResult {
+                    stored_as: (acl.image_id != upload.image_id).then_some(acl.image_id),
+                    image_id: upload.image_id,
+                    durable: true,
+                    acks: replicas as u32,
+                    quorum: quorum as u32,
+                    error: None,
+                };
+                self.send_result(&origin, &result).await;
+                return;
+            }
+            info!("Image {} is stored as {} on too few nodes - storing it again", upload.image_id, acl.image_id);
+        }
+
+        if self.in_flight.len() >= image::MAX_IN_FLIGHT_UPLOADS {
+            let error = format!("{} uploads already in flight", self.in_flight.len());
+            warn!("⛔ Refusing image {}: {}", upload.image_id, error);
+            self.report_write(&origin, upload.image_id, false, 0, 0, Some(error)).await;
+            return;
+        }
+
+        // A new upload of a deleted image starts the ID's next generation, so
+        // tombstones for the earlier ones leave it alone
+        let next = self.image_store.as_ref().map_or(Ok(0), |store| store.next_generation(&upload.image_id));
+        match next {
+            Ok(generation) => upload.generation = Some(generation),
+            Err(e) => {
+                warn!("Rejecting image {}: {:#}", upload.image_id, e);
+                self.report_write(&origin, upload.image_id, false, 0, 0, Some(format!("{:#}", e))).await;
+                return;
+            }
+        }
+
+        // Decoding, scaling and watermarking a large image would stall a
+        // runtime worker. Watermark once, on the leader, so every replica
+        // carries the same bytes.
+        let bytes = std::mem::take(&mut upload.bytes);
+        let owner = upload.watermark_owner;
+        let image_id = upload.image_id.clone();
+        let made = tokio::task::spawn_blocking(move || {
+            let thumbnail = image::make_thumbnail(&bytes)?;
+            let bytes = match owner {
+                Some(owner_node_id) => image::embed_watermark(&bytes, owner_node_id, &image_id)?,
+                None => bytes,
+            };
+            Ok((bytes, thumbnail))
+        })
+        .await
+        .context("Image preparation task failed")
+        .and_then(|made| made);
+        let thumbnail = match made {
+            Ok((bytes, thumbnail)) => {
+                upload.bytes = bytes;
+                thumbnail
+            }
+            Err(e) => {
+                warn!("Rejecting image {}: {:#}", upload.image_id, e);
+                self.report_write(&origin, upload.image_id, false, 0, 0, Some(format!("{:#}", e))).await;
+                return;
+            }
+        };
+        if let Some(owner_node_id) = owner {
+            info!("🔏 Watermarked image {} for owner Node {}", upload.image_id, owner_node_id);
+        }
+
+        let mut acks = HashSet::new();
+        if self.save_image(&upload) {
+            acks.insert(self.my_id);
+        }
+        self.save_thumbnail(&upload.image_id, &thumbnail);
+
+        let (followers, quorum) = self.write_quorum().await;
+        // Send to every follower at once, outside the peers lock and the message
+        // loop, so a slow follower holds up neither the others, nor connection
+        // changes, nor the acks and uploads that follow; its ack just comes later
+        let image_id = upload.image_id.clone();
+        let acl = upload.acl();
+        let followers_count = followers.len();
+        let conns: Vec<(u32, PeerConnection)> = {
+            let peers_lock = self.peers.read().await;
+            followers
+                .iter()
+                .filter_map(|id| peers_lock.get(id).map(|conn| (*id, conn.clone())))
+                .collect()
+        };
+        let store_thumbnail = Message::StoreThumbnail {
+            image_id: upload.image_id.clone(),
+            bytes: thumbnail,
+        };
+        tokio::spawn(async move {
+            let results = join_all(conns.iter().map(|(id, conn)| async {
+                let sent = match image::send_image(conn, &upload).await {
+                    Ok(()) => conn.send(&store_thumbnail).await,
+                    Err(e) => Err(e),
+                };
+                (*id, sent)
+            }))
+            .await;
+            let mut replicated = 0;
+            for (id, sent) in results {
+                match sent {
+                    Ok(()) => replicated += 1,
+                    Err(e) => debug!("Failed to replicate image {} to Node {}: {}", upload.image_id, id, e),
+                }
+            }
+            info!("📦 Replicated image {} to {}/{} followers", upload.image_id, replicated, followers_count);
+        });
+
+        // A different version of the same image may still be in flight; its
+        // client never hears back, as the newer write replaces it
+        if self.finish_write(&image_id).is_some() {
+            debug!("Image {} was overwritten before its quorum", image_id);
+        }
+        self.in_flight.insert(content_hash.clone(), image_id.clone());
+        self.pending_writes.insert(
+            image_id.clone(),
+            PendingWrite {
+                origins: vec![(origin, image_id.clone())],
+                acl,
+                content_hash,
+                acks,
+                quorum,
+                started: Instant::now(),
+            },
+        );
+        self.check_quorum(&image_id).await;
+    }
+
+    /// The alive followers a write goes to, and how many nodes, the leader
+    /// included, must hold it for it to be durable: a majority of those alive
+    async fn write_quorum(&self) -> (Vec<u32>, usize) {
+        let followers: Vec<u32> = self
+            .alive_nodes
+            .read()
+            .await
+            .iter()
+            .copied()
+            .filter(|&id| id != self.my_id)
+            .collect();
+        let alive = followers.len() + 1; // followers plus the leader itself
+        (followers, alive / 2 + 1)
+    }
+
+    /// Stop tracking a pending write, and drop it from the in-flight uploads
+    /// unless another write of the same bytes has taken its place there
+    fn finish_write(&mut self, image_id: &str) -> Option<PendingWrite> {
+        let write = self.pending_writes.remove(image_id)?;
+        if self.in_flight.get(&write.content_hash).is_some_and(|id| id == image_id) {
+            self.in_flight.remove(&write.content_hash);
+        }
+        Some(write)
+    }
+
+    /// Report a pending write as durable once a majority of alive nodes hold it
+    async fn check_quorum(&mut self, image_id: &str) {
+        let reached = self
+            .pending_writes
+            .get(image_id)
+            .is_some_and(|write| write.acks.len() >= write.quorum);
+        if !reached {
+            return;
+        }
+
+        if let Some(write) = self.finish_write(image_id) {
+            info!("✅ Image {} is durable ({}/{} acks)", image_id, write.acks.len(), write.quorum);
+            self.report_pending(image_id, &write, None).await;
+        }
+    }
+
+    /// Fail writes that missed their quorum and forget stale forwarded uploads
+    async fn expire_writes(&mut self) {
+        let expired: Vec<String> = self
+            .pending_writes
+            .iter()
+            .filter(|(_, write)| write.started.elapsed() > REPLICATION_TIMEOUT)
+            .map(|(image_id, _)| image_id.clone())
+            .collect();
+
+        for image_id in expired {
+            if let Some(write) = self.finish_write(&image_id) {
+                warn!(
+                    "⌛ Image {} missed its quorum ({}/{} acks)",
+                    image_id,
+                    write.acks.len(),
+                    write.quorum
+                );
+                let error = "replication quorum timed out".to_string();
+                self.report_pending(&image_id, &write, Some(error)).await;
+            }
+        }
+
+        // The leader answers within REPLICATION_TIMEOUT, so anything older was lost with it
+        self.forwarded_uploads
+            .retain(|_, (_, forwarded)| forwarded.elapsed() <= REPLICATION_TIMEOUT * 2);
+        self.checksum_resends
+            .retain(|_, (_, last_sent)| last_sent.elapsed() <= REPLICATION_TIMEOUT * 2);
+    }
+
+    /// Send the outcome of a write to whoever asked for it
+    async fn report_write(
+        &self,
+        origin: &WriteOrigin,
+        image_id: String,
+        durable: bool,
+        acks: usize,
+        quorum: usize,
+        error: Option<String>,
+    ) {
+        let result = Message::StoreResult {
+            image_id,
+            durable,
+            acks: acks as u32,
+            quorum: quorum as u32,
+            error,
+            stored_as: None,
+        };
+        self.send_result(origin, &result).await;
+    }
+
+    /// Send the outcome of a finished write to everyone waiting on it,
+    /// durable unless there is an `error`, naming `image_id` to those who
+    /// asked for another ID
+    async fn report_pending(&self, image_id: &str, write: &PendingWrite, error: Option<String>) {
+        for (origin, requested_id) in &write.origins {
+            let result = Message::StoreResult {
+                image_id: requested_id.clone(),
+                durable: error.is_none(),
+                acks: write.acks.len() as u32,
+                quorum: write.quorum as u32,
+                error: error.clone(),
+                stored_as: (requested_id != image_id).then(|| image_id.to_string()),
+            };
+            self.send_result(origin, &result).await;
+        }
+    }
+
+    /// Send a `StoreResult` to whoever asked for the write
+    async fn send_result(&self, origin: &WriteOrigin, result: &Message) {
+        let sent = match origin {
+            WriteOrigin::Client(conn) => conn.send(result).await,
+            WriteOrigin::Peer(node_id) => match peer(&self.peers, *node_id).await {
+                Some(conn) => conn.send(result).await,
+                None => Ok(()),
+            },
+        };
+        if let Err(e) = sent {
+            debug!("Failed to report write result: {}", e);
+        }
+    }
+
+    /// A complete image arrived from a peer: keep and acknowledge it if it is a
+    /// replica pushed by our leader, otherwise route it like an upload
+    async fn receive_image(&mut self, from_id: u32, mut upload: Upload) {
+        let from_leader = *self.current_leader.read().await == Some(from_id);
+        if !from_leader || *self.am_i_leader.read().await {
+            // Only our leader's replicas keep their generation; anything else
+            // is a new upload, numbered by the leader
+            upload.generation = None;
+            self.store_image(upload, WriteOrigin::Peer(from_id)).await;
+            return;
+        }
+
+        if ImageStore::validate_id(&upload.image_id).is_err() || !self.save_image(&upload) {
+            return;
+        }
+        self.save_replica(&upload.image_id, from_id);
+
+        let ack = Message::ReplicaAck {
+            image_id: upload.image_id,
+            node_id: self.my_id,
+        };
+        if let Some(conn) = peer(&self.peers, from_id).await {
+            let _ = conn.send(&ack).await;
+        }
+    }
+
+    /// The access entry of a stored image with the bytes an upload hashing to
+    /// `content_hash` would duplicate, under whatever ID it is stored
+    fn stored_copy(&self, content_hash: &str) -> Option<AclEntry> {
+        let store = self.image_store.as_ref()?;
+        match store.find_by_hash(content_hash) {
+            Ok(acl) => acl,
+            Err(e) => {
+                warn!("Failed to look for a stored copy of {}: {:#}", content_hash, e);
+                None
+            }
+        }
+    }
+
+    /// Write an image replica and its ACL to the local image directory, if
+    /// configured; returns whether the replica is now on disk
+    fn save_image(&self, upload: &Upload) -> bool {
+        let Some(store) = &self.image_store else {
+            warn!("No image directory configured - not storing image {}", upload.image_id);
+            return false;
+        };
+        if store.is_deleted(&upload.image_id, upload.generation.unwrap_or(0)) {
+            warn!("Not storing image {}: it was deleted", upload.image_id);
+            return false;
+        }
+
+        let mut acl = upload.acl();
+        acl.replicas.insert(self.my_id);
+        match store.save_with_acl(&acl, &upload.bytes) {
+            Ok(()) => {
+                info!("💾 Stored image {} ({} bytes)", upload.image_id, upload.bytes.len());
+                true
+            }
+            Err(e) => {
+                warn!("Failed to store image {}: {:#}", upload.image_id, e);
+                false
+            }
+        }
+    }
+
+    /// Apply a view count replicated by the leader to our copy of the ACL
+    fn save_view_count(&self, image_id: &str, requester_id: u32, views: u32) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
+
+        let saved = store.load_acl(image_id).and_then(|acl| match acl {
+            Some(mut acl) => {
+                acl.views.insert(requester_id, views);
+                store.save_acl(&acl)
+            }
+            None => Ok(()),
+        });
+        if let Err(e) = saved {
+            warn!("Failed to store view count for image {}: {:#}", image_id, e);
+        }
+    }
+
+    /// Note in our copy of the ACL that `node_id` holds a replica of the image
+    fn save_replica(&self, image_id: &str, node_id: u32) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
+
+        let saved = store.load_acl(image_id).and_then(|acl| match acl {
+            Some(mut acl) if !acl.replicas.contains(&node_id) => {
+                acl.replicas.insert(node_id);
+                store.save_acl(&acl)
+            }
+            _ => Ok(()),
+        });
+        if let Err(e) = saved {
+            warn!("Failed to record Node {} as holding image {}: {:#}", node_id, image_id, e);
+        }
+    }
+
+    /// Every image in the local store, for `ListImages`
+    fn image_list(&self) -> Vec<ImageMeta> {
+        let Some(store) = &self.image_store else {
+            return Vec::new();
+        };
+
+        let image_ids = match store.list() {
+            Ok(image_ids) => image_ids,
+            Err(e) => {
+                warn!("Failed to list images: {:#}", e);
+                return Vec::new();
+            }
+        };
+        image_ids
+            .iter()
+            .filter_map(|image_id| match store.meta(image_id) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    warn!("Failed to describe image {}: {:#}", image_id, e);
+                    None
+                }
+            })
+            .collect()
+    }
 
-        // Handle messages
-        self.message_loop().await;
+    /// Write an image's thumbnail to the local image directory, if configured
+    fn save_thumbnail(&self, image_id: &str, bytes: &[u8]) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
+        if store.generation(image_id).is_ok_and(|generation| store.is_deleted(image_id, generation)) {
+            return;
+        }
 
-        Ok(())
+        match store.save_thumbnail(image_id, bytes) {
+            Ok(()) => info!("🖼️  Stored thumbnail for image {} ({} bytes)", image_id, bytes.len()),
+            Err(e) => warn!("Failed to store thumbnail for image {}: {:#}", image_id, e),
+        }
     }
 
-    /// Discover the network and current leader
-    async fn discover_network(&mut self) -> Result<()> {
-        info!("🔍 Discovering network...");
+    /// Tell `node_id` which images we hold, and it pulls back any it lacks with
+    /// `PullImages`. Sent to the new leader after stepping down for a leader we
+    /// were partitioned from (images written while we led on our side are
+    /// missing from its side), and by the leader in answer to `SyncImages`.
+    /// Our tombstones go with it, so deletes it missed are applied too.
+    async fn offer_inventory(&self, node_id: u32) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
+        let (images, deleted) = match store.versions().and_then(|images| Ok((images, store.tombstones()?))) {
+            Ok(inventory) => inventory,
+            Err(e) => {
+                warn!("Failed to list images for Node {}: {:#}", node_id, e);
+                return;
+            }
+        };
 
-        let discovery_msg = Message::WhoIsLeader {
+        debug!("Offering {} image(s) and {} tombstone(s) to Node {}", images.len(), deleted.len(), node_id);
+        let inventory = Message::ImageInventory {
             node_id: self.my_id,
-            from_address: self.my_address.clone(),
+            images,
+            deleted,
+        };
+        if let Some(conn) = peer(&self.peers, node_id).await {
+            let _ = conn.send(&inventory).await;
+        }
+    }
+
+    /// Anti-entropy: replication is one-shot, so a follower that was down
+    /// during an upload asks the leader for its inventory and pulls what it missed
+    async fn sync_images(&self) {
+        if self.image_store.is_none() {
+            return;
+        }
+        if *self.am_i_leader.read().await {
+            return;
+        }
+        let Some(leader_id) = *self.current_leader.read().await else {
+            return;
         };
 
-        // Try to connect to all other nodes
-        let mut connected = false;
-        for node in &self.all_nodes {
-            if node.id == self.my_id {
+        let sync = Message::SyncImages { node_id: self.my_id };
+        if let Some(conn) = peer(&self.peers, leader_id).await {
+            if let Err(e) = conn.send(&sync).await {
+                debug!("Failed to ask leader Node {} for its images: {}", leader_id, e);
+            }
+        }
+    }
+
+    /// Delete our copies of images our leader `node_id` knows were deleted,
+    /// so a replica that missed a delete cannot hand the image back later.
+    /// Only the generations the tombstone covers go; a later upload of the
+    /// same bytes stays.
+    fn apply_tombstones(&self, node_id: u32, deleted: &[ImageVersion]) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
+
+        for ImageVersion { image_id, generation } in deleted {
+            if ImageStore::validate_id(image_id).is_err() || store.is_deleted(image_id, *generation) {
                 continue;
             }
+            match store.delete_through(image_id, *generation) {
+                Ok(()) => info!(
+                    "🗑️  Deleted image {} through generation {}, which Node {} knows was deleted",
+                    image_id, generation, node_id
+                ),
+                Err(e) => warn!("Failed to delete image {}: {:#}", image_id, e),
+            }
+        }
+    }
 
-            match self.network.connect_to_peer(&node.address).await {
-                Ok(conn) => {
-                    if let Err(e) = conn.send(&discovery_msg).await {
-                        warn!("Failed to send discovery to {}: {}", node.address, e);
-                    } else {
-                        self.peers.write().await.insert(node.id, conn.clone());
-                        connected = true;
-                        
-                        // Start read loop for outgoing connection
-                        let node_id = node.id;
-                        let tx = self.message_tx.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = Self::read_from_peer(node_id, conn, tx).await {
-                                debug!("Read loop ended for node {}: {}", node_id, e);
-                            }
-                        });
-                    }
-                }
+    /// Send a node the images it pulled, with their ACLs and thumbnails. They
+    /// are already watermarked, so the leader stores and replicates them as
+    /// they are, and a follower keeps them as replicas.
+    async fn push_images(&self, node_id: u32, image_ids: &[String]) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
+        let Some(conn) = peer(&self.peers, node_id).await else {
+            return;
+        };
+
+        for image_id in image_ids {
+            let upload = match Self::load_upload(store, image_id) {
+                Ok(Some(upload)) => upload,
+                Ok(None) => continue,
                 Err(e) => {
-                    debug!("Could not connect to node {}: {}", node.id, e);
+                    warn!("Failed to load image {} for Node {}: {:#}", image_id, node_id, e);
+                    continue;
                 }
+            };
+            if let Err(e) = image::send_image(&conn, &upload).await {
+                warn!("Failed to send image {} to Node {}: {}", image_id, node_id, e);
+                return;
             }
-        }
+            info!("🔀 Sent image {} to Node {}", image_id, node_id);
 
-        if !connected {
-            info!("📍 No other nodes found - I am the leader!");
-            *self.am_i_leader.write().await = true;
-            *self.current_leader.write().await = Some(self.my_id);
-            self.alive_nodes.write().await.insert(self.my_id);
-        } else {
-            // Wait for coordinator message
-            info!("⏳ Waiting for leader announcement...");
-            
-            match timeout(Duration::from_secs(5), self.wait_for_coordinator()).await {
-                Ok(_) => {
-                    let leader = self.current_leader.read().await;
-                    let successor = self.current_successor.read().await;
-                    info!("✅ Network discovered: Leader={:?}, Successor={:?}", leader, successor);
-                }
-                Err(_) => {
-                    warn!("⚠️  No coordinator received - starting election");
-                    self.become_leader().await;
-                }
+            if let Ok(Some(bytes)) = store.load_thumbnail(image_id) {
+                let thumbnail = Message::StoreThumbnail {
+                    image_id: image_id.clone(),
+                    bytes,
+                };
+                let _ = conn.send(&thumbnail).await;
             }
         }
+    }
 
-        Ok(())
+    /// A stored image, re-checked against its hash, with the upload options from its ACL
+    fn load_upload(store: &ImageStore, image_id: &str) -> Result<Option<Upload>> {
+        let loaded = store
+            .load_verified(image_id)
+            .and_then(|bytes| Ok(bytes.zip(store.load_acl(image_id)?)));
+        Ok(loaded?.map(|(bytes, acl)| Upload {
+            image_id: image_id.to_string(),
+            bytes,
+            allowed_node_ids: acl.allowed_node_ids,
+            watermark_owner: acl.owner,
+            max_views: acl.max_views,
+            generation: Some(acl.generation),
+        }))
     }
 
-    async fn wait_for_coordinator(&mut self) -> Result<()> {
-        while let Some((_, msg)) = self.message_rx.recv().await {
-            if matches!(msg, Message::Coordinator { .. }) {
-                self.handle_message(msg).await;
-                return Ok(());
-            }
+    /// Tell a client its upload arrived corrupted, so it sends it again
+    async fn reject_corrupt(&self, conn: &PeerConnection, corrupt: CorruptImage) {
+        warn!("❌ {} - asking for it again", corrupt);
+        let mismatch = Message::ChecksumMismatch {
+            image_id: corrupt.image_id,
+            node_id: self.my_id,
+        };
+        if let Err(e) = conn.send(&mismatch).await {
+            debug!("Failed to report checksum mismatch: {}", e);
         }
-        Ok(())
     }
 
-    /// Helper to read from a peer connection and forward messages to channel
-    async fn read_from_peer(
-        node_id: u32,
-        conn: PeerConnection,
-        tx: mpsc::UnboundedSender<(u32, Message)>,
-    ) -> Result<()> {
-        loop {
-            match conn.receive_one().await {
-                Ok(message) => {
-                    if tx.send((node_id, message)).is_err() {
-                        warn!("Channel closed for Node {}", node_id);
-                        break;
-                    }
-                }
-                Err(e) => {
-                    if e.to_string().contains("UnexpectedEof") {
-                        debug!("Connection closed: Node {}", node_id);
-                    } else {
-                        debug!("Read error from Node {}: {}", node_id, e);
-                    }
-                    break;
-                }
-            }
+    /// Tell the peer that sent us an image that it arrived corrupted
+    async fn reject_corrupt_from(&self, from_id: u32, corrupt: CorruptImage) {
+        if let Some(conn) = peer(&self.peers, from_id).await {
+            self.reject_corrupt(&conn, corrupt).await;
         }
-        Ok(())
     }
 
-    fn spawn_background_tasks(&self) {
-        // Heartbeat sender (if not leader)
-        let my_id = self.my_id;
-        let peers = self.peers.clone();
-        let am_i_leader = self.am_i_leader.clone();
-        let current_leader = self.current_leader.clone();
-        tokio::spawn(async move {
-            Self::heartbeat_sender_task(my_id, peers, am_i_leader, current_leader).await;
-        });
+    /// Send an image we hold again to a peer that received it corrupted, up
+    /// to `CHECKSUM_RETRIES` times before giving up on that peer
+    async fn resend_image(&mut self, node_id: u32, image_id: &str) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
 
-        // Coordinator broadcaster (if leader)
-        let my_id = self.my_id;
-        let peers = self.peers.clone();
-        let am_i_leader = self.am_i_leader.clone();
-        let current_successor = self.current_successor.clone();
-        tokio::spawn(async move {
-            Self::coordinator_broadcaster_task(my_id, peers, am_i_leader, current_successor).await;
-        });
+        let key = (image_id.to_string(), node_id);
+        let attempts = self.checksum_resends.get(&key).map_or(0, |(attempts, _)| *attempts);
+        if attempts >= CHECKSUM_RETRIES {
+            warn!("Giving up re-sending image {} to Node {} after {} attempts", image_id, node_id, attempts);
+            return;
+        }
+        self.checksum_resends.insert(key, (attempts + 1, Instant::now()));
 
-        // Leader updates successor based on heartbeats
-        let my_id = self.my_id;
-        let am_i_leader = self.am_i_leader.clone();
-        let alive_nodes = self.alive_nodes.clone();
-        let current_successor = self.current_successor.clone();
-        tokio::spawn(async move {
-            Self::successor_updater_task(my_id, am_i_leader, alive_nodes, current_successor).await;
-        });
+        let upload = match Self::load_upload(store, image_id) {
+            Ok(Some(upload)) => upload,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to load image {} for Node {}: {:#}", image_id, node_id, e);
+                return;
+            }
+        };
 
-        // Failure detector
-        let my_id = self.my_id;
-        let current_leader = self.current_leader.clone();
-        let current_successor = self.current_successor.clone();
-        let last_heartbeat = self.last_heartbeat.clone();
-        let peers = self.peers.clone();
-        let am_i_leader = self.am_i_leader.clone();
-        let alive_nodes = self.alive_nodes.clone();
-        tokio::spawn(async move {
-            Self::failure_detector_task(
-                my_id,
-                current_leader,
-                current_successor,
-                last_heartbeat,
-                peers,
-                am_i_leader,
-                alive_nodes,
-            )
-            .await;
-        });
+        info!("🔁 Re-sending image {} to Node {} (attempt {}/{})", image_id, node_id, attempts + 1, CHECKSUM_RETRIES);
+        if let Some(conn) = peer(&self.peers, node_id).await {
+            if let Err(e) = image::send_image(&conn, &upload).await {
+                warn!("Failed to re-send image {} to Node {}: {}", image_id, node_id, e);
+            }
+        }
     }
 
-    /// Background task: Send heartbeats to leader (if not leader)
-    async fn heartbeat_sender_task(
-        my_id: u32,
-        peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
-        am_i_leader: Arc<RwLock<bool>>,
-        current_leader: Arc<RwLock<Option<u32>>>,
-    ) {
-        let mut ticker = interval(HEARTBEAT_INTERVAL);
+    /// Leader authorizes a fetch and redirects it to the least-loaded follower
+    /// (or serves it itself when it has none); followers serve fetches the
+    /// leader granted them
+    async fn serve_fetch(&mut self, conn: &PeerConnection, image_id: String, requester_id: u32) {
+        if !*self.am_i_leader.read().await {
+            if self.fetch_grants.remove(&(image_id.clone(), requester_id)).is_some() {
+                self.send_stored_image(conn, &image_id, requester_id).await;
+                self.report_fetch_done(image_id, requester_id).await;
+                return;
+            }
 
-        loop {
-            ticker.tick().await;
+            // The grant may still be on its way; give it a moment before refusing
+            if self.current_leader.read().await.is_some() {
+                self.ungranted_fetches
+                    .insert((image_id, requester_id), (conn.clone(), Instant::now()));
+                return;
+            }
 
-            if *am_i_leader.read().await {
-                continue; // Leaders don't send heartbeats
+            let reason = self.not_leader_reason().await;
+            Self::deny_fetch(conn, image_id, requester_id, reason).await;
+            return;
+        }
+
+        let acl = match self.authorize_fetch(&image_id, requester_id) {
+            Ok(acl) => acl,
+            Err(reason) => {
+                Self::deny_fetch(conn, image_id, requester_id, reason).await;
+                return;
             }
+        };
+        self.record_view(acl, requester_id).await;
 
-            let leader = *current_leader.read().await;
-            if let Some(leader_id) = leader {
-                let heartbeat = Message::Heartbeat { node_id: my_id };
-                
-                let peers_lock = peers.read().await;
-                if let Some(leader_conn) = peers_lock.get(&leader_id) {
-                    if let Err(e) = leader_conn.send(&heartbeat).await {
-                        debug!("Failed to send heartbeat to leader {}: {}", leader_id, e);
-                    }
-                }
+        if let Some((serve_node_id, address)) = self.grant_fetch(&image_id, requester_id).await {
+            info!("↪️  Redirecting fetch of image {} by Node {} to Node {}", image_id, requester_id, serve_node_id);
+            let redirect = Message::FetchRedirect { image_id, serve_node_id, address };
+            if let Err(e) = conn.send(&redirect).await {
+                debug!("Failed to send fetch redirect: {}", e);
             }
+            return;
         }
+
+        self.send_stored_image(conn, &image_id, requester_id).await;
     }
 
-    /// Background task: Broadcast coordinator messages (if leader)
-    async fn coordinator_broadcaster_task(
-        my_id: u32,
-        peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
-        am_i_leader: Arc<RwLock<bool>>,
-        current_successor: Arc<RwLock<Option<u32>>>,
-    ) {
-        let mut ticker = interval(COORDINATOR_INTERVAL);
+    /// Leader serves thumbnails itself: they are small, so there is no
+    /// load to spread across followers
+    async fn serve_thumbnail(&self, conn: &PeerConnection, image_id: String, requester_id: u32) {
+        if !*self.am_i_leader.read().await {
+            let reason = self.not_leader_reason().await;
+            Self::deny_fetch(conn, image_id, requester_id, reason).await;
+            return;
+        }
 
-        loop {
-            ticker.tick().await;
+        if let Err(reason) = self.authorize_fetch(&image_id, requester_id) {
+            Self::deny_fetch(conn, image_id, requester_id, reason).await;
+            return;
+        }
 
-            if !*am_i_leader.read().await {
-                continue; // Only leaders broadcast
+        let bytes = match self.image_store.as_ref().map(|store| store.load_thumbnail(&image_id)) {
+            Some(Ok(Some(bytes))) => bytes,
+            Some(Err(e)) => {
+                warn!("Failed to load thumbnail for image {}: {:#}", image_id, e);
+                let reason = format!("Node {} could not read the thumbnail of image {}", self.my_id, image_id);
+                Self::deny_fetch(conn, image_id, requester_id, reason).await;
+                return;
             }
+            _ => {
+                let reason = format!("Node {} has no thumbnail for image {}", self.my_id, image_id);
+                Self::deny_fetch(conn, image_id, requester_id, reason).await;
+                return;
+            }
+        };
 
-            let successor = *current_successor.read().await;
-            let coordinator = Message::Coordinator {
-                leader_id: my_id,
-                successor_id: successor,
-            };
+        info!("📤 Serving thumbnail of image {} to Node {}", image_id, requester_id);
+        let thumbnail = Upload {
+            image_id,
+            bytes,
+            allowed_node_ids: Vec::new(),
+            watermark_owner: None,
+            max_views: None,
+            generation: None,
+        };
+        if let Err(e) = image::send_image(conn, &thumbnail).await {
+            debug!("Failed to send thumbnail of image {}: {}", thumbnail.image_id, e);
+        }
+    }
 
-            let peers_lock = peers.read().await;
-            for peer in peers_lock.values() {
-                let _ = peer.send(&coordinator).await;
-            }
+    /// Whether `requester_id` may fetch the image, returning its ACL; unknown
+    /// images are refused like forbidden ones so their existence is not revealed
+    fn authorize_fetch(&self, image_id: &str, requester_id: u32) -> std::result::Result<AclEntry, String> {
+        let not_allowed = || format!("Node {} may not fetch image {}", requester_id, image_id);
+        let Some(store) = &self.image_store else {
+            return Err(not_allowed());
+        };
+
+        let acl = store.load_acl(image_id).map_err(|e| {
+            warn!("Failed to load ACL for image {}: {:#}", image_id, e);
+            not_allowed()
+        })?;
+        let acl = match acl {
+            Some(acl) if acl.allows(requester_id) && store.contains(image_id) => acl,
+            _ => return Err(not_allowed()),
+        };
+        if acl.exhausted(requester_id) {
+            return Err(format!(
+                "Node {} has used all {} views of image {}",
+                requester_id,
+                acl.max_views.unwrap_or(0),
+                image_id
+            ));
         }
+        Ok(acl)
     }
 
-    /// Background task: Leader updates successor based on alive nodes
-    async fn successor_updater_task(
-        my_id: u32,
-        am_i_leader: Arc<RwLock<bool>>,
-        alive_nodes: Arc<RwLock<HashSet<u32>>>,
-        current_successor: Arc<RwLock<Option<u32>>>,
-    ) {
-        let mut ticker = interval(Duration::from_secs(1));
+    /// Count a fetch against a view-limited image and replicate the new count
+    /// to every alive follower, so the quota survives a leader failover
+    async fn record_view(&self, mut acl: AclEntry, requester_id: u32) {
+        let Some(store) = &self.image_store else {
+            return;
+        };
+        if acl.max_views.is_none() {
+            return;
+        }
 
-        loop {
-            ticker.tick().await;
+        let views = acl.views.get(&requester_id).copied().unwrap_or(0) + 1;
+        acl.views.insert(requester_id, views);
+        if let Err(e) = store.save_acl(&acl) {
+            warn!("Failed to record view of image {}: {:#}", acl.image_id, e);
+        }
+        info!(
+            "👁️  Node {} has viewed image {} {}/{} times",
+            requester_id,
+            acl.image_id,
+            views,
+            acl.max_views.unwrap_or(0)
+        );
 
-            if !*am_i_leader.read().await {
+        let count = Message::ViewCount {
+            image_id: acl.image_id,
+            requester_id,
+            views,
+        };
+        let alive = self.alive_nodes.read().await.clone();
+        for (id, conn) in peer_connections(&self.peers).await {
+            if id == self.my_id || !alive.contains(&id) {
                 continue;
             }
+            if let Err(e) = conn.send(&count).await {
+                debug!("Failed to replicate view count to Node {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Pick the reachable follower with the fewest in-flight fetches (then the
+    /// fewest fetches overall, so idle followers take turns) and grant it this fetch
+    async fn grant_fetch(&mut self, image_id: &str, requester_id: u32) -> Option<(u32, String)> {
+        let alive = self.alive_nodes.read().await.clone();
+        let all_nodes = self.all_nodes.read().await.clone();
 
-            // Calculate successor = max(alive_nodes - self)
-            let alive = alive_nodes.read().await;
-            let new_successor = alive
+        let (serve_node_id, address, conn) = {
+            let peers_lock = self.peers.read().await;
+            all_nodes
                 .iter()
-                .filter(|&&id| id != my_id)
-                .max()
-                .copied();
+                .filter(|node| node.id != self.my_id && alive.contains(&node.id))
+                .filter_map(|node| {
+                    let conn = peers_lock.get(&node.id)?.clone();
+                    Some((node.id, node.advertised_address().to_string(), conn))
+                })
+                .min_by_key(|(id, _, _)| {
+                    let load = self.fetch_load.get(id).copied().unwrap_or_default();
+                    (load.in_flight, load.total, *id)
+                })?
+        };
+
+        let grant = Message::FetchGrant {
+            image_id: image_id.to_string(),
+            requester_id,
+        };
+        if let Err(e) = conn.send(&grant).await {
+            debug!("Failed to grant fetch to Node {}: {}", serve_node_id, e);
+            return None;
+        }
+
+        let load = self.fetch_load.entry(serve_node_id).or_default();
+        load.in_flight += 1;
+        load.total += 1;
+        Some((serve_node_id, address))
+    }
 
-            let mut successor = current_successor.write().await;
-            if *successor != new_successor {
-                info!("📋 Successor updated: {:?} → {:?}", *successor, new_successor);
-                *successor = new_successor;
+    /// Stream a locally stored image to a client
+    async fn send_stored_image(&self, conn: &PeerConnection, image_id: &str, requester_id: u32) {
+        let bytes = match self.image_store.as_ref().map(|store| store.load_verified(image_id)) {
+            Some(Ok(Some(bytes))) => bytes,
+            Some(Err(e)) => {
+                warn!("Failed to load image {}: {:#}", image_id, e);
+                let reason = format!("Node {} could not read image {}", self.my_id, image_id);
+                Self::deny_fetch(conn, image_id.to_string(), requester_id, reason).await;
+                return;
             }
+            _ => {
+                let reason = format!("Node {} does not hold image {}", self.my_id, image_id);
+                Self::deny_fetch(conn, image_id.to_string(), requester_id, reason).await;
+                return;
+            }
+        };
+
+        info!("📤 Serving image {} to Node {}", image_id, requester_id);
+        let upload = Upload {
+            image_id: image_id.to_string(),
+            bytes,
+            allowed_node_ids: Vec::new(),
+            watermark_owner: None,
+            max_views: None,
+            generation: None,
+        };
+        if let Err(e) = image::send_image(conn, &upload).await {
+            debug!("Failed to send image {}: {}", image_id, e);
         }
     }
 
-    /// Background task: Detect leader failures
-    async fn failure_detector_task(
-        my_id: u32,
-        current_leader: Arc<RwLock<Option<u32>>>,
-        current_successor: Arc<RwLock<Option<u32>>>,
-        last_heartbeat: Arc<RwLock<HashMap<u32, Instant>>>,
-        peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
-        am_i_leader: Arc<RwLock<bool>>,
-        alive_nodes: Arc<RwLock<HashSet<u32>>>,
-    ) {
-        let mut ticker = interval(Duration::from_secs(1));
+    async fn deny_fetch(conn: &PeerConnection, image_id: String, requester_id: u32, reason: String) {
+        info!("🚫 Denied fetch of image {} by Node {}: {}", image_id, requester_id, reason);
+        let denied = Message::AccessDenied { image_id, reason };
+        if let Err(e) = conn.send(&denied).await {
+            debug!("Failed to send access denial: {}", e);
+        }
+    }
 
-        loop {
-            ticker.tick().await;
+    /// Leader deletes an image for its owner and relays the delete to every
+    /// alive follower; a follower that is down catches up through anti-entropy
+    async fn delete_image(&mut self, conn: &PeerConnection, image_id: String, requester_id: u32) {
+        if !*self.am_i_leader.read().await {
+            let reason = self.not_leader_reason().await;
+            Self::deny_delete(conn, image_id, requester_id, reason).await;
+            return;
+        }
+        if let Err(reason) = self.check_lease().await {
+            warn!("⛔ Refusing to delete image {}: {}", image_id, reason);
+            Self::deny_delete(conn, image_id, requester_id, reason).await;
+            return;
+        }
+        if let Err(reason) = self.authorize_delete(&image_id, requester_id) {
+            Self::deny_delete(conn, image_id, requester_id, reason).await;
+            return;
+        }
+        if !self.remove_image(&image_id, requester_id) {
+            let reason = format!("Node {} could not delete image {}", self.my_id, image_id);
+            Self::deny_delete(conn, image_id, requester_id, reason).await;
+            return;
+        }
 
-            if *am_i_leader.read().await {
-                continue; // Leaders don't check for failures
+        let delete = Message::DeleteImage {
+            image_id: image_id.clone(),
+            requester_id,
+        };
+        let alive = self.alive_nodes.read().await.clone();
+        for (id, peer) in peer_connections(&self.peers).await {
+            if id == self.my_id || !alive.contains(&id) {
+                continue;
             }
+            if let Err(e) = peer.send(&delete).await {
+                debug!("Failed to relay delete of image {} to Node {}: {}", image_id, id, e);
+            }
+        }
 
-            let leader_id = match *current_leader.read().await {
-                Some(id) => id,
-                None => continue,
-            };
+        if let Err(e) = conn.send(&Message::ImageDeleted { image_id }).await {
+            debug!("Failed to confirm delete: {}", e);
+        }
+    }
 
-            // Check if leader has timed out
-            let heartbeats = last_heartbeat.read().await;
-            let leader_timeout = heartbeats
-                .get(&leader_id)
-                .map(|t| t.elapsed() > FAILURE_TIMEOUT)
-                .unwrap_or(false);
+    /// Whether `requester_id` may delete the image: the owner named by its
+    /// watermark, or anyone on the ACL of an image without one. Unknown images
+    /// are refused like forbidden ones, as for fetches.
+    fn authorize_delete(&self, image_id: &str, requester_id: u32) -> std::result::Result<(), String> {
+        let not_allowed = || format!("Node {} may not delete image {}", requester_id, image_id);
+        let Some(store) = &self.image_store else {
+            return Err(not_allowed());
+        };
 
-            if !leader_timeout {
-                continue;
+        let stored = store
+            .meta(image_id)
+            .and_then(|meta| Ok(meta.zip(store.load_acl(image_id)?)))
+            .map_err(|e| {
+                warn!("Failed to load image {}: {:#}", image_id, e);
+                not_allowed()
+            })?;
+        let allowed = stored.is_some_and(|(meta, acl)| match meta.owner {
+            Some(owner) => owner == requester_id,
+            None => acl.allows(requester_id),
+        });
+        if !allowed {
+            return Err(not_allowed());
+        }
+        Ok(())
+    }
+
+    /// Delete our copy of an image, leaving its tombstone; returns whether it is gone
+    fn remove_image(&self, image_id: &str, requester_id: u32) -> bool {
+        let Some(store) = &self.image_store else {
+            return false;
+        };
+
+        match store.delete(image_id) {
+            Ok(()) => {
+                info!("🗑️  Deleted image {} for Node {}", image_id, requester_id);
+                true
             }
+            Err(e) => {
+                warn!("Failed to delete image {}: {:#}", image_id, e);
+                false
+            }
+        }
+    }
 
-            // Leader failed!
-            warn!("⚠️  LEADER FAILURE DETECTED: Node {} timeout", leader_id);
+    async fn deny_delete(conn: &PeerConnection, image_id: String, requester_id: u32, reason: String) {
+        info!("🚫 Denied delete of image {} by Node {}: {}", image_id, requester_id, reason);
+        let denied = Message::AccessDenied { image_id, reason };
+        if let Err(e) = conn.send(&denied).await {
+            debug!("Failed to send access denial: {}", e);
+        }
+    }
 
-            let successor_id = *current_successor.read().await;
+    /// Tell the leader a granted fetch is finished so it stops counting it as in flight
+    async fn report_fetch_done(&self, image_id: String, requester_id: u32) {
+        let Some(leader_id) = *self.current_leader.read().await else {
+            return;
+        };
+        let done = Message::FetchDone {
+            image_id,
+            requester_id,
+            node_id: self.my_id,
+        };
+        if let Some(conn) = peer(&self.peers, leader_id).await {
+            let _ = conn.send(&done).await;
+        }
+    }
 
-            if successor_id == Some(my_id) {
-                // I am the successor - take over immediately
-                info!("👑 I am successor - TAKING OVER as leader!");
-                
-                *am_i_leader.write().await = true;
-                *current_leader.write().await = Some(my_id);
-                
-                // Reset alive nodes (I'm alive, at least)
-                let mut alive = alive_nodes.write().await;
-                alive.clear();
-                alive.insert(my_id);
-                
-                // Broadcast takeover
-                let coordinator = Message::Coordinator {
-                    leader_id: my_id,
-                    successor_id: None, // Will be updated as heartbeats arrive
-                };
-                
-                let peers_lock = peers.read().await;
-                for peer in peers_lock.values() {
-                    let _ = peer.send(&coordinator).await;
-                }
-                
-                info!("✅ Successfully became leader (Node {})", my_id);
-                
-            } else if let Some(succ_id) = successor_id {
-                // I'm not the successor - notify successor and wait
-                info!("📨 Notifying successor (Node {}) to take over", succ_id);
-                
-                let takeover = Message::Takeover { from_id: my_id };
-                
-                let peers_lock = peers.read().await;
-                if let Some(succ_conn) = peers_lock.get(&succ_id) {
-                    let _ = succ_conn.send(&takeover).await;
-                }
-                
-                // Wait for new coordinator message
-                tokio::time::sleep(TAKEOVER_TIMEOUT).await;
-                
-                // Check if we got a new leader
-                let heartbeats = last_heartbeat.read().await;
-                let successor_alive = heartbeats
-                    .get(&succ_id)
-                    .map(|t| t.elapsed() < FAILURE_TIMEOUT)
-                    .unwrap_or(false);
-                
-                if !successor_alive {
-                    // Successor also failed - I'm the only one left
-                    warn!("⚠️  Successor also failed - I'm taking over!");
-                    
-                    *am_i_leader.write().await = true;
-                    *current_leader.write().await = Some(my_id);
-                    
-                    let mut alive = alive_nodes.write().await;
-                    alive.clear();
-                    alive.insert(my_id);
-                    
-                    let coordinator = Message::Coordinator {
-                        leader_id: my_id,
-                        successor_id: None,
-                    };
-                    
-                    for peer in peers_lock.values() {
-                        let _ = peer.send(&coordinator).await;
-                    }
-                    
-                    info!("✅ Successfully became leader (last node standing)");
-                }
-                
-            } else {
-                // No successor known - become leader
-                warn!("⚠️  No successor known - becoming leader");
-                
-                *am_i_leader.write().await = true;
-                *current_leader.write().await = Some(my_id);
-                
-                let mut alive = alive_nodes.write().await;
-                alive.clear();
-                alive.insert(my_id);
-            }
+    async fn not_leader_reason(&self) -> String {
+        match *self.current_leader.read().await {
+            Some(leader_id) => format!("Node {} is not the leader; ask Node {}", self.my_id, leader_id),
+            None => format!("Node {} is not the leader and knows of none", self.my_id),
+        }
+    }
 
-            // Reset failure detection
-            tokio::time::sleep(Duration::from_secs(2)).await;
+    /// A grant arrived from the leader: serve the fetch now if its client got
+    /// here first, otherwise hold the grant until the client shows up
+    async fn accept_fetch_grant(&mut self, image_id: String, requester_id: u32) {
+        match self.ungranted_fetches.remove(&(image_id.clone(), requester_id)) {
+            Some((conn, _)) => {
+                self.send_stored_image(&conn, &image_id, requester_id).await;
+                self.report_fetch_done(image_id, requester_id).await;
+            }
+            None => {
+                self.fetch_grants.insert((image_id, requester_id), Instant::now());
+            }
         }
     }
 
-    async fn message_loop(&mut self) {
-        while let Some((from_id, message)) = self.message_rx.recv().await {
-            self.handle_message_from(from_id, message).await;
+    /// Drop grants whose client never showed up, releasing them at the leader,
+    /// and refuse fetches whose grant never came
+    async fn expire_fetch_grants(&mut self) {
+        let refused: Vec<(String, u32)> = self
+            .ungranted_fetches
+            .iter()
+            .filter(|(_, (_, arrived))| arrived.elapsed() > FETCH_GRANT_WAIT)
+            .map(|(fetch, _)| fetch.clone())
+            .collect();
+
+        for (image_id, requester_id) in refused {
+            if let Some((conn, _)) = self.ungranted_fetches.remove(&(image_id.clone(), requester_id)) {
+                let reason = self.not_leader_reason().await;
+                Self::deny_fetch(&conn, image_id, requester_id, reason).await;
+            }
+        }
+
+        let expired: Vec<(String, u32)> = self
+            .fetch_grants
+            .iter()
+            .filter(|(_, granted)| granted.elapsed() > FETCH_GRANT_TIMEOUT)
+            .map(|(grant, _)| grant.clone())
+            .collect();
+
+        for (image_id, requester_id) in expired {
+            self.fetch_grants.remove(&(image_id.clone(), requester_id));
+            debug!("Fetch grant for image {} by Node {} expired", image_id, requester_id);
+            self.report_fetch_done(image_id, requester_id).await;
         }
     }
 
     async fn handle_message_from(&mut self, from_id: u32, message: Message) {
         // Update last heartbeat time for any message
         self.last_heartbeat.write().await.insert(from_id, Instant::now());
+
+        // Only periodic liveness messages feed the detector: bulk traffic such
+        // as image chunks arrives in bursts that would skew arrival statistics
+        if matches!(message, Message::Heartbeat { .. } | Message::Coordinator { .. }) {
+            self.detector.write().await.heartbeat(from_id, Instant::now());
+        }
         
-        self.handle_message(message).await;
+        self.handle_message(from_id, message).await;
     }
 
-    async fn handle_message(&mut self, message: Message) {
+    async fn handle_message(&mut self, from_id: u32, message: Message) {
         match message {
             Message::WhoIsLeader { node_id, from_address } => {
                 info!("📩 Received WhoIsLeader from Node {}", node_id);
                 
                 // Connect back if not already connected
-                if !self.peers.read().await.contains_key(&node_id) {
-                    if let Ok(conn) = self.network.connect_to_peer(&from_address).await {
-                        self.peers.write().await.insert(node_id, conn);
+                let connect_back = self.get_or_connect(node_id, &from_address).await;
+                match connect_back {
+                    Ok((conn, true)) => {
+                        Self::spawn_peer_reader(node_id, conn, self.peers.clone(), self.message_tx.clone());
                     }
+                    Ok((_, false)) => {}
+                    Err(e) => debug!("Could not connect back to Node {}: {}", node_id, e),
                 }
                 
                 // All nodes respond with their known leader info (not just the leader)
                 let am_leader = *self.am_i_leader.read().await;
                 let known_leader = *self.current_leader.read().await;
                 let known_successor = *self.current_successor.read().await;
+                let known_term = *self.current_term.read().await;
                 
                 // Send coordinator info we know about
                 if let Some(leader_id) = known_leader {
                     let coordinator = Message::Coordinator {
                         leader_id,
                         successor_id: known_successor,
+                        backup_successors: self.backup_successors.read().await.clone(),
+                        term: known_term,
+                        correlation_id: *self.election_id.read().await,
                     };
                     
-                    if let Some(conn) = self.peers.read().await.get(&node_id) {
+                    if let Some(conn) = peer(&self.peers, node_id).await {
                         let _ = conn.send(&coordinator).await;
                         info!("📤 Sent coordinator info to Node {}: Leader={}, Successor={:?}", 
                               node_id, leader_id, known_successor);
@@ -528,32 +3585,327 @@ impl Node {
                 
                 // If I'm the leader, also add this node to alive set
                 if am_leader {
-                    self.alive_nodes.write().await.insert(node_id);
+                    self.mark_alive(node_id).await;
                 }
             }
 
-            Message::Coordinator { leader_id, successor_id } => {
+            Message::Coordinator { leader_id, successor_id, backup_successors, term, correlation_id } => {
+                // The leader rebroadcasts every `coordinator_interval`; a repeat
+                // of what we already believe changes nothing (liveness was
+                // recorded by `handle_message_from`)
+                if *self.current_leader.read().await == Some(leader_id)
+                    && *self.current_successor.read().await == successor_id
+                    && *self.backup_successors.read().await == backup_successors
+                    && *self.current_term.read().await == term
+                    && *self.am_i_leader.read().await == (leader_id == self.my_id)
+                    && (correlation_id == 0 || *self.election_id.read().await == correlation_id)
+                {
+                    return;
+                }
+
+                let mut stepped_down = false;
+                
+                // Split brain (e.g. a healed partition): the higher (term, rank) keeps leadership
+                if leader_id != self.my_id && *self.am_i_leader.read().await {
+                    let my_term = *self.current_term.read().await;
+                    let (their_rank, my_rank) = {
+                        let all_nodes = self.all_nodes.read().await;
+                        (rank(&all_nodes, leader_id), rank(&all_nodes, self.my_id))
+                    };
+                    if (term, their_rank) < (my_term, my_rank) {
+                        warn!("⚔️  Competing leader Node {} (term {}) outranked by us (term {})",
+                              leader_id, term, my_term);
+                        
+                        // Remind the rival who leads so it steps down promptly
+                        let coordinator = Message::Coordinator {
+                            leader_id: self.my_id,
+                            successor_id: *self.current_successor.read().await,
+                            backup_successors: self.backup_successors.read().await.clone(),
+                            term: my_term,
+                            correlation_id: *self.election_id.read().await,
+                        };
+                        if let Some(conn) = peer(&self.peers, leader_id).await {
+                            let _ = conn.send(&coordinator).await;
+                        }
+                        return;
+                    }
+                    
+                    warn!("⚔️  Competing leader Node {} (term {}) outranks us - stepping down",
+                          leader_id, term);
+                    stepped_down = true;
+                }
+                
+                let view = self.view_lock.write().await;
+                
+                // Ignore coordinators from leaders deposed by a newer election
+                let term_changed = {
+                    let mut current_term = self.current_term.write().await;
+                    if term < *current_term {
+                        debug!("Ignoring stale coordinator from Node {} (term {} < {})",
+                               leader_id, term, *current_term);
+                        return;
+                    }
+                    let term_changed = term != *current_term;
+                    *current_term = term;
+                    term_changed
+                };
+                
                 let old_leader = *self.current_leader.read().await;
+                let old_successor = *self.current_successor.read().await;
                 
-                if old_leader != Some(leader_id) {
-                    info!("👑 Leader is Node {}, Successor: {:?}", leader_id, successor_id);
+                // Within a term, back only the leader the split-brain rule
+                // keeps: a follower never moves to a lower ranked rival, so
+                // heartbeats do not flip between two leaders of one term
+                if let Some(current) = old_leader.filter(|&id| !term_changed && id != leader_id && id != self.my_id) {
+                    let all_nodes = self.all_nodes.read().await;
+                    if rank(&all_nodes, leader_id) < rank(&all_nodes, current) {
+                        debug!("Ignoring coordinator from Node {}: we follow Node {} in term {}",
+                               leader_id, current, term);
+                        return;
+                    }
                 }
                 
                 *self.current_leader.write().await = Some(leader_id);
                 *self.current_successor.write().await = successor_id;
+                *self.backup_successors.write().await = backup_successors.clone();
                 *self.am_i_leader.write().await = leader_id == self.my_id;
+                if correlation_id != 0 {
+                    *self.election_id.write().await = correlation_id;
+                }
+                if stepped_down {
+                    self.alive_nodes.write().await.clear();
+                }
+                drop(view);
+                
+                if old_leader != Some(leader_id) {
+                    info!("👑 Leader is Node {}, Successor: {:?}, then {:?}, Term: {}",
+                          leader_id, successor_id, backup_successors, term);
+
+                    // A leader whose config doesn't mark us as an observer
+                    // would otherwise pick us as its successor
+                    if self.observer {
+                        let join = Message::Join {
+                            node_id: self.my_id,
+                            address: self.my_address.clone(),
+                            priority: rank(&self.all_nodes.read().await, self.my_id).priority,
+                            observer: true,
+                        };
+                        if let Some(conn) = peer(&self.peers, leader_id).await {
+                            let _ = conn.send(&join).await;
+                        }
+                    }
+                }
+
+                let reason = if stepped_down {
+                    LeaderChangeReason::Outranked
+                } else {
+                    LeaderChangeReason::Announced
+                };
+                self.publish(reason).await;
+                
+                if old_leader != Some(leader_id) || old_successor != successor_id || term_changed {
+                    self.save_state().await;
+                }
+                
+                if stepped_down {
+                    info!("🔀 Offering our images to new leader Node {}", leader_id);
+                    self.offer_inventory(leader_id).await;
+                }
             }
 
-            Message::Heartbeat { node_id } => {
+            Message::Heartbeat { node_id, term, sent_at_us, rtt_us } => {
                 debug!("💓 Heartbeat from Node {}", node_id);
                 
-                // Leader tracks alive nodes
+                // Leader tracks alive nodes, except those that have moved on to
+                // a newer term: they follow whoever deposed us
                 if *self.am_i_leader.read().await {
-                    self.alive_nodes.write().await.insert(node_id);
+                    let my_term = *self.current_term.read().await;
+                    if term <= my_term {
+                        self.mark_alive(node_id).await;
+                    } else {
+                        debug!("Node {} is on term {}, newer than ours", node_id, term);
+                    }
+                    if term == my_term {
+                        self.renew_lease(node_id, term);
+                    }
+                }
+                
+                // Echo the stamp so the sender can time the round trip, and
+                // keep the time it measured to us on its last round
+                let ack = Message::HeartbeatAck { node_id: self.my_id, sent_at_us };
+                if let Some(conn) = peer(&self.peers, node_id).await {
+                    let _ = conn.send(&ack).await;
+                }
+                if let Some(rtt_us) = rtt_us {
+                    let rtt = Duration::from_micros(rtt_us);
+                    self.peer_rtt.write().await.insert(node_id, rtt);
+                    self.metrics.set_peer_rtt(node_id, rtt.as_secs_f64());
+                }
+            }
+
+            Message::HeartbeatAck { node_id, sent_at_us } => {
+                let Some(sample) = Instant::now().checked_duration_since(self.epoch + Duration::from_micros(sent_at_us))
+                else {
+                    return;
+                };
+                
+                // Smooth like TCP's SRTT: each sample moves the average by an eighth
+                let rtt = match self.peer_rtt.read().await.get(&node_id) {
+                    Some(&average) => average.mul_f64(0.875) + sample.mul_f64(0.125),
+                    None => sample,
+                };
+                debug!("⏱️  Round trip to Node {}: {:?} (average {:?})", node_id, sample, rtt);
+                self.peer_rtt.write().await.insert(node_id, rtt);
+                self.metrics.set_peer_rtt(node_id, rtt.as_secs_f64());
+            }
+
+            Message::Join { node_id, address, mut priority, observer } => {
+                // A node speaks only for itself, or our leader relays it
+                let relayed = from_id != self.my_id && *self.current_leader.read().await == Some(from_id);
+                if node_id != from_id && !relayed {
+                    warn!("⛔ Ignoring Join for Node {} sent by Node {}", node_id, from_id);
+                    return;
+                }
+                
+                let is_new = {
+                    let mut all_nodes = self.all_nodes.write().await;
+                    if let Some(node) = all_nodes.iter_mut().find(|n| n.id == node_id) {
+                        // A known node keeps the rank we have for it, so no
+                        // Join can lift one above the others; it may only
+                        // step back to observing
+                        if observer && !node.observer {
+                            info!("👀 Node {} observes only", node_id);
+                            node.observer = true;
+                        }
+                        false
+                    } else {
+                        // A joining node tells us only where it can be
+                        // reached; a stranger's word can't lift it above
+                        // the lowest priority we know
+                        let lowest = all_nodes.iter().map(|node| node.priority).min().unwrap_or(0);
+                        if priority > lowest {
+                            info!("⬇️  Node {} asked for priority {} - joining at {}", node_id, priority, lowest);
+                            priority = lowest;
+                        }
+                        all_nodes.push(NodeInfo {
+                            id: node_id,
+                            bind_address: address.clone(),
+                            advertise_address: None,
+                            priority,
+                            observer,
+                        });
+                        true
+                    }
+                };
+                
+                if is_new {
+                    info!("➕ Node {} joined at {}", node_id, address);
+                }
+                
+                if !*self.am_i_leader.read().await {
+                    return;
+                }
+                
+                self.mark_alive(node_id).await;
+                
+                // Tell the newcomer who leads
+                let coordinator = Message::Coordinator {
+                    leader_id: self.my_id,
+                    successor_id: *self.current_successor.read().await,
+                    backup_successors: self.backup_successors.read().await.clone(),
+                    term: *self.current_term.read().await,
+                    correlation_id: *self.election_id.read().await,
+                };
+                let conns = peer_connections(&self.peers).await;
+                if let Some((_, conn)) = conns.iter().find(|(id, _)| *id == node_id) {
+                    let _ = conn.send(&coordinator).await;
+                }
+                
+                // Gossip the new member so every peer can dial it
+                if is_new {
+                    let join = Message::Join { node_id, address, priority, observer };
+                    for (peer_id, peer) in conns {
+                        if peer_id != node_id {
+                            let _ = peer.send(&join).await;
+                        }
+                    }
+                }
+            }
+
+            Message::Membership { leader_id, nodes } => {
+                // Only our leader's view is authoritative
+                if *self.current_leader.read().await != Some(leader_id) || leader_id == self.my_id {
+                    return;
+                }
+                
+                let mut all_nodes = self.all_nodes.write().await;
+                for node in nodes {
+                    if node.id == self.my_id {
+                        continue;
+                    }
+                    if let Some(known) = all_nodes.iter_mut().find(|n| n.id == node.id) {
+                        // Keep our own address for it, but take the role the leader learned
+                        known.priority = node.priority;
+                        known.observer = node.observer;
+                    } else {
+                        info!("➕ Learned Node {} at {} from leader", node.id, node.advertised_address());
+                        all_nodes.push(node);
+                    }
+                }
+            }
+
+            Message::Leave { node_id } => {
+                // A node speaks only for itself, or our leader relays it
+                let relayed = from_id != self.my_id && *self.current_leader.read().await == Some(from_id);
+                if node_id != from_id && !relayed {
+                    warn!("⛔ Ignoring Leave for Node {} sent by Node {}", node_id, from_id);
+                    return;
+                }
+                info!("🚪 Node {} left the cluster", node_id);
+                
+                // Pass a departure heard first-hand on to peers that may
+                // have no connection to the leaving node
+                if node_id == from_id && *self.am_i_leader.read().await {
+                    let leave = Message::Leave { node_id };
+                    for (peer_id, peer) in peer_connections(&self.peers).await {
+                        if peer_id != node_id {
+                            let _ = peer.send(&leave).await;
+                        }
+                    }
+                }
+                
+                self.all_nodes.write().await.retain(|n| n.id != node_id);
+                self.peers.write().await.remove(&node_id);
+                self.last_heartbeat.write().await.remove(&node_id);
+                self.detector.write().await.remove(node_id);
+                self.last_pong.write().await.remove(&node_id);
+                self.peer_rtt.write().await.remove(&node_id);
+                self.metrics.remove_peer_rtt(node_id);
+                
+                if !*self.am_i_leader.read().await {
+                    return;
+                }
+                
+                {
+                    let _view = self.view_lock.write().await;
+                    self.alive_nodes.write().await.remove(&node_id);
+                }
+                if self.refresh_successors().await {
+                    self.announce_successor().await;
                 }
             }
 
-            Message::Takeover { from_id } => {
+            Message::Ping { from_id } => {
+                if let Some(conn) = peer(&self.peers, from_id).await {
+                    let _ = conn.send(&Message::Pong { from_id: self.my_id }).await;
+                }
+            }
+
+            Message::Pong { from_id } => {
+                self.last_pong.write().await.insert(from_id, Instant::now());
+            }
+
+            Message::Takeover { from_id, correlation_id } => {
                 info!("📨 Received Takeover notification from Node {}", from_id);
                 
                 // Verify leader is actually down
@@ -562,41 +3914,572 @@ impl Node {
                     None => return,
                 };
                 
-                let leader_down = {
-                    let heartbeats = self.last_heartbeat.read().await;
-                    heartbeats
-                        .get(&leader_id)
-                        .map(|t| t.elapsed() > FAILURE_TIMEOUT)
-                        .unwrap_or(true)
-                };
+                let leader_down = !self.last_heartbeat.read().await.contains_key(&leader_id)
+                    || self.detector.read().await.is_failed(leader_id, Instant::now());
                 
                 if leader_down && *self.current_successor.read().await == Some(self.my_id) {
                     info!("✅ Confirmed leader down - taking over as requested");
-                    self.become_leader().await;
+                    self.become_leader(LeaderChangeReason::SuccessorTakeover, correlation_id).await;
+                }
+            }
+
+            Message::Resign { leader_id, successor_id } => {
+                if *self.current_leader.read().await != Some(leader_id) {
+                    debug!("Ignoring Resign from Node {} (not the current leader)", leader_id);
+                    return;
+                }
+
+                info!("👋 Leader Node {} resigned, successor: {:?}", leader_id, successor_id);
+
+                // Without a named successor, the highest ranked remaining node we know of takes over
+                let new_leader = match successor_id {
+                    Some(id) => id,
+                    None => {
+                        let peers = self.peers.read().await;
+                        let candidates = peers.keys().copied().chain(std::iter::once(self.my_id));
+                        select_successor(&self.all_nodes.read().await, leader_id, candidates)
+                            .unwrap_or(self.my_id)
+                    }
+                };
+
+                if new_leader == self.my_id {
+                    self.start_election(LeaderChangeReason::Handoff).await;
+                    return;
+                }
+
+                {
+                    let _view = self.view_lock.write().await;
+                    *self.current_leader.write().await = Some(new_leader);
+                    *self.current_successor.write().await = None;
+                    *self.am_i_leader.write().await = false;
+                }
+                self.publish(LeaderChangeReason::Handoff).await;
+                // If the new leader is already dead, the failure detector takes it from here
+                self.last_heartbeat.write().await.insert(new_leader, Instant::now());
+                self.detector.write().await.reset(new_leader, Instant::now());
+                self.save_state().await;
+            }
+
+            Message::ForceElection { requester_id } => self.force_election(requester_id, false).await,
+
+            Message::Election { from_id, correlation_id } => {
+                let outranked = {
+                    let nodes = self.all_nodes.read().await;
+                    rank(&nodes, from_id) < rank(&nodes, self.my_id)
+                };
+                if self.observer || !outranked {
+                    return;
+                }
+
+                // We outrank the sender: tell it we're alive, then run our own
+                // round unless we already lead
+                info!("🗳️  Election from lower ranked Node {} - answering", from_id);
+                if let Some(conn) = peer(&self.peers, from_id).await {
+                    let _ = conn.send(&Message::ElectionOk { from_id: self.my_id, correlation_id }).await;
+                }
+                if !*self.am_i_leader.read().await {
+                    let _ = self.election_tx.try_send(correlation_id);
+                }
+            }
+
+            Message::ElectionOk { from_id, .. } => {
+                info!("🙋 Higher ranked Node {} answered our election", from_id);
+                *self.last_election_ok.write().await = Some(Instant::now());
+            }
+
+            Message::Reliable { .. } | Message::ReliableAck { .. } => {
+                // TCP delivers every message reliably; only UDP nodes wrap them
+            }
+
+            Message::Hello { .. } => {
+                // Consumed by `NetworkLayer` when it accepts the connection
+            }
+
+            Message::StoreImage { image_id, bytes, allowed_node_ids, watermark_owner, max_views, content_hash, generation } => {
+                if let Err(corrupt) = image::verify_hash(&image_id, &bytes, &content_hash) {
+                    self.reject_corrupt_from(from_id, corrupt).await;
+                    return;
+                }
+                let upload = Upload { image_id, bytes, allowed_node_ids, watermark_owner, max_views, generation };
+                self.receive_image(from_id, upload).await;
+            }
+
+            chunk @ Message::ImageChunk { .. } => match self.reassembler.insert(ChunkSender::Peer(from_id), chunk) {
+                Some(Ok(upload)) => self.receive_image(from_id, upload).await,
+                Some(Err(corrupt)) => self.reject_corrupt_from(from_id, corrupt).await,
+                None => {}
+            },
+
+            Message::ChecksumMismatch { image_id, node_id } => {
+                // An upload we forwarded: only the client still has its bytes
+                if let Some((conn, _)) = self.forwarded_uploads.remove(&image_id) {
+                    let mismatch = Message::ChecksumMismatch { image_id, node_id };
+                    if let Err(e) = conn.send(&mismatch).await {
+                        debug!("Failed to relay checksum mismatch: {}", e);
+                    }
+                    return;
+                }
+                self.resend_image(node_id, &image_id).await;
+            }
+
+            Message::ReplicaAck { image_id, node_id } => {
+                // Each follower counts once towards the quorum, for itself only
+                if node_id != from_id {
+                    warn!("⛔ Ignoring ack of image {} for Node {} sent by Node {}", image_id, node_id, from_id);
+                    return;
+                }
+                self.checksum_resends.remove(&(image_id.clone(), node_id));
+                self.save_replica(&image_id, node_id);
+                if let Some(write) = self.pending_writes.get_mut(&image_id) {
+                    write.acks.insert(node_id);
+                    self.check_quorum(&image_id).await;
+                }
+            }
+
+            Message::ViewCount { image_id, requester_id, views } => {
+                let from_leader = *self.current_leader.read().await == Some(from_id);
+                if from_leader && !*self.am_i_leader.read().await {
+                    self.save_view_count(&image_id, requester_id, views);
+                }
+            }
+
+            Message::SyncImages { node_id } => {
+                if node_id == from_id && *self.am_i_leader.read().await {
+                    self.offer_inventory(node_id).await;
+                }
+            }
+
+            Message::ImageInventory { node_id, images, deleted } => {
+                // Either a former leader offering what it wrote while partitioned,
+                // or our leader answering an anti-entropy check
+                if node_id != from_id {
+                    return;
+                }
+                let am_leader = *self.am_i_leader.read().await;
+                if !am_leader && *self.current_leader.read().await != Some(node_id) {
+                    return;
+                }
+                // Only deletes the leader authorized are applied: it creates
+                // tombstones through `DeleteImage` alone, never from a peer's say-so
+                if !am_leader {
+                    self.apply_tombstones(node_id, &deleted);
+                }
+                let Some(store) = &self.image_store else {
+                    return;
+                };
+                
+                // A copy we deleted is pulled again only if it is a later upload
+                let missing: Vec<String> = images
+                    .into_iter()
+                    .filter(|ImageVersion { image_id, generation }| {
+                        ImageStore::validate_id(image_id).is_ok()
+                            && !store.contains(image_id)
+                            && !store.is_deleted(image_id, *generation)
+                    })
+                    .map(|version| version.image_id)
+                    .collect();
+                if missing.is_empty() {
+                    debug!("Node {} holds no images we lack", node_id);
+                    return;
+                }
+                
+                if am_leader {
+                    info!("🔀 Pulling {} image(s) written by Node {} while partitioned", missing.len(), node_id);
+                } else {
+                    info!("🩹 Pulling {} image(s) missed while down from leader Node {}", missing.len(), node_id);
+                }
+                let pull = Message::PullImages {
+                    node_id: self.my_id,
+                    image_ids: missing,
+                };
+                if let Some(conn) = peer(&self.peers, node_id).await {
+                    let _ = conn.send(&pull).await;
+                }
+            }
+
+            Message::PullImages { node_id, image_ids } => {
+                // Our leader reconciling after a partition, or a follower repairing its replicas
+                if *self.am_i_leader.read().await || *self.current_leader.read().await == Some(node_id) {
+                    self.push_images(node_id, &image_ids).await;
+                }
+            }
+
+            Message::DeleteImage { image_id, requester_id } => {
+                // Our leader relaying a delete it authorized
+                let from_leader = *self.current_leader.read().await == Some(from_id);
+                if from_leader && !*self.am_i_leader.read().await {
+                    self.remove_image(&image_id, requester_id);
+                }
+            }
+
+            Message::StoreThumbnail { image_id, bytes } => {
+                let from_leader = *self.current_leader.read().await == Some(from_id);
+                if from_leader && !*self.am_i_leader.read().await && ImageStore::validate_id(&image_id).is_ok() {
+                    self.save_thumbnail(&image_id, &bytes);
+                }
+            }
+
+            Message::StoreResult { image_id, durable, acks, quorum, error, stored_as } => {
+                // Outcome of an upload we forwarded: relay it to the waiting client
+                if let Some((conn, _)) = self.forwarded_uploads.remove(&image_id) {
+                    let result = Message::StoreResult { image_id, durable, acks, quorum, error, stored_as };
+                    if let Err(e) = conn.send(&result).await {
+                        debug!("Failed to relay write result: {}", e);
+                    }
+                }
+            }
+
+            Message::FetchGrant { image_id, requester_id } => {
+                if *self.current_leader.read().await == Some(from_id) {
+                    self.accept_fetch_grant(image_id, requester_id).await;
                 }
             }
+
+            Message::FetchDone { node_id, .. } => {
+                if let Some(load) = self.fetch_load.get_mut(&node_id) {
+                    load.in_flight = load.in_flight.saturating_sub(1);
+                }
+            }
+
+            Message::FetchImage { .. }
+            | Message::FetchThumbnail { .. }
+            | Message::AccessDenied { .. }
+            | Message::FetchRedirect { .. }
+            | Message::ImageDeleted { .. } => {
+                // Fetches and the answers to fetches and deletes travel on client
+                // connections handled by `handle_client_request`; members never
+                // exchange them
+            }
+
+            Message::StatusRequest {}
+            | Message::StatusResponse { .. }
+            | Message::ListImages {}
+            | Message::ImageList { .. }
+            | Message::ListMembers {}
+            | Message::MemberList { .. } => {
+                // Status, image list and member list queries arrive on their own
+                // connection and are answered by `handle_client_request`; members
+                // never exchange them
+            }
         }
     }
 
-    async fn become_leader(&mut self) {
-        info!("👑 Becoming leader (Node {})", self.my_id);
+    /// Lead on our own initiative, as a new election with its own correlation ID
+    async fn start_election(&mut self, reason: LeaderChangeReason) {
+        let correlation_id = new_correlation_id();
+        self.become_leader(reason, correlation_id)
+            .instrument(logging::election_span(correlation_id))
+            .await;
+    }
+
+    /// Lead on behalf of election `correlation_id`; the caller runs this in its span.
+    /// A sitting leader only re-announces itself, keeping its term and followers.
+    async fn become_leader(&mut self, reason: LeaderChangeReason, correlation_id: u64) {
+        if self.observer {
+            info!("👀 Observer - not becoming leader");
+            return;
+        }
+
+        // A redundant Takeover or election must not wipe alive_nodes, or the
+        // successor would churn while followers are rediscovered
+        if *self.am_i_leader.read().await {
+            info!("👑 Already leader (Node {}) - re-announcing instead: {}", self.my_id, reason);
+            self.announce_successor().await;
+            return;
+        }
+
+        let visible = Self::visible_nodes(&self.peers, *self.current_leader.read().await).await;
+        if visible < self.min_quorum {
+            warn!("⛔ No quorum ({}/{} nodes visible) - not becoming leader", visible, self.min_quorum);
+            self.enter_no_quorum().await;
+            return;
+        }
+
+        info!("👑 Becoming leader (Node {}): {}", self.my_id, reason);
         
-        *self.am_i_leader.write().await = true;
-        *self.current_leader.write().await = Some(self.my_id);
+        let term = self.shared().claim_leadership(correlation_id, reason).await;
         
-        let mut alive = self.alive_nodes.write().await;
-        alive.clear();
-        alive.insert(self.my_id);
+        self.save_state().await;
         
         // Announce leadership
         let coordinator = Message::Coordinator {
             leader_id: self.my_id,
             successor_id: None,
+            backup_successors: Vec::new(),
+            term,
+            correlation_id,
         };
         
-        let peers_lock = self.peers.read().await;
-        for peer in peers_lock.values() {
+        for (_, peer) in peer_connections(&self.peers).await {
+            let _ = peer.send(&coordinator).await;
+        }
+    }
+
+    /// Nodes a would-be leader can see: itself plus every connected peer
+    /// except `failed_leader`, the leader it would replace
+    async fn visible_nodes(peers: &RwLock<HashMap<u32, PeerConnection>>, failed_leader: Option<u32>) -> usize {
+        let peers = peers.read().await;
+        peers.keys().filter(|&&id| Some(id) != failed_leader).count() + 1
+    }
+
+    /// Ping each of `ids` and give them `probe_timeout` to answer; whether
+    /// any did. Nodes we hold no connection to count as down without a wait.
+    async fn any_answers(
+        my_id: u32,
+        peers: &RwLock<HashMap<u32, PeerConnection>>,
+        last_pong: &RwLock<HashMap<u32, Instant>>,
+        ids: &[u32],
+        probe_timeout: Duration,
+    ) -> bool {
+        let sent = Instant::now();
+        let mut pinged = false;
+        for (id, conn) in peer_connections(peers).await.iter().filter(|(id, _)| ids.contains(id)) {
+            debug!("🏓 Checking whether Node {} is still up", id);
+            pinged |= conn.send(&Message::Ping { from_id: my_id }).await.is_ok();
+        }
+        if !pinged {
+            return false;
+        }
+
+        tokio::time::sleep(probe_timeout).await;
+        let last_pong = last_pong.read().await;
+        ids.iter().any(|id| last_pong.get(id).is_some_and(|&at| at >= sent))
+    }
+
+    async fn enter_no_quorum(&self) {
+        self.shared().clear_leadership(LeaderChangeReason::NoQuorum).await;
+        self.save_state().await;
+    }
+
+    /// Count a heartbeat from `node_id` at our own term toward the lease. A
+    /// gap longer than the lease starts the follower's run over.
+    fn renew_lease(&mut self, node_id: u32, term: u64) {
+        let now = Instant::now();
+        let lease = self.timings.leader_lease;
+        let vote = self.lease_votes.entry(node_id).or_insert(LeaseVote { term, since: now, last: now });
+        if vote.term != term || now.duration_since(vote.last) > lease {
+            *vote = LeaseVote { term, since: now, last: now };
+        }
+        vote.last = now;
+    }
+
+    /// Whether we hold the leader lease, which image writes require once
+    /// `min_quorum` is above 1. It holds while, counting ourselves, a quorum
+    /// of nodes back it: followers that heartbeated us at our term within
+    /// `leader_lease`, and have done so without a gap for at least that long.
+    ///
+    /// The second condition makes a new leader wait out any lease its
+    /// predecessor may still hold. A follower only heartbeats one leader, so
+    /// its last heartbeat to the old leader came before its first to us and
+    /// stops backing the old lease before it starts backing ours. The lease
+    /// lapses the moment too few followers have heartbeated recently, with no
+    /// timer or message needed.
+    async fn check_lease(&self) -> std::result::Result<(), String> {
+        if self.min_quorum <= 1 {
+            return Ok(());
+        }
+
+        let term = *self.current_term.read().await;
+        let lease = self.timings.leader_lease;
+        let backing = self
+            .lease_votes
+            .iter()
+            .filter(|(&id, vote)| id != self.my_id && vote.term == term)
+            .filter(|(_, vote)| vote.last.elapsed() <= lease && vote.since.elapsed() >= lease)
+            .count();
+        if backing + 1 < self.min_quorum {
+            return Err(format!(
+                "Node {} does not hold the leader lease ({}/{} nodes back it)",
+                self.my_id,
+                backing + 1,
+                self.min_quorum
+            ));
+        }
+        Ok(())
+    }
+
+    /// As leader, drop followers we have not heard from within
+    /// `stale_node_timeout` from the alive set, so a crashed follower stops
+    /// being picked as successor. Its next heartbeat puts it back.
+    async fn prune_silent_followers(&mut self) {
+        if !*self.am_i_leader.read().await {
+            return;
+        }
+
+        let silent: Vec<u32> = {
+            let last_heartbeat = self.last_heartbeat.read().await;
+            self.alive_nodes
+                .read()
+                .await
+                .iter()
+                .copied()
+                .filter(|&id| id != self.my_id)
+                .filter(|id| last_heartbeat.get(id).is_none_or(|seen| seen.elapsed() > self.timings.stale_node_timeout))
+                .collect()
+        };
+        if silent.is_empty() {
+            return;
+        }
+
+        {
+            let _view = self.view_lock.write().await;
+            let mut alive_nodes = self.alive_nodes.write().await;
+            for id in &silent {
+                warn!("💀 No heartbeat from Node {} for {:?} - presuming it dead", id, self.timings.stale_node_timeout);
+                alive_nodes.remove(id);
+            }
+            self.metrics.set_alive_nodes(alive_nodes.len());
+        }
+        if self.refresh_successors().await {
+            self.announce_successor().await;
+        }
+    }
+
+    /// Recompute our successor and backups with [`SharedState::update_successors`]
+    async fn refresh_successors(&self) -> bool {
+        self.shared().update_successors(self.successor_depth).await
+    }
+
+    /// As leader, count `node_id` alive. Under the view lock, and only if we
+    /// still lead then, so a step-down racing this can't leave a follower
+    /// with an alive set.
+    async fn mark_alive(&self, node_id: u32) {
+        let _view = self.view_lock.write().await;
+        if *self.am_i_leader.read().await {
+            self.alive_nodes.write().await.insert(node_id);
+        }
+    }
+
+    /// Publish a new successor right away rather than on the next broadcast tick
+    async fn announce_successor(&self) {
+        let coordinator = Message::Coordinator {
+            leader_id: self.my_id,
+            successor_id: *self.current_successor.read().await,
+            backup_successors: self.backup_successors.read().await.clone(),
+            term: *self.current_term.read().await,
+            correlation_id: *self.election_id.read().await,
+        };
+        for (_, peer) in peer_connections(&self.peers).await {
             let _ = peer.send(&coordinator).await;
         }
     }
-}
\ No newline at end of file
+
+    /// Hold leadership to `min_quorum`. A leader steps down once it has heard
+    /// from too few followers within `stale_node_timeout`; a leaderless node
+    /// claims leadership when it sees a quorum again and no connected peer
+    /// outranks it.
+    async fn enforce_quorum(&mut self) {
+        if self.min_quorum <= 1 || self.observer {
+            return;
+        }
+
+        let term = *self.current_term.read().await;
+        if *self.am_i_leader.read().await {
+            // Followers only start heartbeating us once our Coordinator reaches
+            // them, so give them a staleness window before counting
+            let since = match self.leading_since {
+                Some((leading_term, since)) if leading_term == term => since,
+                _ => {
+                    self.leading_since = Some((term, Instant::now()));
+                    return;
+                }
+            };
+            if since.elapsed() < self.timings.stale_node_timeout {
+                return;
+            }
+
+            let heard = self
+                .last_heartbeat
+                .read()
+                .await
+                .iter()
+                .filter(|(&id, seen)| id != self.my_id && seen.elapsed() <= self.timings.stale_node_timeout)
+                .count();
+            if heard + 1 < self.min_quorum {
+                warn!("⛔ Lost quorum ({}/{} nodes heard from) - stepping down", heard + 1, self.min_quorum);
+                self.enter_no_quorum().await;
+            }
+            return;
+        }
+
+        self.leading_since = None;
+        if self.current_leader.read().await.is_some() {
+            return;
+        }
+
+        let visible = Self::visible_nodes(&self.peers, None).await;
+        let outranked = {
+            let all_nodes = self.all_nodes.read().await;
+            let my_rank = rank(&all_nodes, self.my_id);
+            self.peers.read().await.keys().any(|&id| rank(&all_nodes, id) > my_rank)
+        };
+        if visible >= self.min_quorum && !outranked {
+            info!("✅ Quorum regained ({}/{} nodes visible)", visible, self.min_quorum);
+            self.start_election(LeaderChangeReason::QuorumRegained).await;
+        }
+    }
+
+    async fn publish(&self, reason: LeaderChangeReason) {
+        Self::publish_state(&self.leader_tx, &self.current_leader, &self.am_i_leader, &self.current_term, reason)
+            .await;
+    }
+
+    /// Notify subscribers if the leader, our own role, or the term changed,
+    /// attributing the change to `reason`
+    async fn publish_state(
+        leader_tx: &watch::Sender<LeaderState>,
+        current_leader: &RwLock<Option<u32>>,
+        am_i_leader: &RwLock<bool>,
+        current_term: &RwLock<u64>,
+        reason: LeaderChangeReason,
+    ) {
+        let state = LeaderState {
+            leader: *current_leader.read().await,
+            am_i_leader: *am_i_leader.read().await,
+            term: *current_term.read().await,
+            reason,
+        };
+
+        leader_tx.send_if_modified(|current| {
+            // A republish of unchanged leadership keeps the earlier reason
+            if (current.leader, current.am_i_leader, current.term) == (state.leader, state.am_i_leader, state.term) {
+                return false;
+            }
+            *current = state;
+            true
+        });
+    }
+
+    async fn save_state(&self) {
+        Self::persist_state(
+            &self.state_store,
+            &self.current_leader,
+            &self.current_successor,
+            &self.current_term,
+        )
+        .await;
+    }
+
+    /// Write current leadership beliefs through to the state file, if configured
+    async fn persist_state(
+        state_store: &Option<StateStore>,
+        current_leader: &RwLock<Option<u32>>,
+        current_successor: &RwLock<Option<u32>>,
+        current_term: &RwLock<u64>,
+    ) {
+        let Some(store) = state_store else {
+            return;
+        };
+
+        let state = PersistedState {
+            leader: *current_leader.read().await,
+            successor: *current_successor.read().await,
+            term: *current_term.read().await,
+        };
+
+        if let Err(e) = store.save(&state) {
+            warn!("Failed to persist state: {:#}", e);
+        }
+    }
+}
+
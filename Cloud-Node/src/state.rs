@@ -0,0 +1,70 @@
+use crate::durability::{Durability, DurabilityPolicy};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Leadership beliefs that survive a process restart
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub leader: Option<u32>,
+    pub successor: Option<u32>,
+    pub term: u64,
+}
+
+/// JSON state file for a single node under the configured state directory
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+    durability: Durability,
+}
+
+impl StateStore {
+    pub fn new(state_dir: &Path, node_id: u32) -> Self {
+        Self {
+            path: state_dir.join(format!("node-{}.json", node_id)),
+            durability: Durability::new(DurabilityPolicy::None),
+        }
+    }
+
+    /// fsync saves as `durability` says, rather than leaving them to the OS
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Load the persisted state, or `None` if this node has never written one
+    pub fn load(&self) -> Result<Option<PersistedState>> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!("Failed to read {}", self.path.display()))
+            }
+        };
+
+        let state = serde_json::from_str(&content)
+            .context(format!("Failed to parse {}", self.path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Atomically replace the state file (write to a temp file, then rename)
+    pub fn save(&self, state: &PersistedState) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)
+            .context(format!("Failed to create {}", dir.display()))?;
+
+        let tmp = self.path.with_extension("json.tmp");
+        File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(&serde_json::to_vec_pretty(state)?)?;
+                self.durability.renaming(&file, &self.path)
+            })
+            .context(format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .and_then(|()| self.durability.dir_changed(dir))
+            .context(format!("Failed to replace {}", self.path.display()))?;
+        Ok(())
+    }
+}
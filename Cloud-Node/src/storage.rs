@@ -0,0 +1,292 @@
+use crate::durability::{Durability, DurabilityPolicy};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where [`ImageStore`](crate::image::ImageStore) keeps its bytes: a flat
+/// namespace of named blobs. Encryption, ACLs and ID validation all sit above
+/// it, so a backend only moves bytes. An object store (S3 and the like) fits
+/// by mapping each call onto the matching request and [`Storage::scoped`]
+/// onto a key prefix.
+///
+/// Calls block, like the filesystem reads and writes they replace.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Store `bytes` under `key`, replacing any earlier value
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<()>;
+
+    /// The bytes stored under `key`, or `None` if there are none
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Every key in this namespace, sorted
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// Remove `key`; removing a missing key is not an error
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Whether anything is stored under `key`
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// A separate namespace called `name` in the same backend, for data kept
+    /// alongside the images. Its keys do not show up in this one's `list`.
+    fn scoped(&self, name: &str) -> Box<dyn Storage>;
+}
+
+/// One file per key under a directory; namespaces are subdirectories.
+/// Keys are file names, so they cannot be empty, start with `.` or contain a
+/// path separator.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    dir: PathBuf,
+    durability: Durability,
+}
+
+impl FileStorage {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            durability: Durability::new(DurabilityPolicy::None),
+        }
+    }
+
+    /// fsync writes and deletes as `durability` says, rather than leaving
+    /// them to the OS; namespaces from [`Storage::scoped`] follow suit
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+            anyhow::bail!("Invalid storage key {:?}", key);
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl Storage for FileStorage {
+    /// Atomically write `bytes` (write to a temp file, then rename). Under a
+    /// batched policy only a write replacing an existing key is fsynced at once.
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        std::fs::create_dir_all(&self.dir).context(format!("Failed to create {}", self.dir.display()))?;
+
+        let tmp = self.dir.join(format!(".{}.tmp", key));
+        File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(bytes)?;
+                self.durability.renaming(&file, &path)
+            })
+            .context(format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path)
+            .and_then(|()| self.durability.dir_changed(&self.dir))
+            .context(format!("Failed to replace {}", path.display()))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+        match std::fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(format!("Failed to read {}", self.dir.display())),
+        };
+
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry.context(format!("Failed to read {}", self.dir.display()))?;
+            // Skips namespace subdirectories and in-flight temp files
+            let Ok(key) = entry.file_name().into_string() else {
+                continue;
+            };
+            if entry.file_type().is_ok_and(|kind| kind.is_file()) && !key.starts_with('.') {
+                keys.push(key);
+            }
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match std::fs::remove_file(&path) {
+            Ok(()) => self.durability.dir_changed(&self.dir).context(format!("Failed to remove {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).context(format!("Failed to remove {}", path.display())),
+        }
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key)?.is_file())
+    }
+
+    /// A `.`-prefixed subdirectory, so `list` never mistakes it for a key
+    fn scoped(&self, name: &str) -> Box<dyn Storage> {
+        Box::new(Self::new(&self.dir.join(format!(".{}", name))).with_durability(self.durability.clone()))
+    }
+}
+
+/// Bytes by (namespace, key)
+type Blobs = BTreeMap<(String, String), Vec<u8>>;
+
+/// Blobs kept in process memory, for tests and throwaway nodes. Clones share
+/// the same contents, so a test can keep one to inspect what a node stored.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    namespace: String,
+    blobs: Arc<Mutex<Blobs>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entry(&self, key: &str) -> (String, String) {
+        (self.namespace.clone(), key.to_string())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        self.blobs.lock().unwrap().insert(self.entry(key), bytes.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.blobs.lock().unwrap().get(&self.entry(key)).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .keys()
+            .filter(|(namespace, _)| *namespace == self.namespace)
+            .map(|(_, key)| key.clone())
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.blobs.lock().unwrap().remove(&self.entry(key));
+        Ok(())
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.blobs.lock().unwrap().contains_key(&self.entry(key)))
+    }
+
+    fn scoped(&self, name: &str) -> Box<dyn Storage> {
+        Box::new(Self {
+            namespace: format!("{}/{}", self.namespace, name),
+            blobs: self.blobs.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A storage directory of its own for one test, removed on drop
+    struct StorageDir(PathBuf);
+
+    impl StorageDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("cloud-p2p-storage-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for StorageDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Puts, reads, lists and deletes through `storage` and a namespace of it
+    fn keeps_blobs_and_namespaces_apart(storage: &dyn Storage) {
+        assert_eq!(storage.list().unwrap(), Vec::<String>::new());
+        assert_eq!(storage.get("b").unwrap(), None);
+
+        storage.put("b", b"first").unwrap();
+        storage.put("a", b"other").unwrap();
+        storage.put("b", b"second").unwrap();
+        assert_eq!(storage.get("b").unwrap().as_deref(), Some(&b"second"[..]));
+        assert_eq!(storage.list().unwrap(), ["a", "b"]);
+
+        // A namespace shares no keys with its parent, either way
+        let acl = storage.scoped("acl");
+        assert!(!acl.contains("a").unwrap());
+        acl.put("a", b"acl").unwrap();
+        acl.put("c", b"acl").unwrap();
+        assert_eq!(acl.list().unwrap(), ["a", "c"]);
+        assert_eq!(storage.list().unwrap(), ["a", "b"]);
+        assert_eq!(storage.get("a").unwrap().as_deref(), Some(&b"other"[..]));
+        assert_eq!(storage.scoped("thumb").list().unwrap(), Vec::<String>::new());
+
+        // Deleting is idempotent and leaves the namespace alone
+        storage.delete("a").unwrap();
+        storage.delete("a").unwrap();
+        assert!(!storage.contains("a").unwrap());
+        assert!(acl.contains("a").unwrap());
+        assert_eq!(storage.list().unwrap(), ["b"]);
+    }
+
+    #[test]
+    fn both_backends_keep_blobs_and_namespaces_apart() {
+        keeps_blobs_and_namespaces_apart(&MemoryStorage::new());
+
+        let dir = StorageDir::new("contract");
+        keeps_blobs_and_namespaces_apart(&FileStorage::new(&dir.0));
+        // Laid out as the image directory always was
+        assert_eq!(std::fs::read(dir.0.join("b")).unwrap(), b"second");
+        assert_eq!(std::fs::read(dir.0.join(".acl").join("c")).unwrap(), b"acl");
+    }
+
+    #[test]
+    fn batched_puts_wait_for_the_flush_unless_they_replace_a_key() {
+        let dir = StorageDir::new("batched");
+        let durability = Durability::new(DurabilityPolicy::default());
+        let storage = FileStorage::new(&dir.0).with_durability(durability.clone());
+
+        // A new key has nothing to lose: it is fsynced with the next flush
+        storage.put("a", b"first").unwrap();
+        assert!(durability.is_dirty(&dir.0.join("a")));
+        assert!(durability.is_dirty(&dir.0));
+        durability.flush().unwrap();
+        assert!(!durability.is_dirty(&dir.0.join("a")));
+
+        // Replacing it is fsynced at once, so the rename cannot outlive the contents
+        storage.put("a", b"second").unwrap();
+        assert!(!durability.is_dirty(&dir.0.join("a")));
+        assert!(durability.is_dirty(&dir.0));
+        assert_eq!(storage.get("a").unwrap().as_deref(), Some(&b"second"[..]));
+    }
+
+    #[test]
+    fn file_keys_that_would_leave_the_directory_are_refused() {
+        let dir = StorageDir::new("keys");
+        let storage = FileStorage::new(&dir.0);
+        for key in ["", ".hidden", "../escape", "a/b", "a\\b"] {
+            assert!(storage.put(key, b"x").is_err(), "stored {:?}", key);
+        }
+        // An interrupted write's temp file is not a key
+        std::fs::create_dir_all(&dir.0).unwrap();
+        std::fs::write(dir.0.join(".b.tmp"), b"partial").unwrap();
+        assert_eq!(storage.list().unwrap(), Vec::<String>::new());
+    }
+}
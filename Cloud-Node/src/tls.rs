@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Certificates for encrypting inter-node traffic: every node presents its
+/// own certificate and verifies its peers' against the cluster CA. With
+/// [`with_client_auth`](Self::with_client_auth), nodes also verify the
+/// certificates of those dialing them.
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    certs: Vec<CertificateDer<'static>>,
+    key: Arc<PrivateKeyDer<'static>>,
+    roots: Arc<RootCertStore>,
+    client_auth: bool,
+}
+
+impl TlsConfig {
+    /// Load this node's certificate chain and private key, and the CA that
+    /// signed every node's certificate, all PEM-encoded
+    pub fn from_files(cert_path: &Path, key_path: &Path, ca_path: &Path) -> Result<Self> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .context(format!("Failed to read certificates from {}", cert_path.display()))?;
+        if certs.is_empty() {
+            anyhow::bail!("{} contains no certificates", cert_path.display());
+        }
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .context(format!("Failed to read private key from {}", key_path.display()))?;
+
+        let server_config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs.clone(), key.clone_key())
+            .context("Certificate does not match private key")?;
+
+        // Present our certificate when dialing, for peers that ask for one
+        let roots = Arc::new(roots(ca_path)?);
+        let client_config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots.clone())
+            .with_client_auth_cert(certs.clone(), key.clone_key())
+            .context("Certificate does not match private key")?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            certs,
+            key: Arc::new(key),
+            roots,
+            client_auth: false,
+        })
+    }
+
+    /// Refuse connections that don't present a certificate signed by the
+    /// cluster CA, and admit a node only if its certificate's common name is
+    /// its [`node_common_name`]. Tooling needs a CA-signed certificate too,
+    /// of any name.
+    pub fn with_client_auth(mut self) -> Result<Self> {
+        let verifier = WebPkiClientVerifier::builder_with_provider(self.roots.clone(), provider())
+            .build()
+            .context("Invalid CA for verifying client certificates")?;
+        let server_config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(self.certs.clone(), self.key.clone_key())
+            .context("Certificate does not match private key")?;
+        self.acceptor = TlsAcceptor::from(Arc::new(server_config));
+        self.client_auth = true;
+        Ok(self)
+    }
+
+    /// Whether peers must prove their node ID with a client certificate
+    pub fn requires_client_auth(&self) -> bool {
+        self.client_auth
+    }
+
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+
+    pub fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+}
+
+/// Client-side TLS for tooling that talks to a TLS cluster: trusts
+/// certificates signed by the PEM-encoded CA at `ca_path`
+pub fn connector(ca_path: &Path) -> Result<TlsConnector> {
+    let client_config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots(ca_path)?)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// The CA certificates in the PEM file at `ca_path`
+fn roots(ca_path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path)
+        .context(format!("Failed to read CA certificates from {}", ca_path.display()))?
+    {
+        let cert = cert.context(format!("Invalid CA certificate in {}", ca_path.display()))?;
+        roots
+            .add(cert)
+            .context(format!("Invalid CA certificate in {}", ca_path.display()))?;
+    }
+    if roots.is_empty() {
+        anyhow::bail!("{} contains no CA certificates", ca_path.display());
+    }
+    Ok(roots)
+}
+
+/// Common name node `node_id`'s certificate must carry when peers verify
+/// client certificates
+///
+/// ```
+/// assert_eq!(cloud_p2p::tls::node_common_name(2), "node-2");
+/// ```
+pub fn node_common_name(node_id: u32) -> String {
+    format!("node-{}", node_id)
+}
+
+/// The subject common name of a DER-encoded certificate, if it has one.
+/// Only call this on a certificate the handshake has already verified.
+pub fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?;
+    name.as_str().ok().map(str::to_string)
+}
+
+/// Name a peer's certificate must carry: the host part of its `host:port`
+/// address, either as an IP address or a DNS name
+pub fn server_name(addr: &str) -> Result<ServerName<'static>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).context(format!("Invalid TLS server name in {}", addr))
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
@@ -0,0 +1,351 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context as TaskContext, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+
+/// Default accept backlog for the TCP listener
+pub const LISTEN_BACKLOG: u32 = 1024;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A bidirectional byte stream between two nodes
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// The byte-stream layer under [`NetworkLayer`](crate::network::NetworkLayer):
+/// opening and accepting raw connections. Framing, TLS, authentication and
+/// connection bookkeeping all sit above it, so they work the same over any
+/// transport.
+pub trait Transport: Send + Sync {
+    /// Start accepting connections on `addr`
+    fn listen<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<Box<dyn Listener>>>;
+
+    /// Open a connection to `addr`
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<BoxedStream>>;
+}
+
+/// Accepts connections opened by [`Transport::connect`]
+pub trait Listener: Send {
+    /// Wait for the next connection, returning it and the remote end's address
+    fn accept(&mut self) -> BoxFuture<'_, Result<(BoxedStream, String)>>;
+}
+
+/// Resolve a `host:port` address, whether an IPv4 literal, a bracketed IPv6
+/// literal or a hostname, to the first address the resolver returns. The
+/// choice is logged when a hostname resolves to several.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> anyhow::Result<()> {
+/// use cloud_p2p::transport::resolve;
+///
+/// assert_eq!(resolve("[::1]:8081").await?, "[::1]:8081".parse()?);
+/// assert_eq!(resolve("localhost:8081").await?.port(), 8081);
+/// assert!(resolve("localhost").await.is_err());
+/// # Ok(())
+/// # }
+/// ```
+pub async fn resolve(addr: &str) -> Result<SocketAddr> {
+    if let Ok(literal) = addr.parse() {
+        return Ok(literal);
+    }
+    let mut resolved = lookup_host(addr).await.context(format!("Failed to resolve {}", addr))?;
+    let first = resolved.next().context(format!("{} resolved to no addresses", addr))?;
+    let others: Vec<_> = resolved.collect();
+    if others.is_empty() {
+        debug!("Resolved {} to {}", addr, first);
+    } else {
+        debug!("Resolved {} to {}, passing over {:?}", addr, first, others);
+    }
+    Ok(first)
+}
+
+/// Real TCP sockets; the default transport
+#[derive(Debug, Clone, Copy)]
+pub struct TcpTransport {
+    backlog: u32,
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        Self {
+            backlog: LISTEN_BACKLOG,
+        }
+    }
+}
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Override how many connections may wait to be accepted
+    pub fn with_backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Bind with `SO_REUSEADDR` so a restarted node can take its port back
+    /// while connections from its previous run linger in TIME_WAIT
+    fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        socket.listen(self.backlog)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn listen<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let mut last_error = None;
+            for resolved in lookup_host(addr).await.context(format!("Failed to resolve {}", addr))? {
+                match self.bind(resolved) {
+                    Ok(listener) => return Ok(Box::new(listener) as Box<dyn Listener>),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            let error = last_error.unwrap_or_else(|| io::ErrorKind::AddrNotAvailable.into());
+            Err(error).context(format!("Failed to bind to {}", addr))
+        })
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<BoxedStream>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(resolve(addr).await?)
+                .await
+                .context(format!("Failed to connect to {}", addr))?;
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, Result<(BoxedStream, String)>> {
+        Box::pin(async move {
+            let (stream, addr) = TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as BoxedStream, addr.to_string()))
+        })
+    }
+}
+
+/// One direction of an in-memory connection
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Waker>,
+}
+
+type SharedPipe = Arc<Mutex<Pipe>>;
+
+fn close_pipe(pipe: &SharedPipe) {
+    let mut pipe = pipe.lock().unwrap();
+    pipe.closed = true;
+    if let Some(waker) = pipe.reader.take() {
+        waker.wake();
+    }
+}
+
+/// One end of an in-memory connection. Writes never block; closing either
+/// end, or severing the link through [`MemoryNetwork::kill`], shows up as
+/// end-of-stream to readers and a broken pipe to writers.
+#[derive(Debug)]
+pub struct MemoryStream {
+    incoming: SharedPipe,
+    outgoing: SharedPipe,
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = self.incoming.lock().unwrap();
+        if !pipe.buf.is_empty() {
+            let n = buf.remaining().min(pipe.buf.len());
+            let (front, back) = pipe.buf.as_slices();
+            let from_front = n.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            pipe.buf.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        if pipe.closed {
+            return Poll::Ready(Ok(()));
+        }
+        pipe.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.outgoing.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        pipe.buf.extend(data);
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        close_pipe(&self.outgoing);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        close_pipe(&self.incoming);
+        close_pipe(&self.outgoing);
+    }
+}
+
+/// A live in-memory connection, remembered so it can be severed
+struct Link {
+    ends: [String; 2],
+    pipes: [Weak<Mutex<Pipe>>; 2],
+}
+
+#[derive(Default)]
+struct MemoryState {
+    listeners: HashMap<String, mpsc::UnboundedSender<(BoxedStream, String)>>,
+    down: HashSet<String>,
+    links: Vec<Link>,
+}
+
+/// An in-process network for tests: nodes built with [`MemoryNetwork::transport`]
+/// reach each other by address without touching real sockets, and a test can
+/// take any of them off the network at once with [`MemoryNetwork::kill`]
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transport for the node at `addr`; its outgoing connections come from `addr`
+    pub fn transport(&self, addr: &str) -> Arc<dyn Transport> {
+        Arc::new(MemoryTransport {
+            network: self.clone(),
+            local_addr: addr.to_string(),
+        })
+    }
+
+    /// Sever every connection to or from `addr` and refuse new ones, as if
+    /// the node had crashed
+    pub fn kill(&self, addr: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.down.insert(addr.to_string());
+        state.links.retain(|link| {
+            if link.ends.iter().any(|end| end == addr) {
+                for pipe in link.pipes.iter().filter_map(Weak::upgrade) {
+                    close_pipe(&pipe);
+                }
+                false
+            } else {
+                link.pipes.iter().any(|pipe| pipe.strong_count() > 0)
+            }
+        });
+    }
+
+    /// Let `addr` open and accept connections again
+    pub fn revive(&self, addr: &str) {
+        self.state.lock().unwrap().down.remove(addr);
+    }
+
+    /// Whether `addr` has been killed and not revived
+    pub fn is_down(&self, addr: &str) -> bool {
+        self.state.lock().unwrap().down.contains(addr)
+    }
+
+    fn connect(&self, from: &str, to: &str) -> io::Result<MemoryStream> {
+        let mut state = self.state.lock().unwrap();
+        if state.down.contains(from) || state.down.contains(to) {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        let listener = state
+            .listeners
+            .get(to)
+            .filter(|listener| !listener.is_closed())
+            .ok_or(io::ErrorKind::ConnectionRefused)?;
+
+        let there = SharedPipe::default();
+        let back = SharedPipe::default();
+        let server = MemoryStream {
+            incoming: there.clone(),
+            outgoing: back.clone(),
+        };
+        listener
+            .send((Box::new(server), from.to_string()))
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+        state.links.retain(|link| link.pipes.iter().any(|pipe| pipe.strong_count() > 0));
+        state.links.push(Link {
+            ends: [from.to_string(), to.to_string()],
+            pipes: [Arc::downgrade(&there), Arc::downgrade(&back)],
+        });
+        Ok(MemoryStream {
+            incoming: back,
+            outgoing: there,
+        })
+    }
+}
+
+struct MemoryTransport {
+    network: MemoryNetwork,
+    local_addr: String,
+}
+
+impl Transport for MemoryTransport {
+    fn listen<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let mut state = self.network.state.lock().unwrap();
+            if state.listeners.get(addr).is_some_and(|listener| !listener.is_closed()) {
+                anyhow::bail!("Failed to bind to {}: address in use", addr);
+            }
+            let (tx, rx) = mpsc::unbounded_channel();
+            state.listeners.insert(addr.to_string(), tx);
+            Ok(Box::new(MemoryListener { rx }) as Box<dyn Listener>)
+        })
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, Result<BoxedStream>> {
+        Box::pin(async move {
+            let stream = self
+                .network
+                .connect(&self.local_addr, addr)
+                .context(format!("Failed to connect to {}", addr))?;
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+}
+
+struct MemoryListener {
+    rx: mpsc::UnboundedReceiver<(BoxedStream, String)>,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, Result<(BoxedStream, String)>> {
+        Box::pin(async move { self.rx.recv().await.context("Memory network listener closed") })
+    }
+}
@@ -0,0 +1,1069 @@
+use crate::detector::{wall_clock, ClockWatch};
+use crate::message::{new_correlation_id, ClusterKey, Message, ProtocolError};
+use crate::node::{is_observer, rank, select_successor, Config, LeaderChangeReason, NodeInfo, Timings};
+use crate::transport::resolve;
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::time::{interval, sleep, Duration, Instant};
+
+/// Largest datagram the listener will accept
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Sends of a `Reliable` message before giving up on its ack
+const RETRANSMIT_ATTEMPTS: u32 = 4;
+
+/// Wait for an ack before the first resend; doubled after each
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// `Reliable` messages awaiting acks at once; past it they go out unreliably
+const RETRANSMIT_BUFFER: usize = 64;
+
+/// `Reliable` sequence numbers remembered per sender to drop resent copies
+const DEDUP_WINDOW: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum NodeState {
+    Follower,
+    Leader,
+}
+
+/// Leader election over connectionless UDP datagrams, using the classic
+/// Bully algorithm with a successor hint from the leader
+pub struct UdpNode {
+    id: u32,
+    my_address: String,
+    all_nodes: HashMap<u32, SocketAddr>,
+    nodes: Vec<NodeInfo>,  // Config entries, for election ranks
+    observer: bool,  // Tracks the cluster but never runs for leader
+    state: Arc<RwLock<NodeState>>,
+    current_leader: Arc<RwLock<Option<u32>>>,
+    successor_hint: Arc<RwLock<Option<u32>>>,  // Known successor from leader
+    current_term: Arc<RwLock<u64>>,  // Highest election term seen
+    election_id: Arc<RwLock<u64>>,  // Correlation ID of the election behind `current_term`; 0 if unknown
+    active_nodes: Arc<RwLock<HashMap<u32, Instant>>>,  // Last seen time of each *other* node (never self)
+    stale_node_timeout: Duration,  // Entries older than this are swept from active_nodes
+    timings: Timings,
+    last_heartbeat: Arc<RwLock<Instant>>,
+    election_in_progress: Arc<RwLock<bool>>,  // Claimed by `try_begin_election`, cleared by the runner
+    election_tx: mpsc::Sender<u64>,  // Claimed elections waiting for the runner, by correlation ID
+    election_rx: Mutex<Option<mpsc::Receiver<u64>>>,  // Taken by the election runner in `start`
+    socket: Arc<UdpSocket>,
+    cluster_key: Option<ClusterKey>,  // Tags outgoing datagrams and verifies incoming ones
+    next_seq: AtomicU64,  // Next `Reliable` sequence number
+    unacked: Arc<RwLock<HashSet<(u32, u64)>>>,  // `Reliable` messages still being resent, by receiver and seq
+    delivered: RwLock<HashMap<u32, VecDeque<u64>>>,  // Recently handled `Reliable` seqs, by sender
+}
+
+impl UdpNode {
+    pub async fn new(id: u32, config: &Config) -> anyhow::Result<Self> {
+        config.validate()?;
+        let node_config = config
+            .nodes
+            .iter()
+            .find(|n| n.id == id)
+            .ok_or_else(|| anyhow::anyhow!("Node ID {} not found in config", id))?;
+        // Followers heartbeat only the leader, so a UDP candidate can't tell
+        // how many nodes it could lead
+        if config.min_quorum > 1 {
+            anyhow::bail!("min_quorum is only supported over the TCP transport");
+        }
+
+        let address: SocketAddr = node_config
+            .bind_address
+            .parse()
+            .context(format!("Node {} needs an ip:port bind address over UDP", id))?;
+        let socket = UdpSocket::bind(address).await?;
+        
+        // Peers are addressed by datagram, so host names are resolved once,
+        // at startup
+        let mut all_nodes = HashMap::new();
+        for node in &config.nodes {
+            let address = resolve(node.advertised_address())
+                .await
+                .context(format!("Node {} has no usable address", node.id))?;
+            all_nodes.insert(node.id, address);
+        }
+
+        info!("Node {} starting at {}", id, address);
+
+        let (election_tx, election_rx) = mpsc::channel(1);
+
+        Ok(Self {
+            id,
+            my_address: node_config.advertised_address().to_string(),
+            all_nodes,
+            nodes: config.nodes.clone(),
+            observer: node_config.observer,
+            state: Arc::new(RwLock::new(NodeState::Follower)),
+            current_leader: Arc::new(RwLock::new(None)),
+            successor_hint: Arc::new(RwLock::new(None)),
+            current_term: Arc::new(RwLock::new(0)),
+            election_id: Arc::new(RwLock::new(0)),
+            active_nodes: Arc::new(RwLock::new(HashMap::new())),
+            stale_node_timeout: config.timings.stale_node_timeout,
+            timings: config.timings,
+            last_heartbeat: Arc::new(RwLock::new(Instant::now())),
+            election_in_progress: Arc::new(RwLock::new(false)),
+            election_tx,
+            election_rx: Mutex::new(Some(election_rx)),
+            socket: Arc::new(socket),
+            cluster_key: None,
+            next_seq: AtomicU64::new(Self::first_seq(id)),
+            unacked: Arc::new(RwLock::new(HashSet::new())),
+            delivered: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Seeded from the wall clock so a restarted node never reuses a sequence
+    /// number its peers still remember. A clock stepped back across the
+    /// restart could make it do so, and one set before 1970 certainly would,
+    /// so that is reported rather than papered over.
+    fn first_seq(id: u32) -> u64 {
+        match wall_clock().duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_micros() as u64,
+            Err(e) => {
+                warn!(
+                    "Node {}: System clock is {:.1}s before the Unix epoch; reliable sequence numbers start at 0",
+                    id,
+                    e.duration().as_secs_f64()
+                );
+                0
+            }
+        }
+    }
+
+    /// Authenticate every datagram with the shared cluster secret
+    pub fn with_cluster_key(mut self, key: ClusterKey) -> Self {
+        self.cluster_key = Some(key);
+        self
+    }
+
+    pub async fn start(self: Arc<Self>) {
+        // Start message listener
+        let node_clone = Arc::clone(&self);
+        tokio::spawn(async move {
+            node_clone.listen().await;
+        });
+
+        // Run elections one at a time, however many paths ask for one
+        if let Some(election_rx) = self.election_rx.lock().await.take() {
+            let node_clone = Arc::clone(&self);
+            tokio::spawn(async move {
+                node_clone.run_elections(election_rx).await;
+            });
+        }
+
+        // Give listener time to start
+        sleep(Duration::from_millis(500)).await;
+
+        // Discover cluster
+        self.discover_cluster().await;
+
+        // Start heartbeat monitor
+        let node_clone = Arc::clone(&self);
+        tokio::spawn(async move {
+            node_clone.monitor_leader().await;
+        });
+
+        // Start heartbeat sender
+        let node_clone = Arc::clone(&self);
+        tokio::spawn(async move {
+            node_clone.send_heartbeats().await;
+        });
+
+        // Start status reporter
+        let node_clone = Arc::clone(&self);
+        tokio::spawn(async move {
+            node_clone.report_status().await;
+        });
+
+        info!("Node {} started successfully", self.id);
+    }
+
+    /// Successor of `leader_id` by [`select_successor`], over the fresh
+    /// entries of `active_nodes` plus ourselves (never tracked there)
+    fn calculate_successor(
+        &self,
+        active_nodes: &HashMap<u32, Instant>,
+        leader_id: u32,
+    ) -> Option<u32> {
+        let fresh = active_nodes
+            .iter()
+            .filter(|(_, &seen)| !self.is_stale(seen))
+            .map(|(&id, _)| id);
+        select_successor(&self.nodes, leader_id, fresh.chain(std::iter::once(self.id)))
+    }
+
+    /// Whether a node last seen at `seen` has been silent past the staleness window
+    fn is_stale(&self, seen: Instant) -> bool {
+        seen.elapsed() > self.stale_node_timeout
+    }
+
+    /// Drop nodes that have been silent past the staleness window
+    async fn sweep_stale_nodes(&self) {
+        let mut active_nodes = self.active_nodes.write().await;
+        let before = active_nodes.len();
+        active_nodes.retain(|_, seen| !self.is_stale(*seen));
+
+        let swept = before - active_nodes.len();
+        if swept > 0 {
+            info!("Node {}: Swept {} stale node(s) from active set", self.id, swept);
+        }
+    }
+
+    /// Number of live cluster members as seen by this node, counting self exactly once
+    fn active_count(&self, active_nodes: &HashMap<u32, Instant>) -> usize {
+        active_nodes.keys().filter(|&&id| id != self.id).count() + 1
+    }
+
+    async fn discover_cluster(&self) {
+        info!("Node {}: Starting cluster discovery...", self.id);
+
+        let discovery_msg = Message::WhoIsLeader {
+            node_id: self.id,
+            from_address: self.my_address.clone(),
+        };
+
+        // Send to all other nodes
+        for (node_id, addr) in &self.all_nodes {
+            if *node_id != self.id {
+                self.send_message(addr, &discovery_msg).await;
+            }
+        }
+
+        // Wait for responses
+        sleep(self.timings.discovery_timeout).await;
+
+        let leader = *self.current_leader.read().await;
+        if let Some(leader_id) = leader {
+            info!("Node {}: Discovered leader is Node {}", self.id, leader_id);
+        } else {
+            info!("Node {}: No leader found, starting election...", self.id);
+            self.request_election(new_correlation_id()).await;
+        }
+    }
+
+    /// Claim the next election: tests and sets `election_in_progress` under a
+    /// single write lock, so of several concurrent triggers exactly one wins.
+    /// The winner must follow up with `dispatch_election`.
+    async fn try_begin_election(&self) -> bool {
+        let mut election_in_progress = self.election_in_progress.write().await;
+        if *election_in_progress {
+            return false;
+        }
+        *election_in_progress = true;
+        true
+    }
+
+    /// Hand a claimed election to the runner
+    fn dispatch_election(&self, correlation_id: u64) {
+        let _ = self.election_tx.try_send(correlation_id);
+    }
+
+    /// Start an election unless one is already in progress; never blocks on it
+    async fn request_election(&self, correlation_id: u64) {
+        if self.try_begin_election().await {
+            self.dispatch_election(correlation_id);
+        }
+    }
+
+    /// Background task: run claimed elections one at a time
+    async fn run_elections(&self, mut election_rx: mpsc::Receiver<u64>) {
+        while let Some(correlation_id) = election_rx.recv().await {
+            self.start_election(correlation_id).await;
+        }
+    }
+
+    /// Run one election; the caller must have claimed it with `try_begin_election`.
+    /// Every message it sends carries `correlation_id`.
+    async fn start_election(&self, correlation_id: u64) {
+        if self.observer {
+            *self.election_in_progress.write().await = false;
+            return;
+        }
+
+        info!("Node {}: Starting election {:016x}...", self.id, correlation_id);
+        let leader_before = *self.current_leader.read().await;
+
+        // Check if we have a successor hint
+        let successor_hint = *self.successor_hint.read().await;
+        
+        // IMPROVED BULLY: Check if we ARE the successor
+        if let Some(successor_id) = successor_hint {
+            if successor_id == self.id {
+                info!("Node {}: I am the successor! Becoming leader directly.", self.id);
+                self.become_leader(LeaderChangeReason::SuccessorTakeover, correlation_id).await;
+                *self.election_in_progress.write().await = false;
+                return;
+            } else if rank(&self.nodes, successor_id) > rank(&self.nodes, self.id) {
+                // We know about a higher ranked successor, defer to it first
+                info!("Node {}: Deferring to known successor Node {}", self.id, successor_id);
+                
+                self.send_reliable(successor_id, Message::Election { from_id: self.id, correlation_id }).await;
+                
+                // Wait briefly for successor to respond
+                sleep(self.timings.probe_timeout).await;
+                
+                // Check if we got a response
+                let state = self.state.read().await;
+                if *state == NodeState::Leader {
+                    drop(state);
+                    *self.election_in_progress.write().await = false;
+                    return;
+                }
+                drop(state);
+                
+                // Successor didn't respond, fall back to normal election
+                info!("Node {}: Successor didn't respond, falling back to normal election", self.id);
+            }
+        }
+
+        // Normal Bully Algorithm election
+        let election_msg = Message::Election { from_id: self.id, correlation_id };
+
+        let my_rank = rank(&self.nodes, self.id);
+        let higher_nodes: Vec<_> = self
+            .all_nodes
+            .iter()
+            .filter(|(id, _)| rank(&self.nodes, **id) > my_rank && !is_observer(&self.nodes, **id))
+            .collect();
+
+        if higher_nodes.is_empty() {
+            // No higher nodes, become leader
+            self.become_leader(LeaderChangeReason::ElectionWon, correlation_id).await;
+            *self.election_in_progress.write().await = false;
+            return;
+        }
+
+        // Back off briefly so nodes that noticed the failure together don't all
+        // flood the higher nodes; a coordinator announced meanwhile ends this election
+        sleep(self.timings.election_backoff(self.id, &self.nodes)).await;
+        let leader_now = *self.current_leader.read().await;
+        if let Some(new_leader) = leader_now.filter(|&id| Some(id) != leader_before) {
+            info!("Node {}: Node {} became leader while backing off", self.id, new_leader);
+            *self.election_in_progress.write().await = false;
+            return;
+        }
+
+        // Contact all higher nodes
+        for (&node_id, _) in &higher_nodes {
+            self.send_reliable(node_id, election_msg.clone()).await;
+        }
+
+        // Wait for OK responses
+        sleep(self.timings.probe_timeout).await;
+
+        // Check if we should become leader
+        let state = self.state.read().await;
+        if *state != NodeState::Leader {
+            info!("Node {}: No response from higher nodes", self.id);
+            drop(state);
+            self.become_leader(LeaderChangeReason::ElectionWon, correlation_id).await;
+        }
+
+        *self.election_in_progress.write().await = false;
+    }
+
+    async fn become_leader(&self, reason: LeaderChangeReason, correlation_id: u64) {
+        if self.observer {
+            info!("Node {}: Observer - not becoming leader", self.id);
+            return;
+        }
+
+        info!("Node {}: Becoming leader! ({})", self.id, reason);
+    
+        *self.state.write().await = NodeState::Leader;
+        *self.current_leader.write().await = Some(self.id);
+        *self.last_heartbeat.write().await = Instant::now();
+        let term = {
+            let mut term = self.current_term.write().await;
+            *term += 1;
+            *self.election_id.write().await = correlation_id;
+            *term
+        };
+    
+        // NEW: successor_hint is meaningless for a leader—clear it
+        *self.successor_hint.write().await = None;
+    
+        // (Optional) clear any stale active_nodes, start fresh
+        self.active_nodes.write().await.clear();
+    
+        // announce...
+        let coordinator_msg = Message::Coordinator {
+            leader_id: self.id,
+            successor_id: None,
+            backup_successors: Vec::new(),
+            term,
+            correlation_id,
+        };
+        for &node_id in self.all_nodes.keys() {
+            if node_id != self.id {
+                self.send_reliable(node_id, coordinator_msg.clone()).await;
+            }
+        }
+    }
+    
+    async fn send_heartbeats(&self) {
+        let mut interval = interval(self.timings.heartbeat_interval);
+        
+        loop {
+            interval.tick().await;
+            
+            let state = self.state.read().await;
+            if *state == NodeState::Leader {
+                drop(state);
+                
+                // An election can clear the leader between our state check and
+                // here; skip this round rather than announce a leader we lack
+                let Some(leader_id) = *self.current_leader.read().await else {
+                    continue;
+                };
+
+                // Calculate successor from active nodes
+                let active_nodes = self.active_nodes.read().await;
+                // exclude self (the leader) to get the next-highest active node
+                let successor_id = self.calculate_successor(&active_nodes, leader_id);
+                drop(active_nodes);
+
+                if let Some(succ_id) = successor_id {
+                    info!("Node {}: Current successor is Node {}", self.id, succ_id);
+                }
+
+                
+                // The periodic coordinator doubles as the leader heartbeat
+                let heartbeat_msg = Message::Coordinator {
+                    leader_id: self.id,
+                    successor_id,
+                    backup_successors: Vec::new(),
+                    term: *self.current_term.read().await,
+                    correlation_id: *self.election_id.read().await,
+                };
+
+                for (node_id, addr) in &self.all_nodes {
+                    if *node_id != self.id {
+                        self.send_message(addr, &heartbeat_msg).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn monitor_leader(&self) {
+        let mut interval = interval(self.timings.check_interval());
+        let mut clock = ClockWatch::new(Instant::now());
+        
+        loop {
+            interval.tick().await;
+            
+            // Timeouts below run on the monotonic clock; a step is only worth a note
+            if let Some(step) = clock.check(Instant::now()) {
+                warn!("Node {}: Wall clock stepped {}", self.id, step);
+            }
+            
+            self.sweep_stale_nodes().await;
+            
+            let state = self.state.read().await;
+            if *state != NodeState::Leader {
+                drop(state);
+                
+                let last_hb = self.last_heartbeat.read().await;
+                let elapsed = last_hb.elapsed();
+                
+                drop(last_hb);
+                
+                // Claim the election before touching leader state, so no other
+                // trigger can start one between our check and the reset
+                if elapsed > self.timings.failure_timeout && self.try_begin_election().await {
+                    warn!("Node {}: Leader timeout detected!", self.id);
+                    *self.current_leader.write().await = None;
+                    self.dispatch_election(new_correlation_id());
+                }
+            }
+        }
+    }
+
+    async fn listen(&self) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        
+        loop {
+            match self.socket.recv_from(&mut buf).await {
+                Ok((len, addr)) => {
+                    match Message::from_bytes(&buf[..len], self.cluster_key.as_ref()) {
+                        Ok((message, _)) => self.receive(message, addr).await,
+                        Err(e) if e.downcast_ref::<ProtocolError>() == Some(&ProtocolError::BadTag) => {
+                            warn!("Node {}: Dropped unauthenticated datagram from {}", self.id, addr);
+                        }
+                        Err(e) if matches!(e.downcast_ref::<ProtocolError>(), Some(ProtocolError::Replayed { .. })) => {
+                            warn!("Node {}: Dropped replayed datagram from {}", self.id, addr);
+                        }
+                        Err(_) => {}
+                    }
+                }
+                Err(e) => {
+                    error!("Node {}: Error receiving: {}", self.id, e);
+                }
+            }
+        }
+    }
+
+    /// Acknowledge and unwrap a `Reliable` message, dropping copies already
+    /// handled, then handle it
+    async fn receive(&self, message: Message, addr: SocketAddr) {
+        let message = match message {
+            Message::Reliable { from_id, seq, message } => {
+                // Ack every copy, since the ack for an earlier one may have been lost
+                if let Some(sender_addr) = self.all_nodes.get(&from_id) {
+                    self.send_message(sender_addr, &Message::ReliableAck { from_id: self.id, seq }).await;
+                }
+                if !self.first_delivery(from_id, seq).await {
+                    return;
+                }
+                *message
+            }
+            message => message,
+        };
+        self.handle_message(message, addr).await;
+    }
+
+    /// Record `seq` from `from_id` as handled; false if it already was
+    async fn first_delivery(&self, from_id: u32, seq: u64) -> bool {
+        let mut delivered = self.delivered.write().await;
+        let seen = delivered.entry(from_id).or_default();
+        if seen.contains(&seq) {
+            return false;
+        }
+        if seen.len() == DEDUP_WINDOW {
+            seen.pop_front();
+        }
+        seen.push_back(seq);
+        true
+    }
+
+    async fn handle_message(&self, message: Message, _addr: SocketAddr) {
+        match message {
+            Message::WhoIsLeader { node_id, .. } => {
+                // Track that this node is active
+                let mut active_nodes = self.active_nodes.write().await;
+                active_nodes.insert(node_id, Instant::now());
+                drop(active_nodes);
+                
+                let state = self.state.read().await;
+                if *state == NodeState::Leader {
+                    drop(state);
+                    
+                    let successor_id = *self.successor_hint.read().await;
+                    let response = Message::Coordinator {
+                        leader_id: self.id,
+                        successor_id,
+                        backup_successors: Vec::new(),
+                        term: *self.current_term.read().await,
+                        correlation_id: *self.election_id.read().await,
+                    };
+                    
+                    self.send_reliable(node_id, response).await;
+                }
+            }
+            
+            Message::Election { from_id, correlation_id } => {
+                // Track that this node is active
+                let mut active_nodes = self.active_nodes.write().await;
+                active_nodes.insert(from_id, Instant::now());
+                drop(active_nodes);
+                
+                if rank(&self.nodes, from_id) < rank(&self.nodes, self.id) && !self.observer {
+                    // We outrank the sender, send OK and start our own election
+                    self.send_reliable(from_id, Message::ElectionOk { from_id: self.id, correlation_id }).await;
+                    
+                    // Start our own election (without stalling the listener),
+                    // as part of the same one the sender started
+                    self.request_election(correlation_id).await;
+                }
+            }
+            
+            Message::ElectionOk { from_id, .. } => {
+                info!("Node {}: Higher node {} responded to election", self.id, from_id);
+                *self.state.write().await = NodeState::Follower;
+            }
+            
+            Message::Coordinator { leader_id, successor_id, term, correlation_id, .. } => {
+                // A leader deposed by a newer election may still be announcing
+                // itself; following it, or taking it as a sign of life, would
+                // keep us from failing over to the real leader
+                {
+                    let mut current_term = self.current_term.write().await;
+                    if term < *current_term {
+                        debug!(
+                            "Node {}: Ignoring stale coordinator from Node {} (term {} < {})",
+                            self.id, leader_id, term, *current_term
+                        );
+                        return;
+                    }
+                    *current_term = term;
+                    if correlation_id != 0 {
+                        *self.election_id.write().await = correlation_id;
+                    }
+                }
+                
+                let current = *self.current_leader.read().await;
+                if current != Some(leader_id) {
+                    info!("Node {}: New coordinator is Node {}", self.id, leader_id);
+                    *self.current_leader.write().await = Some(leader_id);
+                    *self.state.write().await = NodeState::Follower;
+                }
+                *self.last_heartbeat.write().await = Instant::now();
+                
+                // Store successor hint
+                *self.successor_hint.write().await = successor_id;
+                
+                // Send acknowledgment back to leader
+                let ack_msg = Message::Heartbeat {
+                    node_id: self.id,
+                    term,
+                    sent_at_us: 0,
+                    rtt_us: None,
+                };
+                
+                if let Some(leader_addr) = self.all_nodes.get(&leader_id) {
+                    self.send_message(leader_addr, &ack_msg).await;
+                }
+            }
+            
+            Message::Heartbeat { node_id, term, .. } => {
+                // Leader receives acks to track active nodes; one on a newer
+                // term comes from a node following whoever deposed us
+                let state = self.state.read().await;
+                if *state == NodeState::Leader && term <= *self.current_term.read().await {
+                    drop(state);
+                    let mut active_nodes = self.active_nodes.write().await;
+                    active_nodes.insert(node_id, Instant::now());
+                }
+            }
+            
+            Message::ReliableAck { from_id, seq } => {
+                self.unacked.write().await.remove(&(from_id, seq));
+            }
+            
+            Message::Reliable { .. } => {
+                // Unwrapped by `receive`; one nested inside another is ignored
+            }
+            
+            Message::Hello { .. } => {
+                // Datagrams name their sender already; only TCP connections open with Hello
+            }
+            
+            Message::HeartbeatAck { .. } | Message::Ping { .. } | Message::Pong { .. } => {
+                // UDP followers ack the leader's Coordinator instead, and
+                // judge it by timeout alone
+            }
+            
+            Message::Takeover { .. } | Message::Resign { .. } => {
+                // Takeover/Resign are part of the TCP successor protocol; UDP
+                // nodes notice the departure by timeout and run an election
+            }
+            
+            Message::Join { .. } | Message::Leave { .. } | Message::Membership { .. } => {
+                // UDP membership is fixed by the config file
+            }
+            
+            Message::StatusRequest {}
+            | Message::StatusResponse { .. }
+            | Message::ForceElection { .. }
+            | Message::ListImages {}
+            | Message::ImageList { .. }
+            | Message::ListMembers {}
+            | Message::MemberList { .. }
+            | Message::StoreImage { .. }
+            | Message::ImageChunk { .. }
+            | Message::FetchImage { .. }
+            | Message::AccessDenied { .. }
+            | Message::ReplicaAck { .. }
+            | Message::ChecksumMismatch { .. }
+            | Message::StoreThumbnail { .. }
+            | Message::ViewCount { .. }
+            | Message::ImageInventory { .. }
+            | Message::SyncImages { .. }
+            | Message::PullImages { .. }
+            | Message::FetchThumbnail { .. }
+            | Message::StoreResult { .. }
+            | Message::FetchRedirect { .. }
+            | Message::FetchGrant { .. }
+            | Message::FetchDone { .. }
+            | Message::DeleteImage { .. }
+            | Message::ImageDeleted { .. } => {
+                // Status queries, forced elections and images are served over TCP only
+            }
+        }
+    }
+
+    async fn send_message(&self, addr: &SocketAddr, message: &Message) {
+        if let Ok(data) = message.to_bytes(self.cluster_key.as_ref()) {
+            let _ = self.socket.send_to(&data, addr).await;
+        }
+    }
+
+    /// Send a message that must not be lost: resend it with exponential
+    /// backoff until `node_id` acks it or `RETRANSMIT_ATTEMPTS` run out. With
+    /// `RETRANSMIT_BUFFER` messages already outstanding it is sent once, as
+    /// any other datagram.
+    async fn send_reliable(&self, node_id: u32, message: Message) {
+        let Some(&addr) = self.all_nodes.get(&node_id) else {
+            return;
+        };
+
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut unacked = self.unacked.write().await;
+            if unacked.len() >= RETRANSMIT_BUFFER {
+                drop(unacked);
+                self.send_message(&addr, &message).await;
+                return;
+            }
+            unacked.insert((node_id, seq));
+        }
+
+        let reliable = Message::Reliable {
+            from_id: self.id,
+            seq,
+            message: Box::new(message),
+        };
+
+        let socket = Arc::clone(&self.socket);
+        let unacked = Arc::clone(&self.unacked);
+        let cluster_key = self.cluster_key.clone();
+        tokio::spawn(async move {
+            let mut wait = RETRANSMIT_INTERVAL;
+            for _ in 0..RETRANSMIT_ATTEMPTS {
+                // Tag each copy afresh: the receiver drops a byte-for-byte
+                // repeat of an authenticated datagram as a replay
+                let Ok(data) = reliable.to_bytes(cluster_key.as_ref()) else {
+                    break;
+                };
+                let _ = socket.send_to(&data, addr).await;
+                sleep(wait).await;
+                if !unacked.read().await.contains(&(node_id, seq)) {
+                    return;
+                }
+                wait *= 2;
+            }
+            unacked.write().await.remove(&(node_id, seq));
+        });
+    }
+
+    async fn report_status(&self) {
+        let mut interval = interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+    
+            let state = self.state.read().await.clone();
+            let leader = *self.current_leader.read().await;
+            let last_hb = self.last_heartbeat.read().await;
+            let elapsed = last_hb.elapsed().as_secs_f64();
+            drop(last_hb);
+    
+            if state == NodeState::Leader {
+                // Leader: compute successor from current acks
+                let active_nodes = self.active_nodes.read().await;
+                let computed_succ = self.calculate_successor(&active_nodes, self.id);
+                let active_count = self.active_count(&active_nodes);
+                drop(active_nodes);
+    
+                info!(
+                    "Node {} Status: State={:?}, Leader={:?}, Successor(computed)={:?}, Active nodes={}, Time since heartbeat={:.1}s",
+                    self.id, state, leader, computed_succ, active_count, elapsed
+                );
+            } else {
+                // Follower: show the hint learned from leader heartbeats
+                let successor_hint = *self.successor_hint.read().await;
+                info!(
+                    "Node {} Status: State={:?}, Leader={:?}, Successor(hint)={:?}, Time since heartbeat={:.1}s",
+                    self.id, state, leader, successor_hint, elapsed
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::time::timeout;
+    use tracing_subscriber::prelude::*;
+
+    /// Collects formatted log output for inspection
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// `count` nodes on free loopback UDP ports
+    fn udp_config(count: u32) -> Config {
+        let nodes = (0..count)
+            .map(|id| {
+                let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+                NodeInfo {
+                    id,
+                    bind_address: format!("127.0.0.1:{}", port),
+                    advertise_address: None,
+                    priority: 0,
+                    observer: false,
+                }
+            })
+            .collect();
+        Config {
+            nodes,
+            ..Config::default()
+        }
+    }
+
+    /// What a lone UDP node logs over its first 12 seconds with the logger at `level`
+    fn logged_at(level: &str) -> String {
+        // `log` records reach the thread's subscriber through the bridge
+        let _ = tracing_log::LogTracer::init();
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::logging::filter(Some(level)).unwrap())
+            .with(tracing_subscriber::fmt::layer().with_ansi(false).with_writer({
+                let captured = captured.clone();
+                move || captured.clone()
+            }));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let config = udp_config(1);
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().start_paused(true).build().unwrap();
+        runtime.block_on(async {
+            Arc::new(UdpNode::new(0, &config).await.unwrap()).start().await;
+            sleep(Duration::from_secs(12)).await;
+        });
+        drop(runtime);
+
+        let bytes = captured.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn routine_status_lines_are_suppressed_at_warn() {
+        let info = logged_at("info");
+        assert!(info.matches("Status: State=").count() >= 2, "{}", info);
+        assert!(info.contains("started successfully"));
+
+        let warn = logged_at("warn");
+        assert!(!warn.contains("Status: State="), "{}", warn);
+        assert!(!warn.contains("started successfully"), "{}", warn);
+    }
+
+    #[tokio::test]
+    async fn the_active_count_includes_this_node_exactly_once() {
+        let node = UdpNode::new(0, &udp_config(4)).await.unwrap();
+        let now = Instant::now();
+        let active = |ids: &[u32]| ids.iter().map(|&id| (id, now)).collect::<HashMap<_, _>>();
+
+        assert_eq!(node.active_count(&active(&[])), 1);
+        assert_eq!(node.active_count(&active(&[1, 2, 3])), 4);
+        // An entry for ourselves, as a stray message could leave, is not counted twice
+        assert_eq!(node.active_count(&active(&[0, 1, 3])), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_node_silent_past_the_window_is_never_the_successor() {
+        let node = UdpNode::new(0, &udp_config(4)).await.unwrap();
+        let window = node.stale_node_timeout;
+
+        // Node 3 outranks the rest, but was last heard from before the window
+        node.active_nodes.write().await.insert(3, Instant::now());
+        tokio::time::sleep(window + Duration::from_secs(1)).await;
+        for id in [1, 2] {
+            node.active_nodes.write().await.insert(id, Instant::now());
+        }
+        assert_eq!(node.calculate_successor(&*node.active_nodes.read().await, 0), Some(2));
+
+        // The sweep drops it, and it counts as active no more
+        node.sweep_stale_nodes().await;
+        let active_nodes = node.active_nodes.read().await;
+        assert!(!active_nodes.contains_key(&3));
+        assert_eq!(node.active_count(&active_nodes), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_flood_of_election_messages_claims_one_election() {
+        let node = UdpNode::new(1, &udp_config(3)).await.unwrap();
+        let from = node.all_nodes[&0];
+
+        // Node 0 challenges us a hundred times at once, each a different election
+        let challenges = (0..100).map(|correlation_id| {
+            node.handle_message(Message::Election { from_id: 0, correlation_id }, from)
+        });
+        futures::future::join_all(challenges).await;
+
+        // The node is not started, so the one claimed election waits for a runner
+        let mut election_rx = node.election_rx.lock().await.take().unwrap();
+        assert!(election_rx.try_recv().is_ok());
+        assert!(election_rx.try_recv().is_err(), "more than one election was started");
+        assert!(*node.election_in_progress.read().await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn triggers_racing_on_separate_workers_claim_one_election() {
+        let node = Arc::new(UdpNode::new(1, &udp_config(3)).await.unwrap());
+
+        // As two monitors ticking together would, released at the same instant
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+        let triggers: Vec<_> = (0..2)
+            .map(|_| {
+                let node = node.clone();
+                let barrier = barrier.clone();
+                tokio::spawn(async move {
+                    barrier.wait().await;
+                    node.try_begin_election().await
+                })
+            })
+            .collect();
+        let mut claimed = 0;
+        for trigger in triggers {
+            claimed += usize::from(trigger.await.unwrap());
+        }
+        assert_eq!(claimed, 1);
+
+        // A monitor finding the leader silent meanwhile starts nothing more
+        *node.last_heartbeat.write().await = Instant::now() - Duration::from_secs(10);
+        let monitor = tokio::spawn({
+            let node = node.clone();
+            async move { node.monitor_leader().await }
+        });
+        sleep(Duration::from_millis(200)).await;
+        monitor.abort();
+        let mut election_rx = node.election_rx.lock().await.take().unwrap();
+        assert!(election_rx.try_recv().is_err(), "a second election was started");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_leader_without_a_leader_id_skips_heartbeats_until_it_has_one() {
+        let config = udp_config(2);
+        let peer = UdpSocket::bind(&config.nodes[1].bind_address).await.unwrap();
+        let node = Arc::new(UdpNode::new(0, &config).await.unwrap());
+
+        // Caught between becoming leader and recording itself as such
+        *node.state.write().await = NodeState::Leader;
+        let heartbeats = tokio::spawn({
+            let node = node.clone();
+            async move { node.send_heartbeats().await }
+        });
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        assert!(timeout(Duration::from_millis(500), peer.recv_from(&mut buf)).await.is_err());
+        assert!(!heartbeats.is_finished());
+
+        // The next round goes out once the leader is known
+        *node.current_leader.write().await = Some(0);
+        let (len, _) = timeout(Duration::from_secs(5), peer.recv_from(&mut buf)).await.expect("no heartbeat").unwrap();
+        let (message, _) = Message::from_bytes(&buf[..len], None).unwrap();
+        assert!(matches!(message, Message::Coordinator { leader_id: 0, .. }), "{:?}", message);
+        heartbeats.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_deposed_leader_announcing_itself_does_not_hold_off_failover() {
+        let node = Arc::new(UdpNode::new(1, &udp_config(3)).await.unwrap());
+        let coordinator = |leader_id, term| Message::Coordinator {
+            leader_id,
+            successor_id: None,
+            backup_successors: Vec::new(),
+            term,
+            correlation_id: 0,
+        };
+
+        // Node 0 led on term 3 until node 2 took over on term 5
+        node.handle_message(coordinator(0, 3), node.all_nodes[&0]).await;
+        node.handle_message(coordinator(2, 5), node.all_nodes[&2]).await;
+        assert_eq!(*node.current_leader.read().await, Some(2));
+
+        // Node 2 then falls silent while node 0 keeps announcing its old term
+        let monitor = tokio::spawn({
+            let node = node.clone();
+            async move { node.monitor_leader().await }
+        });
+        let mut election_rx = node.election_rx.lock().await.take().unwrap();
+        for _ in 0..8 {
+            node.handle_message(coordinator(0, 3), node.all_nodes[&0]).await;
+            assert_ne!(*node.current_leader.read().await, Some(0), "followed the deposed leader");
+            sleep(Duration::from_secs(1)).await;
+        }
+        monitor.abort();
+        assert_eq!(*node.current_term.read().await, 5);
+        assert!(election_rx.try_recv().is_ok(), "the stale announcements held off the election");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_leader_is_presumed_dead_after_the_configured_failure_timeout() {
+        let mut config = udp_config(2);
+        config.timings.heartbeat_interval = Duration::from_millis(200);
+        config.timings.failure_timeout = Duration::from_secs(1);
+        let node = Arc::new(UdpNode::new(1, &config).await.unwrap());
+        let coordinator = Message::Coordinator {
+            leader_id: 0,
+            successor_id: None,
+            backup_successors: Vec::new(),
+            term: 1,
+            correlation_id: 0,
+        };
+        node.handle_message(coordinator, node.all_nodes[&0]).await;
+
+        let monitor = tokio::spawn({
+            let node = node.clone();
+            async move { node.monitor_leader().await }
+        });
+        let mut election_rx = node.election_rx.lock().await.take().unwrap();
+        sleep(Duration::from_millis(700)).await;
+        assert!(election_rx.try_recv().is_err(), "the leader was presumed dead between heartbeats");
+        sleep(Duration::from_millis(700)).await;
+        monitor.abort();
+        assert!(election_rx.try_recv().is_ok(), "no election once the leader was silent past the timeout");
+        assert_eq!(*node.current_leader.read().await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn peers_advertised_by_hostname_or_ipv6_literal_are_resolved_and_reached() {
+        let mut config = udp_config(3);
+        let peer = UdpSocket::bind(&config.nodes[1].bind_address).await.unwrap();
+        let port = peer.local_addr().unwrap().port();
+        config.nodes[1].advertise_address = Some(format!("localhost:{}", port));
+        config.nodes[2].advertise_address = Some("[::1]:8082".into());
+        let node = Arc::new(UdpNode::new(0, &config).await.unwrap());
+        assert_eq!(node.all_nodes[&1], SocketAddr::from(([127, 0, 0, 1], port)));
+        assert_eq!(node.all_nodes[&2], "[::1]:8082".parse().unwrap());
+
+        // Leading, the node announces itself to the peer it knows by name
+        *node.state.write().await = NodeState::Leader;
+        *node.current_leader.write().await = Some(0);
+        let heartbeats = tokio::spawn({
+            let node = node.clone();
+            async move { node.send_heartbeats().await }
+        });
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let (len, _) = timeout(Duration::from_secs(5), peer.recv_from(&mut buf)).await.expect("no heartbeat").unwrap();
+        let (message, _) = Message::from_bytes(&buf[..len], None).unwrap();
+        assert!(matches!(message, Message::Coordinator { leader_id: 0, .. }), "{:?}", message);
+        heartbeats.abort();
+
+        // A name that resolves to nothing stops the node at startup
+        config.nodes[0] = udp_config(1).nodes.remove(0);
+        config.nodes[2].advertise_address = Some("no-such-node.invalid:8082".into());
+        let error = UdpNode::new(0, &config).await.err().expect("started with an unresolvable peer");
+        assert!(error.to_string().contains("Node 2 has no usable address"), "{}", error);
+    }
+}
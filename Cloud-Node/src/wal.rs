@@ -0,0 +1,414 @@
+use crate::durability::{Durability, DurabilityPolicy};
+use crate::image::AclEntry;
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Default size past which the log moves on to a new segment (16 MiB)
+pub const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "wal";
+const HASH_LEN: usize = 32;
+
+/// An image write or delete as the log records it, so it can be redone.
+/// Stored bytes are logged as they go to storage: encrypted if replicas are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalOp {
+    Store { acl: AclEntry, sealed: Vec<u8> },
+    /// Bury every generation of the image up to `generation`
+    Delete { image_id: String, generation: u64 },
+}
+
+/// One entry in a segment. An operation is logged before it is applied and
+/// marked done after; only operations left without their `Done` are redone.
+#[derive(Debug, Serialize, Deserialize)]
+enum WalRecord {
+    /// The image's bytes follow the record
+    Store { seq: u64, acl: AclEntry },
+    Delete {
+        seq: u64,
+        image_id: String,
+        /// Absent from records logged before generations, which deleted the first
+        #[serde(default)]
+        generation: u64,
+    },
+    Done { seq: u64 },
+}
+
+struct Segments {
+    file: Option<File>,
+    index: u64,
+    len: u64,
+    next_seq: u64,
+    open: BTreeSet<u64>, // Logged but not yet done
+}
+
+/// Write-ahead log of image operations in a directory of numbered segment
+/// files. Each frame is a header length and body length (big-endian `u32`s),
+/// a JSON header, the body, and a SHA-256 of all of it, so a write torn by a
+/// crash is recognised and ignored. Once a segment passes the size threshold
+/// and no operation is open, the next one starts and the older ones go.
+///
+/// Like [`Storage`](crate::storage::Storage), calls block. Every frame is
+/// synced to disk before the call returns, unless
+/// [`with_durability`](Self::with_durability) says otherwise.
+pub struct Wal {
+    dir: PathBuf,
+    segment_size: u64,
+    segments: Mutex<Segments>,
+    durability: Durability,
+}
+
+impl std::fmt::Debug for Wal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wal").field("dir", &self.dir).finish_non_exhaustive()
+    }
+}
+
+impl Wal {
+    /// Open the log in `dir`, returning it with the operations its segments
+    /// show were started but never finished, oldest first. Redo those, then
+    /// [`checkpoint`](Self::checkpoint) before logging anything new.
+    pub fn open(dir: &Path, segment_size: u64) -> Result<(Self, Vec<WalOp>)> {
+        if segment_size == 0 {
+            anyhow::bail!("WAL segment size must be non-zero");
+        }
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+
+        let mut unfinished = BTreeMap::new();
+        let mut last_seq = 0;
+        let segments = Self::segment_indices(dir)?;
+        for &index in &segments {
+            let path = Self::segment_path(dir, index);
+            let bytes = fs::read(&path).context(format!("Failed to read {}", path.display()))?;
+            for (record, body) in Self::parse(&path, &bytes) {
+                match record {
+                    WalRecord::Store { seq, acl } => {
+                        last_seq = last_seq.max(seq);
+                        unfinished.insert(seq, WalOp::Store { acl, sealed: body });
+                    }
+                    WalRecord::Delete { seq, image_id, generation } => {
+                        last_seq = last_seq.max(seq);
+                        unfinished.insert(seq, WalOp::Delete { image_id, generation });
+                    }
+                    WalRecord::Done { seq } => {
+                        unfinished.remove(&seq);
+                    }
+                }
+            }
+        }
+
+        let wal = Self {
+            dir: dir.to_path_buf(),
+            segment_size,
+            segments: Mutex::new(Segments {
+                file: None,
+                index: segments.last().copied().unwrap_or(0),
+                len: 0,
+                next_seq: last_seq + 1,
+                open: BTreeSet::new(),
+            }),
+            durability: Durability::new(DurabilityPolicy::Always),
+        };
+        Ok((wal, unfinished.into_values().collect()))
+    }
+
+    /// fsync frames and segment changes as `durability` says
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Start a fresh segment and drop every older one; call once whatever
+    /// [`open`](Self::open) returned has been redone
+    pub fn checkpoint(&self) -> Result<()> {
+        let mut segments = self.segments.lock().unwrap();
+        self.rotate(&mut segments)
+    }
+
+    /// Log that an image and its ACL are about to be stored
+    pub fn begin_store(&self, acl: &AclEntry, sealed: &[u8]) -> Result<u64> {
+        self.begin(|seq| WalRecord::Store { seq, acl: acl.clone() }, sealed)
+    }
+
+    /// Log that an image is about to be deleted through `generation`
+    pub fn begin_delete(&self, image_id: &str, generation: u64) -> Result<u64> {
+        self.begin(
+            |seq| WalRecord::Delete {
+                seq,
+                image_id: image_id.to_string(),
+                generation,
+            },
+            &[],
+        )
+    }
+
+    /// Log that operation `seq` was fully applied
+    pub fn done(&self, seq: u64) -> Result<()> {
+        let mut segments = self.segments.lock().unwrap();
+        self.append(&mut segments, &WalRecord::Done { seq }, &[])?;
+        segments.open.remove(&seq);
+        if segments.open.is_empty() && segments.len >= self.segment_size {
+            self.rotate(&mut segments)?;
+        }
+        Ok(())
+    }
+
+    fn begin(&self, record: impl FnOnce(u64) -> WalRecord, body: &[u8]) -> Result<u64> {
+        let mut segments = self.segments.lock().unwrap();
+        let seq = segments.next_seq;
+        self.append(&mut segments, &record(seq), body)?;
+        segments.next_seq += 1;
+        segments.open.insert(seq);
+        Ok(seq)
+    }
+
+    fn append(&self, segments: &mut Segments, record: &WalRecord, body: &[u8]) -> Result<()> {
+        if segments.file.is_none() {
+            self.rotate(segments)?;
+        }
+        let header = serde_json::to_vec(record)?;
+        let mut frame = Vec::with_capacity(8 + header.len() + body.len() + HASH_LEN);
+        frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(body);
+        let hash = Sha256::digest(&frame);
+        frame.extend_from_slice(&hash);
+
+        let path = Self::segment_path(&self.dir, segments.index);
+        let file = segments.file.as_mut().expect("segment opened above");
+        file.write_all(&frame)
+            .and_then(|()| self.durability.written(file, &path))
+            .context(format!("Failed to append to {}", path.display()))?;
+        segments.len += frame.len() as u64;
+        Ok(())
+    }
+
+    /// Move on to a new segment, then remove the ones before it
+    fn rotate(&self, segments: &mut Segments) -> Result<()> {
+        let index = segments.index + 1;
+        let path = Self::segment_path(&self.dir, index);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("Failed to create {}", path.display()))?;
+        segments.file = Some(file);
+        segments.index = index;
+        segments.len = 0;
+
+        for old in Self::segment_indices(&self.dir)?.into_iter().filter(|&old| old < index) {
+            let old = Self::segment_path(&self.dir, old);
+            fs::remove_file(&old).context(format!("Failed to remove {}", old.display()))?;
+        }
+        self.durability
+            .dir_changed(&self.dir)
+            .context(format!("Failed to sync {}", self.dir.display()))
+    }
+
+    /// Frames in a segment up to the first incomplete or corrupt one
+    fn parse(path: &Path, bytes: &[u8]) -> Vec<(WalRecord, Vec<u8>)> {
+        let mut records = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            match Self::parse_frame(rest) {
+                Some((record, body, used)) => {
+                    records.push((record, body));
+                    rest = &rest[used..];
+                }
+                None => {
+                    warn!(
+                        "Ignoring the last {} bytes of {}: torn or corrupt WAL record",
+                        rest.len(),
+                        path.display()
+                    );
+                    break;
+                }
+            }
+        }
+        records
+    }
+
+    fn parse_frame(bytes: &[u8]) -> Option<(WalRecord, Vec<u8>, usize)> {
+        let header_len = u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+        let body_len = u32::from_be_bytes(bytes.get(4..8)?.try_into().ok()?) as usize;
+        let end = 8 + header_len + body_len;
+        let hash = bytes.get(end..end + HASH_LEN)?;
+        if Sha256::digest(&bytes[..end]).as_slice() != hash {
+            return None;
+        }
+        let record = serde_json::from_slice(&bytes[8..8 + header_len]).ok()?;
+        Some((record, bytes[8 + header_len..end].to_vec(), end + HASH_LEN))
+    }
+
+    fn segment_path(dir: &Path, index: u64) -> PathBuf {
+        dir.join(format!("{:016}.{}", index, SEGMENT_EXTENSION))
+    }
+
+    /// Indices of the segment files in `dir`, in order
+    fn segment_indices(dir: &Path) -> Result<Vec<u64>> {
+        let entries = fs::read_dir(dir).context(format!("Failed to list {}", dir.display()))?;
+        let mut indices = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+                if let Some(index) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                    indices.push(index);
+                }
+            }
+        }
+        indices.sort_unstable();
+        Ok(indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{content_hash, ImageStore};
+    use crate::storage::{MemoryStorage, Storage};
+
+    /// A log directory of its own for one test, removed on drop
+    struct WalDir(PathBuf);
+
+    impl WalDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("cloud-p2p-wal-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+
+        fn segments(&self) -> Vec<u64> {
+            Wal::segment_indices(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for WalDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn acl(image_id: &str, bytes: &[u8]) -> AclEntry {
+        AclEntry {
+            image_id: image_id.to_string(),
+            allowed_node_ids: vec![1, 2],
+            max_views: None,
+            views: BTreeMap::new(),
+            replicas: BTreeSet::from([0]),
+            content_hash: Some(content_hash(bytes)),
+            generation: 0,
+            owner: None,
+            size: Some(bytes.len() as u64),
+        }
+    }
+
+    /// Every ACL in the store has its image and every image its ACL
+    fn consistent(storage: &MemoryStorage) -> bool {
+        let store = ImageStore::with_storage(Box::new(storage.clone()));
+        let images = store.list().unwrap();
+        let acls: Vec<String> = storage
+            .scoped("acl")
+            .list()
+            .unwrap()
+            .into_iter()
+            .filter_map(|name| name.strip_suffix(".json").map(str::to_string))
+            .collect();
+        images == acls && images.iter().all(|image_id| store.load_verified(image_id).is_ok())
+    }
+
+    #[test]
+    fn a_crash_mid_operation_is_redone_on_reopen() {
+        let dir = WalDir::new("crash");
+        let storage = MemoryStorage::new();
+        let store = ImageStore::with_storage(Box::new(storage.clone()))
+            .with_wal(&dir.0, SEGMENT_SIZE, Durability::new(DurabilityPolicy::Always))
+            .unwrap();
+        store.save_with_acl(&acl("kept", b"kept bytes"), b"kept bytes").unwrap();
+        store.save_with_acl(&acl("doomed", b"doomed bytes"), b"doomed bytes").unwrap();
+        drop(store);
+
+        // The crash: a store logged with only its ACL written, a delete logged
+        // and not started, and nothing marked done
+        let (wal, unfinished) = Wal::open(&dir.0, SEGMENT_SIZE).unwrap();
+        assert!(unfinished.is_empty());
+        let half_stored = acl("half", b"half bytes");
+        wal.begin_store(&half_stored, b"half bytes").unwrap();
+        storage.scoped("acl").put("half.json", &serde_json::to_vec(&half_stored).unwrap()).unwrap();
+        wal.begin_delete("doomed", 0).unwrap();
+        drop(wal);
+        assert!(!consistent(&storage));
+
+        let store = ImageStore::with_storage(Box::new(storage.clone()))
+            .with_wal(&dir.0, SEGMENT_SIZE, Durability::new(DurabilityPolicy::Always))
+            .unwrap();
+        assert!(consistent(&storage));
+        assert_eq!(store.list().unwrap(), ["half", "kept"]);
+        assert_eq!(store.load("half").unwrap().as_deref(), Some(&b"half bytes"[..]));
+        assert!(store.is_deleted("doomed", 0));
+
+        // Redone and checkpointed, so a second restart has nothing to do
+        drop(store);
+        assert!(Wal::open(&dir.0, SEGMENT_SIZE).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn a_torn_last_record_is_ignored() {
+        let dir = WalDir::new("torn");
+        let (wal, _) = Wal::open(&dir.0, SEGMENT_SIZE).unwrap();
+        let seq = wal.begin_store(&acl("img", b"bytes"), b"bytes").unwrap();
+        wal.done(seq).unwrap();
+        wal.begin_delete("img", 0).unwrap();
+        drop(wal);
+
+        // The crash cut the delete's record short
+        let path = Wal::segment_path(&dir.0, *dir.segments().last().unwrap());
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 5).unwrap();
+
+        let (_, unfinished) = Wal::open(&dir.0, SEGMENT_SIZE).unwrap();
+        assert!(unfinished.is_empty());
+    }
+
+    #[test]
+    fn segments_rotate_past_the_size_once_nothing_is_open() {
+        let dir = WalDir::new("rotate");
+        let (wal, _) = Wal::open(&dir.0, 512).unwrap();
+        let body = vec![7; 100];
+
+        // Finished operations fill a segment and move on, leaving one behind
+        for n in 0..20 {
+            let seq = wal.begin_store(&acl(&format!("img{}", n), &body), &body).unwrap();
+            wal.done(seq).unwrap();
+            assert_eq!(dir.segments().len(), 1);
+        }
+        let rotated_to = dir.segments()[0];
+        assert!(rotated_to > 3, "still on segment {}", rotated_to);
+
+        // An open operation holds its segment past the size, so it survives a crash
+        let open = wal.begin_delete("img0", 0).unwrap();
+        for n in 20..30 {
+            let seq = wal.begin_store(&acl(&format!("img{}", n), &body), &body).unwrap();
+            wal.done(seq).unwrap();
+        }
+        assert_eq!(dir.segments(), [rotated_to]);
+        let (_, unfinished) = Wal::open(&dir.0, 512).unwrap();
+        let expected = WalOp::Delete {
+            image_id: "img0".to_string(),
+            generation: 0,
+        };
+        assert_eq!(unfinished, [expected]);
+
+        // Once it is done the segment rotates away with it
+        wal.done(open).unwrap();
+        assert_eq!(dir.segments(), [rotated_to + 1]);
+        assert!(Wal::open(&dir.0, 512).unwrap().1.is_empty());
+    }
+}
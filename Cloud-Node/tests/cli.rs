@@ -0,0 +1,195 @@
+//! The command-line client driving a cluster of in-process nodes over
+//! loopback TCP, and the node binary itself
+
+mod common;
+
+use cloud_p2p::image::{self, ImageStore};
+use cloud_p2p::storage::MemoryStorage;
+use cloud_p2p::Config;
+use common::{fast_timings, loopback_nodes, Cluster};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+/// A file of its own for one test, removed on drop
+struct ScratchFile(PathBuf);
+
+impl ScratchFile {
+    fn new(name: &str, bytes: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!("cloud-p2p-cli-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Run the node binary with `args`, returning what it printed, or what it
+/// reported on failure
+async fn run(args: &[&str]) -> Result<String, String> {
+    let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_cloud-p2p"));
+    command.args(args).kill_on_drop(true);
+    let output = timeout(Duration::from_secs(30), command.output()).await.expect("client hung").unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        Err(format!("{}{}", stdout, String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+/// A small PNG, which the leader can make a thumbnail of
+fn png() -> Vec<u8> {
+    let pixels = ::image::RgbImage::from_fn(16, 16, |x, y| ::image::Rgb([x as u8 * 16, y as u8 * 16, 128]));
+    let mut bytes = Vec::new();
+    pixels.write_to(&mut std::io::Cursor::new(&mut bytes), ::image::ImageFormat::Png).unwrap();
+    bytes
+}
+
+/// Three nodes keeping images in memory, with the storage of each, and the
+/// settled leader
+async fn image_cluster() -> (Cluster, BTreeMap<u32, MemoryStorage>, u32) {
+    let config = Config {
+        nodes: loopback_nodes(3),
+        timings: fast_timings(),
+        ..Config::default()
+    };
+    let storages: BTreeMap<u32, MemoryStorage> = (0..3).map(|id| (id, MemoryStorage::new())).collect();
+    let cluster = Cluster::tcp_with(config, |id, node| node.with_storage(Box::new(storages[&id].clone())));
+    let leader = timeout(Duration::from_secs(10), cluster.agreed_leader()).await.expect("no leader");
+    // Let the leader hear from both followers, so it replicates to them
+    sleep(Duration::from_secs(1)).await;
+    (cluster, storages, leader)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn an_upload_through_a_follower_is_sent_to_the_leader_and_replicated() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    let file = ScratchFile::new("upload.png", &png());
+    let file_name = file.0.to_str().unwrap();
+    let address = &cluster.config.nodes[follower as usize].bind_address;
+    let output = run(&["upload", "--connect", address, "--file", file_name, "--acl", "7"]).await.unwrap();
+
+    // Named after its contents, as the client reports
+    let image_id = &image::content_id(&png());
+    let redirect = format!("Node {} is not the leader; using leader Node {}", follower, leader);
+    assert!(output.contains(&redirect), "{}", output);
+    assert!(output.contains(&format!("Uploaded {} as image {} (", file_name, image_id)), "{}", output);
+
+    // Every node holds it, with the ACL the client gave
+    timeout(Duration::from_secs(5), async {
+        let store = |storage: &MemoryStorage| ImageStore::with_storage(Box::new(storage.clone()));
+        let stores: Vec<_> = storages.values().map(store).collect();
+        while !stores.iter().all(|store| store.contains(image_id)) {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("not every node stored the image");
+    let acl = ImageStore::with_storage(Box::new(storages[&leader].clone())).load_acl(image_id).unwrap().unwrap();
+    assert_eq!(acl.allowed_node_ids, [7]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_download_writes_the_uploaded_bytes_for_a_node_on_the_acl_only() {
+    let (cluster, _, leader) = image_cluster().await;
+    let file = ScratchFile::new("source.png", &png());
+    let leader_address = &cluster.config.nodes[leader as usize].bind_address;
+    run(&["upload", "--connect", leader_address, "--file", file.0.to_str().unwrap(), "--acl", "7"]).await.unwrap();
+    let image_id = image::content_id(&png());
+
+    // Asked through any node, the copy is served by a follower and checks out
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    let address = &cluster.config.nodes[follower as usize].bind_address;
+    let out = ScratchFile::new("download.png", &[]);
+    let out_path = out.0.to_str().unwrap();
+    let args = |requester: &'static str| {
+        ["download", "--connect", address, "--id", &image_id, "--requester-id", requester, "--out", out_path]
+    };
+    let output = run(&args("7")).await.unwrap();
+    assert!(output.contains("Redirected to Node"), "{}", output);
+    assert_eq!(std::fs::read(&out.0).unwrap(), png());
+
+    // A node off the ACL is told so, and gets nothing written
+    std::fs::write(&out.0, b"").unwrap();
+    let refused = run(&args("3")).await.expect_err("a node off the ACL downloaded the image");
+    assert!(refused.contains("Access denied"), "{}", refused);
+    assert!(std::fs::read(&out.0).unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_delete_through_a_follower_is_sent_to_the_leader_and_removes_every_copy() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let file = ScratchFile::new("doomed.png", &png());
+    let leader_address = &cluster.config.nodes[leader as usize].bind_address;
+    run(&["upload", "--connect", leader_address, "--file", file.0.to_str().unwrap(), "--acl", "7"]).await.unwrap();
+    let image_id = &image::content_id(&png());
+    let store = |storage: &MemoryStorage| ImageStore::with_storage(Box::new(storage.clone()));
+    let stores: Vec<_> = storages.values().map(store).collect();
+    timeout(Duration::from_secs(5), async {
+        while !stores.iter().all(|store| store.contains(image_id)) {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("not every node stored the image");
+
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    let address = &cluster.config.nodes[follower as usize].bind_address;
+    let output = run(&["delete", "--connect", address, "--image-id", image_id, "--requester-id", "7"]).await.unwrap();
+    let redirect = format!("Node {} is not the leader; using leader Node {}", follower, leader);
+    assert!(output.contains(&redirect), "{}", output);
+    assert!(output.contains(&format!("Deleted image {}", image_id)), "{}", output);
+
+    timeout(Duration::from_secs(5), async {
+        while stores.iter().any(|store| store.contains(image_id)) {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("a node kept its copy");
+}
+
+/// What a lone node logs in its first moments, run with `args` and
+/// `RUST_LOG` set to `rust_log`
+async fn startup_log(args: &[&str], rust_log: &str) -> String {
+    let address = &loopback_nodes(1)[0].bind_address;
+    let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_cloud-p2p"));
+    command
+        .args(["--id", "0"])
+        .args(args)
+        .env("CLOUD_NODES", format!("0@{}", address))
+        .env("RUST_LOG", rust_log)
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = command.spawn().unwrap();
+    sleep(Duration::from_secs(2)).await;
+    child.start_kill().unwrap();
+    let output = timeout(Duration::from_secs(10), child.wait_with_output()).await.expect("node hung").unwrap();
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn log_level_filters_each_module_on_its_own_over_rust_log() {
+    // Both modules log at info on startup: the banner, and the listener
+    let everything = startup_log(&[], "info").await;
+    assert!(everything.contains("cloud_p2p::node") && everything.contains("cloud_p2p::network"), "{}", everything);
+
+    let quiet_node = startup_log(&["--log-level", "info,cloud_p2p::node=warn"], "info").await;
+    assert!(quiet_node.contains("Listening on"), "{}", quiet_node);
+    assert!(!quiet_node.contains("cloud_p2p::node"), "{}", quiet_node);
+
+    // The flag wins over RUST_LOG, which would have let everything through
+    let node_only = startup_log(&["--log-level", "warn,cloud_p2p::node=info"], "debug").await;
+    assert!(node_only.contains("Node Starting"), "{}", node_only);
+    assert!(!node_only.contains("cloud_p2p::network"), "{}", node_only);
+
+    let refused = run(&["--id", "0", "--log-level", "cloud_p2p::node=loud"]).await.expect_err("started on a bad level");
+    assert!(refused.contains("Invalid log level"), "{}", refused);
+}
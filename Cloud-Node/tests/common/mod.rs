@@ -0,0 +1,219 @@
+//! Helpers shared by the integration tests: starting a cluster of embedded
+//! nodes, over loopback TCP or a `MemoryNetwork`, and waiting for it to agree
+#![allow(dead_code)]
+
+use cloud_p2p::message::{Message, PROTOCOL_VERSION};
+use cloud_p2p::network::PeerConnection;
+use cloud_p2p::transport::{MemoryNetwork, Transport};
+use cloud_p2p::{Config, Node, NodeHandle, NodeInfo, Timings};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Timings scaled down for real-time tests, keeping the ratios the
+/// defaults validate against
+pub fn fast_timings() -> Timings {
+    let ms = Duration::from_millis;
+    Timings {
+        heartbeat_interval: ms(100),
+        coordinator_interval: ms(100),
+        failure_timeout: ms(300),
+        takeover_timeout: ms(400),
+        reconnect_interval: ms(100),
+        reconnect_max_interval: ms(1000),
+        stale_node_timeout: ms(300),
+        probe_timeout: ms(100),
+        election_jitter: ms(50),
+        discovery_timeout: ms(200),
+        io_timeout: ms(1000),
+        leader_lease: ms(200),
+    }
+}
+
+/// Nodes `0..count` on loopback ports the OS has just handed out
+pub fn loopback_nodes(count: u32) -> Vec<NodeInfo> {
+    (0..count)
+        .map(|id| {
+            let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            node(id, &format!("127.0.0.1:{}", port))
+        })
+        .collect()
+}
+
+/// Nodes `0..count` named for a `MemoryNetwork`, which binds no ports
+pub fn memory_nodes(count: u32) -> Vec<NodeInfo> {
+    Config::localhost(count).nodes
+}
+
+pub fn node(id: u32, address: &str) -> NodeInfo {
+    NodeInfo {
+        id,
+        bind_address: address.to_string(),
+        advertise_address: None,
+        priority: 0,
+        observer: false,
+    }
+}
+
+/// Embedded nodes driven by their `run` tasks, aborted on drop
+pub struct Cluster {
+    pub config: Config,
+    /// Set when the nodes talk over a `MemoryNetwork` rather than TCP
+    pub network: Option<MemoryNetwork>,
+    pub handles: Vec<NodeHandle>,
+    tasks: Vec<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl Cluster {
+    /// Start every node in `config` over loopback TCP
+    pub fn tcp(config: Config) -> Self {
+        Self::tcp_with(config, |_, node| node)
+    }
+
+    /// Start every node in `config` over loopback TCP, after `build` has
+    /// customized each, e.g. with its TLS config
+    pub fn tcp_with(config: Config, build: impl Fn(u32, Node) -> Node) -> Self {
+        let mut cluster = Self {
+            config,
+            network: None,
+            handles: Vec::new(),
+            tasks: Vec::new(),
+        };
+        for info in cluster.config.nodes.clone() {
+            cluster.spawn(info.id, |node| build(info.id, node));
+        }
+        cluster
+    }
+
+    /// Start every node in `config` on one `MemoryNetwork`
+    pub fn memory(config: Config) -> Self {
+        Self::memory_with(config, |_, transport| transport)
+    }
+
+    /// Start every node in `config` on one `MemoryNetwork`, each reaching it
+    /// through whatever `wrap` makes of its transport, e.g. a `FaultyTransport`
+    pub fn memory_with(config: Config, wrap: impl Fn(u32, Arc<dyn Transport>) -> Arc<dyn Transport>) -> Self {
+        let mut cluster = Self::memory_idle(config);
+        let network = cluster.network.clone().unwrap();
+        for info in cluster.config.nodes.clone() {
+            let transport = wrap(info.id, network.transport(&info.bind_address));
+            cluster.spawn(info.id, |node| node.with_transport(transport));
+        }
+        cluster
+    }
+
+    /// A fresh `MemoryNetwork` for the nodes in `config`, none of them started
+    pub fn memory_idle(config: Config) -> Self {
+        Self {
+            config,
+            network: Some(MemoryNetwork::new()),
+            handles: Vec::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Start node `id` from `config` on the `MemoryNetwork`
+    pub fn start(&mut self, id: u32) -> NodeHandle {
+        let address = &self.config.nodes.iter().find(|node| node.id == id).expect("node in config").bind_address;
+        let transport = self.network.as_ref().expect("memory cluster").transport(address);
+        self.spawn(id, |node| node.with_transport(transport))
+    }
+
+    /// Start node `id` from `config`, after `build` has customized it
+    pub fn spawn(&mut self, id: u32, build: impl FnOnce(Node) -> Node) -> NodeHandle {
+        self.spawn_with(id, self.config.clone(), build)
+    }
+
+    /// Start node `id` from a config of its own rather than the cluster's
+    pub fn spawn_with(&mut self, id: u32, config: Config, build: impl FnOnce(Node) -> Node) -> NodeHandle {
+        let (node, _) = Node::new(id, config).unwrap();
+        let node = build(node);
+        let handle = node.handle();
+        self.handles.push(handle.clone());
+        self.tasks.push(tokio::spawn(node.run()));
+        handle
+    }
+
+    pub fn handle(&self, id: u32) -> &NodeHandle {
+        self.handles.iter().find(|handle| handle.node_id() == id).expect("node in cluster")
+    }
+
+    /// Cut node `id` off the `MemoryNetwork` and stop it, as if it crashed
+    pub fn kill(&mut self, id: u32) {
+        let address = self.config.nodes.iter().find(|node| node.id == id).unwrap().bind_address.clone();
+        self.network.as_ref().expect("memory cluster").kill(&address);
+        let index = self.handles.iter().position(|handle| handle.node_id() == id).unwrap();
+        self.handles.remove(index);
+        self.tasks.remove(index).abort();
+    }
+
+    /// Ask node `id` to leave as on SIGTERM and wait for its `run` to return
+    pub async fn shut_down(&mut self, id: u32) {
+        let index = self.handles.iter().position(|handle| handle.node_id() == id).unwrap();
+        self.handles.remove(index).shutdown();
+        self.tasks.remove(index).await.unwrap().unwrap();
+    }
+
+    /// Dial node `target` on the `MemoryNetwork` from `address`, introducing
+    /// the connection as member `id`, as a real or forged peer would
+    pub async fn dial_as(&self, id: u32, address: &str, target: u32) -> PeerConnection {
+        let target = &self.config.nodes.iter().find(|node| node.id == target).unwrap().bind_address;
+        let network = self.network.as_ref().expect("memory cluster");
+        let conn = PeerConnection::from_stream(network.transport(address).connect(target).await.unwrap());
+        let hello = Message::Hello {
+            node_id: id,
+            address: address.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        };
+        conn.send(&hello).await.unwrap();
+        conn
+    }
+
+    /// Dial node `target` on the `MemoryNetwork` from `address` as a client,
+    /// which sends its requests without introducing itself
+    pub async fn dial_client(&self, address: &str, target: u32) -> PeerConnection {
+        let target = &self.config.nodes.iter().find(|node| node.id == target).unwrap().bind_address;
+        let network = self.network.as_ref().expect("memory cluster");
+        PeerConnection::from_stream(network.transport(address).connect(target).await.unwrap())
+    }
+
+    /// Wait until every running node follows one leader that knows it leads
+    pub async fn agreed_leader(&self) -> u32 {
+        let mut changes: Vec<_> = self.handles.iter().map(NodeHandle::leader_changes).collect();
+        loop {
+            for rx in &mut changes {
+                rx.mark_unchanged();
+            }
+            if let Some(leader) = self.leader_if_agreed().await {
+                return leader;
+            }
+            // Look again once any node's leadership changes, rather than
+            // polling; the timeout only guards against a missed change
+            let any_change = futures::future::select_all(changes.iter_mut().map(|rx| Box::pin(rx.changed())));
+            let _ = tokio::time::timeout(Duration::from_millis(100), any_change).await;
+        }
+    }
+
+    /// The leader every running node follows, if they agree on one and it
+    /// knows it leads
+    pub async fn leader_if_agreed(&self) -> Option<u32> {
+        let mut leaders = Vec::new();
+        for handle in &self.handles {
+            leaders.push(handle.current_leader().await);
+        }
+        let leader = (*leaders.first()?)?;
+        if !leaders.iter().all(|&l| l == Some(leader)) {
+            return None;
+        }
+        let handle = self.handles.iter().find(|handle| handle.node_id() == leader)?;
+        handle.is_leader().await.then_some(leader)
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Loading cluster configs from JSON files and `CLOUD_NODES`-style node
+//! lists, and the mistakes they are refused for
+
+use cloud_p2p::node::{parse_node_list, LOCALHOST_BASE_PORT};
+use cloud_p2p::Config;
+use std::path::PathBuf;
+
+/// A config file of its own in the temp directory, removed on drop
+struct ConfigFile(PathBuf);
+
+impl ConfigFile {
+    fn new(name: &str, json: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cloud-p2p-config-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, json).unwrap();
+        Self(path)
+    }
+
+    fn load(&self) -> anyhow::Result<Config> {
+        Config::from_file(self.0.to_str().unwrap())
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// The full error chain `from_file` gives for `json`
+fn refusal(name: &str, json: &str) -> String {
+    let file = ConfigFile::new(name, json);
+    format!("{:#}", file.load().expect_err("a malformed config was accepted"))
+}
+
+#[test]
+fn a_well_formed_config_loads() {
+    let file = ConfigFile::new(
+        "valid",
+        r#"{"nodes": [{"id": 0, "address": "10.0.0.1:8080"}, {"id": 1, "address": "node-1.local:8080"}]}"#,
+    );
+    let config = file.load().unwrap();
+    assert_eq!(config.nodes.len(), 2);
+    assert_eq!(config.nodes[1].bind_address, "node-1.local:8080");
+}
+
+#[test]
+fn each_mistake_is_refused_with_its_own_error() {
+    let cases = [
+        ("empty", r#"{"nodes": []}"#, "The config lists no nodes"),
+        (
+            "duplicate-id",
+            r#"{"nodes": [{"id": 0, "address": "10.0.0.1:8080"}, {"id": 0, "address": "10.0.0.2:8080"}]}"#,
+            "Node ID 0 is listed more than once",
+        ),
+        (
+            "duplicate-address",
+            r#"{"nodes": [{"id": 0, "address": "10.0.0.1:8080"}, {"id": 1, "address": "10.0.0.1:8080"}]}"#,
+            "Nodes 0 and 1 are both reached at 10.0.0.1:8080",
+        ),
+        (
+            "no-port",
+            r#"{"nodes": [{"id": 0, "address": "10.0.0.1"}]}"#,
+            "Node 0 has a bad bind address: \"10.0.0.1\" is not a valid address",
+        ),
+        (
+            "bad-port",
+            r#"{"nodes": [{"id": 3, "address": "10.0.0.1:99999"}]}"#,
+            "Node 3 has a bad bind address: \"10.0.0.1:99999\" is not a valid address",
+        ),
+    ];
+    for (name, json, expected) in cases {
+        let error = refusal(name, json);
+        assert!(error.contains(expected), "{}: {}", name, error);
+    }
+}
+
+#[test]
+fn a_file_that_is_not_json_names_the_file() {
+    let file = ConfigFile::new("garbled", "nodes: [0, 1]");
+    let error = format!("{:#}", file.load().expect_err("garbage was accepted"));
+    assert!(error.starts_with(&format!("Failed to parse config {}", file.0.display())), "{}", error);
+}
+
+#[test]
+fn a_node_list_from_the_environment_stands_alone() {
+    let nodes = parse_node_list(" 0@10.0.0.1:8080, 1@node-1.local:8080 ,").unwrap();
+    let config = Config {
+        nodes,
+        ..Config::default()
+    };
+    config.validate().unwrap();
+    let listed: Vec<(u32, &str)> = config.nodes.iter().map(|node| (node.id, node.bind_address.as_str())).collect();
+    assert_eq!(listed, [(0, "10.0.0.1:8080"), (1, "node-1.local:8080")]);
+
+    for (spec, expected) in [
+        ("0@10.0.0.1:8080,1", "\"1\" is not an id@address entry"),
+        ("zero@10.0.0.1:8080", "\"zero@10.0.0.1:8080\" does not start with a node ID"),
+        ("0@10.0.0.1:8080,0@10.0.0.2:8080", "Node ID 0 is listed more than once"),
+    ] {
+        let error = format!("{:#}", parse_node_list(spec).expect_err(spec));
+        assert!(error.starts_with(expected), "{}: {}", spec, error);
+    }
+}
+
+#[test]
+fn the_environment_moves_and_adds_to_the_nodes_of_a_file() {
+    let file = ConfigFile::new(
+        "merged",
+        r#"{"nodes": [
+            {"id": 0, "address": "10.0.0.1:8080", "priority": 5},
+            {"id": 1, "address": "10.0.0.2:8080", "observer": true}
+        ]}"#,
+    );
+    let mut config = Config::load(file.0.to_str().unwrap()).unwrap();
+    config.merge_nodes(parse_node_list("1@10.0.1.2:9090,2@10.0.1.3:9090").unwrap());
+    config.validate().unwrap();
+
+    // Node 0 is as the file has it; node 1 moves but stays an observer
+    let [zero, one, two] = &config.nodes[..] else {
+        panic!("expected three nodes, got {:?}", config.nodes);
+    };
+    assert_eq!((zero.bind_address.as_str(), zero.priority), ("10.0.0.1:8080", 5));
+    assert_eq!((one.bind_address.as_str(), one.observer), ("10.0.1.2:9090", true));
+    assert_eq!((two.id, two.bind_address.as_str(), two.observer), (2, "10.0.1.3:9090", false));
+}
+
+#[test]
+fn default_nodes_listen_at_the_base_port_plus_their_id() {
+    for count in [1, 3, 10] {
+        let config = Config::localhost(count);
+        config.validate().unwrap();
+        let listed: Vec<(u32, String)> = config.nodes.iter().map(|node| (node.id, node.bind_address.clone())).collect();
+        let expected: Vec<(u32, String)> =
+            (0..count).map(|id| (id, format!("127.0.0.1:{}", u32::from(LOCALHOST_BASE_PORT) + id))).collect();
+        assert_eq!(listed, expected);
+    }
+    assert_eq!(LOCALHOST_BASE_PORT, 8080);
+}
@@ -0,0 +1,710 @@
+//! Elections among embedded nodes on a `MemoryNetwork`, under tokio's paused
+//! clock: default timings, with idle stretches skipped instantly
+
+mod common;
+
+use cloud_p2p::fault::FaultyTransport;
+use cloud_p2p::message::{ClusterKey, Message, PROTOCOL_VERSION};
+use cloud_p2p::network::PeerConnection;
+use cloud_p2p::node::{select_successor, select_successors};
+use cloud_p2p::{Config, LeaderChangeReason, LeaderState, Node, Timings};
+use common::{fast_timings, memory_nodes, Cluster};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+/// Virtual time allowed for a cluster to settle
+const SETTLE: Duration = Duration::from_secs(60);
+
+#[tokio::test(start_paused = true)]
+async fn five_nodes_replace_a_killed_leader() {
+    let config = Config {
+        nodes: memory_nodes(5),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    // Let a few coordinator rounds name a successor
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let before = cluster.handle(leader).snapshot().await;
+    assert_eq!(before.alive_nodes, [0, 1, 2, 3, 4]);
+    let successor = before.successor.expect("leader named no successor");
+
+    cluster.kill(leader);
+    let new_leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    assert_eq!(new_leader, successor);
+    let after = cluster.handle(new_leader).snapshot().await;
+    assert!(after.term > before.term);
+    assert_ne!(after.successor, Some(leader));
+
+    // Heartbeats from the survivors rebuild the new leader's alive set
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let survivors: Vec<u32> = (0..5).filter(|&id| id != leader).collect();
+    assert_eq!(cluster.handle(new_leader).snapshot().await.alive_nodes, survivors);
+}
+
+#[tokio::test(start_paused = true)]
+async fn failover_runs_in_simulated_time() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    // The survivors last heard the leader up to one coordinator round
+    // before the kill, and time its silence from then
+    let silence = config.timings.failure_timeout - config.timings.coordinator_interval;
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let wall = std::time::Instant::now();
+    let virtual_start = tokio::time::Instant::now();
+    cluster.kill(leader);
+    let new_leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    let (wall, simulated) = (wall.elapsed(), virtual_start.elapsed());
+
+    assert_ne!(new_leader, leader);
+    // The survivors really waited out the failure timeout, on a clock that
+    // skipped ahead instead of sleeping
+    assert!(simulated >= silence, "failed over after {:?}", simulated);
+    assert!(wall < Duration::from_millis(10), "failover took {:?} of wall time", wall);
+}
+
+/// Simulated time from killing a settled three-node cluster's leader to the
+/// survivors agreeing on another, under `timings`
+async fn failover_time(timings: Timings) -> Duration {
+    let config = Config {
+        nodes: memory_nodes(3),
+        timings,
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let killed = tokio::time::Instant::now();
+    cluster.kill(leader);
+    timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    killed.elapsed()
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_configured_failure_timeout_sets_the_failover_time() {
+    for failure_timeout in [Duration::from_secs(6), Duration::from_secs(30)] {
+        let timings = Timings {
+            failure_timeout,
+            ..Timings::default()
+        };
+        let took = failover_time(timings).await;
+        let earliest = failure_timeout - timings.coordinator_interval;
+        let latest = failure_timeout + Duration::from_secs(3);
+        assert!(took >= earliest && took <= latest, "failed over after {:?} at {:?}", took, failure_timeout);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn sub_second_timings_fail_over_in_well_under_a_second() {
+    // Failure checks follow the heartbeat rather than a fixed one-second tick
+    let timings = fast_timings();
+    let took = failover_time(timings).await;
+    let latest = timings.failure_timeout + timings.probe_timeout + timings.heartbeat_interval * 4;
+    assert!(took <= latest, "failed over after {:?}", took);
+}
+
+#[test]
+fn a_failure_timeout_shorter_than_three_heartbeats_is_refused() {
+    let timings = Timings {
+        heartbeat_interval: Duration::from_secs(2),
+        failure_timeout: Duration::from_secs(5),
+        ..Timings::default()
+    };
+    let config = Config {
+        nodes: memory_nodes(3),
+        timings,
+        ..Config::default()
+    };
+    let error = Node::new(0, config).err().expect("node built with a failure timeout under 3 heartbeats");
+    assert!(error.to_string().contains("at least 3x the heartbeat interval"), "{}", error);
+}
+
+/// Election and takeover messages sent while the leader and its successor
+/// both die and nodes 0 to 2, none of them in line, notice at once
+async fn election_traffic(election_jitter: Duration) -> usize {
+    let mut config = Config {
+        nodes: memory_nodes(5),
+        successor_depth: 1,
+        ..Config::default()
+    };
+    config.timings.election_jitter = election_jitter;
+    let sent = Arc::new(AtomicUsize::new(0));
+    let counted = sent.clone();
+    let mut cluster = Cluster::memory_with(config, move |id, transport| {
+        let counted = counted.clone();
+        let count = move |message: &Message| {
+            if matches!(message, Message::Election { .. } | Message::Takeover { .. }) {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+            false
+        };
+        Arc::new(FaultyTransport::new(transport, u64::from(id)).with_drop_rate(count, 0.0))
+    });
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader"), 4);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    sent.store(0, Ordering::SeqCst);
+    cluster.kill(4);
+    cluster.kill(3);
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no new leader"), 2);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    sent.load(Ordering::SeqCst)
+}
+
+#[tokio::test(start_paused = true)]
+async fn followers_tripping_together_do_not_all_call_elections() {
+    // Without the backoff nodes 0 and 1 both challenge the nodes above them
+    assert_eq!(election_traffic(Duration::ZERO).await, 3);
+
+    // With it node 2 draws the earliest slot and has won before the others wake
+    assert_eq!(election_traffic(Timings::default().election_jitter).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn asking_the_sitting_leader_to_lead_keeps_its_followers() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let before = cluster.handle(leader).snapshot().await;
+    assert_eq!(before.alive_nodes, [0, 1, 2]);
+
+    // A member outside the config dials the leader directly
+    let network = cluster.network.as_ref().unwrap();
+    let leader_address = &cluster.config.nodes[leader as usize].bind_address;
+    let stream = network.transport("127.0.0.1:9107").connect(leader_address).await.unwrap();
+    let conn = PeerConnection::from_stream(stream);
+    conn.send(&Message::Hello {
+        node_id: 7,
+        address: "127.0.0.1:9107".into(),
+        protocol_version: PROTOCOL_VERSION,
+    })
+    .await
+    .unwrap();
+
+    // A duplicate takeover, then a stale hand-off naming the leader as its
+    // own successor, which reaches `become_leader` while it already leads
+    conn.send(&Message::Takeover { from_id: 7, correlation_id: 42 }).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(cluster.handle(leader).snapshot().await, before);
+
+    conn.send(&Message::Resign {
+        leader_id: leader,
+        successor_id: Some(leader),
+    })
+    .await
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(cluster.handle(leader).snapshot().await, before);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_coordinator_from_an_older_term_is_discarded() {
+    // Node 0 is configured but never started, so it can be forged
+    let config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory_idle(config);
+    for id in 1..4 {
+        cluster.start(id);
+    }
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let term = cluster.handle(leader).snapshot().await.term;
+    let follower = (1..4).find(|&id| id != leader).unwrap();
+    let conn = cluster.dial_as(0, "127.0.0.1:8080", follower).await;
+    let coordinator = |leader_id, term| Message::Coordinator {
+        leader_id,
+        successor_id: None,
+        backup_successors: Vec::new(),
+        term,
+        correlation_id: 0,
+    };
+
+    // A newer term is followed, and the sitting leader's own coordinator,
+    // arriving after it, is now stale
+    conn.send(&coordinator(0, term + 1)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    conn.send(&coordinator(leader, term)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let view = cluster.handle(follower).snapshot().await;
+    assert_eq!((view.leader, view.term), (Some(0), term + 1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_shut_down_leader_hands_off_to_its_successor_at_once() {
+    let config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    let failure_timeout = config.timings.failure_timeout;
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let successor = cluster.handle(leader).snapshot().await.successor.expect("no successor");
+
+    // As the binary does on SIGTERM: the leader resigns naming its successor
+    cluster.shut_down(leader).await;
+    let handed_off = timeout(failure_timeout / 2, cluster.agreed_leader()).await.expect("waited out the failure timeout");
+    assert_eq!(handed_off, successor);
+    assert_eq!(cluster.handle(successor).leader_changes().borrow().reason, LeaderChangeReason::Handoff);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_resignation_naming_a_dead_successor_falls_back_to_an_election() {
+    let config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let successor = cluster.handle(leader).snapshot().await.successor.expect("no successor");
+
+    // The successor dies, and the leader resigns in its favour before noticing
+    cluster.kill(successor);
+    cluster.shut_down(leader).await;
+    let remaining: Vec<u32> = (0..4).filter(|&id| id != leader && id != successor).collect();
+    let next = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the dead hand-off");
+    assert_eq!(next, *remaining.iter().max().unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_node_leads_only_while_it_sees_a_quorum() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        min_quorum: 2,
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory_idle(config);
+
+    // Alone, the highest ranked node never declares itself leader
+    let states = record(cluster.start(2).leader_changes());
+    tokio::time::sleep(SETTLE).await;
+    assert!(!cluster.handle(2).is_leader().await);
+    assert!(states.lock().unwrap().iter().all(|state| !state.am_i_leader), "{:?}", states);
+
+    // A second node makes a quorum, and losing it again ends the leadership
+    cluster.start(0);
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader with a quorum"), 2);
+    cluster.kill(0);
+    tokio::time::sleep(SETTLE).await;
+    let state = *cluster.handle(2).leader_changes().borrow();
+    assert!(!state.am_i_leader);
+    assert_eq!(state.reason, LeaderChangeReason::NoQuorum);
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_observer_with_the_highest_id_never_leads() {
+    let mut config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    config.nodes[2].observer = true;
+    let mut cluster = Cluster::memory(config);
+    let states = record(cluster.handle(2).leader_changes());
+
+    // It is never named successor, and follows the next candidate once the
+    // leader dies
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader"), 1);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_ne!(cluster.handle(1).snapshot().await.successor, Some(2));
+    cluster.kill(1);
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no second leader"), 0);
+    assert!(!cluster.handle(2).is_leader().await);
+    assert!(states.lock().unwrap().iter().all(|state| !state.am_i_leader), "{:?}", states);
+}
+
+/// Ask node `target` to force an election, as `cloud-node force-election` does
+async fn force_election(cluster: &Cluster, target: u32, key: Option<ClusterKey>) {
+    let conn = cluster.dial_client("127.0.0.1:9100", target).await.with_cluster_key(key);
+    conn.send(&Message::ForceElection { requester_id: 9 }).await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_forced_election_replaces_the_leader_only_in_a_keyed_cluster() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory_idle(config);
+    for id in 0..3 {
+        let transport = cluster.network.as_ref().unwrap().transport(&cluster.config.nodes[id as usize].bind_address);
+        let key = ClusterKey::new(b"secret").unwrap();
+        cluster.spawn(id, |node| node.with_transport(transport).with_cluster_key(key));
+    }
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let successor = cluster.handle(leader).snapshot().await.successor.expect("no successor");
+
+    // Sent to a follower, the request is passed on to the leader
+    let follower = (0..3).find(|&id| id != leader && id != successor).unwrap();
+    force_election(&cluster, follower, Some(ClusterKey::new(b"secret").unwrap())).await;
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(cluster.leader_if_agreed().await, Some(successor));
+
+    // Without a cluster key anyone could ask, so no one may
+    let unkeyed = Cluster::memory(Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    });
+    let leader = timeout(SETTLE, unkeyed.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    force_election(&unkeyed, leader, None).await;
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(unkeyed.leader_if_agreed().await, Some(leader));
+}
+
+/// Every state `rx` publishes from now on
+fn record(mut rx: watch::Receiver<LeaderState>) -> Arc<Mutex<Vec<LeaderState>>> {
+    rx.mark_unchanged();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    tokio::spawn({
+        let seen = seen.clone();
+        async move {
+            while rx.changed().await.is_ok() {
+                seen.lock().unwrap().push(*rx.borrow_and_update());
+            }
+        }
+    });
+    seen
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_failover_publishes_one_state_per_change() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let view = cluster.handle(leader).snapshot().await;
+    let successor = view.successor.expect("no successor");
+    let follower = (0..3).find(|&id| id != leader && id != successor).unwrap();
+    let on_successor = record(cluster.handle(successor).leader_changes());
+    let on_follower = record(cluster.handle(follower).leader_changes());
+
+    cluster.kill(leader);
+    timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let term = view.term + 1;
+    let took_over = LeaderState {
+        leader: Some(successor),
+        am_i_leader: true,
+        term,
+        reason: LeaderChangeReason::SuccessorTakeover,
+    };
+    let followed = LeaderState {
+        leader: Some(successor),
+        am_i_leader: false,
+        term,
+        reason: LeaderChangeReason::Announced,
+    };
+    assert_eq!(*on_successor.lock().unwrap(), [took_over]);
+    assert_eq!(*on_follower.lock().unwrap(), [followed]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn higher_priority_outranks_a_higher_id() {
+    let mut config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    config.nodes[0].priority = 10;
+    let mut cluster = Cluster::memory_idle(config);
+    for id in 1..4 {
+        cluster.start(id);
+    }
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    assert_ne!(leader, 0);
+
+    // Node 0 starts late, under the sitting leader, but outranks every
+    // other follower and so becomes the successor, then the leader
+    cluster.start(0);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(cluster.handle(leader).snapshot().await.successor, Some(0));
+
+    cluster.kill(leader);
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill"), 0);
+}
+
+/// On worker threads and real time, so the sampler reads snapshots while
+/// the nodes' tasks are midway through changing them
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn snapshots_never_show_half_a_failover() {
+    let config = Config {
+        nodes: memory_nodes(5),
+        timings: fast_timings(),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+    let settle = Duration::from_secs(10);
+    let leader = timeout(settle, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Sample every survivor throughout the failover and the heartbeats
+    // that rebuild the new leader's alive set
+    let survivors: Vec<_> = cluster.handles.iter().filter(|handle| handle.node_id() != leader).cloned().collect();
+    let sampler = tokio::spawn(async move {
+        let mut samples = 0;
+        let until = tokio::time::Instant::now() + Duration::from_secs(3);
+        while tokio::time::Instant::now() < until {
+            for handle in &survivors {
+                let view = handle.snapshot().await;
+                assert_eq!(view.am_i_leader, view.leader == Some(view.node_id), "{:?}", view);
+                if view.am_i_leader {
+                    assert!(view.alive_nodes.contains(&view.node_id), "{:?}", view);
+                    assert_ne!(view.successor, Some(view.node_id), "{:?}", view);
+                } else {
+                    assert!(view.alive_nodes.is_empty(), "{:?}", view);
+                }
+                samples += 1;
+            }
+            tokio::task::yield_now().await;
+        }
+        samples
+    });
+
+    cluster.kill(leader);
+    let new_leader = timeout(settle, cluster.agreed_leader()).await.expect("no leader after the kill");
+    assert_ne!(new_leader, leader);
+    assert!(sampler.await.unwrap() > 1000);
+}
+
+#[tokio::test(start_paused = true)]
+async fn two_leaders_reconcile_to_one_when_a_partition_heals() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    // Cut the leader off without stopping it: it leads alone, and the
+    // others elect a leader of their own
+    let network = cluster.network.clone().unwrap();
+    let address = &cluster.config.nodes[leader as usize].bind_address;
+    network.kill(address);
+    let rival = timeout(SETTLE, async {
+        loop {
+            for handle in cluster.handles.iter().filter(|handle| handle.node_id() != leader) {
+                if handle.is_leader().await {
+                    return handle.node_id();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("the majority elected no leader");
+    assert!(cluster.handle(leader).is_leader().await);
+
+    // Once the partition heals, one of the two steps down and every node
+    // follows the other. The lower one may briefly win agreement before
+    // the higher one bullies it aside, so judge only once that has played out.
+    network.revive(address);
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    let survivor = timeout(SETTLE, cluster.agreed_leader()).await.expect("no agreement after the heal");
+    assert!([leader, rival].contains(&survivor));
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let mut leaders = Vec::new();
+    for handle in &cluster.handles {
+        if handle.is_leader().await {
+            leaders.push(handle.node_id());
+        }
+    }
+    assert_eq!(leaders, [survivor]);
+    assert_eq!(cluster.leader_if_agreed().await, Some(survivor));
+}
+
+#[test]
+fn the_successor_is_the_highest_ranked_alive_non_leader() {
+    let mut rng = StdRng::seed_from_u64(44);
+    for round in 0..2_000 {
+        // Up to eight nodes with a few priorities, some observers, and any
+        // subset alive; the leader need not be among them
+        let mut nodes = memory_nodes(rng.gen_range(1..=8));
+        for node in &mut nodes {
+            node.priority = rng.gen_range(0..3);
+            node.observer = rng.gen_bool(0.2);
+        }
+        let alive: Vec<u32> = nodes.iter().map(|node| node.id).filter(|_| rng.gen_bool(0.6)).collect();
+        let leader = rng.gen_range(0..nodes.len() as u32);
+
+        let candidates: Vec<_> = nodes
+            .iter()
+            .filter(|node| alive.contains(&node.id) && node.id != leader && !node.observer)
+            .collect();
+        let expected = candidates.iter().max_by_key(|node| (node.priority, node.id)).map(|node| node.id);
+        let context = format!("round {}: leader {}, alive {:?}, nodes {:?}", round, leader, alive, nodes);
+        assert_eq!(select_successor(&nodes, leader, alive.iter().copied()), expected, "{}", context);
+
+        // The line of succession starts with that pick and runs down the ranks
+        let line = select_successors(&nodes, leader, alive.iter().copied(), nodes.len());
+        assert_eq!(line.first().copied(), expected, "{}", context);
+        assert_eq!(line.len(), candidates.len(), "{}", context);
+        let ranks: Vec<_> = line.iter().map(|&id| (nodes[id as usize].priority, id)).collect();
+        assert!(ranks.windows(2).all(|pair| pair[0] > pair[1]), "{}: line {:?}", context, line);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_takeover_hands_its_correlation_id_to_the_new_leaders_coordinator() {
+    let config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    // Correlation IDs of the Takeovers sent, and of the Coordinators node 2 sends
+    let takeovers = Arc::new(Mutex::new(Vec::new()));
+    let coordinators = Arc::new(Mutex::new(Vec::new()));
+    let mut cluster = Cluster::memory_idle(config.clone());
+    for id in 0..4 {
+        let (takeovers, coordinators) = (takeovers.clone(), coordinators.clone());
+        let record = move |message: &Message| {
+            match *message {
+                Message::Takeover { correlation_id, .. } => takeovers.lock().unwrap().push(correlation_id),
+                Message::Coordinator { leader_id: 2, correlation_id, .. } => {
+                    coordinators.lock().unwrap().push(correlation_id)
+                }
+                _ => {}
+            }
+            false
+        };
+        let address = &config.nodes[id as usize].bind_address;
+        let transport = cluster.network.as_ref().unwrap().transport(address);
+        let transport = Arc::new(FaultyTransport::new(transport, u64::from(id)).with_drop_rate(record, 0.0));
+        // The successor waits so long on its own ping that only a Takeover can
+        // make it act
+        let mut config = config.clone();
+        if id == 2 {
+            config.timings.probe_timeout = Duration::from_secs(600);
+        }
+        cluster.spawn_with(id, config, |node| node.with_transport(transport));
+    }
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader"), 3);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(cluster.handle(3).snapshot().await.successor, Some(2));
+    // Node 2 may have led briefly while the cluster formed
+    coordinators.lock().unwrap().clear();
+
+    cluster.kill(3);
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no new leader"), 2);
+    let takeovers = takeovers.lock().unwrap().clone();
+    let coordinators = coordinators.lock().unwrap().clone();
+    assert!(!takeovers.is_empty());
+    assert!(takeovers.iter().all(|&correlation_id| correlation_id != 0), "{:?}", takeovers);
+    assert!(takeovers.contains(&coordinators[0]), "{:?} after takeovers {:?}", coordinators, takeovers);
+}
+
+#[tokio::test(start_paused = true)]
+async fn with_leader_and_successor_gone_the_next_node_wins_the_election_alone() {
+    let config = Config {
+        nodes: memory_nodes(5),
+        successor_depth: 1,
+        ..Config::default()
+    };
+    let answers = Arc::new(AtomicUsize::new(0));
+    let mut cluster = Cluster::memory_idle(config.clone());
+    for id in 0..5 {
+        let counted = answers.clone();
+        let count = move |message: &Message| {
+            if matches!(message, Message::ElectionOk { .. }) {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+            false
+        };
+        let address = &config.nodes[id as usize].bind_address;
+        let transport = cluster.network.as_ref().unwrap().transport(address);
+        let transport = Arc::new(FaultyTransport::new(transport, u64::from(id)).with_drop_rate(count, 0.0));
+        // Node 2 is slow to give up on the leader, so nodes 0 and 1 call their
+        // elections first and only its answers hold them back
+        let mut config = config.clone();
+        if id == 2 {
+            config.timings.probe_timeout = Duration::from_secs(5);
+        }
+        cluster.spawn_with(id, config, |node| node.with_transport(transport));
+    }
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader"), 4);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let states: Vec<_> = (0..2).map(|id| record(cluster.handle(id).leader_changes())).collect();
+    answers.store(0, Ordering::SeqCst);
+
+    cluster.kill(4);
+    cluster.kill(3);
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no new leader"), 2);
+    let state = *cluster.handle(2).leader_changes().borrow();
+    assert_eq!(state.reason, LeaderChangeReason::LastNodeStanding);
+
+    // Nodes 0 and 1 were answered by those above them and never claimed to lead
+    assert!(answers.load(Ordering::SeqCst) > 0);
+    for (id, states) in states.iter().enumerate() {
+        let states = states.lock().unwrap();
+        assert!(states.iter().all(|state| !state.am_i_leader), "Node {}: {:?}", id, states);
+    }
+}
+
+/// Kill a settled five-node cluster's leader, node 4, and its successor,
+/// node 3, together: the simulated time until the rest agree on node 2, how
+/// node 2 came to lead, and the elections called on the way
+async fn double_failover(successor_depth: usize) -> (Duration, LeaderChangeReason, usize) {
+    let config = Config {
+        nodes: memory_nodes(5),
+        successor_depth,
+        ..Config::default()
+    };
+    let elections = Arc::new(AtomicUsize::new(0));
+    let counted = elections.clone();
+    let mut cluster = Cluster::memory_with(config, move |id, transport| {
+        let counted = counted.clone();
+        let count = move |message: &Message| {
+            if matches!(message, Message::Election { .. }) {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+            false
+        };
+        Arc::new(FaultyTransport::new(transport, u64::from(id)).with_drop_rate(count, 0.0))
+    });
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader"), 4);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    elections.store(0, Ordering::SeqCst);
+
+    let killed = tokio::time::Instant::now();
+    cluster.kill(4);
+    cluster.kill(3);
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no new leader"), 2);
+    let took = killed.elapsed();
+    let reason = cluster.handle(2).leader_changes().borrow().reason;
+    (took, reason, elections.load(Ordering::SeqCst))
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_next_in_line_takes_over_when_the_leader_and_successor_fail_together() {
+    let timings = Timings::default();
+    let (alone, reason, _) = double_failover(1).await;
+    assert_eq!(reason, LeaderChangeReason::LastNodeStanding);
+
+    // Named as backup, node 2 finds node 3 down and takes over without an
+    // election, in about the time losing the leader alone takes
+    let (in_line, reason, elections) = double_failover(2).await;
+    assert_eq!(reason, LeaderChangeReason::SuccessorTakeover);
+    assert_eq!(elections, 0);
+    assert!(in_line <= timings.failure_timeout + timings.probe_timeout, "took over after {:?}", in_line);
+    assert!(in_line + timings.takeover_timeout <= alone, "{:?} in line, {:?} alone", in_line, alone);
+}
@@ -0,0 +1,190 @@
+//! Elections over a `FaultyTransport` that drops or delays selected messages
+
+mod common;
+
+use cloud_p2p::fault::FaultyTransport;
+use cloud_p2p::message::Message;
+use cloud_p2p::Config;
+use common::{memory_nodes, Cluster};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+#[tokio::test(start_paused = true)]
+async fn cluster_converges_with_thirty_percent_of_heartbeats_dropped() {
+    let config = Config {
+        nodes: memory_nodes(5),
+        ..Config::default()
+    };
+    let cluster = Cluster::memory_with(config, |id, transport| {
+        Arc::new(
+            FaultyTransport::new(transport, 0x5eed + u64::from(id))
+                .with_drop_rate(|message| matches!(message, Message::Heartbeat { .. }), 0.3),
+        )
+    });
+
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+
+    // A minute of lost heartbeats: every 100 ms, all nodes still follow the
+    // same leader and no other node believes it leads
+    for _ in 0..600 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cluster.leader_if_agreed().await, Some(leader));
+        for handle in &cluster.handles {
+            assert_eq!(handle.is_leader().await, handle.node_id() == leader);
+        }
+    }
+
+    // The faults were live: followers sent heartbeats the leader never saw
+    let mut sent = 0;
+    for handle in cluster.handles.iter().filter(|handle| handle.node_id() != leader) {
+        for traffic in handle.peer_traffic().await {
+            if traffic.peer == leader && traffic.msg_type == "Heartbeat" {
+                sent += traffic.sent_messages;
+            }
+        }
+    }
+    let received: u64 = cluster
+        .handle(leader)
+        .peer_traffic()
+        .await
+        .iter()
+        .filter(|traffic| traffic.msg_type == "Heartbeat")
+        .map(|traffic| traffic.received_messages)
+        .sum();
+    let delivered = received as f64 / sent as f64;
+    assert!((0.55..0.85).contains(&delivered), "{} of {} heartbeats delivered", received, sent);
+}
+
+#[tokio::test(start_paused = true)]
+async fn coordinator_delayed_past_the_first_discovery_round_is_still_followed() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let discovery_timeout = config.timings.discovery_timeout;
+    let mut cluster = Cluster::memory_idle(config);
+
+    // Node 0 leads alone, and its answers reach newcomers only after their
+    // first round of discovery has given up on them
+    let transport = cluster.network.as_ref().unwrap().transport("127.0.0.1:8080");
+    let transport = Arc::new(
+        FaultyTransport::new(transport, 0x5eed)
+            .with_delay(|message| matches!(message, Message::Coordinator { .. }), discovery_timeout * 3 / 2),
+    );
+    cluster.spawn(0, |node| node.with_transport(transport));
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader"), 0);
+
+    let mut changes = cluster.start(1).leader_changes();
+    let promoted = Arc::new(AtomicBool::new(false));
+    let watcher = tokio::spawn({
+        let promoted = promoted.clone();
+        async move {
+            while changes.changed().await.is_ok() {
+                if changes.borrow_and_update().am_i_leader {
+                    promoted.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+    });
+
+    // Node 2, never started, asks node 1 while it is still discovering
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let asker = cluster.dial_as(2, "127.0.0.1:8082", 1).await;
+    let who = Message::WhoIsLeader {
+        node_id: 2,
+        from_address: "127.0.0.1:8082".into(),
+    };
+    asker.send(&who).await.unwrap();
+
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader"), 0);
+    assert!(!promoted.load(Ordering::SeqCst), "Node 1 led before the Coordinator arrived");
+    watcher.abort();
+
+    // The question was held through discovery and answered after it
+    let answer = timeout(SETTLE, async {
+        loop {
+            if let Message::Coordinator { leader_id, .. } = asker.receive_one().await.unwrap() {
+                return leader_id;
+            }
+        }
+    })
+    .await
+    .expect("Node 1 never answered WhoIsLeader");
+    assert_eq!(answer, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_leader_that_stops_announcing_but_answers_pings_is_kept() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    // Node 2 will lead. Once silent it sends neither heartbeats nor election
+    // answers, so only its pongs can show it is alive; then those stop too.
+    let silent = Arc::new(AtomicBool::new(false));
+    let unreachable = Arc::new(AtomicBool::new(false));
+    let cluster = Cluster::memory_with(config, |id, transport| {
+        if id != 2 {
+            return transport;
+        }
+        let (silent, unreachable) = (silent.clone(), unreachable.clone());
+        let dropped = move |message: &Message| match message {
+            Message::Coordinator { .. } | Message::HeartbeatAck { .. } | Message::ElectionOk { .. } => {
+                silent.load(Ordering::SeqCst)
+            }
+            Message::Pong { .. } => unreachable.load(Ordering::SeqCst),
+            _ => false,
+        };
+        Arc::new(FaultyTransport::new(transport, 0x5eed).with_drop_rate(dropped, 1.0))
+    });
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader"), 2);
+    let term = cluster.handle(0).snapshot().await.term;
+
+    // A minute without a heartbeat from it, yet it still leads the same term
+    silent.store(true, Ordering::SeqCst);
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(cluster.leader_if_agreed().await, Some(2));
+    assert_eq!(cluster.handle(0).snapshot().await.term, term);
+
+    // Once the pongs stop too, the followers move on
+    unreachable.store(true, Ordering::SeqCst);
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(cluster.handle(0).current_leader().await, Some(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_follower_gone_silent_is_dropped_and_replaced_as_successor() {
+    let config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    let stale_node_timeout = config.timings.stale_node_timeout;
+    // Node 2 stays connected, but while silenced nothing it sends arrives
+    let silenced = Arc::new(AtomicBool::new(false));
+    let cluster = Cluster::memory_with(config, |id, transport| {
+        let silenced = silenced.clone();
+        let silent = move |_: &Message| id == 2 && silenced.load(Ordering::SeqCst);
+        Arc::new(FaultyTransport::new(transport, 0x5eed + u64::from(id)).with_drop_rate(silent, 1.0))
+    });
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader"), 3);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(cluster.handle(3).snapshot().await.successor, Some(2));
+
+    silenced.store(true, Ordering::SeqCst);
+    tokio::time::sleep(stale_node_timeout + Duration::from_secs(3)).await;
+    let view = cluster.handle(3).snapshot().await;
+    assert_eq!(view.alive_nodes, [0, 1, 3]);
+    assert_eq!(view.successor, Some(1));
+    assert_eq!(cluster.handle(0).snapshot().await.successor, Some(1));
+
+    // Heard from again, it is back in line
+    silenced.store(false, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let view = cluster.handle(3).snapshot().await;
+    assert_eq!(view.alive_nodes, [0, 1, 2, 3]);
+    assert_eq!(view.successor, Some(2));
+}
@@ -0,0 +1,36 @@
+//! Steering elections with `set_id_order`. The order is process-wide, so
+//! these tests live in their own binary, apart from the ones expecting
+//! numeric order.
+
+mod common;
+
+use cloud_p2p::node::set_id_order;
+use cloud_p2p::Config;
+use common::{memory_nodes, Cluster};
+use std::time::Duration;
+use tokio::time::timeout;
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+#[tokio::test(start_paused = true)]
+async fn reversed_id_order_makes_the_lowest_id_lead() {
+    // In numeric order this cluster elects node 3 and names node 2 its successor
+    set_id_order(|a, b| b.cmp(&a));
+    let config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    assert_eq!(leader, 0);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let view = cluster.handle(0).snapshot().await;
+    assert_eq!(view.successor, Some(1));
+    assert_eq!(view.alive_nodes, [0, 1, 2, 3]);
+
+    // The order also decides who takes over
+    cluster.kill(0);
+    let next = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    assert_eq!(next, 1);
+}
@@ -0,0 +1,871 @@
+//! Image uploads and deletes through the leader of an in-memory cluster
+
+mod common;
+
+use cloud_p2p::fault::FaultyTransport;
+use cloud_p2p::image::{self, ImageStore, ImageVersion, Upload, ANTI_ENTROPY_INTERVAL};
+use cloud_p2p::message::Message;
+use cloud_p2p::network::PeerConnection;
+use cloud_p2p::storage::MemoryStorage;
+use cloud_p2p::transport::{BoxFuture, BoxedStream, Listener, Transport};
+use cloud_p2p::{Config, Timings};
+use common::{memory_nodes, Cluster};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{timeout, Sleep};
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// The client's node ID, allowed to fetch and delete what it uploads
+const OWNER: u32 = 7;
+
+/// Nodes 1 to 3 keeping images in memory, with the storage of each so the
+/// test can look inside, and the settled leader. Node 0 is configured but
+/// never started, so a test can speak for it as a member.
+async fn image_cluster() -> (Cluster, BTreeMap<u32, MemoryStorage>, u32) {
+    image_cluster_with(image_config(), |_, transport| transport).await
+}
+
+fn image_config() -> Config {
+    Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    }
+}
+
+/// [`image_cluster`] from `config`, with each node's transport passed through `wrap`
+async fn image_cluster_with(
+    config: Config,
+    wrap: impl Fn(u32, Arc<dyn Transport>) -> Arc<dyn Transport>,
+) -> (Cluster, BTreeMap<u32, MemoryStorage>, u32) {
+    let mut cluster = Cluster::memory_idle(config);
+    let mut storages = BTreeMap::new();
+    for id in 1..4 {
+        let storage = MemoryStorage::new();
+        let address = cluster.config.nodes[id as usize].bind_address.clone();
+        let transport = wrap(id, cluster.network.as_ref().unwrap().transport(&address));
+        let node_storage = Box::new(storage.clone());
+        cluster.spawn(id, |node| node.with_transport(transport).with_storage(node_storage));
+        storages.insert(id, storage);
+    }
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    (cluster, storages, leader)
+}
+
+fn store(storage: &MemoryStorage) -> ImageStore {
+    ImageStore::with_storage(Box::new(storage.clone()))
+}
+
+/// A new upload of a small PNG, filled with `shade`, under its content ID
+fn upload(shade: u8) -> Upload {
+    png_upload(::image::RgbImage::from_pixel(4, 4, ::image::Rgb([shade; 3])))
+}
+
+/// A new upload of a PNG of noise, too big for one write, seeded by `seed`
+fn large_upload(seed: u32) -> Upload {
+    let mut state = seed;
+    png_upload(::image::RgbImage::from_fn(256, 256, |_, _| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        ::image::Rgb((state >> 8).to_be_bytes()[..3].try_into().unwrap())
+    }))
+}
+
+fn png_upload(pixels: ::image::RgbImage) -> Upload {
+    let mut bytes = Vec::new();
+    pixels
+        .write_to(&mut std::io::Cursor::new(&mut bytes), ::image::ImageFormat::Png)
+        .unwrap();
+    Upload {
+        image_id: image::content_id(&bytes),
+        bytes,
+        allowed_node_ids: vec![OWNER],
+        watermark_owner: None,
+        max_views: None,
+        generation: None,
+    }
+}
+
+/// Passes connections through, handing each one to `wrap` along with the
+/// address at its other end
+struct Links {
+    inner: Arc<dyn Transport>,
+    wrap: StreamWrap,
+}
+
+type StreamWrap = Arc<dyn Fn(BoxedStream, &str) -> BoxedStream + Send + Sync>;
+
+impl Links {
+    fn around(
+        inner: Arc<dyn Transport>,
+        wrap: impl Fn(BoxedStream, &str) -> BoxedStream + Send + Sync + 'static,
+    ) -> Arc<dyn Transport> {
+        Arc::new(Links { inner, wrap: Arc::new(wrap) })
+    }
+}
+
+impl Transport for Links {
+    fn listen<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, anyhow::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let inner = self.inner.listen(addr).await?;
+            Ok(Box::new(LinksListener { inner, wrap: self.wrap.clone() }) as Box<dyn Listener>)
+        })
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, anyhow::Result<BoxedStream>> {
+        Box::pin(async move { Ok((self.wrap)(self.inner.connect(addr).await?, addr)) })
+    }
+}
+
+struct LinksListener {
+    inner: Box<dyn Listener>,
+    wrap: StreamWrap,
+}
+
+impl Listener for LinksListener {
+    fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<(BoxedStream, String)>> {
+        Box::pin(async move {
+            let (stream, addr) = self.inner.accept().await?;
+            Ok(((self.wrap)(stream, &addr), addr))
+        })
+    }
+}
+
+/// Every write of more than 4 KiB takes a second, as on a congested link
+struct SlowStream {
+    inner: BoxedStream,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl AsyncRead for SlowStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SlowStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        if data.len() > 4096 {
+            let sleep = self.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(Duration::from_secs(1))));
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+        Pin::new(&mut self.inner).poll_write(cx, data)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Once `cut` is set, writes vanish and reads never return, as across a
+/// network partition
+struct CutStream {
+    inner: BoxedStream,
+    cut: Arc<AtomicBool>,
+}
+
+impl AsyncRead for CutStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.cut.load(Ordering::Relaxed) {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        // Drained, so the sender is never held up by a full pipe
+        let mut scratch = [0; 4096];
+        loop {
+            let mut discard = ReadBuf::new(&mut scratch);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut discard))?;
+            if discard.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for CutStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        if self.cut.load(Ordering::Relaxed) {
+            return Poll::Ready(Ok(data.len()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, data)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Read replies on `conn` until `pick` accepts one
+async fn reply<T>(conn: &PeerConnection, mut pick: impl FnMut(Message) -> Option<T>) -> T {
+    timeout(SETTLE, async {
+        loop {
+            if let Some(picked) = pick(conn.receive_one().await.unwrap()) {
+                return picked;
+            }
+        }
+    })
+    .await
+    .expect("no reply")
+}
+
+/// Upload through `conn`, returning the ID the leader stored it under
+async fn store_image(conn: &PeerConnection, upload: &Upload) -> String {
+    image::send_image(conn, upload).await.unwrap();
+    reply(conn, |message| match message {
+        Message::StoreResult { image_id, durable, error, stored_as, .. } => {
+            assert!(durable, "upload of {} failed: {:?}", image_id, error);
+            Some(stored_as.unwrap_or(image_id))
+        }
+        _ => None,
+    })
+    .await
+}
+
+async fn delete_image(conn: &PeerConnection, image_id: &str) {
+    let delete = Message::DeleteImage {
+        image_id: image_id.to_string(),
+        requester_id: OWNER,
+    };
+    conn.send(&delete).await.unwrap();
+    reply(conn, |message| match message {
+        Message::ImageDeleted { image_id } => Some(image_id),
+        Message::AccessDenied { reason, .. } => panic!("delete denied: {}", reason),
+        _ => None,
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_bytes_uploaded_twice_are_stored_once_under_one_id() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image = upload(1);
+
+    let first = store_image(&conn, &image).await;
+    let second = store_image(&conn, &image).await;
+    assert_eq!(first, second);
+    assert_eq!(store(&storages[&leader]).list().unwrap(), [first]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_bytes_under_another_id_return_the_stored_copy() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image = upload(3);
+    let first = store_image(&conn, &image).await;
+
+    // The client names its retry, but the bytes are already stored
+    let renamed = Upload {
+        image_id: "holiday".into(),
+        ..image
+    };
+    assert_eq!(store_image(&conn, &renamed).await, first);
+    assert_eq!(store(&storages[&leader]).list().unwrap(), [first]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_bytes_with_another_acl_are_stored_as_their_own_image() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image = upload(2);
+    let first = store_image(&conn, &image).await;
+
+    // Another uploader shares the bytes with someone else, under their own ID
+    let shared = Upload {
+        image_id: "shared".into(),
+        allowed_node_ids: vec![OWNER + 1],
+        max_views: Some(1),
+        ..image
+    };
+    image::send_image(&conn, &shared).await.unwrap();
+    let stored_as = reply(&conn, |message| match message {
+        Message::StoreResult { durable: true, stored_as, .. } => Some(stored_as),
+        Message::StoreResult { error, .. } => panic!("upload failed: {:?}", error),
+        _ => None,
+    })
+    .await;
+    assert_eq!(stored_as, None, "the reply named the first uploader's image");
+
+    // Each keeps the access it asked for
+    let leader_store = store(&storages[&leader]);
+    assert_eq!(leader_store.list().unwrap(), [first.clone(), "shared".to_string()]);
+    let acl = leader_store.load_acl("shared").unwrap().unwrap();
+    assert_eq!((acl.allowed_node_ids, acl.max_views), (vec![OWNER + 1], Some(1)));
+    assert!(matches!(fetch(&conn, "shared", OWNER + 1).await, Message::FetchRedirect { .. }));
+    assert!(matches!(fetch(&conn, &first, OWNER + 1).await, Message::AccessDenied { .. }));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_retry_of_an_upload_too_few_nodes_acked_is_replicated_again() {
+    // Followers store the replica but their acks are lost, so the leader's
+    // copy is the only one it knows of when the write times out
+    let lose_acks = Arc::new(AtomicBool::new(true));
+    let losing = lose_acks.clone();
+    let (cluster, _, leader) = image_cluster_with(image_config(), move |id, transport| {
+        let losing = losing.clone();
+        let acks = move |message: &Message| {
+            losing.load(Ordering::SeqCst) && matches!(message, Message::ReplicaAck { .. })
+        };
+        Arc::new(FaultyTransport::new(transport, u64::from(id)).with_drop_rate(acks, 1.0))
+    })
+    .await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image = upload(6);
+    let result = |conn| {
+        reply(conn, |message| match message {
+            Message::StoreResult { durable, acks, quorum, .. } => Some((durable, acks, quorum)),
+            _ => None,
+        })
+    };
+    image::send_image(&conn, &image).await.unwrap();
+    assert_eq!(result(&conn).await, (false, 1, 2));
+
+    // The retry is durable only once a majority acks it again, not on the
+    // strength of the leader's copy alone
+    lose_acks.store(false, Ordering::SeqCst);
+    image::send_image(&conn, &image).await.unwrap();
+    let (durable, acks, quorum) = result(&conn).await;
+    assert!(durable);
+    assert_eq!(quorum, 2);
+    assert!(acks >= quorum, "durable with {}/{} acks", acks, quorum);
+}
+
+#[tokio::test(start_paused = true)]
+async fn deleted_image_can_be_uploaded_again() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image = upload(2);
+
+    let image_id = store_image(&conn, &image).await;
+    delete_image(&conn, &image_id).await;
+    assert!(!store(&storages[&leader]).contains(&image_id));
+
+    // Stored again as the next generation, which every node keeps
+    assert_eq!(store_image(&conn, &image).await, image_id);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for storage in storages.values() {
+        let store = store(storage);
+        assert!(store.contains(&image_id));
+        assert_eq!(store.generation(&image_id).unwrap(), 1);
+    }
+
+    // Followers still hold the tombstone for the first and offer it in
+    // anti-entropy, which must not take the second away
+    tokio::time::sleep(ANTI_ENTROPY_INTERVAL * 2).await;
+    for storage in storages.values() {
+        assert!(store(storage).contains(&image_id));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn members_cannot_delete_images_through_anti_entropy() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image_id = store_image(&conn, &upload(3)).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Node 0 holds no rights over the image, yet offers a tombstone for it
+    // to the leader as itself, and to a follower in the leader's name
+    let tombstone = vec![ImageVersion { image_id: image_id.clone(), generation: 0 }];
+    let follower = (1..4).find(|&id| id != leader).unwrap();
+    let inventories = [(leader, 0), (follower, leader)];
+    for (target, node_id) in inventories {
+        let forger = cluster.dial_as(0, "127.0.0.1:8080", target).await;
+        let inventory = Message::ImageInventory { node_id, images: Vec::new(), deleted: tombstone.clone() };
+        forger.send(&inventory).await.unwrap();
+        forger.send(&Message::SyncImages { node_id: 0 }).await.unwrap();
+    }
+
+    tokio::time::sleep(ANTI_ENTROPY_INTERVAL * 2).await;
+    for (id, storage) in &storages {
+        let store = store(storage);
+        assert!(store.contains(&image_id), "Node {} lost the image", id);
+        assert!(!store.is_deleted(&image_id, 0), "Node {} took the forged tombstone", id);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn uploads_cannot_choose_their_generation() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let follower = (1..4).find(|&id| id != leader).unwrap();
+
+    // Straight to the leader, and forwarded to it by a follower
+    let mut image_ids = Vec::new();
+    for (shade, target) in [(4, leader), (5, follower)] {
+        let conn = cluster.dial_client("127.0.0.1:9000", target).await;
+        let mut image = upload(shade);
+        image.generation = Some(u64::MAX);
+        image_ids.push(store_image(&conn, &image).await);
+    }
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for storage in storages.values() {
+        let store = store(storage);
+        for image_id in &image_ids {
+            assert_eq!(store.generation(image_id).unwrap(), 0);
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn one_member_cannot_ack_for_the_others() {
+    // No follower's own ack ever reaches the leader
+    let (cluster, _storages, leader) = image_cluster_with(image_config(), |id, transport| {
+        Arc::new(
+            FaultyTransport::new(transport, u64::from(id))
+                .with_drop_rate(|message| matches!(message, Message::ReplicaAck { .. }), 1.0),
+        )
+    })
+    .await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let forger = cluster.dial_as(0, "127.0.0.1:8080", leader).await;
+    let image = upload(6);
+    image::send_image(&conn, &image).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Node 0 acks in the name of every follower
+    for node_id in (1..4).filter(|&id| id != leader) {
+        let ack = Message::ReplicaAck { image_id: image.image_id.clone(), node_id };
+        forger.send(&ack).await.unwrap();
+    }
+
+    let durable = reply(&conn, |message| match message {
+        Message::StoreResult { durable, .. } => Some(durable),
+        _ => None,
+    })
+    .await;
+    assert!(!durable, "forged acks made the write durable");
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_follower_does_not_hold_up_the_others() {
+    // Node 3 is an observer, so it is always a follower, and every write to
+    // it is slow
+    let mut config = image_config();
+    config.nodes[3].observer = true;
+    let slow = config.nodes[3].bind_address.clone();
+    let (cluster, storages, leader) = image_cluster_with(config, |_, inner| {
+        let slow = slow.clone();
+        Links::around(inner, move |stream, remote| {
+            if remote == slow {
+                Box::new(SlowStream { inner: stream, sleep: None })
+            } else {
+                stream
+            }
+        })
+    })
+    .await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+
+    let image = large_upload(1);
+    assert!(image::chunks(&image).len() > 1);
+    let image_id = store_image(&conn, &image).await;
+    for (&id, storage) in &storages {
+        assert_eq!(store(storage).contains(&image_id), id != 3, "Node {}", id);
+    }
+
+    // The slow follower still gets its copy, just later
+    tokio::time::sleep(SETTLE).await;
+    assert!(store(&storages[&3]).contains(&image_id));
+}
+
+#[tokio::test(start_paused = true)]
+async fn every_replica_carries_the_leaders_watermark() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let mut image = large_upload(2);
+    image.watermark_owner = Some(OWNER);
+    let image_id = store_image(&conn, &image).await;
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let leader_bytes = store(&storages[&leader]).load(&image_id).unwrap().unwrap();
+    assert_eq!(image::extract_watermark(&leader_bytes), Some((OWNER, image_id.clone())));
+    for storage in storages.values() {
+        assert_eq!(store(storage).load(&image_id).unwrap().unwrap(), leader_bytes);
+        assert!(store(storage).load_thumbnail(&image_id).unwrap().is_some());
+    }
+}
+
+/// Ask for `image_id` on behalf of `requester_id` through `conn`, returning
+/// the redirect, refusal or first chunk it answers with
+async fn fetch(conn: &PeerConnection, image_id: &str, requester_id: u32) -> Message {
+    let request = Message::FetchImage {
+        image_id: image_id.to_string(),
+        requester_id,
+    };
+    conn.send(&request).await.unwrap();
+    reply(conn, |message| match message {
+        Message::FetchRedirect { .. } | Message::AccessDenied { .. } | Message::ImageChunk { .. } => Some(message),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_node_on_the_acl_fetches_the_image_from_a_follower() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image_id = store_image(&conn, &upload(4)).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // The leader authorizes the fetch and hands it to a follower
+    let Message::FetchRedirect { serve_node_id, .. } = fetch(&conn, &image_id, OWNER).await else {
+        panic!("fetch was not redirected");
+    };
+    assert_ne!(serve_node_id, leader);
+    let follower = cluster.dial_client("127.0.0.1:9001", serve_node_id).await;
+    let mut chunk = fetch(&follower, &image_id, OWNER).await;
+    let mut reassembler = image::Reassembler::new();
+    let fetched = loop {
+        if let Some(fetched) = reassembler.insert(image::ChunkSender::Connection(follower.id()), chunk) {
+            break fetched.unwrap();
+        }
+        chunk = follower.receive_one().await.unwrap();
+    };
+    assert_eq!(fetched.image_id, image_id);
+    assert_eq!(Some(fetched.bytes), store(&storages[&leader]).load(&image_id).unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_node_off_the_acl_is_refused() {
+    let (cluster, _, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image_id = store_image(&conn, &upload(5)).await;
+
+    let Message::AccessDenied { image_id: denied, reason } = fetch(&conn, &image_id, OWNER + 1).await else {
+        panic!("fetch by a node off the ACL was not refused");
+    };
+    assert_eq!(denied, image_id);
+    assert_eq!(reason, format!("Node {} may not fetch image {}", OWNER + 1, image_id));
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_unknown_image_is_refused_like_a_forbidden_one() {
+    let (cluster, _, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+
+    // Telling the two apart would let anyone probe for image IDs
+    let Message::AccessDenied { reason, .. } = fetch(&conn, "no-such-image", OWNER).await else {
+        panic!("fetch of an unknown image was not refused");
+    };
+    assert_eq!(reason, format!("Node {} may not fetch image no-such-image", OWNER));
+}
+
+#[tokio::test(start_paused = true)]
+async fn repeated_fetches_are_spread_across_the_followers() {
+    let (cluster, _, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image_id = store_image(&conn, &upload(7)).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // None of the grants is used, so each follower keeps its fetches in flight
+    let mut served = BTreeMap::new();
+    for _ in 0..4 {
+        let Message::FetchRedirect { serve_node_id, .. } = fetch(&conn, &image_id, OWNER).await else {
+            panic!("fetch was not redirected");
+        };
+        *served.entry(serve_node_id).or_insert(0) += 1;
+    }
+    let followers: Vec<u32> = (1..4).filter(|&id| id != leader).collect();
+    assert_eq!(served.keys().copied().collect::<Vec<_>>(), followers);
+    assert!(served.values().all(|&count| count == 2), "uneven spread: {:?}", served);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_thumbnail_is_a_small_decodable_copy_on_every_node() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image = large_upload(3);
+    let image_id = store_image(&conn, &image).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let request = Message::FetchThumbnail {
+        image_id: image_id.clone(),
+        requester_id: OWNER,
+    };
+    conn.send(&request).await.unwrap();
+    let mut reassembler = image::Reassembler::new();
+    let fetched = reply(&conn, |message| match message {
+        Message::AccessDenied { reason, .. } => panic!("thumbnail denied: {}", reason),
+        Message::ImageChunk { .. } => reassembler.insert(image::ChunkSender::Connection(conn.id()), message),
+        _ => None,
+    })
+    .await
+    .unwrap();
+
+    // A quarter of the pixels, and noise barely compresses
+    assert!(fetched.bytes.len() * 4 < image.bytes.len(), "{} of {} bytes", fetched.bytes.len(), image.bytes.len());
+    let decoded = ::image::load_from_memory(&fetched.bytes).expect("thumbnail does not decode");
+    assert_eq!((decoded.width(), decoded.height()), (image::THUMBNAIL_SIZE, image::THUMBNAIL_SIZE));
+    for storage in storages.values() {
+        assert_eq!(store(storage).load_thumbnail(&image_id).unwrap(), Some(fetched.bytes.clone()));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_upload_that_is_not_an_image_is_rejected() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let bytes = b"just some text".to_vec();
+    let text = Upload {
+        image_id: image::content_id(&bytes),
+        bytes,
+        ..upload(8)
+    };
+
+    image::send_image(&conn, &text).await.unwrap();
+    let (durable, error) = reply(&conn, |message| match message {
+        Message::StoreResult { durable, error, .. } => Some((durable, error)),
+        _ => None,
+    })
+    .await;
+    assert!(!durable);
+    assert!(error.unwrap().starts_with("Upload is not a PNG or JPEG image"));
+    for storage in storages.values() {
+        assert!(!store(storage).contains(&text.image_id));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_view_quota_is_kept_across_a_leader_failover() {
+    let (mut cluster, _, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image_id = store_image(&conn, &Upload { max_views: Some(3), ..upload(9) }).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    for _ in 0..2 {
+        let granted = fetch(&conn, &image_id, OWNER).await;
+        assert!(matches!(granted, Message::FetchRedirect { .. }), "fetch refused: {:?}", granted);
+    }
+
+    // The new leader knows of the two views its predecessor counted
+    cluster.kill(leader);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no new leader");
+    let conn = cluster.dial_client("127.0.0.1:9001", leader).await;
+    let granted = fetch(&conn, &image_id, OWNER).await;
+    assert!(!matches!(granted, Message::AccessDenied { .. }), "third fetch refused: {:?}", granted);
+
+    let Message::AccessDenied { reason, .. } = fetch(&conn, &image_id, OWNER).await else {
+        panic!("a fourth fetch of a three-view grant was allowed");
+    };
+    assert_eq!(reason, format!("Node {} has used all 3 views of image {}", OWNER, image_id));
+}
+
+#[tokio::test(start_paused = true)]
+async fn every_replica_lists_the_images_it_was_sent() {
+    let (cluster, _, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let plain = upload(10);
+    let mut marked = large_upload(4);
+    marked.watermark_owner = Some(OWNER);
+    let plain_id = store_image(&conn, &plain).await;
+    let marked_id = store_image(&conn, &marked).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    for id in 1..4 {
+        let conn = cluster.dial_client("127.0.0.1:9001", id).await;
+        conn.send(&Message::ListImages {}).await.unwrap();
+        let entries = reply(&conn, |message| match message {
+            Message::ImageList { entries } => Some(entries),
+            _ => None,
+        })
+        .await;
+        // The leader hears from every replica; a follower knows of the leader's and its own
+        let replicas = if id == leader { vec![1, 2, 3] } else { vec![id.min(leader), id.max(leader)] };
+        let mut expected = vec![(plain_id.clone(), None, replicas.clone()), (marked_id.clone(), Some(OWNER), replicas)];
+        expected.sort();
+        let listed: Vec<_> =
+            entries.iter().map(|meta| (meta.image_id.clone(), meta.owner, meta.replicas.clone())).collect();
+        assert_eq!(listed, expected, "Node {}", id);
+        assert_eq!(entries.iter().find(|meta| meta.image_id == plain_id).unwrap().size, plain.bytes.len() as u64);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_upload_with_a_flipped_byte_is_refused_until_sent_intact() {
+    let (cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let image = large_upload(5);
+
+    // One bit of the second chunk flips on the way
+    let mut chunks = image::chunks(&image);
+    let Message::ImageChunk { data, compressed: false, .. } = &mut chunks[1] else {
+        panic!("expected an uncompressed chunk");
+    };
+    data[100] ^= 0x01;
+    for chunk in &chunks {
+        conn.send(chunk).await.unwrap();
+    }
+    let (image_id, node_id) = reply(&conn, |message| match message {
+        Message::ChecksumMismatch { image_id, node_id } => Some((image_id, node_id)),
+        Message::StoreResult { .. } => panic!("a corrupted upload was stored"),
+        _ => None,
+    })
+    .await;
+    assert_eq!((image_id.as_str(), node_id), (image.image_id.as_str(), leader));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for storage in storages.values() {
+        assert!(!store(storage).contains(&image.image_id));
+    }
+
+    // Sent again as the client was asked to, it is stored
+    assert_eq!(store_image(&conn, &image).await, image.image_id);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_node_down_during_an_upload_repairs_its_replica_on_return() {
+    let (mut cluster, storages, leader) = image_cluster().await;
+    let conn = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let follower = (1..4).find(|&id| id != leader).unwrap();
+    let address = cluster.config.nodes[follower as usize].bind_address.clone();
+
+    cluster.kill(follower);
+    let image_id = store_image(&conn, &large_upload(6)).await;
+    assert!(!store(&storages[&follower]).contains(&image_id));
+
+    // Back with the storage it had, it pulls what it missed
+    let network = cluster.network.clone().unwrap();
+    network.revive(&address);
+    let storage = Box::new(storages[&follower].clone());
+    cluster.spawn(follower, |node| node.with_transport(network.transport(&address)).with_storage(storage));
+    tokio::time::sleep(SETTLE + ANTI_ENTROPY_INTERVAL * 2).await;
+    let leader_bytes = store(&storages[&leader]).load(&image_id).unwrap();
+    assert!(leader_bytes.is_some());
+    assert_eq!(store(&storages[&follower]).load(&image_id).unwrap(), leader_bytes);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_leader_cut_off_from_its_quorum_stops_writing_before_the_next_starts() {
+    // Slow to give up on silent followers, so only the lease can stop the
+    // leader in time
+    let config = Config {
+        min_quorum: 2,
+        timings: Timings {
+            stale_node_timeout: Duration::from_secs(20),
+            ..Timings::default()
+        },
+        ..image_config()
+    };
+    // Links between members pass through a switch per node, to cut the leader off
+    let members: Vec<String> = config.nodes.iter().map(|node| node.bind_address.clone()).collect();
+    let cuts: BTreeMap<u32, Arc<AtomicBool>> = (1..4).map(|id| (id, Arc::default())).collect();
+    let (cluster, storages, leader) = image_cluster_with(config, |id, inner| {
+        let members = members.clone();
+        let cut = cuts[&id].clone();
+        Links::around(inner, move |stream, remote| {
+            if members.iter().any(|member| member == remote) {
+                Box::new(CutStream { inner: stream, cut: cut.clone() })
+            } else {
+                stream
+            }
+        })
+    })
+    .await;
+    let mut clients = BTreeMap::new();
+    for id in 1..4 {
+        clients.insert(id, cluster.dial_client(&format!("127.0.0.1:{}", 9000 + id), id).await);
+    }
+    // The leader keeps its clients but loses every other member, both ways
+    cuts[&leader].store(true, Ordering::Relaxed);
+
+    // Every quarter second each node is handed an image of its own
+    let start = tokio::time::Instant::now();
+    let mut sent = Vec::new();
+    for tick in 0..120u16 {
+        for (&id, conn) in &clients {
+            let pixel = ::image::Rgb([(tick >> 8) as u8, tick as u8, id as u8]);
+            let upload = png_upload(::image::RgbImage::from_pixel(4, 4, pixel));
+            image::send_image(conn, &upload).await.unwrap();
+            sent.push((start.elapsed(), id, upload.image_id));
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+    let mut next = None;
+    for id in (1..4).filter(|&id| id != leader) {
+        if cluster.handle(id).is_leader().await {
+            next = Some(id);
+        }
+    }
+    let next = next.expect("no new leader");
+
+    // A node handed an image stores it only if it writes it as leader; as a
+    // follower it forwards it, and nothing crosses to or from the cut-off leader
+    let written_by = |id: u32| {
+        let store = store(&storages[&id]);
+        let written = sent.iter().filter(|(_, to, image_id)| *to == id && store.contains(image_id));
+        written.map(|(at, _, _)| *at).collect::<Vec<_>>()
+    };
+    let new_first = written_by(next).into_iter().min().expect("the new leader never wrote");
+    let old_last = written_by(leader).into_iter().max().expect("the old leader never wrote");
+    assert!(old_last < new_first, "old leader wrote until {:?}, new one from {:?}", old_last, new_first);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_retry_sent_while_the_upload_replicates_joins_it_instead_of_replicating_again() {
+    // Followers are slow to ack, so the first write is still in flight when
+    // the retry arrives; every replication sends each follower a thumbnail
+    let thumbnails = Arc::new(AtomicUsize::new(0));
+    let counted = thumbnails.clone();
+    let (cluster, storages, leader) = image_cluster_with(image_config(), move |id, transport| {
+        let counted = counted.clone();
+        let count = move |message: &Message| {
+            if matches!(message, Message::StoreThumbnail { .. }) {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+            false
+        };
+        let acks = |message: &Message| matches!(message, Message::ReplicaAck { .. });
+        let faults = FaultyTransport::new(transport, u64::from(id)).with_drop_rate(count, 0.0);
+        Arc::new(faults.with_delay(acks, Duration::from_secs(2)))
+    })
+    .await;
+    thumbnails.store(0, Ordering::SeqCst);
+
+    // The client gives up waiting and sends the upload again on a new
+    // connection, this time under its content ID
+    let retried = upload(5);
+    let image = Upload {
+        image_id: "holiday".into(),
+        ..retried.clone()
+    };
+    let first = cluster.dial_client("127.0.0.1:9000", leader).await;
+    let retry = cluster.dial_client("127.0.0.1:9001", leader).await;
+    image::send_image(&first, &image).await.unwrap();
+    image::send_image(&retry, &retried).await.unwrap();
+
+    // Both hear back only once the one write has its quorum of acks, which
+    // a stored copy with the leader's replica alone does not have yet
+    let durable = |conn| {
+        reply(conn, |message| match message {
+            Message::StoreResult { durable: true, image_id, stored_as, acks, quorum, .. } => {
+                Some((image_id, stored_as, acks, quorum))
+            }
+            Message::StoreResult { error, .. } => panic!("upload failed: {:?}", error),
+            _ => None,
+        })
+    };
+    let (first, retry) = tokio::join!(durable(&first), durable(&retry));
+    assert_eq!(first, ("holiday".to_string(), None, 2, 2));
+    assert_eq!(retry, (retried.image_id, Some("holiday".to_string()), 2, 2));
+    assert_eq!(thumbnails.load(Ordering::SeqCst), storages.len() - 1);
+    for storage in storages.values() {
+        assert_eq!(store(storage).list().unwrap(), ["holiday"]);
+    }
+}
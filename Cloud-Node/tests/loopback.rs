@@ -0,0 +1,93 @@
+//! Embedded nodes talking over real TCP sockets on 127.0.0.1
+
+mod common;
+
+use cloud_p2p::transport::{TcpTransport, Transport};
+use cloud_p2p::Config;
+use common::{fast_timings, loopback_nodes, Cluster};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::time::timeout;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn three_embedded_nodes_agree_on_one_leader() {
+    let config = Config {
+        nodes: loopback_nodes(3),
+        timings: fast_timings(),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::tcp(config);
+    let mut changes = cluster.handle(0).leader_changes();
+
+    let leader = timeout(Duration::from_secs(10), cluster.agreed_leader())
+        .await
+        .expect("cluster did not agree on a leader");
+    for handle in &cluster.handles {
+        assert_eq!(handle.is_leader().await, handle.node_id() == leader);
+    }
+    // A node can follow the leader's `Coordinator` a moment before its own
+    // discovery round ends and it reports ready
+    timeout(Duration::from_secs(5), async {
+        for handle in &cluster.handles {
+            while !handle.is_healthy().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    })
+    .await
+    .expect("a node never became ready");
+
+    // The watch channel has caught up with the agreed leader
+    let state = *changes.borrow_and_update();
+    assert_eq!(state.leader, Some(leader));
+    assert!(state.term > 0);
+
+    // Heartbeats keep it: a few failure timeouts later nothing has changed
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(cluster.leader_if_agreed().await, Some(leader));
+
+    // A host can stop its node and get `run`'s result back
+    for id in [0, 1, 2] {
+        timeout(Duration::from_secs(5), cluster.shut_down(id)).await.expect("node did not shut down");
+    }
+}
+
+#[tokio::test]
+async fn a_port_left_in_time_wait_can_be_bound_again_at_once() {
+    let transport = TcpTransport::new();
+    let address = loopback_nodes(1).remove(0).bind_address;
+    let mut listener = transport.listen(&address).await.unwrap();
+
+    // Closing the accepted side first leaves it in TIME_WAIT on our port
+    let mut client = transport.connect(&address).await.unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    drop(accepted);
+    assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+    drop(client);
+    drop(listener);
+
+    transport.listen(&address).await.expect("could not bind the port again");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn nodes_addressed_by_ipv6_literal_and_hostname_agree_on_a_leader() {
+    let port = |host: &str| std::net::TcpListener::bind((host, 0)).unwrap().local_addr().unwrap().port();
+    let nodes = vec![
+        common::node(0, &format!("[::1]:{}", port("::1"))),
+        common::node(1, &format!("localhost:{}", port("localhost"))),
+        common::node(2, &format!("127.0.0.1:{}", port("127.0.0.1"))),
+    ];
+    let config = Config {
+        nodes,
+        timings: fast_timings(),
+        ..Config::default()
+    };
+    let cluster = Cluster::tcp(config);
+
+    // Agreeing takes the leader reaching each follower, whatever its address
+    let leader = timeout(Duration::from_secs(10), cluster.agreed_leader())
+        .await
+        .expect("cluster did not agree on a leader");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(cluster.handle(leader).snapshot().await.alive_nodes, [0, 1, 2]);
+}
@@ -0,0 +1,290 @@
+//! Membership changes at runtime: nodes joining through the leader and
+//! leaving the cluster
+
+mod common;
+
+use cloud_p2p::fault::FaultyTransport;
+use cloud_p2p::message::{Message, PeerState};
+use cloud_p2p::{Config, NodeHandle};
+use common::{memory_nodes, node, Cluster};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// Whether `handle`'s node has an address for node `id`
+async fn knows(handle: &NodeHandle, id: u32) -> bool {
+    handle.peer_states().await.iter().any(|&(peer, _)| peer == id)
+}
+
+#[tokio::test(start_paused = true)]
+async fn followers_learn_a_joined_node_from_the_next_membership_broadcast() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let coordinator_interval = config.timings.coordinator_interval;
+    // The leader's relayed `Join` is lost, leaving `Membership` to spread it
+    let mut cluster = Cluster::memory_with(config, |id, transport| {
+        Arc::new(
+            FaultyTransport::new(transport, u64::from(id))
+                .with_drop_rate(|message| matches!(message, Message::Join { .. }), 1.0),
+        )
+    });
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+
+    // Only the newcomer's own config lists it
+    cluster.config.nodes.push(node(3, "127.0.0.1:8083"));
+    cluster.start(3);
+
+    timeout(SETTLE, async {
+        while !knows(cluster.handle(leader), 3).await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the leader never heard the Join");
+    let joined = Instant::now();
+
+    for id in (0..3).filter(|&id| id != leader) {
+        timeout(coordinator_interval + Duration::from_millis(100), async {
+            while !knows(cluster.handle(id), 3).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Node {} did not learn Node 3 within one broadcast", id));
+    }
+    assert!(joined.elapsed() <= coordinator_interval + Duration::from_millis(100));
+}
+
+#[tokio::test(start_paused = true)]
+async fn three_node_cluster_absorbs_a_fourth_at_runtime() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+
+    cluster.config.nodes.push(node(3, "127.0.0.1:8083"));
+    cluster.start(3);
+
+    // The newcomer follows the sitting leader, which counts it alive, and
+    // every original member learns where to reach it
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader"), leader);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(cluster.handle(leader).snapshot().await.alive_nodes, [0, 1, 2, 3]);
+    for id in 0..3 {
+        assert!(knows(cluster.handle(id), 3).await, "Node {} never learned Node 3", id);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_node_knowing_one_seed_discovers_a_five_node_cluster() {
+    let config = Config {
+        nodes: memory_nodes(5),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+
+    // Node 5's config lists only itself, and a follower to ask for the rest
+    let seed = cluster.config.nodes.iter().find(|info| info.id != leader).unwrap().bind_address.clone();
+    let newcomer = Config {
+        nodes: vec![node(5, "127.0.0.1:8085")],
+        seeds: vec![seed],
+        ..cluster.config.clone()
+    };
+    let transport = cluster.network.as_ref().unwrap().transport("127.0.0.1:8085");
+    let handle = cluster.spawn_with(5, newcomer, |node| node.with_transport(transport));
+
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader"), leader);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    for id in 0..5 {
+        assert!(knows(&handle, id).await, "Node 5 never learned Node {}", id);
+        assert!(knows(cluster.handle(id), 5).await, "Node {} never learned Node 5", id);
+    }
+    assert_eq!(cluster.handle(leader).snapshot().await.alive_nodes, [0, 1, 2, 3, 4, 5]);
+}
+
+/// Three running nodes, plus node 0 in the config but never started, so a
+/// forged connection can speak for it without displacing a live one.
+/// Returns the settled leader and its successor.
+async fn cluster_with_idle_node_0() -> (Cluster, u32, Option<u32>) {
+    let config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory_idle(config);
+    for id in 1..4 {
+        cluster.start(id);
+    }
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let successor = cluster.handle(leader).snapshot().await.successor;
+    assert!(successor.is_some());
+    (cluster, leader, successor)
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_connection_relaying_the_leaders_coordinator_belongs_to_its_sender() {
+    let (cluster, leader, _) = cluster_with_idle_node_0().await;
+    let follower = (1..4).find(|&id| id != leader).unwrap();
+    let connected = |states: &[(u32, PeerState)], id| states.contains(&(id, PeerState::Connected));
+
+    // Node 0 introduces itself, then passes on the leader's announcement
+    let term = cluster.handle(leader).snapshot().await.term;
+    let relay = cluster.dial_as(0, "127.0.0.1:8080", follower).await;
+    let coordinator = Message::Coordinator {
+        leader_id: leader,
+        successor_id: None,
+        backup_successors: Vec::new(),
+        term,
+        correlation_id: 0,
+    };
+    relay.send(&coordinator).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(connected(&cluster.handle(follower).peer_states().await, 0));
+
+    // Had the connection been taken for the leader's, closing it would cut
+    // the follower off from the leader
+    drop(relay);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let states = cluster.handle(follower).peer_states().await;
+    assert!(!connected(&states, 0));
+    assert!(connected(&states, leader));
+    assert_eq!(cluster.leader_if_agreed().await, Some(leader));
+}
+
+#[tokio::test(start_paused = true)]
+async fn join_for_another_node_is_ignored() {
+    let (cluster, leader, successor) = cluster_with_idle_node_0().await;
+    let follower = (1..4).find(|&id| id != leader && Some(id) != successor).unwrap();
+
+    // Node 0 tries to promote one follower over the successor and retire
+    // the successor as an observer
+    let forger = cluster.dial_as(0, "127.0.0.1:8080", leader).await;
+    for (node_id, priority, observer) in [(follower, 100, false), (successor.unwrap(), 0, true)] {
+        let address = format!("127.0.0.1:{}", 8080 + node_id);
+        forger.send(&Message::Join { node_id, address, priority, observer }).await.unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(cluster.handle(leader).snapshot().await.successor, successor);
+}
+
+#[tokio::test(start_paused = true)]
+async fn join_cannot_raise_a_configured_nodes_priority() {
+    let (cluster, leader, successor) = cluster_with_idle_node_0().await;
+
+    // Node 0 is configured at priority 0; its own Join asks for more
+    let conn = cluster.dial_as(0, "127.0.0.1:8080", leader).await;
+    let join = Message::Join {
+        node_id: 0,
+        address: "127.0.0.1:8080".into(),
+        priority: 100,
+        observer: false,
+    };
+    conn.send(&join).await.unwrap();
+
+    // The leader takes node 0 in, but ranks it by the config
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let view = cluster.handle(leader).snapshot().await;
+    assert!(view.alive_nodes.contains(&0));
+    assert_eq!(view.successor, successor);
+}
+
+#[tokio::test(start_paused = true)]
+async fn unknown_node_cannot_join_above_the_configured_ones() {
+    // The running nodes outrank node 0, which sets the lowest priority
+    let mut config = Config {
+        nodes: memory_nodes(4),
+        ..Config::default()
+    };
+    for node in &mut config.nodes[1..] {
+        node.priority = 1;
+    }
+    let mut cluster = Cluster::memory_idle(config);
+    for id in 1..4 {
+        cluster.start(id);
+    }
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let successor = cluster.handle(leader).snapshot().await.successor;
+
+    // Node 7 is in no config, and claims to outrank everyone
+    let conn = cluster.dial_as(7, "127.0.0.1:8087", leader).await;
+    let join = Message::Join {
+        node_id: 7,
+        address: "127.0.0.1:8087".into(),
+        priority: 100,
+        observer: false,
+    };
+    conn.send(&join).await.unwrap();
+
+    // It joins at node 0's priority, below the successor's
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let view = cluster.handle(leader).snapshot().await;
+    assert!(view.alive_nodes.contains(&7));
+    assert_eq!(view.successor, successor);
+}
+
+#[tokio::test(start_paused = true)]
+async fn leave_for_another_node_is_ignored() {
+    let (cluster, leader, successor) = cluster_with_idle_node_0().await;
+
+    let forger = cluster.dial_as(0, "127.0.0.1:8080", leader).await;
+    forger.send(&Message::Leave { node_id: successor.unwrap() }).await.unwrap();
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let view = cluster.handle(leader).snapshot().await;
+    assert!(view.alive_nodes.contains(&successor.unwrap()));
+    assert_eq!(view.successor, successor);
+}
+
+#[tokio::test(start_paused = true)]
+async fn leader_picks_a_new_successor_when_the_old_one_leaves() {
+    let (mut cluster, leader, successor) = cluster_with_idle_node_0().await;
+    let failure_timeout = cluster.config.timings.failure_timeout;
+
+    let left = Instant::now();
+    cluster.shut_down(successor.unwrap()).await;
+
+    // The Leave spares the leader waiting out the failure timeout
+    timeout(failure_timeout / 2, async {
+        loop {
+            let view = cluster.handle(leader).snapshot().await;
+            let gone = !view.alive_nodes.contains(&successor.unwrap());
+            if gone && view.successor.is_some() && view.successor != successor {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the leader kept the departed successor");
+    assert!(left.elapsed() < failure_timeout);
+}
+
+#[tokio::test(start_paused = true)]
+async fn peers_drop_a_shut_down_node_before_the_failure_timeout() {
+    let (mut cluster, leader, successor) = cluster_with_idle_node_0().await;
+    let failure_timeout = cluster.config.timings.failure_timeout;
+    let follower = (1..4).find(|&id| id != leader && Some(id) != successor).unwrap();
+
+    // As the binary does on SIGTERM
+    cluster.shut_down(follower).await;
+
+    timeout(failure_timeout / 2, async {
+        for id in [leader, successor.unwrap()] {
+            while knows(cluster.handle(id), follower).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    })
+    .await
+    .expect("a peer waited for failure detection to drop the node");
+    assert!(!cluster.handle(leader).snapshot().await.alive_nodes.contains(&follower));
+}
@@ -0,0 +1,136 @@
+//! The Prometheus and readiness endpoints of embedded nodes on a
+//! `MemoryNetwork`, scraped over loopback HTTP before and after a failover
+
+mod common;
+
+use cloud_p2p::Config;
+use common::{memory_nodes, Cluster};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// `GET path` from `addr`, waiting for the endpoint to come up; returns the
+/// whole response
+async fn get(addr: &str, path: &str) -> String {
+    let mut stream = loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Fetch `/metrics` from `addr`
+async fn scrape(addr: &str) -> String {
+    let response = get(addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    response
+}
+
+/// The value of the sample named `series`, labels included, e.g.
+/// `leader_changes_total{reason="announced"}`
+fn sample(body: &str, series: &str) -> Option<f64> {
+    body.lines().find_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.parse().ok())
+}
+
+#[tokio::test(start_paused = true)]
+async fn counters_and_gauges_follow_a_failover() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory_idle(config);
+    let mut endpoints = BTreeMap::new();
+    for info in cluster.config.nodes.clone() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let endpoint = format!("127.0.0.1:{}", port);
+        let transport = cluster.network.as_ref().unwrap().transport(&info.bind_address);
+        cluster.spawn(info.id, |node| node.with_transport(transport).with_metrics_addr(endpoint.clone()));
+        endpoints.insert(info.id, endpoint);
+    }
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let successor = cluster.handle(leader).snapshot().await.successor.expect("no successor");
+    let follower = (0..3).find(|&id| id != leader && id != successor).unwrap();
+
+    let body = scrape(&endpoints[&leader]).await;
+    assert_eq!(sample(&body, "current_is_leader"), Some(1.0));
+    assert_eq!(sample(&body, "known_alive_nodes"), Some(3.0));
+    let before = scrape(&endpoints[&successor]).await;
+    assert_eq!(sample(&before, "current_is_leader"), Some(0.0));
+    assert_eq!(sample(&before, "elections_started_total"), Some(0.0));
+    assert!(sample(&before, "heartbeats_sent_total").unwrap() > 0.0);
+    let follower_heartbeats = sample(&scrape(&endpoints[&follower]).await, "heartbeats_sent_total").unwrap();
+
+    cluster.kill(leader);
+    timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let after = scrape(&endpoints[&successor]).await;
+    assert_eq!(sample(&after, "current_is_leader"), Some(1.0));
+    assert_eq!(sample(&after, "known_alive_nodes"), Some(2.0));
+    assert_eq!(sample(&after, "elections_started_total"), Some(1.0));
+    assert_eq!(sample(&after, "leader_changes_total{reason=\"successor_takeover\"}"), Some(1.0));
+    assert_eq!(sample(&after, "seconds_since_last_leader_heartbeat"), Some(0.0));
+
+    // The follower heartbeats its new leader
+    let body = scrape(&endpoints[&follower]).await;
+    assert!(sample(&body, "heartbeats_sent_total").unwrap() > follower_heartbeats);
+    assert!(sample(&body, "seconds_since_last_leader_heartbeat").unwrap() < 6.0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_are_ready_only_once_they_follow_a_connected_leader() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let mut cluster = Cluster::memory_idle(config);
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let endpoint = format!("127.0.0.1:{}", port);
+    for info in cluster.config.nodes.clone() {
+        let transport = cluster.network.as_ref().unwrap().transport(&info.bind_address);
+        if info.id == 0 {
+            cluster.spawn(0, |node| node.with_transport(transport).with_metrics_addr(endpoint.clone()));
+        } else {
+            cluster.spawn(info.id, |node| node.with_transport(transport));
+        }
+    }
+
+    // Still discovering the network, nobody leads or follows yet
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    for id in 0..3 {
+        assert!(!cluster.handle(id).is_healthy().await, "Node {} ready during discovery", id);
+    }
+    let response = get(&endpoint, "/healthz").await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    for id in 0..3 {
+        assert!(cluster.handle(id).is_healthy().await, "Node {} not ready under leader {}", id, leader);
+    }
+    let response = get(&endpoint, "/healthz").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\nready\n"), "{}", response);
+
+    // With the leader gone, the others take no traffic until a new one leads
+    cluster.kill(leader);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let survivors: Vec<u32> = (0..3).filter(|&id| id != leader).collect();
+    for &id in &survivors {
+        assert!(!cluster.handle(id).is_healthy().await, "Node {} ready with its leader gone", id);
+    }
+    timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    for &id in &survivors {
+        assert!(cluster.handle(id).is_healthy().await, "Node {} not ready after the failover", id);
+    }
+}
@@ -0,0 +1,196 @@
+//! Re-dialing peers whose connection dropped, backing off from those that
+//! stay unreachable
+
+mod common;
+
+use cloud_p2p::message::PeerState;
+use cloud_p2p::transport::{BoxFuture, BoxedStream, Listener, Transport};
+use cloud_p2p::{Config, NodeHandle, Timings};
+use common::{memory_nodes, Cluster};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::timeout;
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// Dials made on the network, by dialing node and dialed address
+type Dials = Arc<Mutex<HashMap<(u32, String), usize>>>;
+
+/// Passes connections through, counting every dial
+struct CountingDials {
+    inner: Arc<dyn Transport>,
+    node_id: u32,
+    dials: Dials,
+}
+
+impl Transport for CountingDials {
+    fn listen<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, anyhow::Result<Box<dyn Listener>>> {
+        self.inner.listen(addr)
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, anyhow::Result<BoxedStream>> {
+        *self.dials.lock().unwrap().entry((self.node_id, addr.to_string())).or_default() += 1;
+        self.inner.connect(addr)
+    }
+}
+
+/// Passes connections through, each dial taking `delay` to complete
+struct SlowDials {
+    inner: Arc<dyn Transport>,
+    delay: Duration,
+}
+
+impl Transport for SlowDials {
+    fn listen<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, anyhow::Result<Box<dyn Listener>>> {
+        self.inner.listen(addr)
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, anyhow::Result<BoxedStream>> {
+        Box::pin(async move {
+            tokio::time::sleep(self.delay).await;
+            self.inner.connect(addr).await
+        })
+    }
+}
+
+/// Every state `handle`'s node reports for `peer` from now on, each run of
+/// one state recorded once
+fn record_states(handle: NodeHandle, peer: u32) -> (Arc<Mutex<Vec<PeerState>>>, tokio::task::JoinHandle<()>) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let task = tokio::spawn({
+        let seen = seen.clone();
+        async move {
+            loop {
+                let states = handle.peer_states().await;
+                if let Some(&(_, state)) = states.iter().find(|&&(id, _)| id == peer) {
+                    let mut seen = seen.lock().unwrap();
+                    if seen.last() != Some(&state) {
+                        seen.push(state);
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    });
+    (seen, task)
+}
+
+#[test]
+fn backoff_doubles_up_to_the_cap_and_jitters_down_by_at_most_half() {
+    let timings = Timings::default();
+    for failures in 1..=10 {
+        let full = (timings.reconnect_interval * 2u32.pow(failures - 1)).min(timings.reconnect_max_interval);
+        for _ in 0..50 {
+            let wait = timings.reconnect_backoff(failures);
+            assert!(wait >= full / 2 && wait <= full, "{:?} after {} failures", wait, failures);
+        }
+    }
+    assert!(timings.reconnect_backoff(u32::MAX) <= timings.reconnect_max_interval);
+}
+
+#[tokio::test(start_paused = true)]
+async fn dead_peer_is_redialed_ever_less_often_until_it_returns() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let timings = config.timings;
+    let dials = Dials::default();
+    let mut cluster = Cluster::memory_with(config, |node_id, inner| {
+        Arc::new(CountingDials { inner, node_id, dials: dials.clone() })
+    });
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let victim = (0..3).find(|&id| id != leader).unwrap();
+    let address = cluster.config.nodes[victim as usize].bind_address.clone();
+    let dialed = |dials: &Dials| dials.lock().unwrap().get(&(leader, address.clone())).copied().unwrap_or(0);
+    cluster.kill(victim);
+    let before = dialed(&dials);
+
+    // Five minutes at a fixed reconnect_interval would be 150 dials; doubling
+    // up to the cap spends most of them waiting
+    tokio::time::sleep(Duration::from_secs(300)).await;
+    let redials = dialed(&dials) - before;
+    assert!((10..=30).contains(&redials), "{} dials in five minutes", redials);
+    let states = cluster.handle(leader).peer_states().await;
+    assert!(states.contains(&(victim, PeerState::Backoff)), "{:?}", states);
+
+    // Back on the network, it is reached within one capped wait
+    cluster.network.as_ref().unwrap().revive(&address);
+    cluster.start(victim);
+    tokio::time::sleep(timings.reconnect_max_interval + Duration::from_secs(1)).await;
+    let states = cluster.handle(leader).peer_states().await;
+    assert!(states.contains(&(victim, PeerState::Connected)), "{:?}", states);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_lost_peer_goes_dead_then_backs_off_until_a_dial_reconnects_it() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let timings = config.timings;
+    let delay = Duration::from_millis(200);
+    let mut cluster = Cluster::memory_with(config, |_, inner| Arc::new(SlowDials { inner, delay }));
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let victim = (0..3).find(|&id| id != leader).unwrap();
+    let address = cluster.config.nodes[victim as usize].bind_address.clone();
+    let (seen, recorder) = record_states(cluster.handle(leader).clone(), victim);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    cluster.kill(victim);
+    tokio::time::sleep(Duration::from_secs(60)).await;
+
+    // Back, but slow to dial out itself, so the leader's redial reconnects it
+    let network = cluster.network.clone().unwrap();
+    network.revive(&address);
+    let inner = network.transport(&address);
+    cluster.spawn(victim, |node| {
+        node.with_transport(Arc::new(SlowDials {
+            inner,
+            delay: Duration::from_secs(600),
+        }))
+    });
+    tokio::time::sleep(timings.reconnect_max_interval + Duration::from_secs(1)).await;
+    recorder.abort();
+
+    use PeerState::*;
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(seen[..4], [Connected, Dead, Connecting, Backoff], "{:?}", seen);
+    assert!(seen[4..seen.len() - 2].chunks(2).all(|pair| pair == [Connecting, Backoff]), "{:?}", seen);
+    assert_eq!(seen[seen.len() - 2..], [Connecting, Connected], "{:?}", seen);
+}
+
+#[tokio::test(start_paused = true)]
+async fn heartbeats_resume_after_a_connection_blip() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let stale_node_timeout = config.timings.stale_node_timeout;
+    let cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    let before = cluster.handle(follower).snapshot().await;
+
+    // Every connection to the follower drops, but the node keeps running
+    let network = cluster.network.as_ref().unwrap();
+    let address = &cluster.config.nodes[follower as usize].bind_address;
+    network.kill(address);
+    network.revive(address);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!cluster.handle(leader).peer_states().await.contains(&(follower, PeerState::Connected)));
+
+    // Reconnected before anyone presumed a failure, and still heartbeating
+    // the leader long after a silent follower would have gone stale
+    tokio::time::sleep(stale_node_timeout * 5).await;
+    let states = cluster.handle(leader).peer_states().await;
+    assert!(states.contains(&(follower, PeerState::Connected)), "{:?}", states);
+    assert!(cluster.handle(leader).snapshot().await.alive_nodes.contains(&follower));
+    let after = cluster.handle(follower).snapshot().await;
+    assert_eq!((after.leader, after.term), (before.leader, before.term));
+}
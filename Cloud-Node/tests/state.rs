@@ -0,0 +1,135 @@
+//! Leadership beliefs written to each node's state file, and read back when
+//! the node starts again
+
+mod common;
+
+use cloud_p2p::state::{PersistedState, StateStore};
+use cloud_p2p::{Config, LeaderChangeReason, NodeHandle};
+use common::{memory_nodes, Cluster};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::timeout;
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// A state directory of its own for one test, removed on drop
+struct StateDir(PathBuf);
+
+impl StateDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cloud-p2p-state-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        Self(dir)
+    }
+
+    fn load(&self, node_id: u32) -> Option<PersistedState> {
+        StateStore::new(&self.0, node_id).load().unwrap()
+    }
+}
+
+impl Drop for StateDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Start every node in `config` on a `MemoryNetwork`, keeping state in `dir`
+fn cluster_with_state(config: Config, dir: &StateDir) -> Cluster {
+    let mut cluster = Cluster::memory_idle(config);
+    for info in cluster.config.nodes.clone() {
+        let transport = cluster.network.as_ref().unwrap().transport(&info.bind_address);
+        cluster.spawn(info.id, |node| node.with_transport(transport).with_state_dir(&dir.0));
+    }
+    cluster
+}
+
+#[tokio::test(start_paused = true)]
+async fn every_leadership_change_is_written_through() {
+    let dir = StateDir::new("write-through");
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let mut cluster = cluster_with_state(config, &dir);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no first leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let term = cluster.handle(leader).snapshot().await.term;
+    for id in 0..3 {
+        let state = dir.load(id).expect("no state file written");
+        assert_eq!((state.leader, state.term), (Some(leader), term), "Node {}", id);
+    }
+
+    cluster.kill(leader);
+    let new_leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader after the kill");
+    let new_term = cluster.handle(new_leader).snapshot().await.term;
+    assert!(new_term > term);
+    for id in (0..3).filter(|&id| id != leader) {
+        let state = dir.load(id).unwrap();
+        assert_eq!((state.leader, state.term), (Some(new_leader), new_term), "Node {}", id);
+    }
+    // The dead leader's file still holds what it last believed
+    assert_eq!(dir.load(leader).unwrap().term, term);
+}
+
+/// Node 0 of a three-node cluster, started alone with `before` in its state file
+fn start_alone(config: Config, dir: &StateDir, before: &PersistedState) -> (Cluster, NodeHandle) {
+    StateStore::new(&dir.0, 0).save(before).unwrap();
+    let mut cluster = Cluster::memory_idle(config);
+    let transport = cluster.network.as_ref().unwrap().transport(&cluster.config.nodes[0].bind_address);
+    let handle = cluster.spawn(0, |node| node.with_transport(transport).with_state_dir(&dir.0));
+    (cluster, handle)
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_starting_node_restores_the_leader_and_term_it_last_saw() {
+    let dir = StateDir::new("restore");
+    let before = PersistedState {
+        leader: Some(2),
+        successor: Some(1),
+        term: 9,
+    };
+    // Short of a quorum, node 0 cannot lead and keeps what it restored
+    let config = Config {
+        nodes: memory_nodes(3),
+        min_quorum: 2,
+        ..Config::default()
+    };
+    let (_cluster, handle) = start_alone(config, &dir, &before);
+
+    let mut changes = handle.leader_changes();
+    let restored = *timeout(SETTLE, changes.wait_for(|state| state.reason == LeaderChangeReason::Restored))
+        .await
+        .expect("state was not restored")
+        .unwrap();
+    assert_eq!((restored.leader, restored.am_i_leader, restored.term), (Some(2), false, 9));
+    let view = handle.snapshot().await;
+    assert_eq!((view.leader, view.successor, view.term), (Some(2), Some(1), 9));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_restarted_node_leads_in_the_term_after_the_one_it_saved() {
+    let dir = StateDir::new("next-term");
+    let before = PersistedState {
+        leader: Some(2),
+        successor: Some(1),
+        term: 9,
+    };
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let (_cluster, handle) = start_alone(config, &dir, &before);
+
+    // Finding nobody, node 0 leads; the term carries on rather than restarting
+    let led = *timeout(SETTLE, handle.leader_changes().wait_for(|state| state.am_i_leader))
+        .await
+        .expect("never led")
+        .unwrap();
+    assert_eq!(led.term, 10);
+    let after = PersistedState {
+        leader: Some(0),
+        successor: None,
+        term: 10,
+    };
+    assert_eq!(dir.load(0), Some(after));
+}
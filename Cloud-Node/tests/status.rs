@@ -0,0 +1,82 @@
+//! `StatusRequest` answered by any member of an in-memory cluster, as the
+//! `status` subcommand sends it
+
+mod common;
+
+use cloud_p2p::fault::FaultyTransport;
+use cloud_p2p::message::{Message, PeerState};
+use cloud_p2p::Config;
+use common::{memory_nodes, Cluster};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// Ask node `id` for its status on a fresh client connection
+async fn status(cluster: &Cluster, id: u32) -> Message {
+    let conn = cluster.dial_client("127.0.0.1:9200", id).await;
+    conn.send(&Message::StatusRequest {}).await.unwrap();
+    timeout(Duration::from_secs(5), conn.receive_one()).await.expect("no reply").unwrap()
+}
+
+#[tokio::test(start_paused = true)]
+async fn leader_and_follower_both_report_their_view() {
+    let config = Config {
+        nodes: memory_nodes(3),
+        ..Config::default()
+    };
+    let cluster = Cluster::memory(config);
+    let leader = timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let view = cluster.handle(leader).snapshot().await;
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+
+    for id in [leader, follower] {
+        let reply = status(&cluster, id).await;
+        let Message::StatusResponse { node_id, is_leader, leader: reported, successor, term, alive_nodes, peer_states, .. } =
+            reply
+        else {
+            panic!("expected a StatusResponse, got {:?}", reply);
+        };
+        assert_eq!(node_id, id);
+        assert_eq!(is_leader, id == leader);
+        assert_eq!((reported, successor, term), (Some(leader), view.successor, view.term));
+        // The follower counts its connections rather than heartbeats, which agree here
+        assert_eq!(alive_nodes, vec![0, 1, 2]);
+        let others: Vec<_> = (0..3).filter(|&other| other != id).map(|other| (other, PeerState::Connected)).collect();
+        assert_eq!(peer_states, others);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn round_trips_reflect_a_slow_link() {
+    // Node 1 leads, and its acks take 150 ms to reach node 0
+    let config = Config {
+        nodes: memory_nodes(2),
+        ..Config::default()
+    };
+    let delay = Duration::from_millis(150);
+    let cluster = Cluster::memory_with(config, |id, transport| {
+        if id == 1 {
+            let acks = |message: &Message| matches!(message, Message::HeartbeatAck { .. });
+            Arc::new(FaultyTransport::new(transport, 1).with_delay(acks, delay))
+        } else {
+            transport
+        }
+    });
+    assert_eq!(timeout(SETTLE, cluster.agreed_leader()).await.expect("no leader"), 1);
+    tokio::time::sleep(Duration::from_secs(30)).await;
+
+    // The follower times its heartbeats, and passes the figure on to the leader
+    for (id, peer) in [(0, 1), (1, 0)] {
+        let Message::StatusResponse { peer_rtt_ms, .. } = status(&cluster, id).await else {
+            panic!("expected a StatusResponse");
+        };
+        let [(rtt_peer, rtt_ms)] = peer_rtt_ms[..] else {
+            panic!("Node {} reports round trips {:?}", id, peer_rtt_ms);
+        };
+        assert_eq!(rtt_peer, peer);
+        assert!((150.0..160.0).contains(&rtt_ms), "Node {} measured {} ms", id, rtt_ms);
+    }
+}
@@ -0,0 +1,197 @@
+//! Mutual TLS between nodes over loopback TCP: only a certificate signed by
+//! the cluster CA and naming the node ID it claims gets a connection admitted
+
+mod common;
+
+use cloud_p2p::metrics::Metrics;
+use cloud_p2p::network::{MessageSender, NetworkLayer, PeerConnection};
+use cloud_p2p::tls::{self, TlsConfig};
+use cloud_p2p::Config;
+use common::{fast_timings, loopback_nodes, Cluster};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::{sleep, timeout};
+
+/// A throwaway CA, its certificate and the ones it issues kept as PEM files
+/// in a directory of their own, removed on drop
+struct Pki {
+    dir: PathBuf,
+    ca: CertifiedIssuer<'static, KeyPair>,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cloud-p2p-tls-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, format!("{} CA", name));
+        let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        Self { dir, ca }
+    }
+
+    /// A node's TLS config: a certificate for 127.0.0.1 named `common_name`,
+    /// signed by this CA, trusting the peers `trusted` has signed
+    fn issue(&self, common_name: &str, trusted: &Pki) -> TlsConfig {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = params.signed_by(&key, &self.ca).unwrap();
+
+        let cert_path = self.dir.join(format!("{}.pem", common_name));
+        let key_path = self.dir.join(format!("{}.key", common_name));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        TlsConfig::from_files(&cert_path, &key_path, &trusted.dir.join("ca.pem")).unwrap()
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Node 0 listening on `address` with `tls`, requiring client certificates
+struct Listener {
+    peers: Arc<RwLock<HashMap<u32, PeerConnection>>>,
+    metrics: Arc<Metrics>,
+    _rx: mpsc::Receiver<(u32, cloud_p2p::message::Message)>,
+}
+
+async fn listen(address: &str, tls: TlsConfig) -> Listener {
+    let network = NetworkLayer::new(address.to_string()).with_tls(tls.with_client_auth().unwrap());
+    let (tx, _rx) = mpsc::channel(64);
+    let metrics = Arc::new(Metrics::default());
+    let sender = MessageSender::new(tx, metrics.clone());
+    let (client_tx, _) = mpsc::channel(64);
+    let peers: Arc<RwLock<HashMap<u32, PeerConnection>>> = Arc::default();
+    tokio::spawn({
+        let peers = peers.clone();
+        async move { network.start_listener(0, sender, client_tx, peers).await }
+    });
+    timeout(Duration::from_secs(5), async {
+        while TcpStream::connect(address).await.is_err() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("listener did not start");
+    Listener { peers, metrics, _rx }
+}
+
+/// Dial node 0 at `target` as node 1 with `tls`. The handshake may complete
+/// on our side before node 0 has judged our certificate, so a refusal shows
+/// as the connection closing.
+async fn dial(address: &str, target: &str, tls: TlsConfig) -> Option<PeerConnection> {
+    NetworkLayer::new(address.to_string()).with_tls(tls).connect_to_peer(1, address, target).await.ok()
+}
+
+/// Wait a while for node 0 to register node 1
+async fn admitted(listener: &Listener) -> bool {
+    timeout(Duration::from_secs(2), async {
+        while !listener.peers.read().await.contains_key(&1) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_node_certified_by_the_cluster_ca_under_its_own_id_is_admitted() {
+    let pki = Pki::new("admitted");
+    let nodes = loopback_nodes(2);
+    let listener = listen(&nodes[0].bind_address, pki.issue("node-0", &pki)).await;
+
+    let conn = dial(&nodes[1].bind_address, &nodes[0].bind_address, pki.issue("node-1", &pki)).await;
+    assert!(conn.is_some(), "handshake failed");
+    assert!(admitted(&listener).await, "node 1 was not admitted");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_node_with_a_certificate_from_another_ca_is_refused() {
+    let pki = Pki::new("untrusted");
+    let rogue = Pki::new("untrusted-rogue");
+    let nodes = loopback_nodes(2);
+    let listener = listen(&nodes[0].bind_address, pki.issue("node-0", &pki)).await;
+
+    // The rogue trusts the cluster CA, so only node 0 can object
+    if let Some(conn) = dial(&nodes[1].bind_address, &nodes[0].bind_address, rogue.issue("node-1", &pki)).await {
+        assert!(conn.receive_one().await.is_err(), "connection stayed open");
+    }
+    assert!(!admitted(&listener).await, "an untrusted node was admitted");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_node_whose_certificate_names_another_node_is_refused() {
+    let pki = Pki::new("wrong-name");
+    let nodes = loopback_nodes(2);
+    let listener = listen(&nodes[0].bind_address, pki.issue("node-0", &pki)).await;
+
+    // A valid certificate, but for node 2, presented by a node claiming to be 1
+    let conn = dial(&nodes[1].bind_address, &nodes[0].bind_address, pki.issue("node-2", &pki))
+        .await
+        .expect("handshake failed");
+    assert!(conn.receive_one().await.is_err(), "connection stayed open");
+    assert!(!admitted(&listener).await, "node 1 was admitted on node 2's certificate");
+    assert!(listener.metrics.render().contains("\nconnections_refused_total 1\n"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn two_nodes_elect_a_leader_over_tls_and_turn_away_plaintext() {
+    let pki = Pki::new("cluster");
+    let config = Config {
+        nodes: loopback_nodes(2),
+        timings: fast_timings(),
+        ..Config::default()
+    };
+    let cluster = Cluster::tcp_with(config, |id, node| node.with_tls(pki.issue(&format!("node-{}", id), &pki)));
+    // Agreeing takes messages both ways: whichever node leads, the other follows it
+    timeout(Duration::from_secs(10), cluster.agreed_leader())
+        .await
+        .expect("no leader over TLS");
+
+    // A dialer speaking plaintext never gets a message through
+    let nodes = &cluster.config.nodes;
+    let plain = NetworkLayer::new(nodes[0].bind_address.clone())
+        .connect_to_peer(0, &nodes[0].bind_address, &nodes[1].bind_address)
+        .await;
+    if let Ok(conn) = plain {
+        let reply = timeout(Duration::from_secs(2), conn.receive_one()).await;
+        assert!(!matches!(reply, Ok(Ok(_))), "a plaintext peer got a reply: {:?}", reply);
+    }
+}
+
+#[test]
+fn common_name_is_found_among_other_subject_attributes() {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+    params.distinguished_name.push(DnType::CountryName, "SE");
+    params.distinguished_name.push(DnType::OrganizationName, "Cloud P2P");
+    params.distinguished_name.push(DnType::OrganizationalUnitName, "Storage");
+    params.distinguished_name.push(DnType::CommonName, "node-3");
+    let cert = params.self_signed(&key).unwrap();
+    assert_eq!(tls::common_name(cert.der()).as_deref(), Some("node-3"));
+
+    // Cut short anywhere, the certificate yields no name rather than a wrong one
+    for len in [0, 1, 4, cert.der().len() / 2, cert.der().len() - 1] {
+        let truncated = cert.der()[..len].to_vec().into();
+        assert_eq!(tls::common_name(&truncated), None, "read a name from {} bytes", len);
+    }
+
+    let mut params = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(DnType::OrganizationName, "Cloud P2P");
+    let cert = params.self_signed(&key).unwrap();
+    assert_eq!(tls::common_name(cert.der()), None);
+}